@@ -1,4 +1,4 @@
-use cleyto_coin::{generate, send};
+use cleyto_coin::{address, chain::key_type::KeyType, generate, send, DEFAULT_NODE_URL};
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -18,15 +18,19 @@ enum Args {
 
         #[structopt(long, short)]
         password: Option<String>,
+
+        /// Type of the key: rsa, secp256k1 or ed25519
+        #[structopt(long, default_value = "secp256k1")]
+        key_type: KeyType,
     },
 
     /// Send a transaction
     Send {
-        /// Recipient’s public key as a string
+        /// Recipient’s public key or address as a string
         #[structopt(long = "recipient-key", required_unless = "recipient-key-file")]
         recipient_key: Option<String>,
 
-        /// Recipient’s public key from a file
+        /// Recipient’s public key or address from a file
         #[structopt(
             long = "recipient-key-file",
             parse(from_os_str),
@@ -53,6 +57,25 @@ enum Args {
         /// Transaction amount
         #[structopt(long, short)]
         amount: u64,
+
+        /// The HTTP API of the node to get the funds from and send the transaction to
+        #[structopt(long, default_value = DEFAULT_NODE_URL)]
+        node_url: String,
+    },
+
+    /// Print the address of a public key, which can be used instead of it as a recipient
+    Address {
+        /// The public key as a string
+        #[structopt(long = "public-key", required_unless = "public-key-file")]
+        public_key: Option<String>,
+
+        /// The public key from a file
+        #[structopt(
+            long = "public-key-file",
+            parse(from_os_str),
+            required_unless = "public-key"
+        )]
+        public_key_file: Option<PathBuf>,
     },
 }
 
@@ -64,7 +87,8 @@ async fn main() {
             private_key_file,
             public_key_file,
             password,
-        } => generate(&private_key_file, &public_key_file, &password),
+            key_type,
+        } => generate(&private_key_file, &public_key_file, &password, key_type),
         Args::Send {
             recipient_key,
             recipient_key_file,
@@ -72,6 +96,7 @@ async fn main() {
             sender_key_file,
             password,
             amount,
+            node_url,
         } => {
             match send(
                 recipient_key,
@@ -80,6 +105,7 @@ async fn main() {
                 sender_key_file,
                 password,
                 amount,
+                &node_url,
             )
             .await
             {
@@ -87,5 +113,12 @@ async fn main() {
                 Err(e) => println!("Error {e} when sending transaction to server"),
             }
         }
+        Args::Address {
+            public_key,
+            public_key_file,
+        } => match address(&public_key, &public_key_file) {
+            Ok(address) => println!("{address}"),
+            Err(e) => println!("Error: {e}"),
+        },
     }
 }
@@ -16,7 +16,7 @@ async fn main() {
 //     let (wallet2, _) = Wallet::new();
 //     let transaction_info = TransactionInfo::new(0.3, Utc::now());
 //     let signature = wallet1_pk.sign_transaction(&transaction_info).unwrap();
-//     let transaction = match Transaction::new(wallet1, wallet2, transaction_info, signature) {
+//     let transaction = match Transaction::new(transaction_info, signature) {
 //         Ok(tx) => tx,
 //         Err(e) => match e {
 //             TransactionValidationError::OpenSSLError(_) => panic!("{e}"),
@@ -53,8 +53,7 @@ async fn post_json() -> Result<(), Box<dyn Error>> {
         signature
     );
 
-    let transaction: Transaction =
-        Transaction::new(wallet_sender, wallet_receiver, transactioninfo, signature).unwrap();
+    let transaction: Transaction = Transaction::new(transactioninfo, signature).unwrap();
     let transaction_json = transaction.serialize();
 
     // Send the POST request
@@ -1,11 +1,27 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
 use cleyto_coin::{
-    add_name_to_running_servers, kill_all_nodes, kill_node, new_server_name, run_server,
-    run_server_new_process, run_server_with_gui,
+    add_name_to_running_servers, kill_all_nodes, kill_node, new_server_name,
+    node::{data, devnet, p2p::NodeIdentity, NodeOptions},
+    run_server, run_server_new_process, run_server_with_gui, send_node_command, set_data_dir,
+    ConfigPaths,
 };
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "cleyto-coin-wallet")]
+struct Opt {
+    /// Where the node keeps its blocks, sockets, logs and running servers. Defaults to the
+    /// `data_dir` of the config file, or the data directory of the platform
+    #[structopt(long, global = true, parse(from_os_str))]
+    datadir: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Args,
+}
+
+#[derive(Debug, StructOpt)]
 enum Args {
     /// Kills the running server
     Kill {
@@ -27,13 +43,162 @@ enum Args {
 
         #[structopt(long)]
         name: Option<String>,
+
+        /// Keeps only the bodies of the last N blocks on disk, plus the headers and the UTXO set
+        #[structopt(long)]
+        prune: Option<u32>,
+
+        /// Data directory of a node with the whole history, to check the UTXO snapshot this node
+        /// was started from in the background
+        #[structopt(long, parse(from_os_str))]
+        verify_history: Option<PathBuf>,
+
+        /// Keeps an index of every confirmed transaction, for GET /tx/<txid>
+        #[structopt(long, conflicts_with = "prune")]
+        txindex: bool,
+
+        /// Keeps an index of what every address received and spent, for GET /address/<address>/...
+        #[structopt(long, conflicts_with = "prune")]
+        addrindex: bool,
+
+        /// Accepts other nodes on this port of localhost
+        #[structopt(long)]
+        p2p_port: Option<u16>,
+
+        /// Address of a node to connect to, like 127.0.0.1:9474. Can be given more than once
+        #[structopt(long, number_of_values = 1)]
+        connect: Vec<SocketAddr>,
+
+        /// Port of the HTTP API. Defaults to the port of the config file, or 9473
+        #[structopt(long)]
+        port: Option<u16>,
+
+        /// Address to accept HTTP requests and peers on, like 0.0.0.0 for every interface.
+        /// Defaults to the bind of the config file, or 127.0.0.1
+        #[structopt(long)]
+        bind: Option<IpAddr>,
+
+        /// Encrypts the connections to the nodes it connects to. Peers that encrypt theirs are
+        /// always accepted
+        #[structopt(long)]
+        encrypt: bool,
+    },
+
+    /// Prints the key this node encrypts its peer connections with, for other nodes to pin
+    Identity,
+
+    /// Runs several nodes on this machine, connected to each other
+    Devnet(DevnetCommand),
+
+    /// Keeps the peers from an address out of a running node
+    Ban {
+        /// Name of the running server
+        node: String,
+
+        ip: IpAddr,
+
+        /// In seconds. Defaults to the ban_duration of the config file
+        #[structopt(long)]
+        duration: Option<u64>,
+    },
+
+    /// Lets the peers from a banned address connect to a running node again
+    Unban {
+        /// Name of the running server
+        node: String,
+
+        ip: IpAddr,
+    },
+
+    /// Lists the addresses a running node has banned, and until when
+    Bans {
+        /// Name of the running server
+        node: String,
+    },
+
+    /// Adds the blocks still kept one per file, from before the block store, to the store
+    MigrateBlocks,
+
+    /// Rebuilds the block index from the raw block files
+    Reindex,
+
+    /// Writes the blocks between two heights to a file that import-chain can read
+    ExportChain {
+        /// Defaults to the first block with a body
+        #[structopt(long)]
+        from: Option<u32>,
+
+        /// Defaults to the tip of the chain
+        #[structopt(long)]
+        to: Option<u32>,
+
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+
+    /// Validates and stores the blocks of a file written by export-chain
+    ImportChain {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+
+    /// Writes the UTXO set at a height, with the headers needed to build on it, to a snapshot file
+    DumpUtxo {
+        /// Defaults to the tip of the chain
+        #[structopt(long)]
+        height: Option<u32>,
+
+        #[structopt(parse(from_os_str))]
+        snapshot: PathBuf,
+    },
+
+    /// Starts an empty data directory from a snapshot written by dump-utxo
+    LoadUtxo {
+        #[structopt(parse(from_os_str))]
+        snapshot: PathBuf,
+
+        /// The commitment printed by dump-utxo, from a source you trust
+        #[structopt(long)]
+        commitment: Option<String>,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum DevnetCommand {
+    /// Starts the nodes, each with its own data directory under devnet/ in the data directory
+    Up {
+        #[structopt(long, default_value = "3")]
+        nodes: u16,
+
+        /// HTTP port of the first node, the others take the ones after it
+        #[structopt(long, default_value = "9473")]
+        port: u16,
+
+        /// Peer port of the first node, the others take the ones after it. Defaults to the one
+        /// after the last HTTP port
+        #[structopt(long)]
+        p2p_port: Option<u16>,
+
+        /// Encrypts the connections between the nodes
+        #[structopt(long)]
+        encrypt: bool,
+    },
+
+    /// Stops every node of the devnet
+    Down {
+        /// Deletes their data directories too
+        #[structopt(long)]
+        clean: bool,
     },
 }
 
 fn main() {
-    let args = Args::from_args();
+    let opt = Opt::from_args();
+    if let Some(datadir) = opt.datadir {
+        set_data_dir(datadir).expect("The data directory was already set");
+    }
 
-    match args {
+    match opt.command {
         Args::Kill { node, all } => {
             if all {
                 kill_all_nodes();
@@ -42,10 +207,39 @@ fn main() {
                 kill_node(node).expect("Couldn't kill node");
             }
         }
+        Args::Ban { node, ip, duration } => {
+            let command = match duration {
+                Some(seconds) => format!("ban {ip} {seconds}"),
+                None => format!("ban {ip}"),
+            };
+            println!(
+                "{}",
+                send_node_command(&node, &command).expect("Couldn't reach the node")
+            );
+        }
+        Args::Unban { node, ip } => {
+            let reply = send_node_command(&node, &format!("unban {ip}"));
+            println!("{}", reply.expect("Couldn't reach the node"));
+        }
+        Args::Bans { node } => {
+            println!(
+                "{}",
+                send_node_command(&node, "bans").expect("Couldn't reach the node")
+            );
+        }
         Args::Start {
             gui,
             blocking,
             name,
+            prune,
+            verify_history,
+            txindex,
+            addrindex,
+            p2p_port,
+            connect,
+            port,
+            bind,
+            encrypt,
         } => {
             let server_name = if let Some(name) = name {
                 name
@@ -55,13 +249,87 @@ fn main() {
             println!("Starting server: {}", server_name);
             add_name_to_running_servers(server_name.clone());
 
+            let options = NodeOptions {
+                prune,
+                verify_history,
+                txindex,
+                addrindex,
+                p2p_port,
+                connect,
+                port,
+                bind,
+                encrypt,
+            };
             if gui {
-                run_server_with_gui(server_name.clone()).unwrap();
+                run_server_with_gui(server_name.clone(), options).unwrap();
             } else if blocking {
-                run_server(server_name.clone());
+                run_server(server_name.clone(), options);
             } else {
-                run_server_new_process(server_name.clone());
+                run_server_new_process(server_name.clone(), options);
+            }
+        }
+        Args::Devnet(DevnetCommand::Up {
+            nodes,
+            port,
+            p2p_port,
+            encrypt,
+        }) => {
+            let started = devnet::up(&ConfigPaths::get(), nodes, port, p2p_port, encrypt)
+                .expect("Couldn't start the devnet");
+            for node in started {
+                println!(
+                    "Started {} on port {}, peers on {}, pid {}",
+                    node.name,
+                    node.port,
+                    node.p2p_port,
+                    node.pid.unwrap_or_default()
+                );
             }
         }
+        Args::Devnet(DevnetCommand::Down { clean }) => {
+            let stopped =
+                devnet::down(&ConfigPaths::get(), clean).expect("Couldn't stop the devnet");
+            println!("Stopped {} devnet nodes", stopped.len());
+        }
+        Args::Identity => {
+            let identity = NodeIdentity::open_or_create(ConfigPaths::get().node_key_file())
+                .expect("Couldn't read the node key");
+            println!("{}", hex::encode(identity.public_key()));
+        }
+        Args::MigrateBlocks => {
+            let migrated = data::migrate_blocks().expect("Couldn't migrate the stored blocks");
+            println!("Added {migrated} blocks to the block store");
+        }
+        Args::Reindex => {
+            let stored = data::reindex_blocks().expect("Couldn't rebuild the block index");
+            println!("Indexed {stored} blocks");
+        }
+        Args::ExportChain { from, to, file } => {
+            let exported = data::export_chain(from, to, &file).expect("Couldn't export the chain");
+            println!("Exported {exported} blocks");
+        }
+        Args::ImportChain { file } => {
+            let imported = data::import_chain(&file).expect("Couldn't import the chain");
+            println!("Imported {imported} blocks");
+        }
+        Args::DumpUtxo { height, snapshot } => {
+            let dumped =
+                data::dump_utxo(height, &snapshot).expect("Couldn't write the UTXO snapshot");
+            println!(
+                "Wrote the UTXO set at height {} with commitment {}",
+                dumped.height, dumped.commitment
+            );
+        }
+        Args::LoadUtxo {
+            snapshot,
+            commitment,
+        } => {
+            let base = data::load_utxo(&snapshot, commitment.as_deref())
+                .expect("Couldn't load the UTXO snapshot");
+            println!(
+                "Loaded the UTXO set at height {} with commitment {}",
+                base.height, base.commitment
+            );
+        }
     }
 }
@@ -0,0 +1,140 @@
+use serde::{de, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::str::FromStr;
+
+/// Version byte of the addresses of the main network. It's what makes them start with a `C`
+pub const ADDRESS_VERSION: u8 = 0x1c;
+
+const HASH_LENGTH: usize = 20;
+const CHECKSUM_LENGTH: usize = 4;
+const ADDRESS_LENGTH: usize = 1 + HASH_LENGTH + CHECKSUM_LENGTH;
+
+// ------------------------------------------- Address errors definition -------------------------------------------
+#[derive(Debug, PartialEq, Eq)]
+pub enum AddressError {
+    InvalidBase58,
+    InvalidLength(usize),
+    InvalidChecksum,
+    UnknownVersion(u8),
+}
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AddressError::InvalidBase58 => write!(f, "The address is not valid Base58"),
+            AddressError::InvalidLength(length) => write!(
+                f,
+                "The address decodes to {length} bytes instead of {ADDRESS_LENGTH}"
+            ),
+            AddressError::InvalidChecksum => write!(
+                f,
+                "The checksum of the address doesn't match, there's probably a typo in it"
+            ),
+            AddressError::UnknownVersion(version) => {
+                write!(f, "The address has an unknown version byte {version:#04x}")
+            }
+        }
+    }
+}
+impl std::error::Error for AddressError {}
+// -----------------------------------------------------------------------------------------------------------------
+
+// ---------------------------------------------- Address definition -----------------------------------------------
+/// Short name for a public key: the first 20 bytes of the SHA-256 of the DER encoded key, behind a
+/// version byte, encoded in Base58Check. Outputs can be paid to it without knowing the whole key,
+/// which only has to be revealed when the output is spent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Address {
+    version: u8,
+    hash: [u8; HASH_LENGTH],
+}
+
+impl Address {
+    pub fn from_public_key_der(der: &[u8]) -> Self {
+        let digest = Sha256::digest(der);
+        let mut hash = [0u8; HASH_LENGTH];
+        hash.copy_from_slice(&digest[..HASH_LENGTH]);
+
+        Self {
+            version: ADDRESS_VERSION,
+            hash,
+        }
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn hash(&self) -> [u8; HASH_LENGTH] {
+        self.hash
+    }
+
+    fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LENGTH] {
+        let digest = Sha256::digest(Sha256::digest(payload));
+        let mut checksum = [0u8; CHECKSUM_LENGTH];
+        checksum.copy_from_slice(&digest[..CHECKSUM_LENGTH]);
+        checksum
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut bytes = Vec::with_capacity(ADDRESS_LENGTH);
+        bytes.push(self.version);
+        bytes.extend_from_slice(&self.hash);
+        let checksum = Self::checksum(&bytes);
+        bytes.extend_from_slice(&checksum);
+
+        write!(f, "{}", bs58::encode(bytes).into_string())
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = bs58::decode(s)
+            .into_vec()
+            .map_err(|_| AddressError::InvalidBase58)?;
+
+        if bytes.len() != ADDRESS_LENGTH {
+            return Err(AddressError::InvalidLength(bytes.len()));
+        }
+
+        let (payload, checksum) = bytes.split_at(1 + HASH_LENGTH);
+        if Self::checksum(payload) != checksum {
+            return Err(AddressError::InvalidChecksum);
+        }
+
+        if payload[0] != ADDRESS_VERSION {
+            return Err(AddressError::UnknownVersion(payload[0]));
+        }
+
+        let mut hash = [0u8; HASH_LENGTH];
+        hash.copy_from_slice(&payload[1..]);
+        Ok(Self {
+            version: payload[0],
+            hash,
+        })
+    }
+}
+
+impl Serialize for Address {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Address {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let address = String::deserialize(deserializer)?;
+        Address::from_str(&address).map_err(de::Error::custom)
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
@@ -3,7 +3,9 @@ use openssl::hash::{Hasher, MessageDigest};
 use serde::{Deserialize, Serialize};
 
 use super::transaction::Transaction;
-use super::utils::PROOF_OF_WORK_DIFFICULTY;
+use super::utils::{BLOCK_REWARD, PROOF_OF_WORK_DIFFICULTY};
+use super::utxo::UTXO;
+use super::wallet::Wallet;
 use super::Chain;
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -17,7 +19,85 @@ pub struct Block {
     nonce: u64,
 }
 
+/// Everything about a block but its transactions, which are only there as the merkle root. It's
+/// what's left of a block once a pruned node deletes its body
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub version: u32,
+    pub previous_hash: String,
+    pub merkle_root: [u8; 32],
+    pub index: u64,
+    pub timestamp: DateTime<Utc>,
+    pub hash: String,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    /// The hash the block should have. Only the merkle root of the transactions goes into it, so
+    /// the header is enough to check it
+    pub fn calculate_hash(&self) -> String {
+        let serialized = serde_json::to_string(&(
+            "BEGIN::BEGIN_PREVIOUS_HASH::",
+            &self.previous_hash,
+            "::END_PREVIOUS_HASH::BEGIN_TRANSACTIONS::",
+            "BEGIN_OF_TRANSACTION::",
+            self.merkle_root,
+            "::END_OF_TRANSACTION",
+            "::END_TRANSACTIONS::BEGIN_INDEX::",
+            &self.index,
+            "::END_INDEX::BEGIN_TIMESTAMP::",
+            &self.timestamp.to_string(),
+            "::END_TIMESTAMP::BEGIN_NONCE::",
+            &self.nonce,
+            "::END_NONCE::END",
+        ))
+        .expect("Coudn't serialize the block to create the hash");
+
+        let mut hasher = Hasher::new(MessageDigest::sha256()).unwrap();
+        hasher.update(serialized.as_bytes()).unwrap();
+        let result = hasher.finish().unwrap();
+
+        hex::encode(result) // Converts bytes to a hex string
+    }
+
+    /// Whether the hash has the proof of work, `difficulty` leading zeros
+    pub fn meets_difficulty(&self, difficulty: u8) -> bool {
+        self.hash
+            .bytes()
+            .take(difficulty.into())
+            .filter(|digit| *digit == b'0')
+            .count()
+            == difficulty as usize
+    }
+}
+
 impl Block {
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            version: self.version,
+            previous_hash: self.previous_hash.clone(),
+            merkle_root: Self::calculate_merkle_root(&self.transactions),
+            index: self.index,
+            timestamp: self.timestamp,
+            hash: self.hash.clone(),
+            nonce: self.nonce,
+        }
+    }
+
+    /// The block with this header and these transactions. Whether they go together is up to the
+    /// caller to check, `calculate_hash` gives the header's hash only if they do
+    pub fn from_header(header: BlockHeader, transactions: Vec<Transaction>) -> Self {
+        Self {
+            version: header.version,
+            previous_hash: header.previous_hash,
+            transactions,
+            index: header.index,
+            timestamp: header.timestamp,
+            hash: header.hash,
+            nonce: header.nonce,
+        }
+    }
+
     pub fn get_hash(&self) -> String {
         self.hash.clone()
     }
@@ -26,11 +106,28 @@ impl Block {
         self.index
     }
 
+    pub fn get_previous_hash(&self) -> &str {
+        &self.previous_hash
+    }
+
+    pub fn get_transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    pub fn get_timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
     /// This merkle tree does not work the same way as the bitcoin core one. If the number of
     /// leaves is not a power of two, it copies the last transaction's hash until we have enough
     /// leaves for a binary tree, and then it collapses the tree into the root, which is then
     /// returned.
     fn calculate_merkle_root(transactions: &[Transaction]) -> [u8; 32] {
+        // Like the genesis block. There's nothing to hash, and the loop below needs a last one
+        if transactions.is_empty() {
+            return [0; 32];
+        }
+
         // This gets the closest bigger power of 2
         let log_2 = f32::log2(transactions.len() as f32);
         let mut closest_log_2: u32 = log_2 as u32;
@@ -86,40 +183,7 @@ impl Block {
     }
 
     pub fn calculate_hash(&self) -> String {
-        //This here should be replaced by the calculation of the merkle tree
-        // let transactions_string = self
-        //     .transactions
-        //     .iter()
-        //     .map(|t| t.to_string()) // Calls the `to_string()` method of `Transaction`
-        //     .collect::<Vec<String>>() // Collects into a Vec<String>
-        //     .join("::END_OF_TRANSACTION::BEGIN_OF_TRANSACTION::"); // Joins all elements with "; " as separator
-
-        let merkle_root = Self::calculate_merkle_root(&self.transactions);
-
-        let serialized = serde_json::to_string(&(
-            "BEGIN::BEGIN_PREVIOUS_HASH::",
-            &self.previous_hash,
-            "::END_PREVIOUS_HASH::BEGIN_TRANSACTIONS::",
-            "BEGIN_OF_TRANSACTION::",
-            merkle_root,
-            "::END_OF_TRANSACTION",
-            "::END_TRANSACTIONS::BEGIN_INDEX::",
-            &self.index,
-            "::END_INDEX::BEGIN_TIMESTAMP::",
-            &self.timestamp.to_string(),
-            "::END_TIMESTAMP::BEGIN_NONCE::",
-            &self.nonce,
-            "::END_NONCE::END",
-        ))
-        .expect("Coudn't serialize the block to create the hash");
-
-        // println!("behold the serialized block:\n{serialized}");
-
-        let mut hasher = Hasher::new(MessageDigest::sha256()).unwrap();
-        hasher.update(serialized.as_bytes()).unwrap();
-        let result = hasher.finish().unwrap();
-
-        hex::encode(result) // Converts bytes to a hex string
+        self.header().calculate_hash()
     }
 
     pub fn new(chain: &mut Chain, transactions: Vec<Transaction>) -> Block {
@@ -156,8 +220,13 @@ impl Block {
         }
     }
 
-    pub fn mine_block(mut self) -> Self {
-        let prefix = "0".repeat(PROOF_OF_WORK_DIFFICULTY.into());
+    pub fn mine_block(self) -> Self {
+        self.mine_with_difficulty(PROOF_OF_WORK_DIFFICULTY)
+    }
+
+    /// Tries nonces until the hash starts with `difficulty` zeros
+    pub fn mine_with_difficulty(mut self, difficulty: u8) -> Self {
+        let prefix = "0".repeat(difficulty.into());
 
         while !self.hash.starts_with(&prefix) {
             self.nonce += 1;
@@ -167,16 +236,17 @@ impl Block {
         self
     }
 
+    /// A block on top of the chain with just a coinbase, paying the block reward to a new wallet
     pub fn test_block(chain: &Chain) -> Self {
         let previous_hash = chain.get_last_hash();
         let index = chain.get_last_index() + 1;
         let timestamp = Utc::now();
 
-        let transactions = vec![
-            Transaction::default(),
-            Transaction::default(),
-            Transaction::default(),
-        ];
+        let (miner, _) = Wallet::new();
+        let transactions = vec![Transaction::coinbase(
+            index,
+            vec![UTXO::new(BLOCK_REWARD, miner)],
+        )];
 
         let mut block = Self {
             version: 1,
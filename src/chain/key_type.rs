@@ -0,0 +1,165 @@
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey, EcPoint, PointConversionForm};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private, Public};
+use openssl::rsa::Rsa;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+// Tags in front of the compact encodings. PEM always starts with a '-', so there's no confusion
+// with the old format, in which every key was written as PEM
+const SECP256K1_TAG: u8 = 0x01;
+const ED25519_TAG: u8 = 0x02;
+
+// ---------------------------------------------- KeyType definition -----------------------------------------------
+/// The kinds of keys a wallet can have. RSA is what every wallet used to be, the elliptic curve
+/// ones have much smaller keys and signatures and are a lot faster to sign with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyType {
+    /// 2048-bit RSA, PKCS#1 v1.5 signatures over SHA-256
+    Rsa,
+    /// ECDSA over secp256k1 with SHA-256, same curve as bitcoin
+    #[default]
+    Secp256k1,
+    /// Ed25519, hashes the message itself
+    Ed25519,
+}
+
+impl KeyType {
+    pub fn generate(&self) -> Result<PKey<Private>, ErrorStack> {
+        match self {
+            KeyType::Rsa => PKey::from_rsa(Rsa::generate(2048)?),
+            KeyType::Secp256k1 => {
+                let group = EcGroup::from_curve_name(Nid::SECP256K1)?;
+                PKey::from_ec_key(EcKey::generate(&group)?)
+            }
+            KeyType::Ed25519 => PKey::generate_ed25519(),
+        }
+    }
+
+    /// Finds out the type of a key. Returns `None` for keys we don't support, like other curves
+    pub fn of<T: HasPublic>(key: &PKeyRef<T>) -> Option<KeyType> {
+        match key.id() {
+            Id::RSA => Some(KeyType::Rsa),
+            Id::ED25519 => Some(KeyType::Ed25519),
+            Id::EC => match key.ec_key().ok()?.group().curve_name() {
+                Some(Nid::SECP256K1) => Some(KeyType::Secp256k1),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Whether the key can only be used by transactions of version 2 onwards
+    pub fn is_elliptic_curve(&self) -> bool {
+        !matches!(self, KeyType::Rsa)
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyType::Rsa => write!(f, "rsa"),
+            KeyType::Secp256k1 => write!(f, "secp256k1"),
+            KeyType::Ed25519 => write!(f, "ed25519"),
+        }
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "rsa" => Ok(KeyType::Rsa),
+            "secp256k1" => Ok(KeyType::Secp256k1),
+            "ed25519" => Ok(KeyType::Ed25519),
+            _ => Err(format!(
+                "Unknown key type {s}, use one of rsa, secp256k1 or ed25519"
+            )),
+        }
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+// ------------------------------------------- Compact public key encoding -----------------------------------------
+/// Encodes a public key as small as it gets: a tag byte and the 33 byte compressed point for
+/// secp256k1, a tag byte and the 32 raw bytes for Ed25519. RSA keys have no compact form and stay
+/// as PEM, which also keeps them readable by older nodes.
+pub fn public_key_to_compact(key: &PKey<Public>) -> Result<Vec<u8>, ErrorStack> {
+    match KeyType::of(key) {
+        Some(KeyType::Secp256k1) => {
+            let ec_key = key.ec_key()?;
+            let mut ctx = BigNumContext::new()?;
+            let point = ec_key.public_key().to_bytes(
+                ec_key.group(),
+                PointConversionForm::COMPRESSED,
+                &mut ctx,
+            )?;
+
+            let mut compact = vec![SECP256K1_TAG];
+            compact.extend(point);
+            Ok(compact)
+        }
+        Some(KeyType::Ed25519) => {
+            let mut compact = vec![ED25519_TAG];
+            compact.extend(key.raw_public_key()?);
+            Ok(compact)
+        }
+        Some(KeyType::Rsa) | None => key.public_key_to_pem(),
+    }
+}
+
+/// Reads a key written by `public_key_to_compact`, or a PEM key as every key used to be written
+pub fn public_key_from_compact(bytes: &[u8]) -> Result<PKey<Public>, ErrorStack> {
+    match bytes.split_first() {
+        Some((&SECP256K1_TAG, point)) => {
+            let group = EcGroup::from_curve_name(Nid::SECP256K1)?;
+            let mut ctx = BigNumContext::new()?;
+            let point = EcPoint::from_bytes(&group, point, &mut ctx)?;
+            PKey::from_ec_key(EcKey::from_public_key(&group, &point)?)
+        }
+        Some((&ED25519_TAG, raw)) => PKey::public_key_from_raw_bytes(raw, Id::ED25519),
+        _ => PKey::public_key_from_pem(bytes),
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+// ------------------------------------------------ ECDSA low-s form -----------------------------------------------
+// (r, s) and (r, n - s) are both valid ECDSA signatures, so anyone relaying a transaction could
+// flip s and change its txid. Only the one with s in the lower half of the order is accepted.
+
+// n, the order of secp256k1, and n / 2
+fn order_and_half() -> Result<(BigNum, BigNum), ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::SECP256K1)?;
+    let mut ctx = BigNumContext::new()?;
+    let mut order = BigNum::new()?;
+    group.order(&mut order, &mut ctx)?;
+    let mut half = BigNum::new()?;
+    half.rshift1(&order)?;
+    Ok((order, half))
+}
+
+/// The same secp256k1 signature in DER, with s in the lower half of the order
+pub fn ecdsa_to_low_s(der: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let signature = EcdsaSig::from_der(der)?;
+    let (order, half) = order_and_half()?;
+    if signature.s() <= &*half {
+        return Ok(der.to_vec());
+    }
+    let mut s = BigNum::new()?;
+    s.checked_sub(&order, signature.s())?;
+    EcdsaSig::from_private_components(signature.r().to_owned()?, s)?.to_der()
+}
+
+/// Whether the secp256k1 signature in DER has s in the lower half of the order
+pub fn is_low_s(der: &[u8]) -> bool {
+    match (EcdsaSig::from_der(der), order_and_half()) {
+        (Ok(signature), Ok((_, half))) => signature.s() <= &*half,
+        _ => false,
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
@@ -1,8 +1,13 @@
+pub mod address;
 pub mod block;
+pub mod key_type;
 pub mod ordered_vector;
+pub mod schnorr;
+pub mod sighash;
 pub mod transaction;
 pub mod utils;
 pub mod utxo;
+pub mod utxo_set;
 pub mod wallet;
 mod wallet_pk;
 use block::Block;
@@ -48,6 +53,7 @@ impl Chain {
 pub mod testing {
     use super::Chain;
     use crate::chain::block::Block;
+    use crate::chain::utils::BLOCK_REWARD;
     use crate::chain::{
         transaction::{Transaction, TransactionInfo},
         utxo::UTXO,
@@ -61,7 +67,14 @@ pub mod testing {
         let wallet_4 = Wallet::new();
         let wallet_5 = Wallet::new();
 
-        // --- Block 1: wallet_1 splits 100000 evenly to itself and wallet_2 ---
+        // --- Block 1: wallet_1 gets the block reward of 100000 and splits it evenly to itself
+        //              and wallet_2 ---
+        let mut chain = Chain::new();
+        let coinbase = Transaction::coinbase(
+            chain.get_last_index() + 1,
+            vec![UTXO::new(BLOCK_REWARD, wallet_1.0.clone())],
+        );
+
         let utxos_1 = vec![UTXO::new(100000, wallet_1.0.clone())];
         let utxos_1_output = vec![
             UTXO::new(50000, wallet_1.0.clone()),
@@ -69,16 +82,9 @@ pub mod testing {
         ];
         let transaction_info_1 = TransactionInfo::new(utxos_1, utxos_1_output);
         let signature_1 = wallet_1.1.sign_transaction(&transaction_info_1).unwrap();
-        let transaction_1 = Transaction::new(
-            wallet_1.0.clone(),
-            wallet_2.0.clone(),
-            transaction_info_1,
-            signature_1,
-        )
-        .unwrap();
+        let transaction_1 = Transaction::new(transaction_info_1, signature_1).unwrap();
 
-        let mut chain = Chain::new();
-        let block_1 = Block::new(&mut chain, vec![transaction_1]);
+        let block_1 = Block::new(&mut chain, vec![coinbase, transaction_1]);
         chain.add_block(block_1);
 
         // --- Block 2: wallet_1 sends 50000 to wallet_3,
@@ -87,13 +93,7 @@ pub mod testing {
         let utxos_2_output = vec![UTXO::new(50000, wallet_3.0.clone())];
         let transaction_info_2 = TransactionInfo::new(utxos_2, utxos_2_output);
         let signature_2 = wallet_1.1.sign_transaction(&transaction_info_2).unwrap();
-        let transaction_2 = Transaction::new(
-            wallet_1.0.clone(),
-            wallet_3.0.clone(),
-            transaction_info_2,
-            signature_2,
-        )
-        .unwrap();
+        let transaction_2 = Transaction::new(transaction_info_2, signature_2).unwrap();
 
         let utxos_3 = vec![UTXO::new(50000, wallet_2.0.clone())];
         let utxos_3_output = vec![
@@ -102,13 +102,7 @@ pub mod testing {
         ];
         let transaction_info_3 = TransactionInfo::new(utxos_3, utxos_3_output);
         let signature_3 = wallet_2.1.sign_transaction(&transaction_info_3).unwrap();
-        let transaction_3 = Transaction::new(
-            wallet_2.0.clone(),
-            wallet_4.0.clone(),
-            transaction_info_3,
-            signature_3,
-        )
-        .unwrap();
+        let transaction_3 = Transaction::new(transaction_info_3, signature_3).unwrap();
 
         let block_2 = Block::new(&mut chain, vec![transaction_2, transaction_3]);
         chain.add_block(block_2);
@@ -122,13 +116,7 @@ pub mod testing {
         let utxos_4_output = vec![UTXO::new(75000, wallet_5.0.clone())];
         let transaction_info_4 = TransactionInfo::new(utxos_4, utxos_4_output);
         let signature_4 = wallet_3.1.sign_transaction(&transaction_info_4).unwrap();
-        let transaction_4 = Transaction::new(
-            wallet_3.0.clone(),
-            wallet_5.0.clone(),
-            transaction_info_4,
-            signature_4,
-        )
-        .unwrap();
+        let transaction_4 = Transaction::new(transaction_info_4, signature_4).unwrap();
 
         let utxos_5 = vec![UTXO::new(25000, wallet_4.0.clone())];
         let utxos_5_output = vec![
@@ -137,13 +125,7 @@ pub mod testing {
         ];
         let transaction_info_5 = TransactionInfo::new(utxos_5, utxos_5_output);
         let signature_5 = wallet_4.1.sign_transaction(&transaction_info_5).unwrap();
-        let transaction_5 = Transaction::new(
-            wallet_4.0.clone(),
-            wallet_5.0.clone(),
-            transaction_info_5,
-            signature_5,
-        )
-        .unwrap();
+        let transaction_5 = Transaction::new(transaction_info_5, signature_5).unwrap();
 
         let block_3 = Block::new(&mut chain, vec![transaction_4, transaction_5]);
         chain.add_block(block_3);
@@ -162,13 +144,7 @@ pub mod testing {
         ];
         let transaction_info_6 = TransactionInfo::new(utxos_6, utxos_6_output);
         let signature_6 = wallet_5.1.sign_transaction(&transaction_info_6).unwrap();
-        let transaction_6 = Transaction::new(
-            wallet_5.0.clone(),
-            wallet_1.0.clone(),
-            transaction_info_6,
-            signature_6,
-        )
-        .unwrap();
+        let transaction_6 = Transaction::new(transaction_info_6, signature_6).unwrap();
 
         let block_4 = Block::new(&mut chain, vec![transaction_6]);
         chain.add_block(block_4);
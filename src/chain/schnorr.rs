@@ -0,0 +1,388 @@
+//! Schnorr signatures over secp256k1, with MuSig style key aggregation so that several keys can
+//! produce a single signature together. A transaction spending inputs from many keys then carries
+//! one signature of 65 bytes instead of one per input, and the node verifies it once.
+//!
+//! Signing together goes in rounds:
+//! 1. every signer creates a `SchnorrNonce` and shares its public part
+//! 2. the public nonces are added up with `aggregate_nonces`
+//! 3. every signer makes its `partial_sign`ature with its nonce and private key
+//! 4. the partial signatures are added up with `aggregate_partial_signatures`
+//!
+//! Nonces must never be reused. When the signers don't trust each other, they should commit to the
+//! hash of their public nonce before any of them reveals it. `sign` runs all the rounds at once for
+//! when every key is at hand.
+
+use super::key_type::KeyType;
+use super::transaction::TransactionInfo;
+use super::wallet::{Wallet, WalletPK};
+use crate::error_handling::TransactionError;
+use openssl::bn::{BigNum, BigNumContext, BigNumRef};
+use openssl::ec::{EcGroup, EcPoint, EcPointRef, PointConversionForm};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::sha::Sha256;
+use serde::{Deserialize, Serialize};
+
+// Domain separation, so a hash made for one purpose can't be passed off as another
+const KEY_AGGREGATION_TAG: &[u8] = b"CLEYTOCOIN::SCHNORR::KEY_AGGREGATION";
+const CHALLENGE_TAG: &[u8] = b"CLEYTOCOIN::SCHNORR::CHALLENGE";
+
+// ------------------------------------------ SchnorrSignature definition ------------------------------------------
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SchnorrSignature {
+    /// The aggregated public nonce R, as a compressed point
+    pub nonce_point: Vec<u8>,
+    /// The aggregated s, 32 bytes big endian
+    pub s: Vec<u8>,
+}
+
+impl SchnorrSignature {
+    pub fn len(&self) -> usize {
+        self.nonce_point.len() + self.s.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+// -------------------------------------------- SchnorrNonce definition --------------------------------------------
+/// The secret nonce of one signer for one signature. Dropped after being used on purpose.
+pub struct SchnorrNonce {
+    secret: BigNum,
+    public: Vec<u8>,
+}
+
+impl SchnorrNonce {
+    pub fn new() -> Result<Self, ErrorStack> {
+        let group = secp256k1()?;
+        let mut ctx = BigNumContext::new()?;
+        let mut order = BigNum::new()?;
+        group.order(&mut order, &mut ctx)?;
+
+        let mut secret = BigNum::new()?;
+        while secret.num_bits() == 0 {
+            order.rand_range(&mut secret)?;
+        }
+
+        let mut point = EcPoint::new(&group)?;
+        point.mul_generator(&group, &secret, &ctx)?;
+        let public = point.to_bytes(&group, PointConversionForm::COMPRESSED, &mut ctx)?;
+
+        Ok(Self { secret, public })
+    }
+
+    /// The part of the nonce that is shared with the other signers
+    pub fn public(&self) -> &[u8] {
+        &self.public
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+fn secp256k1() -> Result<EcGroup, ErrorStack> {
+    EcGroup::from_curve_name(Nid::SECP256K1)
+}
+
+fn hash_to_scalar(
+    parts: &[&[u8]],
+    order: &BigNumRef,
+    ctx: &mut BigNumContext,
+) -> Result<BigNum, ErrorStack> {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    let hash = BigNum::from_slice(&hasher.finish())?;
+
+    let mut scalar = BigNum::new()?;
+    scalar.nnmod(&hash, order, ctx)?;
+    Ok(scalar)
+}
+
+fn compressed_public_key(wallet: &Wallet, ctx: &mut BigNumContext) -> Result<Vec<u8>, ErrorStack> {
+    let ec_key = wallet.public_key.ec_key()?;
+    ec_key
+        .public_key()
+        .to_bytes(ec_key.group(), PointConversionForm::COMPRESSED, ctx)
+}
+
+fn point_to_bytes(
+    group: &EcGroup,
+    point: &EcPointRef,
+    ctx: &mut BigNumContext,
+) -> Result<Vec<u8>, ErrorStack> {
+    point.to_bytes(group, PointConversionForm::COMPRESSED, ctx)
+}
+
+/// The aggregated key of the signers together with the coefficient of each one of them. The
+/// coefficients depend on every key, so no signer can pick its key to cancel out the others'.
+struct AggregatedKey {
+    point: EcPoint,
+    coefficients: Vec<BigNum>,
+}
+
+fn aggregate_keys(
+    signers: &[Wallet],
+    group: &EcGroup,
+    order: &BigNumRef,
+    ctx: &mut BigNumContext,
+) -> Result<Option<AggregatedKey>, ErrorStack> {
+    if signers.is_empty()
+        || signers
+            .iter()
+            .any(|signer| signer.key_type() != Some(KeyType::Secp256k1))
+    {
+        return Ok(None);
+    }
+
+    let keys = signers
+        .iter()
+        .map(|signer| compressed_public_key(signer, ctx))
+        .collect::<Result<Vec<Vec<u8>>, ErrorStack>>()?;
+
+    let mut keys_hasher = Sha256::new();
+    for key in &keys {
+        keys_hasher.update(key);
+    }
+    let keys_hash = keys_hasher.finish();
+
+    let mut point = EcPoint::new(group)?;
+    let mut coefficients = Vec::with_capacity(signers.len());
+    for (signer, key) in signers.iter().zip(&keys) {
+        let coefficient = hash_to_scalar(&[KEY_AGGREGATION_TAG, &keys_hash, key], order, ctx)?;
+
+        let ec_key = signer.public_key.ec_key()?;
+        let mut weighted = EcPoint::new(group)?;
+        weighted.mul(group, ec_key.public_key(), &coefficient, ctx)?;
+
+        let mut sum = EcPoint::new(group)?;
+        sum.add(group, &point, &weighted, ctx)?;
+        point = sum;
+
+        coefficients.push(coefficient);
+    }
+
+    Ok(Some(AggregatedKey {
+        point,
+        coefficients,
+    }))
+}
+
+fn challenge(
+    nonce_point: &[u8],
+    aggregated_key: &[u8],
+    message: &[u8],
+    order: &BigNumRef,
+    ctx: &mut BigNumContext,
+) -> Result<BigNum, ErrorStack> {
+    hash_to_scalar(
+        &[CHALLENGE_TAG, nonce_point, aggregated_key, message],
+        order,
+        ctx,
+    )
+}
+
+/// Adds up the public nonces of every signer into the R of the signature
+pub fn aggregate_nonces(public_nonces: &[&[u8]]) -> Result<Vec<u8>, ErrorStack> {
+    let group = secp256k1()?;
+    let mut ctx = BigNumContext::new()?;
+
+    let mut point = EcPoint::new(&group)?;
+    for public_nonce in public_nonces {
+        let nonce = EcPoint::from_bytes(&group, public_nonce, &mut ctx)?;
+        let mut sum = EcPoint::new(&group)?;
+        sum.add(&group, &point, &nonce, &mut ctx)?;
+        point = sum;
+    }
+
+    point_to_bytes(&group, &point, &mut ctx)
+}
+
+/// The share of the signature of the signer at `signer_index` in `signers`. Returns `None` if the
+/// private key doesn't belong to that signer or if some signer doesn't have a secp256k1 key.
+pub fn partial_sign(
+    private_key: &WalletPK,
+    nonce: SchnorrNonce,
+    aggregated_nonce: &[u8],
+    signers: &[Wallet],
+    signer_index: usize,
+    message: &[u8],
+) -> Result<Option<Vec<u8>>, ErrorStack> {
+    if signers.get(signer_index) != Some(&private_key.public_wallet()) {
+        return Ok(None);
+    }
+
+    let group = secp256k1()?;
+    let mut ctx = BigNumContext::new()?;
+    let mut order = BigNum::new()?;
+    group.order(&mut order, &mut ctx)?;
+
+    let Some(aggregated_key) = aggregate_keys(signers, &group, &order, &mut ctx)? else {
+        return Ok(None);
+    };
+    let aggregated_key_bytes = point_to_bytes(&group, &aggregated_key.point, &mut ctx)?;
+    let e = challenge(
+        aggregated_nonce,
+        &aggregated_key_bytes,
+        message,
+        &order,
+        &mut ctx,
+    )?;
+
+    // s_i = k_i + e * a_i * x_i
+    let ec_key = private_key.private_key.ec_key()?;
+    let mut weighted_secret = BigNum::new()?;
+    weighted_secret.mod_mul(
+        &aggregated_key.coefficients[signer_index],
+        ec_key.private_key(),
+        &order,
+        &mut ctx,
+    )?;
+    let mut challenged_secret = BigNum::new()?;
+    challenged_secret.mod_mul(&e, &weighted_secret, &order, &mut ctx)?;
+    let mut s = BigNum::new()?;
+    s.mod_add(&nonce.secret, &challenged_secret, &order, &mut ctx)?;
+
+    Ok(Some(s.to_vec_padded(32)?))
+}
+
+/// Adds up the partial signatures of every signer into the final signature
+pub fn aggregate_partial_signatures(
+    aggregated_nonce: &[u8],
+    partial_signatures: &[Vec<u8>],
+) -> Result<SchnorrSignature, ErrorStack> {
+    let group = secp256k1()?;
+    let mut ctx = BigNumContext::new()?;
+    let mut order = BigNum::new()?;
+    group.order(&mut order, &mut ctx)?;
+
+    let mut s = BigNum::new()?;
+    for partial_signature in partial_signatures {
+        let partial = BigNum::from_slice(partial_signature)?;
+        let mut sum = BigNum::new()?;
+        sum.mod_add(&s, &partial, &order, &mut ctx)?;
+        s = sum;
+    }
+
+    Ok(SchnorrSignature {
+        nonce_point: aggregated_nonce.to_vec(),
+        s: s.to_vec_padded(32)?,
+    })
+}
+
+/// Runs every round of the signature at once, for when all the private keys are at hand. The keys
+/// must be given in the same order as `signers`.
+pub fn sign(
+    private_keys: &[&WalletPK],
+    signers: &[Wallet],
+    message: &[u8],
+) -> Result<Option<SchnorrSignature>, ErrorStack> {
+    if private_keys.len() != signers.len() {
+        return Ok(None);
+    }
+
+    let nonces = private_keys
+        .iter()
+        .map(|_| SchnorrNonce::new())
+        .collect::<Result<Vec<SchnorrNonce>, ErrorStack>>()?;
+    let public_nonces: Vec<&[u8]> = nonces.iter().map(|nonce| nonce.public()).collect();
+    let aggregated_nonce = aggregate_nonces(&public_nonces)?;
+
+    let mut partial_signatures = Vec::with_capacity(signers.len());
+    for (index, (private_key, nonce)) in private_keys.iter().zip(nonces).enumerate() {
+        match partial_sign(
+            private_key,
+            nonce,
+            &aggregated_nonce,
+            signers,
+            index,
+            message,
+        )? {
+            Some(partial_signature) => partial_signatures.push(partial_signature),
+            None => return Ok(None),
+        }
+    }
+
+    aggregate_partial_signatures(&aggregated_nonce, &partial_signatures).map(Some)
+}
+
+/// Signs every input of the transaction at once with the keys of all of its owners, in any order.
+/// The result goes into `Transaction::new_aggregated`.
+pub fn sign_transaction(
+    transaction_info: &TransactionInfo,
+    private_keys: &[&WalletPK],
+) -> Result<SchnorrSignature, TransactionError> {
+    let signers = transaction_info
+        .signers()
+        .ok_or(TransactionError::MissingPublicKey)?;
+    if signers
+        .iter()
+        .any(|signer| signer.key_type() != Some(KeyType::Secp256k1))
+    {
+        return Err(TransactionError::UnsupportedKeyType);
+    }
+
+    // Line the keys up with the signers they belong to
+    let ordered_keys = signers
+        .iter()
+        .map(|signer| {
+            private_keys
+                .iter()
+                .find(|private_key| &private_key.public_wallet() == signer)
+                .copied()
+                .ok_or(TransactionError::SignatureCountMismatch)
+        })
+        .collect::<Result<Vec<&WalletPK>, TransactionError>>()?;
+
+    sign(
+        &ordered_keys,
+        &signers,
+        transaction_info.aggregate_preimage().as_bytes(),
+    )
+    .map_err(TransactionError::OpenSSLError)?
+    .ok_or(TransactionError::ValidationError)
+}
+
+/// Checks `s * G == R + e * X`, where X is the aggregated key of the signers
+pub fn verify(
+    signers: &[Wallet],
+    message: &[u8],
+    signature: &SchnorrSignature,
+) -> Result<bool, ErrorStack> {
+    let group = secp256k1()?;
+    let mut ctx = BigNumContext::new()?;
+    let mut order = BigNum::new()?;
+    group.order(&mut order, &mut ctx)?;
+
+    let Some(aggregated_key) = aggregate_keys(signers, &group, &order, &mut ctx)? else {
+        return Ok(false);
+    };
+    let aggregated_key_bytes = point_to_bytes(&group, &aggregated_key.point, &mut ctx)?;
+
+    let Ok(nonce_point) = EcPoint::from_bytes(&group, &signature.nonce_point, &mut ctx) else {
+        return Ok(false);
+    };
+    let s = BigNum::from_slice(&signature.s)?;
+    if s >= order {
+        return Ok(false);
+    }
+
+    let e = challenge(
+        &signature.nonce_point,
+        &aggregated_key_bytes,
+        message,
+        &order,
+        &mut ctx,
+    )?;
+
+    let mut left = EcPoint::new(&group)?;
+    left.mul_generator(&group, &s, &ctx)?;
+
+    let mut challenged_key = EcPoint::new(&group)?;
+    challenged_key.mul(&group, &aggregated_key.point, &e, &ctx)?;
+    let mut right = EcPoint::new(&group)?;
+    right.add(&group, &nonce_point, &challenged_key, &mut ctx)?;
+
+    left.eq(&group, &right, &mut ctx)
+}
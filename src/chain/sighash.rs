@@ -0,0 +1,92 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// ----------------------------------------------- SigHash definition ----------------------------------------------
+/// Which outputs an input's signature commits to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SigHashType {
+    /// Commits to every output, nobody can change where the money goes
+    All,
+    /// Commits to no output, whoever finishes the transaction decides where the money goes
+    None,
+    /// Commits only to the output with the same index as the signed input
+    Single,
+    /// Commits to the whole `TransactionInfo`, the way transactions were signed before every input
+    /// had its own signature. Only shows up in migrated blocks, the node won't take new ones
+    Legacy,
+}
+
+/// The sighash flags of a single input signature. `anyone_can_pay` makes the signature commit
+/// only to its own input, so other people are free to add inputs to the transaction afterwards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SigHash {
+    pub kind: SigHashType,
+    pub anyone_can_pay: bool,
+}
+
+impl SigHash {
+    pub const ALL: SigHash = SigHash::new(SigHashType::All, false);
+    pub const NONE: SigHash = SigHash::new(SigHashType::None, false);
+    pub const SINGLE: SigHash = SigHash::new(SigHashType::Single, false);
+    pub const LEGACY: SigHash = SigHash::new(SigHashType::Legacy, false);
+
+    pub const fn new(kind: SigHashType, anyone_can_pay: bool) -> Self {
+        Self {
+            kind,
+            anyone_can_pay,
+        }
+    }
+
+    /// Same encoding bitcoin uses, so the flags can be written as a single byte in the preimage
+    pub fn to_byte(&self) -> u8 {
+        let kind = match self.kind {
+            SigHashType::All => 0x01,
+            SigHashType::None => 0x02,
+            SigHashType::Single => 0x03,
+            SigHashType::Legacy => 0x00,
+        };
+        if self.anyone_can_pay {
+            kind | 0x80
+        } else {
+            kind
+        }
+    }
+}
+
+impl Default for SigHash {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+impl fmt::Display for SigHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            SigHashType::All => "ALL",
+            SigHashType::None => "NONE",
+            SigHashType::Single => "SINGLE",
+            SigHashType::Legacy => "LEGACY",
+        };
+        if self.anyone_can_pay {
+            write!(f, "{kind}|ANYONECANPAY")
+        } else {
+            write!(f, "{kind}")
+        }
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+// -------------------------------------------- InputSignature definition ------------------------------------------
+/// The signature of one input, made by the owner of the UTXO being spent
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputSignature {
+    pub sighash: SigHash,
+    pub signature: Vec<u8>,
+}
+
+impl InputSignature {
+    pub fn new(sighash: SigHash, signature: Vec<u8>) -> Self {
+        Self { sighash, signature }
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
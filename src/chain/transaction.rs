@@ -1,6 +1,9 @@
 use crate::error_handling::TransactionDeserializeError;
 use crate::error_handling::TransactionError;
 
+use super::key_type::KeyType;
+use super::schnorr::{self, SchnorrSignature};
+use super::sighash::{InputSignature, SigHash, SigHashType};
 use super::utxo::UTXO;
 use super::wallet::Wallet;
 use chrono::{DateTime, Utc};
@@ -10,23 +13,135 @@ use std::fmt;
 use std::fmt::Debug;
 use std::fmt::Display;
 
+/// Consensus version of the transactions created by this node.
+/// - 1: only RSA keys, every transaction stored before versions existed is one of these
+/// - 2: elliptic curve keys (secp256k1 and Ed25519) too. RSA outputs are still spendable
+/// - 3: secp256k1 inputs can share one aggregated Schnorr signature instead of one each
+pub const TRANSACTION_VERSION: u32 = 3;
+
+/// First version in which a transaction can carry an aggregated signature
+pub const AGGREGATE_SIGNATURE_VERSION: u32 = 3;
+
+fn first_transaction_version() -> u32 {
+    1
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 // ---------------------------------------------- TransactionInfo definition -----------------------
 pub struct TransactionInfo {
+    #[serde(default = "first_transaction_version")]
+    pub version: u32,
     pub inputs: Vec<UTXO>,
     pub outputs: Vec<UTXO>,
     pub date: DateTime<Utc>,
+    /// Only coinbases have it, the index of the block they're in. It goes into the txid, so
+    /// coinbases paying the same to the same owner in different blocks don't get the same one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub coinbase_height: Option<u64>,
 }
 
 impl TransactionInfo {
     pub fn new(inputs: Vec<UTXO>, outputs: Vec<UTXO>) -> TransactionInfo {
         let date = Utc::now();
         Self {
+            version: TRANSACTION_VERSION,
             inputs,
             outputs,
             date,
+            coinbase_height: None,
+        }
+    }
+
+    /// Checks that the version is one we know, and that it allows the keys used in the inputs and
+    /// outputs
+    pub fn check_version(&self) -> Result<(), TransactionError> {
+        if self.version == 0 || self.version > TRANSACTION_VERSION {
+            return Err(TransactionError::UnsupportedVersion(self.version));
+        }
+
+        for utxo in self.inputs.iter().chain(&self.outputs) {
+            // Outputs paid to an address don't say which kind of key is behind it
+            let Some(owner) = utxo.owner().public_key() else {
+                continue;
+            };
+            match owner.key_type() {
+                Some(key_type) if key_type.is_elliptic_curve() && self.version < 2 => {
+                    return Err(TransactionError::UnsupportedKeyType)
+                }
+                Some(_) => {}
+                None => return Err(TransactionError::UnsupportedKeyType),
+            }
+        }
+        Ok(())
+    }
+
+    // Version 1 had nothing in front, so old signatures and txids stay the same
+    fn version_prefix(&self) -> String {
+        match self.version {
+            1 => String::new(),
+            version => format!("VERSION::{version}::"),
         }
     }
+
+    /// Builds the message signed for the input at `input_index`. The sighash flags decide which
+    /// inputs and outputs end up in it. Returns `None` if there is no such input, or if the flag is
+    /// `SINGLE` and there is no output with the same index.
+    pub fn sighash_preimage(&self, input_index: usize, sighash: SigHash) -> Option<String> {
+        let signed_input = self.inputs.get(input_index)?;
+
+        let outputs: String = match sighash.kind {
+            SigHashType::All => self
+                .outputs
+                .iter()
+                .map(|output| output.to_string())
+                .collect::<Vec<String>>()
+                .join("::"),
+            SigHashType::None => String::new(),
+            SigHashType::Single => self.outputs.get(input_index)?.to_string(),
+            // Old signatures cover the whole transaction info, whatever the other flags say
+            SigHashType::Legacy => return Some(self.to_string()),
+        };
+
+        let inputs: String = if sighash.anyone_can_pay {
+            signed_input.to_string()
+        } else {
+            self.inputs
+                .iter()
+                .map(|input| input.to_string())
+                .collect::<Vec<String>>()
+                .join("::")
+        };
+
+        Some(format!(
+            "{}SIGHASH::{}::INPUT_INDEX::{}::DATE::{}::INPUTS::{}::OUTPUTS::{}",
+            self.version_prefix(),
+            sighash.to_byte(),
+            input_index,
+            self.date.to_rfc3339(),
+            inputs,
+            outputs
+        ))
+    }
+
+    /// The message signed by an aggregated signature. It always commits to the date and to every
+    /// input and output, there are no sighash flags for it.
+    pub fn aggregate_preimage(&self) -> String {
+        format!("AGGREGATE::DATE::{}::{self}", self.date.to_rfc3339())
+    }
+
+    /// The distinct owners of the inputs, in the order they first show up. These are the keys that
+    /// have to take part in an aggregated signature. Returns `None` if some input only has an
+    /// address.
+    pub fn signers(&self) -> Option<Vec<Wallet>> {
+        let mut signers: Vec<Wallet> = Vec::new();
+        for input in &self.inputs {
+            let owner = input.owner().public_key()?;
+            if !signers.contains(owner) {
+                signers.push(owner.clone());
+            }
+        }
+        Some(signers)
+    }
 }
 
 impl Display for TransactionInfo {
@@ -47,7 +162,19 @@ impl Display for TransactionInfo {
             .collect::<Vec<String>>()
             .join("::");
 
-        write!(f, "INPUTS::{}:OUTPUTS::{}", inputs, outputs)
+        let coinbase: String = match self.coinbase_height {
+            Some(height) => format!("COINBASE::{height}::"),
+            None => String::new(),
+        };
+
+        write!(
+            f,
+            "{}{}INPUTS::{}:OUTPUTS::{}",
+            self.version_prefix(),
+            coinbase,
+            inputs,
+            outputs
+        )
     }
 }
 // -------------------------------------------------------------------------------------------------
@@ -55,26 +182,97 @@ impl Display for TransactionInfo {
 // ------------------------------------- Transaction definition ------------------------------------
 
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "TransactionFormat")]
 pub struct Transaction {
-    pub sender: Wallet,
-    pub receiver: Wallet,
-    pub signature: Vec<u8>,
+    /// One signature per input, in the same order as `transaction_info.inputs`. Empty when the
+    /// transaction carries an `aggregate_signature` instead
+    pub signatures: Vec<InputSignature>,
+    /// A single Schnorr signature made together by every owner of the inputs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggregate_signature: Option<SchnorrSignature>,
     pub transaction_info: TransactionInfo,
     pub txid: [u8; 32],
 }
 
-// TODO eventually, I want to make the transactions not need to have the sender adress
+/// Every shape a transaction was ever stored with, so old blocks can still be read. Who sends and
+/// who receives used to be written in the transaction itself, now it only comes from the owners
+/// of the inputs and outputs.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TransactionFormat {
+    Current {
+        signatures: Vec<InputSignature>,
+        #[serde(default)]
+        aggregate_signature: Option<SchnorrSignature>,
+        transaction_info: TransactionInfo,
+        txid: [u8; 32],
+    },
+    // One signature by the `sender` wallet over the whole TransactionInfo. The sender and
+    // receiver fields are ignored, and the txid is kept so the merkle root of the block holding
+    // the transaction doesn't change
+    Legacy {
+        signature: Vec<u8>,
+        transaction_info: TransactionInfo,
+        txid: [u8; 32],
+    },
+}
+
+impl From<TransactionFormat> for Transaction {
+    fn from(format: TransactionFormat) -> Self {
+        match format {
+            TransactionFormat::Current {
+                signatures,
+                aggregate_signature,
+                transaction_info,
+                txid,
+            } => Self {
+                signatures,
+                aggregate_signature,
+                transaction_info,
+                txid,
+            },
+            TransactionFormat::Legacy {
+                signature,
+                transaction_info,
+                txid,
+            } => Self {
+                signatures: transaction_info
+                    .inputs
+                    .iter()
+                    .map(|_| InputSignature::new(SigHash::LEGACY, signature.clone()))
+                    .collect(),
+                aggregate_signature: None,
+                transaction_info,
+                txid,
+            },
+        }
+    }
+}
+
 impl Transaction {
     pub fn new(
-        sender: Wallet,
-        receiver: Wallet,
         transaction_info: TransactionInfo,
-        signature: Vec<u8>,
+        signatures: Vec<InputSignature>,
+    ) -> Result<Self, TransactionError> {
+        Self::build(transaction_info, signatures, None)
+    }
+
+    /// A transaction whose inputs are all covered by one aggregated signature, see `schnorr`
+    pub fn new_aggregated(
+        transaction_info: TransactionInfo,
+        aggregate_signature: SchnorrSignature,
+    ) -> Result<Self, TransactionError> {
+        Self::build(transaction_info, Vec::new(), Some(aggregate_signature))
+    }
+
+    fn build(
+        transaction_info: TransactionInfo,
+        signatures: Vec<InputSignature>,
+        aggregate_signature: Option<SchnorrSignature>,
     ) -> Result<Self, TransactionError> {
         let mut transaction = Self {
-            sender,
-            receiver,
-            signature,
+            signatures,
+            aggregate_signature,
             transaction_info,
             txid: [0; 32], // This could be optimized by avoiding the creation of this Vec, which
                            // serves no function on its own, but I don't really see that being a problem
@@ -88,10 +286,7 @@ impl Transaction {
             return Err(TransactionError::InsufficientInputs);
         }
 
-        let to_hash = transaction.to_string();
-        let mut hasher: Sha256 = Sha256::new();
-        hasher.update(to_hash.as_bytes());
-        transaction.txid = hasher.finish().to_owned();
+        transaction.txid = transaction.calculate_txid();
 
         match transaction.verify_signature() {
             Ok(()) => Ok(transaction),
@@ -99,19 +294,118 @@ impl Transaction {
         }
     }
 
+    /// The first transaction of the block at `height`, paying the block reward and the fees of
+    /// the other transactions to whoever made the block. It spends nothing, so there's nothing
+    /// to sign either
+    pub fn coinbase(height: u64, outputs: Vec<UTXO>) -> Self {
+        let mut transaction_info = TransactionInfo::new(Vec::new(), outputs);
+        transaction_info.coinbase_height = Some(height);
+
+        let mut transaction = Self {
+            signatures: Vec::new(),
+            aggregate_signature: None,
+            transaction_info,
+            txid: [0; 32],
+        };
+        transaction.txid = transaction.calculate_txid();
+        transaction
+    }
+
+    pub fn is_coinbase(&self) -> bool {
+        self.transaction_info.coinbase_height.is_some()
+    }
+
+    fn calculate_txid(&self) -> [u8; 32] {
+        let mut hasher: Sha256 = Sha256::new();
+        hasher.update(self.to_string().as_bytes());
+        hasher.finish()
+    }
+
+    /// Checks that every input carries a valid signature from the owner of the UTXO it spends
     pub(crate) fn verify_signature(&self) -> Result<(), TransactionError> {
-        match self
-            .sender
-            .verify_transaction_info(&self.transaction_info, &self.signature)
+        self.transaction_info.check_version()?;
+
+        if let Some(aggregate_signature) = &self.aggregate_signature {
+            return self.verify_aggregate_signature(aggregate_signature);
+        }
+
+        if self.signatures.len() != self.transaction_info.inputs.len() {
+            return Err(TransactionError::SignatureCountMismatch);
+        }
+
+        for (index, (input, signature)) in self
+            .transaction_info
+            .inputs
+            .iter()
+            .zip(&self.signatures)
+            .enumerate()
         {
-            Ok(value) => match value {
-                true => Ok(()),
-                false => Err(TransactionError::ValidationError),
-            },
+            if self
+                .transaction_info
+                .sighash_preimage(index, signature.sighash)
+                .is_none()
+            {
+                return Err(TransactionError::InvalidSigHash);
+            }
+
+            let owner = input
+                .owner()
+                .public_key()
+                .ok_or(TransactionError::MissingPublicKey)?;
+
+            match owner.verify_transaction_info(&self.transaction_info, index, signature) {
+                Ok(true) => {}
+                Ok(false) => return Err(TransactionError::ValidationError),
+                Err(stack) => return Err(TransactionError::OpenSSLError(stack)),
+            }
+        }
+        Ok(())
+    }
+
+    // One check for the whole transaction, however many inputs it has
+    fn verify_aggregate_signature(
+        &self,
+        aggregate_signature: &SchnorrSignature,
+    ) -> Result<(), TransactionError> {
+        if self.transaction_info.version < AGGREGATE_SIGNATURE_VERSION {
+            return Err(TransactionError::UnsupportedVersion(
+                self.transaction_info.version,
+            ));
+        }
+        if !self.signatures.is_empty() {
+            return Err(TransactionError::SignatureCountMismatch);
+        }
+
+        let signers = self
+            .transaction_info
+            .signers()
+            .ok_or(TransactionError::MissingPublicKey)?;
+        if signers
+            .iter()
+            .any(|signer| signer.key_type() != Some(KeyType::Secp256k1))
+        {
+            return Err(TransactionError::UnsupportedKeyType);
+        }
+
+        match schnorr::verify(
+            &signers,
+            self.transaction_info.aggregate_preimage().as_bytes(),
+            aggregate_signature,
+        ) {
+            Ok(true) => Ok(()),
+            Ok(false) => Err(TransactionError::ValidationError),
             Err(stack) => Err(TransactionError::OpenSSLError(stack)),
         }
     }
 
+    /// Whether the transaction came from a block stored in the old format. Those were signed over
+    /// the whole transaction and are only valid as part of the chain, never as new submissions.
+    pub fn is_legacy(&self) -> bool {
+        self.signatures
+            .iter()
+            .any(|signature| signature.sighash.kind == SigHashType::Legacy)
+    }
+
     pub fn serialize(&self) -> String {
         serde_json::to_string_pretty(self).unwrap()
     }
@@ -143,20 +437,24 @@ impl Default for Transaction {
             vec![UTXO::new(value as u64, receiver.clone())],
         );
 
-        let signature = sender_pk.sign_transaction(&transaction_info).unwrap();
-        Transaction::new(sender, receiver, transaction_info, signature).unwrap()
+        let signatures = sender_pk.sign_transaction(&transaction_info).unwrap();
+        Transaction::new(transaction_info, signatures).unwrap()
     }
 }
 
 impl Display for Transaction {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(aggregate_signature) = &self.aggregate_signature {
+            return write!(
+                f,
+                "{}::AGGREGATE_SIGNATURE::{:?}",
+                self.transaction_info, aggregate_signature
+            );
+        }
         write!(
             f,
-            "SENDER::{:?}::RECEIVER::{:?}::{}::SIGNATURE::{:?}",
-            self.sender,
-            self.receiver.to_pem(),
-            self.transaction_info,
-            self.signature
+            "{}::SIGNATURES::{:?}",
+            self.transaction_info, self.signatures
         )
     }
 }
@@ -2,6 +2,9 @@ use sha2::{Digest, Sha256};
 
 pub const PROOF_OF_WORK_DIFFICULTY: u8 = 4;
 
+/// The most the coinbase of a block can create, on top of the fees of its transactions
+pub const BLOCK_REWARD: u64 = 100_000;
+
 pub struct HashedData {
     hash: [u8; 32],
 }
@@ -3,22 +3,62 @@ use std::{cmp::Ordering, fmt::Display};
 
 use serde::{Deserialize, Serialize};
 
+use super::address::Address;
 use super::wallet::Wallet;
 
+/// Who can spend an output. Outputs can be locked to the whole public key or just to its address,
+/// but inputs always have to carry the public key so the signature can be checked.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum Owner {
+    PublicKey(Wallet),
+    Address(Address),
+}
+impl Owner {
+    pub fn address(&self) -> Address {
+        match self {
+            Owner::PublicKey(wallet) => wallet.address(),
+            Owner::Address(address) => *address,
+        }
+    }
+    pub fn public_key(&self) -> Option<&Wallet> {
+        match self {
+            Owner::PublicKey(wallet) => Some(wallet),
+            Owner::Address(_) => None,
+        }
+    }
+}
+impl From<Wallet> for Owner {
+    fn from(wallet: Wallet) -> Self {
+        Owner::PublicKey(wallet)
+    }
+}
+impl From<Address> for Owner {
+    fn from(address: Address) -> Self {
+        Owner::Address(address)
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct UTXO {
     value: u64,
-    owner: Wallet,
+    owner: Owner,
 }
 impl UTXO {
-    pub fn new(value: u64, owner: Wallet) -> Self {
-        Self { value, owner }
+    pub fn new(value: u64, owner: impl Into<Owner>) -> Self {
+        Self {
+            value,
+            owner: owner.into(),
+        }
     }
     pub fn value(&self) -> u64 {
         self.value
     }
-    pub fn owner(&self) -> Wallet {
-        self.owner.clone()
+    pub fn owner(&self) -> &Owner {
+        &self.owner
+    }
+    pub fn address(&self) -> Address {
+        self.owner.address()
     }
     pub fn sum<T>(vec: &T) -> u64
     where
@@ -31,19 +71,24 @@ impl UTXO {
 
 impl Display for UTXO {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let owner_pem = self.owner.to_pem();
-        let owner = if let Ok(val) = String::from_utf8(owner_pem) {
-            val
-        } else {
-            panic!("Invalid UTF-8 when getting UTXO owner")
+        let owner = match &self.owner {
+            Owner::PublicKey(wallet) => {
+                if let Ok(val) = String::from_utf8(wallet.to_pem()) {
+                    val
+                } else {
+                    panic!("Invalid UTF-8 when getting UTXO owner")
+                }
+            }
+            Owner::Address(address) => address.to_string(),
         };
         write!(f, "VALUE::{}::OWNER::{}", self.value, owner)
     }
 }
 
+// An output paid to an address is the same as the input that later spends it with the public key
 impl PartialEq for UTXO {
     fn eq(&self, other: &Self) -> bool {
-        self.value == other.value && self.owner == other.owner
+        self.value == other.value && self.address() == other.address()
     }
 }
 impl PartialOrd for UTXO {
@@ -0,0 +1,211 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::block::Block;
+use super::transaction::Transaction;
+use super::utxo::UTXO;
+use super::Chain;
+use crate::error_handling::TransactionError;
+
+// ---------------------------------------------- OutPoint definition ----------------------------------------------
+/// Where an unspent output was created: the transaction and the position among its outputs
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub txid: [u8; 32],
+    pub index: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: [u8; 32], index: u32) -> Self {
+        Self { txid, index }
+    }
+}
+
+impl Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", hex::encode(self.txid), self.index)
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+#[derive(Clone, Serialize, Deserialize)]
+struct UtxoEntry {
+    outpoint: OutPoint,
+    utxo: UTXO,
+}
+
+// ----------------------------------------------- UtxoSet definition ----------------------------------------------
+/// Every output of the chain that hasn't been spent yet.
+///
+/// Inputs don't say which output they spend, just its value and owner, so spending removes the
+/// first unspent output that matches them. An input that matches nothing makes the transaction,
+/// and the block with it, invalid.
+#[derive(Clone, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<UtxoEntry>", into = "Vec<UtxoEntry>")]
+pub struct UtxoSet {
+    utxos: BTreeMap<OutPoint, UTXO>,
+}
+
+impl From<Vec<UtxoEntry>> for UtxoSet {
+    fn from(entries: Vec<UtxoEntry>) -> Self {
+        Self {
+            utxos: entries
+                .into_iter()
+                .map(|entry| (entry.outpoint, entry.utxo))
+                .collect(),
+        }
+    }
+}
+
+impl From<UtxoSet> for Vec<UtxoEntry> {
+    fn from(set: UtxoSet) -> Self {
+        set.utxos
+            .into_iter()
+            .map(|(outpoint, utxo)| UtxoEntry { outpoint, utxo })
+            .collect()
+    }
+}
+
+impl UtxoSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replays every block of the chain
+    pub fn from_chain(chain: &Chain) -> Result<Self, TransactionError> {
+        let mut set = Self::new();
+        for block in &chain.blocks {
+            set.apply_block(block)?;
+        }
+        Ok(set)
+    }
+
+    pub fn len(&self) -> usize {
+        self.utxos.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.utxos.is_empty()
+    }
+
+    pub fn get(&self, outpoint: &OutPoint) -> Option<&UTXO> {
+        self.utxos.get(outpoint)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&OutPoint, &UTXO)> {
+        self.utxos.iter()
+    }
+
+    /// The first unspent output that the input could be spending
+    pub fn find(&self, input: &UTXO) -> Option<OutPoint> {
+        self.utxos
+            .iter()
+            .find(|(_, utxo)| *utxo == input)
+            .map(|(outpoint, _)| *outpoint)
+    }
+
+    /// Whether every input matches an unspent output, a different one each
+    pub fn can_spend(&self, inputs: &[UTXO]) -> bool {
+        self.claim(inputs, &HashSet::new()).is_some()
+    }
+
+    /// The unspent outputs the inputs would spend, a different one each and none of `taken`. None
+    /// if some input has nothing left to match
+    pub fn claim(&self, inputs: &[UTXO], taken: &HashSet<OutPoint>) -> Option<Vec<OutPoint>> {
+        let mut matched: Vec<OutPoint> = Vec::new();
+        for input in inputs {
+            let (outpoint, _) = self.utxos.iter().find(|(outpoint, utxo)| {
+                *utxo == input && !taken.contains(outpoint) && !matched.contains(outpoint)
+            })?;
+            matched.push(*outpoint);
+        }
+        Some(matched)
+    }
+
+    /// Spends the inputs of the transaction and adds its outputs. Returns the outputs that were
+    /// spent. If some input matches no unspent output, the set is left as it was
+    pub fn apply_transaction(
+        &mut self,
+        transaction: &Transaction,
+    ) -> Result<Vec<(OutPoint, UTXO)>, TransactionError> {
+        self.apply_in_block(transaction, &[])
+    }
+
+    // `earlier` has what the transactions before this one in the same block spent, to tell an
+    // output spent twice from one that never existed
+    fn apply_in_block(
+        &mut self,
+        transaction: &Transaction,
+        earlier: &[Vec<(OutPoint, UTXO)>],
+    ) -> Result<Vec<(OutPoint, UTXO)>, TransactionError> {
+        let mut spent: Vec<(OutPoint, UTXO)> = Vec::new();
+        for input in &transaction.transaction_info.inputs {
+            let Some(outpoint) = self.find(input) else {
+                let double_spend = earlier
+                    .iter()
+                    .flatten()
+                    .chain(&spent)
+                    .any(|(_, utxo)| utxo == input);
+                self.utxos.extend(spent);
+                return Err(match double_spend {
+                    true => TransactionError::DoubleSpend,
+                    false => TransactionError::UnknownInput,
+                });
+            };
+            let utxo = self.utxos.remove(&outpoint).unwrap();
+            spent.push((outpoint, utxo));
+        }
+
+        for (index, output) in transaction.transaction_info.outputs.iter().enumerate() {
+            self.utxos.insert(
+                OutPoint::new(transaction.txid, index as u32),
+                output.clone(),
+            );
+        }
+
+        Ok(spent)
+    }
+
+    /// Applies every transaction of the block, or none of them if one can't be. Returns what
+    /// `apply_transaction` returned for each of them
+    pub fn apply_block(
+        &mut self,
+        block: &Block,
+    ) -> Result<Vec<Vec<(OutPoint, UTXO)>>, TransactionError> {
+        let mut spent = Vec::new();
+        for transaction in block.get_transactions() {
+            match self.apply_in_block(transaction, &spent) {
+                Ok(spent_by_transaction) => spent.push(spent_by_transaction),
+                Err(e) => {
+                    self.undo_block(block, spent);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(spent)
+    }
+
+    /// Takes back the last block applied, or the part of it in `spent`: removes the outputs its
+    /// transactions created and puts back the ones they spent
+    pub fn undo_block(&mut self, block: &Block, spent: Vec<Vec<(OutPoint, UTXO)>>) {
+        for (transaction, spent) in block.get_transactions().iter().zip(spent).rev() {
+            for index in 0..transaction.transaction_info.outputs.len() {
+                self.utxos
+                    .remove(&OutPoint::new(transaction.txid, index as u32));
+            }
+            self.utxos.extend(spent);
+        }
+    }
+
+    /// SHA-256 of the serialized set. The outputs are kept sorted, so two nodes with the same
+    /// unspent outputs get the same commitment
+    pub fn commitment(&self) -> [u8; 32] {
+        let serialized_set =
+            serde_json::to_vec(self).expect("Couldn't serialize the UTXO set to commit to it");
+        Sha256::digest(serialized_set).into()
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
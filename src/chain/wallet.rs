@@ -1,13 +1,15 @@
+use crate::chain::address::Address;
+use crate::chain::key_type::{is_low_s, public_key_from_compact, public_key_to_compact, KeyType};
 use crate::chain::ordered_vector::OrderedVec;
 use crate::chain::utxo::UTXO;
 
+use super::sighash::InputSignature;
 use super::transaction::TransactionInfo;
 use openssl::error::ErrorStack;
 
 pub use super::wallet_pk::WalletPK;
 use openssl::hash::MessageDigest;
 use openssl::pkey::{PKey, Public};
-use openssl::rsa::Rsa;
 use openssl::sign::Verifier;
 use serde::de;
 use serde::{Deserialize, Serialize};
@@ -30,7 +32,7 @@ fn serialize_public_key<S>(key: &PKey<Public>, serializer: S) -> Result<S::Ok, S
 where
     S: serde::Serializer,
 {
-    let processed: Vec<u8> = key.public_key_to_pem().map_err(serde::ser::Error::custom)?;
+    let processed: Vec<u8> = public_key_to_compact(key).map_err(serde::ser::Error::custom)?;
     serializer.serialize_bytes(&processed)
 }
 
@@ -38,29 +40,8 @@ fn deserialize_public_key<'de, D>(deserializer: D) -> Result<PKey<Public>, D::Er
 where
     D: de::Deserializer<'de>,
 {
-    // struct StringVisitor;
-    // impl<'de> de::Visitor<'de> for StringVisitor {
-    //     type Value = PKey<Public>;
-    //
-    //     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-    //         formatter.write_str("The PEM string as a vector of u8")
-    //     }
-    //
-    //     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
-    //     where
-    //         E: Error,
-    //     {
-    //         PKey::public_key_from_pem(v).map_err(E::custom)
-    //     }
-    //     fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
-    //     where
-    //         E: de::Error,
-    //     {
-    //         PKey::public_key_from_pem(v.as_bytes()).map_err(E::custom)
-    //     }
-    // }
-    let bytes = Vec::<u8>::deserialize(deserializer).expect("Could not deserialize PEM");
-    Ok(PKey::public_key_from_pem(&bytes).expect("Could not deserialize PEM"))
+    let bytes = Vec::<u8>::deserialize(deserializer)?;
+    public_key_from_compact(&bytes).map_err(de::Error::custom)
 }
 // -----------------------------------------------------------------------------------------------------------------
 
@@ -73,10 +54,8 @@ impl PartialEq for Wallet {
 
 impl From<String> for Wallet {
     fn from(value: String) -> Self {
-        let public_rsa = openssl::rsa::Rsa::public_key_from_pem(value.as_bytes())
-            .expect("Could not read the public key");
         let public_key =
-            PKey::from_rsa(public_rsa).expect("Error converting from RSA to PKey<Public>");
+            PKey::public_key_from_pem(value.as_bytes()).expect("Could not read the public key");
         Self {
             public_key,
             available_utxos: None,
@@ -121,11 +100,15 @@ impl Wallet {
      *                         Construction & Utilities                     *
      * --------------------------------------------------------------------- */
 
-    /// Creates a fresh key pair and returns `(wallet, private_key_wrapper)`.
+    /// Creates a fresh key pair of the default key type and returns
+    /// `(wallet, private_key_wrapper)`.
     pub fn new() -> (Self, WalletPK) {
-        // 2048‑bit RSA key – adjust size if you need stronger security.
-        let rsa = Rsa::generate(2048).expect("RSA generation failed");
-        let private_key = PKey::from_rsa(rsa).expect("Invalid RSA key");
+        Self::new_with_key_type(KeyType::default())
+    }
+
+    /// Creates a fresh key pair of the given type and returns `(wallet, private_key_wrapper)`.
+    pub fn new_with_key_type(key_type: KeyType) -> (Self, WalletPK) {
+        let private_key = key_type.generate().expect("Key generation failed");
 
         // Export the public part as PEM and immediately parse it back.
         let public_key = PKey::public_key_from_pem(
@@ -144,15 +127,43 @@ impl Wallet {
         )
     }
 
-    /// Verify a signed `TransactionInfo` using the stored public key.
+    /// Verify the signature of the input at `input_index` of a `TransactionInfo` using the stored
+    /// public key. A signature whose sighash flags don't fit the transaction is just invalid.
     pub fn verify_transaction_info(
         &self,
         transaction_info: &TransactionInfo,
-        signature: &[u8],
+        input_index: usize,
+        signature: &InputSignature,
     ) -> Result<bool, ErrorStack> {
-        let mut verifier = Verifier::new(MessageDigest::sha256(), &self.public_key)?;
-        verifier.update(transaction_info.to_string().as_bytes())?;
-        verifier.verify(signature)
+        let preimage = match transaction_info.sighash_preimage(input_index, signature.sighash) {
+            Some(preimage) => preimage,
+            None => return Ok(false),
+        };
+        match self.key_type() {
+            // Ed25519 hashes the message itself, so it can't be fed through a digest
+            Some(KeyType::Ed25519) => {
+                let mut verifier = Verifier::new_without_digest(&self.public_key)?;
+                verifier.verify_oneshot(&signature.signature, preimage.as_bytes())
+            }
+            // The other half would make the txid malleable
+            Some(KeyType::Secp256k1) if !is_low_s(&signature.signature) => Ok(false),
+            Some(KeyType::Rsa) | Some(KeyType::Secp256k1) => {
+                let mut verifier = Verifier::new(MessageDigest::sha256(), &self.public_key)?;
+                verifier.update(preimage.as_bytes())?;
+                verifier.verify(&signature.signature)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// The type of the key, `None` if it's a kind of key we don't support.
+    pub fn key_type(&self) -> Option<KeyType> {
+        KeyType::of(&self.public_key)
+    }
+
+    /// The public key in its compact encoding. RSA keys have none, so they come out as PEM.
+    pub fn to_compact_bytes(&self) -> Vec<u8> {
+        public_key_to_compact(&self.public_key).expect("Compact conversion failed")
     }
 
     /// Export the public key as PEM bytes.
@@ -162,6 +173,16 @@ impl Wallet {
             .expect("PEM conversion failed")
     }
 
+    /// The short form of the public key that people can pay to.
+    pub fn address(&self) -> Address {
+        Address::from_public_key_der(
+            &self
+                .public_key
+                .public_key_to_der()
+                .expect("DER conversion failed"),
+        )
+    }
+
     /// Rough fee estimate per UTXO – replace with a real estimator later.
     fn estimate_fee_per_utxo(_utxo: &UTXO) -> u64 {
         100
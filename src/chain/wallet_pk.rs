@@ -1,6 +1,8 @@
+use super::key_type::{ecdsa_to_low_s, KeyType};
+use super::sighash::{InputSignature, SigHash};
 use super::transaction::TransactionInfo;
 use super::wallet::Wallet;
-use openssl::error::ErrorStack;
+use crate::error_handling::TransactionError;
 use openssl::hash::MessageDigest;
 use openssl::pkey::{PKey, Private};
 use openssl::sign::Signer;
@@ -12,12 +14,50 @@ pub struct WalletPK {
 }
 
 impl WalletPK {
+    /// Signs every input with `SigHash::ALL`, for the usual case where this key owns all of them.
+    /// Transactions funded by more than one owner are put together with `sign_input` instead.
     pub fn sign_transaction(
         &self,
         transaction_info: &TransactionInfo,
-    ) -> Result<Vec<u8>, ErrorStack> {
-        let mut signer = Signer::new(MessageDigest::sha256(), &self.private_key)?;
-        signer.sign_oneshot_to_vec(transaction_info.to_string().as_bytes())
+    ) -> Result<Vec<InputSignature>, TransactionError> {
+        (0..transaction_info.inputs.len())
+            .map(|index| self.sign_input(transaction_info, index, SigHash::ALL))
+            .collect()
+    }
+
+    /// Signs only the input at `input_index`, committing to the parts of the transaction selected
+    /// by `sighash`.
+    pub fn sign_input(
+        &self,
+        transaction_info: &TransactionInfo,
+        input_index: usize,
+        sighash: SigHash,
+    ) -> Result<InputSignature, TransactionError> {
+        let preimage = transaction_info
+            .sighash_preimage(input_index, sighash)
+            .ok_or(TransactionError::InvalidSigHash)?;
+
+        let mut signer = match self.key_type() {
+            // Ed25519 hashes the message itself, so it can't be fed through a digest
+            Some(KeyType::Ed25519) => Signer::new_without_digest(&self.private_key),
+            Some(KeyType::Rsa) | Some(KeyType::Secp256k1) => {
+                Signer::new(MessageDigest::sha256(), &self.private_key)
+            }
+            None => return Err(TransactionError::UnsupportedKeyType),
+        }
+        .map_err(TransactionError::OpenSSLError)?;
+        let mut signature = signer
+            .sign_oneshot_to_vec(preimage.as_bytes())
+            .map_err(TransactionError::OpenSSLError)?;
+        if self.key_type() == Some(KeyType::Secp256k1) {
+            signature = ecdsa_to_low_s(&signature).map_err(TransactionError::OpenSSLError)?;
+        }
+
+        Ok(InputSignature::new(sighash, signature))
+    }
+    /// The type of the key, `None` if it's a kind of key we don't support.
+    pub fn key_type(&self) -> Option<KeyType> {
+        KeyType::of(&self.private_key)
     }
     pub fn to_pem_with_password(&self, password: &String) -> Vec<u8> {
         self.private_key
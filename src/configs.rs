@@ -1,68 +1,163 @@
-use std::{collections::HashSet, path::PathBuf};
+use directories::ProjectDirs;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
 
-use crate::error_handling::{CleytoResult, CleytonError};
+// Set by `--datadir`, wins over the config file
+static DATA_DIR_OVERRIDE: OnceCell<PathBuf> = OnceCell::new();
 
-// pub const CONFIG_PATH: &str = ".config/cleyto_coin"; // /home/
+fn project_dirs() -> ProjectDirs {
+    ProjectDirs::from("", "CleytoCoin Big Mean Corp", "cleyto_coin")
+        .expect("Could not find the config directory")
+}
 
-pub struct ConfigPaths {
-    pub(crate) servers_running_file: String,
-    pub(crate) sockets_dir: String,
-    #[allow(unused)]
-    pub(crate) block_dir: String,
-    pub(crate) last_block: String,
+fn default_data_dir() -> PathBuf {
+    project_dirs().data_dir().to_path_buf()
 }
-impl ConfigPaths {
-    pub fn get() -> Self {
-        ConfigPaths {
-            servers_running_file: String::from(".config/cleyto_coin/servers_running.json"),
-            sockets_dir: String::from(".config/cleyto_coin/sockets"),
-            block_dir: String::from(".cleyto_coin/blocks"),
-            last_block: String::from(".cleyto_coin/last_block"),
+
+/// What can be set in `config.toml`, in the config directory of the platform
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct NodeConfig {
+    /// Where everything the node writes goes: blocks, sockets, logs and the running servers
+    #[serde(default = "default_data_dir")]
+    pub(crate) data_dir: PathBuf,
+    /// Only needed to keep the logs out of the data directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) log_path: Option<PathBuf>,
+    /// Nodes to ask for other nodes' addresses when the ones in `peers.json` don't answer
+    #[serde(default)]
+    pub(crate) seed_peers: Vec<SocketAddr>,
+    /// How many nodes to keep connections open to
+    #[serde(default = "default_outbound_peers")]
+    pub(crate) outbound_peers: usize,
+    /// The port of the HTTP API, unless `--port` is given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) port: Option<u16>,
+    /// The address to listen on, unless `--bind` is given. 0.0.0.0 to accept every interface
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) bind: Option<IpAddr>,
+    /// Seconds a peer that misbehaves too much stays banned, and the default of `node ban`
+    #[serde(default = "default_ban_duration")]
+    pub(crate) ban_duration: u64,
+    /// Whether to encrypt the connections to the nodes this one connects to, like `--encrypt`
+    #[serde(default)]
+    pub(crate) encrypt_peers: bool,
+    /// Whether to refuse peers that don't encrypt their connection
+    #[serde(default)]
+    pub(crate) require_encrypted_peers: bool,
+    /// The key, as `node identity` prints it, that the node at each address must have. Connections
+    /// to them are always encrypted
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) pinned_peers: BTreeMap<SocketAddr, String>,
+}
+fn default_outbound_peers() -> usize {
+    crate::node::p2p::network::DEFAULT_TARGET_OUTBOUND
+}
+fn default_ban_duration() -> u64 {
+    crate::node::p2p::network::DEFAULT_BAN_DURATION.as_secs()
+}
+impl Default for NodeConfig {
+    fn default() -> Self {
+        Self {
+            data_dir: default_data_dir(),
+            log_path: None,
+            seed_peers: Vec::new(),
+            outbound_peers: default_outbound_peers(),
+            port: None,
+            bind: None,
+            ban_duration: default_ban_duration(),
+            encrypt_peers: false,
+            require_encrypted_peers: false,
+            pinned_peers: BTreeMap::new(),
         }
     }
 }
+impl NodeConfig {
+    /// Reads `config.toml`, writing the default one if there's none yet
+    pub(crate) fn load() -> Self {
+        let proj_dirs = project_dirs();
+        let config_path = proj_dirs.config_dir().join("config.toml");
 
-pub struct Config {
-    #[allow(dead_code)]
-    // This will be used for a cli command to delete servers
-    pub(crate) servers_running: HashSet<String>,
-    last_block: u32,
+        if let Ok(contents) = fs::read_to_string(&config_path) {
+            toml::from_str(&contents).expect("Invalid config format")
+        } else {
+            fs::create_dir_all(proj_dirs.config_dir())
+                .expect("Could not create config directories");
+
+            let default_cfg = NodeConfig::default();
+            let toml_str = toml::to_string_pretty(&default_cfg).unwrap();
+            fs::write(&config_path, &toml_str).expect("Couldn't write default config");
+            default_cfg
+        }
+    }
+}
+
+/// Makes every path of this process come from `data_dir`, whatever the config file says. It's what
+/// `--datadir` does, so it can only be set once, before any path is used
+pub fn set_data_dir(data_dir: PathBuf) -> Result<(), PathBuf> {
+    DATA_DIR_OVERRIDE.set(data_dir)
+}
+
+#[derive(Clone, Debug)]
+pub struct ConfigPaths {
+    pub(crate) servers_running_file: PathBuf,
+    // The peer address book, next to the running servers
+    pub(crate) peers_file: PathBuf,
+    pub(crate) bans_file: PathBuf,
+    // The key the node encrypts its peer connections with
+    pub(crate) node_key_file: PathBuf,
+    pub(crate) sockets_dir: PathBuf,
+    pub(crate) log_file: PathBuf,
+    pub(crate) data_dir: PathBuf,
 }
-impl Config {
+impl ConfigPaths {
+    /// The paths of this process: from `--datadir` if it was given, from the config file otherwise
     pub fn get() -> Self {
-        let last_block_path = PathBuf::from(ConfigPaths::get().last_block);
-        let last_block = match std::fs::read_to_string(&last_block_path) {
-            Ok(v) => str::parse::<u32>(&v).expect("Last block file has non uinteger value"),
-            Err(_) => {
-                std::fs::create_dir_all(last_block_path.parent().unwrap())
-                    .expect("Could not create dir .cleyto_coin");
-                std::fs::write(last_block_path, "0").expect("Could not create last_block file");
-                0
-            }
-        };
-
-        Config {
-            servers_running: get_running_servers(),
-            last_block,
+        if let Some(data_dir) = DATA_DIR_OVERRIDE.get() {
+            return Self::new(data_dir.clone());
+        }
+
+        let config = NodeConfig::load();
+        let mut paths = Self::new(config.data_dir);
+        if let Some(log_path) = config.log_path {
+            paths.log_file = log_path;
         }
+        paths
     }
 
-    pub fn last_block(&self) -> u32 {
-        self.last_block
+    /// Every path derived from one data directory
+    pub fn new(data_dir: impl Into<PathBuf>) -> Self {
+        let data_dir = data_dir.into();
+        ConfigPaths {
+            servers_running_file: data_dir.join("servers_running.json"),
+            peers_file: data_dir.join("peers.json"),
+            bans_file: data_dir.join("bans.json"),
+            node_key_file: data_dir.join("node_key.pem"),
+            sockets_dir: data_dir.join("sockets"),
+            log_file: data_dir.join("logs.log"),
+            data_dir,
+        }
     }
-    pub fn update_last_block(&mut self, amount: i32) -> CleytoResult<()> {
-        let result = {
-            let intermediary = self.last_block as i32 + amount;
-            if intermediary < 0 {
-                return Err(CleytonError::LastBlockLessThanZero);
-            }
-            intermediary as u32
-        };
-
-        std::fs::write(ConfigPaths::get().last_block, result.to_string())
-            .expect("Could not update last_block file");
-        self.last_block = result;
-        Ok(())
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    pub fn node_key_file(&self) -> &Path {
+        &self.node_key_file
+    }
+
+    /// Where the node with this name listens for commands
+    pub fn socket_path(&self, name: &str) -> PathBuf {
+        self.sockets_dir.join(format!("{name}.sock:"))
+    }
+}
+impl Default for ConfigPaths {
+    fn default() -> Self {
+        Self::get()
     }
 }
 
@@ -144,29 +239,28 @@ pub fn add_name_to_running_servers(name: String) {
     let mut servers_running: HashSet<String> = get_running_servers();
     servers_running.insert(name);
 
-    let config = ConfigPaths::get();
-    if let Some(parent) = std::path::Path::new(&config.servers_running_file).parent() {
-        std::fs::create_dir_all(parent).unwrap();
-    }
-    std::fs::write(
-        config.servers_running_file,
-        serde_json::to_string(&servers_running).unwrap(),
-    )
-    .unwrap();
+    write_running_servers(&servers_running);
 }
 
 pub fn remove_name_from_running_servers(name: String) {
     let mut servers_running: HashSet<String> = get_running_servers();
     servers_running.remove(&name);
 
-    let config = ConfigPaths::get();
+    write_running_servers(&servers_running);
+}
 
-    if let Some(parent) = std::path::Path::new(&config.servers_running_file).parent() {
+// A node stopping and the command that stopped it both write the list. Going through a file of
+// their own and a rename, neither can read the other's half written one
+fn write_running_servers(servers_running: &HashSet<String>) {
+    let path = ConfigPaths::get().servers_running_file;
+    if let Some(parent) = path.parent() {
         std::fs::create_dir_all(parent).unwrap();
     }
-    std::fs::write(
-        config.servers_running_file,
-        serde_json::to_string(&servers_running).unwrap(),
-    )
-    .unwrap();
+    let temp_path = path.with_extension(format!(
+        "json.{}.{:?}.tmp",
+        std::process::id(),
+        std::thread::current().id()
+    ));
+    std::fs::write(&temp_path, serde_json::to_string(servers_running).unwrap()).unwrap();
+    std::fs::rename(temp_path, path).unwrap();
 }
@@ -10,8 +10,16 @@ pub enum CleytonError {
     BlockSerializationError(serde_json::Error),
     BlockDeserializationError(serde_json::Error),
     BlockNotFound,
+    BlockNotAtTip,
+    BlockPruned,
+    CorruptBlockStore(String),
+    InvalidBlock(String),
+    InvalidSnapshot(String),
+    TxIndexDisabled,
+    AddressIndexDisabled,
+    InvalidOptions(String),
+    InvalidNodeKey(String),
     ReadWriteError(io::Error),
-    LastBlockLessThanZero,
 }
 
 impl From<io::Error> for CleytonError {
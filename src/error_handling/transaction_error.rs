@@ -1,3 +1,4 @@
+use crate::chain::address::AddressError;
 use openssl::error::ErrorStack;
 use std::fmt;
 use std::fmt::Debug;
@@ -24,8 +25,22 @@ pub enum TransactionError {
     OpenSSLError(ErrorStack),
     InsufficientInputs,
     ValidationError,
+    SignatureCountMismatch,
+    InvalidSigHash,
+    MissingPublicKey,
+    UnsupportedKeyType,
+    UnsupportedVersion(u32),
+    InvalidAddress(AddressError),
+    /// A public key given to the wallet that isn't a PEM one
+    InvalidPublicKey(ErrorStack),
     InsufficientFunds,
     ConnectionError(String),
+    /// The node can't tell the wallet what an address has unspent, it runs without `--addrindex`
+    AddressIndexRequired,
+    /// An input that matches no unspent output
+    UnknownInput,
+    /// An input whose output was already spent by the same block
+    DoubleSpend,
 }
 impl fmt::Display for TransactionError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -46,6 +61,43 @@ impl fmt::Display for TransactionError {
                 did not match the provided transaction info."
                 )
             }
+            TransactionError::SignatureCountMismatch => {
+                write!(
+                    f,
+                    "The validation of the transaction was not successful, as the number of \
+                signatures does not match the number of inputs."
+                )
+            }
+            TransactionError::InvalidSigHash => {
+                write!(
+                    f,
+                    "The sighash flags can't be used on this input. SINGLE needs an output with \
+                the same index as the signed input."
+                )
+            }
+            TransactionError::MissingPublicKey => {
+                write!(
+                    f,
+                    "The validation of the transaction was not successful, as an input only \
+                has the address of its owner instead of the public key needed to check the signature."
+                )
+            }
+            TransactionError::UnsupportedKeyType => {
+                write!(
+                    f,
+                    "The transaction uses a type of key that is unknown or not allowed by its \
+                version."
+                )
+            }
+            TransactionError::UnsupportedVersion(version) => {
+                write!(f, "The transaction has an unknown version {version}.")
+            }
+            TransactionError::InvalidAddress(e) => {
+                write!(f, "The transaction could not be created: {e}")
+            }
+            TransactionError::InvalidPublicKey(e) => {
+                write!(f, "The public key could not be read as a PEM key: {e}")
+            }
             TransactionError::InsufficientInputs => {
                 write!(
                     f,
@@ -59,12 +111,32 @@ impl fmt::Display for TransactionError {
                             "It wasn't possible to execute the transaction because there weren't enough funds."
                         )
             }
+            TransactionError::UnknownInput => {
+                write!(
+                    f,
+                    "The transaction spends an output that doesn't exist or was already spent."
+                )
+            }
+            TransactionError::DoubleSpend => {
+                write!(
+                    f,
+                    "The transaction spends an output that another transaction of the same \
+                block spends too."
+                )
+            }
             TransactionError::ConnectionError(_) => {
                 write!(
                     f,
                     "The transaction was not sent to the server due to a connection error."
                 )
             }
+            TransactionError::AddressIndexRequired => {
+                write!(
+                    f,
+                    "The node doesn't serve the unspent outputs of an address, so there's nothing \
+                to pick the inputs from. Start it with --addrindex."
+                )
+            }
         }
     }
 }
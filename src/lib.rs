@@ -1,21 +1,25 @@
 use crate::{
     chain::{
+        address::Address,
+        key_type::KeyType,
+        schnorr,
         transaction::{self, Transaction, TransactionInfo},
-        utxo::UTXO,
+        utxo::{Owner, UTXO},
         wallet::{Wallet, WalletPK},
-        Chain,
     },
-    configs::{ConfigPaths, SERVERS_NAMES_LIST},
+    configs::SERVERS_NAMES_LIST,
     error_handling::{CleytoResult, CleytonError, TransactionError},
-    node::ui::App,
+    node::{ui::App, NodeOptions},
 };
 use openssl::pkey::{PKey, Private, Public};
 use reqwest::{Client, StatusCode};
 use std::{
-    io::Write,
+    io::{Read, Write},
+    net::Shutdown,
     os::unix::net::UnixStream,
-    path::PathBuf,
-    process::{Command, Stdio},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    str::FromStr,
 };
 use std::{sync::Arc, thread};
 
@@ -24,25 +28,38 @@ mod configs;
 pub mod error_handling;
 pub mod node;
 
-pub use configs::{add_name_to_running_servers, new_server_name, remove_name_from_running_servers};
+pub use configs::{
+    add_name_to_running_servers, new_server_name, remove_name_from_running_servers, set_data_dir,
+    ConfigPaths,
+};
+
+/// Where the wallet finds the node unless it's told otherwise
+pub const DEFAULT_NODE_URL: &str = "http://localhost:9473";
 
-async fn send_transaction(transaction: transaction::Transaction) -> Result<(), TransactionError> {
+async fn send_transaction(
+    node_url: &str,
+    transaction: transaction::Transaction,
+) -> Result<(), TransactionError> {
     let client = Client::new();
 
     let transaction_json = transaction.serialize();
+    let node_url = node_url.trim_end_matches('/');
 
     // Send the POST request
     let response = client
-        .post("http://localhost:9473/submit-transaction")
+        .post(format!("{node_url}/submit-transaction"))
         .header("Content-Type", "application/json")
         .body(transaction_json)
         .send()
         .await
-        .unwrap();
+        .map_err(|e| TransactionError::ConnectionError(e.to_string()))?;
 
     // Check the response status
     let status = response.status();
-    let response_body = response.text().await.unwrap();
+    let response_body = response
+        .text()
+        .await
+        .map_err(|e| TransactionError::ConnectionError(e.to_string()))?;
     match status {
         StatusCode::OK => Ok(()),
 
@@ -52,6 +69,44 @@ async fn send_transaction(transaction: transaction::Transaction) -> Result<(), T
     }
 }
 
+/// The unspent outputs of the address, from the address index of the node. Nodes without one,
+/// started without `--addrindex` or from before it existed, answer with a Bad Request or Not Found
+async fn fetch_utxos(node_url: &str, address: &Address) -> Result<Vec<UTXO>, TransactionError> {
+    let node_url = node_url.trim_end_matches('/');
+    let response = Client::new()
+        .get(format!("{node_url}/address/{address}/utxos"))
+        .send()
+        .await
+        .map_err(|e| TransactionError::ConnectionError(e.to_string()))?;
+
+    let status = response.status();
+    let response_body = response
+        .text()
+        .await
+        .map_err(|e| TransactionError::ConnectionError(e.to_string()))?;
+    match status {
+        StatusCode::OK => {}
+        StatusCode::BAD_REQUEST | StatusCode::NOT_FOUND => {
+            return Err(TransactionError::AddressIndexRequired)
+        }
+        _ => {
+            return Err(TransactionError::ConnectionError(format!(
+                "Error: {status}\n{response_body}"
+            )))
+        }
+    }
+
+    let entries: Vec<serde_json::Value> = serde_json::from_str(&response_body)
+        .map_err(|e| TransactionError::ConnectionError(e.to_string()))?;
+    entries
+        .into_iter()
+        .map(|mut entry| {
+            serde_json::from_value(entry["utxo"].take())
+                .map_err(|e| TransactionError::ConnectionError(e.to_string()))
+        })
+        .collect()
+}
+
 fn read_key_string_or_file(string: &Option<String>, file: &Option<PathBuf>) -> String {
     if let Some(s) = string {
         s.clone()
@@ -62,8 +117,37 @@ fn read_key_string_or_file(string: &Option<String>, file: &Option<PathBuf>) -> S
     }
 }
 
-pub fn generate(private_key_file: &PathBuf, public_key_file: &PathBuf, password: &Option<String>) {
-    let (wallet, walletpk) = Wallet::new();
+/// The recipient can be given either as a PEM public key or as an address
+fn parse_recipient(recipient: &str) -> Result<Owner, TransactionError> {
+    if recipient.trim_start().starts_with("-----BEGIN") {
+        let recipient_pkey: PKey<Public> = PKey::public_key_from_pem(recipient.as_bytes())
+            .map_err(TransactionError::InvalidPublicKey)?;
+        Ok(Owner::PublicKey(Wallet::from(recipient_pkey)))
+    } else {
+        Address::from_str(recipient.trim())
+            .map(Owner::Address)
+            .map_err(TransactionError::InvalidAddress)
+    }
+}
+
+/// Reads a public key, as a string or from a file, and returns its address
+pub fn address(
+    public_key: &Option<String>,
+    public_key_file: &Option<PathBuf>,
+) -> Result<Address, TransactionError> {
+    let public_key_str = read_key_string_or_file(public_key, public_key_file);
+    let public_key: PKey<Public> = PKey::public_key_from_pem(public_key_str.as_bytes())
+        .map_err(TransactionError::InvalidPublicKey)?;
+    Ok(Wallet::from(public_key).address())
+}
+
+pub fn generate(
+    private_key_file: &PathBuf,
+    public_key_file: &PathBuf,
+    password: &Option<String>,
+    key_type: KeyType,
+) {
+    let (wallet, walletpk) = Wallet::new_with_key_type(key_type);
 
     let parents = [
         private_key_file
@@ -91,6 +175,11 @@ pub fn generate(private_key_file: &PathBuf, public_key_file: &PathBuf, password:
 
     std::fs::write(public_key_file, wallet.to_pem())
         .expect("Could not write new wallet's public key to file");
+
+    println!(
+        "Generated {key_type} wallet with address {}",
+        wallet.address()
+    );
 }
 
 pub async fn send(
@@ -100,6 +189,7 @@ pub async fn send(
     sender_key_file: Option<PathBuf>,
     password: Option<String>,
     amount: u64,
+    node_url: &str,
 ) -> Result<(), TransactionError> {
     let recipient_key_str = read_key_string_or_file(&recipient_key, &recipient_key_file);
     let sender_key_str = read_key_string_or_file(&sender_key, &sender_key_file);
@@ -113,62 +203,71 @@ pub async fn send(
             .expect("Failed to parse sender private key")
     };
 
-    let recipient_pkey: PKey<Public> = PKey::public_key_from_pem(recipient_key_str.as_bytes())
-        .expect("Failed to parse recipient public key");
+    let recipient = parse_recipient(&recipient_key_str)?;
 
     // create wallets
     let sender_wallet = WalletPK::from(sender_pkey);
-    let recipient_wallet = Wallet::from(recipient_pkey);
 
-    // find input utxos
-    let input_utxos = match sender_wallet.public_wallet().get_utxos(amount) {
+    // find input utxos. Outputs paid to the address are spent with the public key, since inputs
+    // have to reveal it
+    let sender_public = sender_wallet.public_wallet();
+    let owned_utxos = fetch_utxos(node_url, &sender_public.address())
+        .await?
+        .into_iter()
+        .map(|utxo| UTXO::new(utxo.value(), sender_public.clone()))
+        .collect();
+    let mut sender_funds = sender_wallet.public_wallet();
+    sender_funds.add_utxos(owned_utxos);
+    let input_utxos = match sender_funds.get_utxos(amount) {
         Ok(vec) => vec,
         Err(_) => return Err(TransactionError::InsufficientFunds),
     };
 
     // Create output UTXOs
     let input_sum = UTXO::sum(&input_utxos);
-    let recipients_utxo = UTXO::new(amount, recipient_wallet.clone());
+    let recipients_utxo = UTXO::new(amount, recipient);
     let change_utxo = UTXO::new(input_sum - amount, sender_wallet.public_wallet());
     let output_utxos = vec![change_utxo, recipients_utxo];
 
     // create transaction info
     let transaction_info = TransactionInfo::new(input_utxos, output_utxos);
 
-    // sign the transaction
-    let signature = sender_wallet
-        .sign_transaction(&transaction_info)
-        .expect("Failed on signing of transaction");
-
-    let transaction = Transaction::new(
-        sender_wallet.public_wallet(),
-        recipient_wallet,
-        transaction_info,
-        signature,
-    )
+    // sign the transaction. secp256k1 keys cover every input with a single Schnorr signature
+    let transaction = if sender_wallet.key_type() == Some(KeyType::Secp256k1) {
+        let signature = schnorr::sign_transaction(&transaction_info, &[&sender_wallet])
+            .expect("Failed on signing of transaction");
+        Transaction::new_aggregated(transaction_info, signature)
+    } else {
+        let signature = sender_wallet
+            .sign_transaction(&transaction_info)
+            .expect("Failed on signing of transaction");
+        Transaction::new(transaction_info, signature)
+    }
     .inspect_err(|e| eprintln!("Failed creating the transaction: {e}"))
     .unwrap();
 
-    send_transaction(transaction).await
+    send_transaction(node_url, transaction).await
 }
 
-pub fn run_server_with_gui(server_name: String) -> color_eyre::Result<()> {
+pub fn run_server_with_gui(server_name: String, options: NodeOptions) -> color_eyre::Result<()> {
     // Channel to kill thread
     // let rx = Arc::new(Mutex::new(rx));
 
-    let (mut node, logger) = node::Node::new(Chain::new(), server_name);
+    let (mut node, logger) = node::Node::open(server_name, ConfigPaths::get(), options)
+        .expect("Couldn't load the stored chain");
 
     let node_name = node.name.to_string();
+    let port = node.http_address().port();
     // Run server thread
     let server = thread::spawn(move || {
         // let rx = Arc::clone(&rx);
 
-        node.run(true, 0);
+        node.run();
     });
 
     color_eyre::install()?;
     let terminal = ratatui::init();
-    let result = App::new(Arc::clone(&logger), node::Node::DEFAULT_PORT).run(terminal);
+    let result = App::new(Arc::clone(&logger), port).run(terminal);
     ratatui::restore();
 
     // Quits server
@@ -182,38 +281,91 @@ pub fn run_server_with_gui(server_name: String) -> color_eyre::Result<()> {
 /// Mostly useful for testing
 /// Returns the created server's name, to enable killing it later
 pub fn run_server_thread(server_name: String) -> String {
-    let (mut node, _) = node::Node::new(Chain::new(), server_name.to_string());
+    let (mut node, _) = node::Node::open(
+        server_name.to_string(),
+        ConfigPaths::get(),
+        NodeOptions::default(),
+    )
+    .expect("Couldn't load the stored chain");
 
     thread::spawn(move || {
-        node.run(true, 0);
+        node.run();
     });
 
     server_name
 }
 
-pub fn run_server(server_name: String) {
-    let (mut node, _) = node::Node::new(Chain::new(), server_name);
-    node.run(true, 0);
+pub fn run_server(server_name: String, options: NodeOptions) {
+    let (mut node, _) = node::Node::open(server_name, ConfigPaths::get(), options)
+        .expect("Couldn't load the stored chain");
+    node.run();
 }
 
-pub fn run_server_new_process(server_name: String) {
+pub fn run_server_new_process(server_name: String, options: NodeOptions) {
+    // The new process has to use the same data directory, whatever it came from
     #[allow(clippy::zombie_processes)]
-    let child = Command::new(std::env::current_exe().unwrap())
+    let child = spawn_node(ConfigPaths::get().data_dir(), &server_name, &options)
+        .expect("Failed to start server process");
+    println!("Spawned process with pid {}", child.id());
+}
+
+/// Starts `node start --blocking` in a new process, on the data directory given
+pub(crate) fn spawn_node(
+    data_dir: &Path,
+    server_name: &str,
+    options: &NodeOptions,
+) -> CleytoResult<Child> {
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .arg("--datadir")
+        .arg(data_dir)
         .arg("start")
         .arg("--blocking")
         .arg("--name")
-        .arg(&server_name)
-        .stdout(Stdio::null())
-        .stdin(Stdio::null())
-        .spawn()
-        .expect("Failed to start server process");
-    println!("Spawned process with pid {}", child.id());
+        .arg(server_name);
+    if let Some(port) = options.port {
+        command.arg("--port").arg(port.to_string());
+    }
+    if let Some(bind) = options.bind {
+        command.arg("--bind").arg(bind.to_string());
+    }
+    if let Some(prune) = options.prune {
+        command.arg("--prune").arg(prune.to_string());
+    }
+    if let Some(history_dir) = &options.verify_history {
+        command.arg("--verify-history").arg(history_dir);
+    }
+    if options.txindex {
+        command.arg("--txindex");
+    }
+    if options.addrindex {
+        command.arg("--addrindex");
+    }
+    if let Some(port) = options.p2p_port {
+        command.arg("--p2p-port").arg(port.to_string());
+    }
+    for peer in &options.connect {
+        command.arg("--connect").arg(peer.to_string());
+    }
+    if options.encrypt {
+        command.arg("--encrypt");
+    }
+
+    let child = command.stdout(Stdio::null()).stdin(Stdio::null()).spawn()?;
+    Ok(child)
 }
 
 /// Sends the kill signal to the server
 pub fn kill_node(node: String) -> CleytoResult<()> {
-    let config = ConfigPaths::get();
-    let socket_path = PathBuf::from(format!("{}/{}.sock:", config.sockets_dir, node));
+    kill_node_at(&ConfigPaths::get(), &node)?;
+    remove_name_from_running_servers(node);
+    Ok(())
+}
+
+/// Sends the kill signal to the server with that name, on another data directory. The server
+/// takes itself off the running ones of its data directory
+pub fn kill_node_at(paths: &ConfigPaths, node: &str) -> CleytoResult<()> {
+    let socket_path = paths.socket_path(node);
 
     println!("Socket exists? {}", socket_path.exists());
     if !socket_path.exists() {
@@ -240,11 +392,21 @@ pub fn kill_node(node: String) -> CleytoResult<()> {
     println!("Killed node {}", node);
 
     std::fs::remove_file(socket_path).map_err(|e| CleytonError::KillServerError(e.to_string()))?;
-    remove_name_from_running_servers(node);
 
     Ok(())
 }
 
+/// Sends an admin command, like `ban 10.0.0.7`, to a running node and returns its reply
+pub fn send_node_command(node: &str, command: &str) -> CleytoResult<String> {
+    let mut stream = UnixStream::connect(ConfigPaths::get().socket_path(node))?;
+    stream.write_all(command.as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
 pub fn kill_all_nodes() {
     for server in SERVERS_NAMES_LIST {
         // I don't care if it fails
@@ -0,0 +1,137 @@
+//! Every output each address received, and the transaction that spent it, if any. Outputs locked to
+//! a public key are indexed under its address, since that's what inputs are matched by anyway.
+//!
+//! It's saved to `addrindex.json` in the data directory after every block. Spends can only be
+//! told apart by replaying the UTXO set, so an index that fell behind the chain is rebuilt from the
+//! first block, and it doesn't go together with pruning.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chain::{
+        address::Address,
+        block::Block,
+        utxo::UTXO,
+        utxo_set::{OutPoint, UtxoSet},
+    },
+    error_handling::{CleytoResult, CleytonError},
+    node::{block_store::BlockStore, files::write_atomically},
+};
+
+const ADDRESS_INDEX_FILE: &str = "addrindex.json";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AddressEntry {
+    pub outpoint: OutPoint,
+    pub utxo: UTXO,
+    /// The block the output was created in
+    pub block_hash: String,
+    /// The transaction that spent it, None while it's unspent
+    pub spent_by: Option<[u8; 32]>,
+}
+
+// -------------------------------------------- AddressIndex definition --------------------------------------------
+#[derive(Default, Serialize, Deserialize)]
+pub struct AddressIndex {
+    // The last block indexed
+    tip: Option<String>,
+    // By address, in the order they were received
+    addresses: HashMap<String, Vec<AddressEntry>>,
+}
+
+impl AddressIndex {
+    /// The index saved next to the block store, or a new one built from every stored block if that
+    /// one isn't up to date with the store
+    pub fn open(block_store: &BlockStore) -> CleytoResult<Self> {
+        let saved: Option<Self> = match std::fs::read_to_string(Self::path(block_store.dir())) {
+            Ok(serialized_index) => serde_json::from_str(&serialized_index).ok(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(saved) = saved.filter(|saved| saved.tip.as_deref() == block_store.tip()) {
+            return Ok(saved);
+        }
+
+        if block_store.pruned_height() > 0 {
+            return Err(CleytonError::BlockPruned);
+        }
+        let mut index = Self::default();
+        let mut utxo_set = UtxoSet::new();
+        for height in 0..block_store.len() as u32 {
+            let block = block_store.read_by_height(height)?;
+            let spent = utxo_set.apply_block(&block).map_err(|e| {
+                CleytonError::CorruptBlockStore(format!(
+                    "stored block {} can't be applied: {e}",
+                    block.get_hash()
+                ))
+            })?;
+            index.connect_block(&block, &spent);
+        }
+        index.save(block_store.dir())?;
+
+        Ok(index)
+    }
+
+    fn path(dir: &Path) -> PathBuf {
+        dir.join(ADDRESS_INDEX_FILE)
+    }
+
+    pub fn save(&self, dir: &Path) -> CleytoResult<()> {
+        let serialized_index =
+            serde_json::to_string(self).map_err(CleytonError::BlockSerializationError)?;
+        write_atomically(&Self::path(dir), serialized_index.as_bytes())
+    }
+
+    /// Indexes the outputs of the block, and marks the ones its transactions spent. `spent` is
+    /// what `UtxoSet::apply_block` returned for it
+    pub fn connect_block(&mut self, block: &Block, spent: &[Vec<(OutPoint, UTXO)>]) {
+        let block_hash = block.get_hash();
+        for (transaction, spent) in block.get_transactions().iter().zip(spent) {
+            for (outpoint, utxo) in spent {
+                let entry = self
+                    .addresses
+                    .get_mut(&utxo.address().to_string())
+                    .and_then(|entries| {
+                        entries.iter_mut().find(|entry| entry.outpoint == *outpoint)
+                    });
+                if let Some(entry) = entry {
+                    entry.spent_by = Some(transaction.txid);
+                }
+            }
+
+            for (index, output) in transaction.transaction_info.outputs.iter().enumerate() {
+                self.addresses
+                    .entry(output.address().to_string())
+                    .or_default()
+                    .push(AddressEntry {
+                        outpoint: OutPoint::new(transaction.txid, index as u32),
+                        utxo: output.clone(),
+                        block_hash: block_hash.clone(),
+                        spent_by: None,
+                    });
+            }
+        }
+        self.tip = Some(block_hash);
+    }
+
+    /// Everything the address received, spent or not, oldest first
+    pub fn history(&self, address: &Address) -> &[AddressEntry] {
+        self.addresses
+            .get(&address.to_string())
+            .map_or(&[], |entries| entries.as_slice())
+    }
+
+    pub fn unspent(&self, address: &Address) -> impl Iterator<Item = &AddressEntry> {
+        self.history(address)
+            .iter()
+            .filter(|entry| entry.spent_by.is_none())
+    }
+
+    pub fn balance(&self, address: &Address) -> u64 {
+        self.unspent(address).map(|entry| entry.utxo.value()).sum()
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
@@ -0,0 +1,600 @@
+//! Blocks are appended one after the other to a single `blocks.dat` file, and an index next to it
+//! remembers where each one starts. Looking a block up by hash or by height is then a map lookup
+//! and one read, instead of scanning the whole directory.
+//!
+//! Every record in `blocks.dat` is the magic bytes, the length of the block as a little endian u32
+//! and the block serialized as JSON. That's enough to rebuild the index from the raw file, which is
+//! what `reindex` does.
+//!
+//! `blocks.idx` has one line per block, its location as JSON, so storing a block only appends a
+//! line to it. Anything that takes blocks out, like `pop` or `prune`, replaces the index as a whole
+//! by renaming a synced temp file over it.
+//!
+//! A crash can't leave the store inconsistent: a block is written and synced to disk before the
+//! index mentions it. On open, a last index line cut short is ignored, and whatever `blocks.dat`
+//! has beyond what the index knows is either indexed, if it's a complete block, or cut off, if the
+//! crash came in the middle of it.
+//!
+//! A pruned store deletes the bodies of old blocks with `prune`, and keeps only their headers in
+//! `pruned_headers.json`. Heights don't change: the first body is at the height after the last
+//! pruned header.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chain::block::{Block, BlockHeader},
+    error_handling::{CleytoResult, CleytonError},
+    node::files::{sync_parent_dir, write_atomically},
+};
+
+pub const BLOCK_RECORD_MAGIC: [u8; 4] = *b"CLBK";
+const RECORD_HEADER_LEN: u64 = 8;
+
+const BLOCKS_FILE: &str = "blocks.dat";
+const INDEX_FILE: &str = "blocks.idx";
+const PRUNED_HEADERS_FILE: &str = "pruned_headers.json";
+// Where every block used to be written to its own file
+const LEGACY_BLOCKS_DIR: &str = "blocks";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockLocation {
+    pub hash: String,
+    /// Where the record starts in `blocks.dat`, header included
+    pub offset: u64,
+    /// Length of the serialized block, header excluded
+    pub length: u32,
+}
+
+/// What `blocks.idx` used to be, all the locations in one JSON object. Still read, and replaced
+/// by the line per block one on open
+#[derive(Deserialize)]
+struct LegacyIndex {
+    blocks: Vec<BlockLocation>,
+}
+
+// ----------------------------------------------- BlockStore definition -------------------------------------------
+pub struct BlockStore {
+    dir: PathBuf,
+    // The blocks whose bodies were deleted, from height 0
+    pruned: Vec<BlockHeader>,
+    // The blocks that still have their bodies, right after the pruned ones
+    bodies: Vec<BlockLocation>,
+    by_hash: HashMap<String, usize>,
+}
+
+impl BlockStore {
+    /// Opens the store kept in `dir`, creating it if needed. If there's no index yet, it's built
+    /// from the raw files.
+    pub fn open(dir: impl Into<PathBuf>) -> CleytoResult<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut store = Self {
+            dir,
+            pruned: Vec::new(),
+            bodies: Vec::new(),
+            by_hash: HashMap::new(),
+        };
+        store.pruned = store.read_pruned_headers()?;
+
+        // Left behind by a crash in the middle of pruning
+        match std::fs::remove_file(store.temp_blocks_path()) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        match store.read_index()? {
+            Some((locations, rewrite)) => {
+                // If pruning was cut short, the index can still have blocks that are pruned now
+                let bodies = store.without_pruned(locations);
+                store.set_index(bodies);
+                if rewrite {
+                    store.save_index()?;
+                }
+                store.recover()?;
+            }
+            None => {
+                store.reindex()?;
+            }
+        }
+
+        Ok(store)
+    }
+
+    /// The directory the store lives in
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn blocks_path(&self) -> PathBuf {
+        self.dir.join(BLOCKS_FILE)
+    }
+
+    fn temp_blocks_path(&self) -> PathBuf {
+        self.dir.join(format!("{BLOCKS_FILE}.tmp"))
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.dir.join(INDEX_FILE)
+    }
+
+    fn pruned_headers_path(&self) -> PathBuf {
+        self.dir.join(PRUNED_HEADERS_FILE)
+    }
+
+    fn read_pruned_headers(&self) -> CleytoResult<Vec<BlockHeader>> {
+        match std::fs::read_to_string(self.pruned_headers_path()) {
+            Ok(serialized_headers) => serde_json::from_str(&serialized_headers)
+                .map_err(|e| CleytonError::CorruptBlockStore(e.to_string())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn without_pruned(&self, mut bodies: Vec<BlockLocation>) -> Vec<BlockLocation> {
+        bodies.retain(|location| {
+            !self
+                .pruned
+                .iter()
+                .any(|header| header.hash == location.hash)
+        });
+        bodies
+    }
+
+    fn set_index(&mut self, bodies: Vec<BlockLocation>) {
+        self.by_hash = self
+            .pruned
+            .iter()
+            .map(|header| &header.hash)
+            .chain(bodies.iter().map(|location| &location.hash))
+            .enumerate()
+            .map(|(height, hash)| (hash.clone(), height))
+            .collect();
+        self.bodies = bodies;
+    }
+
+    /// The locations in `blocks.idx`, None if there's no index. Also says whether the index has
+    /// to be rewritten, because it's in the old format or its last line was cut short
+    fn read_index(&self) -> CleytoResult<Option<(Vec<BlockLocation>, bool)>> {
+        let serialized_index = match std::fs::read_to_string(self.index_path()) {
+            Ok(serialized_index) => serialized_index,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if let Ok(index) = serde_json::from_str::<LegacyIndex>(&serialized_index) {
+            return Ok(Some((index.blocks, true)));
+        }
+
+        let mut locations = Vec::new();
+        let mut lines = serialized_index.split_inclusive('\n').peekable();
+        while let Some(line) = lines.next() {
+            match serde_json::from_str(line) {
+                Ok(location) => locations.push(location),
+                // Only the last line can be cut short, by a crash while appending it
+                Err(_) if lines.peek().is_none() && !line.ends_with('\n') => {
+                    return Ok(Some((locations, true)));
+                }
+                Err(e) => return Err(CleytonError::CorruptBlockStore(e.to_string())),
+            }
+        }
+        Ok(Some((locations, false)))
+    }
+
+    fn save_index(&self) -> CleytoResult<()> {
+        let mut serialized_index = Vec::new();
+        for location in &self.bodies {
+            serialized_index.extend(index_line(location)?);
+        }
+        write_atomically(&self.index_path(), &serialized_index)
+    }
+
+    fn append_to_index(&self, location: &BlockLocation) -> CleytoResult<()> {
+        let mut index = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.index_path())?;
+        index.write_all(&index_line(location)?)?;
+        index.sync_data()?;
+        Ok(())
+    }
+
+    /// Where the last indexed record ends
+    fn indexed_end(&self) -> u64 {
+        self.bodies.last().map_or(0, |location| {
+            location.offset + RECORD_HEADER_LEN + location.length as u64
+        })
+    }
+
+    /// Brings the index and `blocks.dat` back in line after a crash
+    fn recover(&mut self) -> CleytoResult<()> {
+        let file_length = match std::fs::metadata(self.blocks_path()) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let indexed_end = self.indexed_end();
+
+        if file_length == indexed_end {
+            return Ok(());
+        }
+
+        if file_length < indexed_end {
+            // The index points past the data, so it can't be trusted at all
+            self.reindex_blocks_file()?;
+            return Ok(());
+        }
+
+        // Blocks written after the index was last saved, maybe the last one only partially
+        let (locations, end) = self.scan_records(indexed_end)?;
+        self.truncate_blocks_file(end)?;
+        let mut bodies = std::mem::take(&mut self.bodies);
+        bodies.extend(locations);
+        self.set_index(bodies);
+        self.save_index()
+    }
+
+    /// Reads every complete record from `offset` on. Returns where they are and where the last one
+    /// ends, which is where a record cut short by a crash would start
+    fn scan_records(&self, offset: u64) -> CleytoResult<(Vec<BlockLocation>, u64)> {
+        let mut locations = Vec::new();
+        let mut end = offset;
+
+        let mut file = match File::open(self.blocks_path()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((locations, end)),
+            Err(e) => return Err(e.into()),
+        };
+        file.seek(SeekFrom::Start(offset))?;
+
+        while let Record::Complete(length, serialized_block) = read_record(&mut file)? {
+            // A record can have the right length and still hold garbage, if the crash came before
+            // its data reached the disk
+            let Ok(block) = serde_json::from_slice::<Block>(&serialized_block) else {
+                break;
+            };
+            locations.push(BlockLocation {
+                hash: block.get_hash(),
+                offset: end,
+                length,
+            });
+            end += RECORD_HEADER_LEN + length as u64;
+        }
+
+        Ok((locations, end))
+    }
+
+    fn truncate_blocks_file(&self, length: u64) -> CleytoResult<()> {
+        let file = match OpenOptions::new().write(true).open(self.blocks_path()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        if file.metadata()?.len() > length {
+            file.set_len(length)?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn reindex_blocks_file(&mut self) -> CleytoResult<()> {
+        self.pruned = self.read_pruned_headers()?;
+        let (locations, end) = self.scan_records(0)?;
+        self.truncate_blocks_file(end)?;
+        let bodies = self.without_pruned(locations);
+        self.set_index(bodies);
+        self.save_index()
+    }
+
+    /// How many blocks are stored, pruned ones included
+    pub fn len(&self) -> usize {
+        self.pruned.len() + self.bodies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The height of the first block that still has its body
+    pub fn pruned_height(&self) -> u32 {
+        self.pruned.len() as u32
+    }
+
+    /// The hash of the last block stored
+    pub fn tip(&self) -> Option<&str> {
+        match self.bodies.last() {
+            Some(location) => Some(location.hash.as_str()),
+            None => self.pruned.last().map(|header| header.hash.as_str()),
+        }
+    }
+
+    pub fn height_of(&self, hash: &str) -> Option<u32> {
+        self.by_hash.get(hash).map(|height| *height as u32)
+    }
+
+    pub fn hash_at(&self, height: u32) -> Option<&str> {
+        let height = height as usize;
+        match height.checked_sub(self.pruned.len()) {
+            None => Some(self.pruned[height].hash.as_str()),
+            Some(body) => self.bodies.get(body).map(|location| location.hash.as_str()),
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.by_hash.contains_key(hash)
+    }
+
+    /// Appends the block and returns its height. Storing a block that's already there does nothing
+    pub fn append(&mut self, block: &Block) -> CleytoResult<u32> {
+        let hash = block.get_hash();
+        if let Some(height) = self.height_of(&hash) {
+            return Ok(height);
+        }
+
+        let serialized_block =
+            serde_json::to_vec(block).map_err(CleytonError::BlockSerializationError)?;
+        let record = encode_record(&serialized_block)?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.blocks_path())?;
+        let offset = file.seek(SeekFrom::End(0))?;
+
+        file.write_all(&record)?;
+        // The block has to be on disk before the index points to it
+        file.sync_data()?;
+
+        let location = BlockLocation {
+            hash,
+            offset,
+            length: serialized_block.len() as u32,
+        };
+        self.append_to_index(&location)?;
+        let height = self.len();
+        self.by_hash.insert(location.hash.clone(), height);
+        self.bodies.push(location);
+
+        Ok(height as u32)
+    }
+
+    pub fn read_by_hash(&self, hash: &str) -> CleytoResult<Block> {
+        let height = self.height_of(hash).ok_or(CleytonError::BlockNotFound)?;
+        self.read_by_height(height)
+    }
+
+    /// Fails with `BlockPruned` if only the header of the block is left
+    pub fn read_by_height(&self, height: u32) -> CleytoResult<Block> {
+        let height = height as usize;
+        let Some(body) = height.checked_sub(self.pruned.len()) else {
+            return Err(CleytonError::BlockPruned);
+        };
+        let location = self.bodies.get(body).ok_or(CleytonError::BlockNotFound)?;
+        self.read_location(location)
+    }
+
+    /// The header of any stored block, pruned or not
+    pub fn read_header(&self, height: u32) -> CleytoResult<BlockHeader> {
+        match self.pruned.get(height as usize) {
+            Some(header) => Ok(header.clone()),
+            None => Ok(self.read_by_height(height)?.header()),
+        }
+    }
+
+    fn read_location(&self, location: &BlockLocation) -> CleytoResult<Block> {
+        let mut file = File::open(self.blocks_path())?;
+        file.seek(SeekFrom::Start(location.offset))?;
+
+        let Record::Complete(length, serialized_block) = read_record(&mut file)? else {
+            return Err(CleytonError::CorruptBlockStore(format!(
+                "block {} is missing from the block file",
+                location.hash
+            )));
+        };
+        if length != location.length {
+            return Err(CleytonError::CorruptBlockStore(format!(
+                "block {} doesn't match the index",
+                location.hash
+            )));
+        }
+
+        serde_json::from_slice(&serialized_block).map_err(CleytonError::BlockDeserializationError)
+    }
+
+    /// Takes the last block off the store. Blocks are only ever appended, so only the tip can go
+    pub fn pop(&mut self) -> CleytoResult<Option<Block>> {
+        let Some(location) = self.bodies.last().cloned() else {
+            if self.pruned.is_empty() {
+                return Ok(None);
+            }
+            return Err(CleytonError::BlockPruned);
+        };
+        let block = self.read_location(&location)?;
+
+        // Cut the file first. If the index were saved first and we crashed in between, the block
+        // would come back as one written after the last save
+        self.truncate_blocks_file(location.offset)?;
+
+        self.bodies.pop();
+        self.by_hash.remove(&location.hash);
+        self.save_index()?;
+
+        Ok(Some(block))
+    }
+
+    /// Starts an empty store from the headers of blocks whose bodies it will never have, as if
+    /// they were pruned. Used to bootstrap a node from a UTXO snapshot
+    pub fn import_headers(&mut self, headers: Vec<BlockHeader>) -> CleytoResult<()> {
+        if !self.is_empty() {
+            return Err(CleytonError::CorruptBlockStore(
+                "headers can only be imported into an empty store".to_string(),
+            ));
+        }
+
+        let serialized_headers =
+            serde_json::to_string(&headers).map_err(CleytonError::BlockSerializationError)?;
+        write_atomically(&self.pruned_headers_path(), serialized_headers.as_bytes())?;
+        self.pruned = headers;
+        self.set_index(Vec::new());
+        self.save_index()
+    }
+
+    /// Deletes the bodies of every block but the last `keep` ones, leaving their headers. At least
+    /// the tip is always kept. Returns how many bodies were deleted
+    pub fn prune(&mut self, keep: u32) -> CleytoResult<usize> {
+        let keep = (keep as usize).max(1);
+        if self.bodies.len() <= keep {
+            return Ok(0);
+        }
+        let prune_count = self.bodies.len() - keep;
+
+        let mut pruned = self.pruned.clone();
+        for location in &self.bodies[..prune_count] {
+            pruned.push(self.read_location(location)?.header());
+        }
+
+        // Copy the bodies that stay to a new file. Until it's renamed over `blocks.dat` below,
+        // a crash just leaves it behind to be deleted
+        let first_kept = self.bodies[prune_count].offset;
+        let mut source = File::open(self.blocks_path())?;
+        source.seek(SeekFrom::Start(first_kept))?;
+        let mut target = File::create(self.temp_blocks_path())?;
+        std::io::copy(&mut source, &mut target)?;
+        target.sync_all()?;
+
+        // From here on, `open` ignores whatever the index says about the pruned blocks, and if
+        // the index doesn't fit the new file, it's rebuilt from it
+        let serialized_headers =
+            serde_json::to_string(&pruned).map_err(CleytonError::BlockSerializationError)?;
+        write_atomically(&self.pruned_headers_path(), serialized_headers.as_bytes())?;
+        std::fs::rename(self.temp_blocks_path(), self.blocks_path())?;
+        sync_parent_dir(&self.blocks_path())?;
+
+        let bodies = self.bodies[prune_count..]
+            .iter()
+            .map(|location| BlockLocation {
+                offset: location.offset - first_kept,
+                ..location.clone()
+            })
+            .collect();
+        self.pruned = pruned;
+        self.set_index(bodies);
+        self.save_index()?;
+
+        Ok(prune_count)
+    }
+
+    /// Rebuilds the index from `blocks.dat`, then adds any block still kept in the old one file
+    /// per block layout that isn't in there yet. Returns how many blocks are stored afterwards
+    pub fn reindex(&mut self) -> CleytoResult<usize> {
+        self.reindex_blocks_file()?;
+        self.import_legacy_blocks()?;
+        Ok(self.len())
+    }
+
+    /// Appends the blocks kept in the old one file per block layout that aren't stored yet, in the
+    /// current format. The old files are left where they are. Returns how many were added
+    pub fn import_legacy_blocks(&mut self) -> CleytoResult<usize> {
+        let mut imported = 0;
+        for block in read_legacy_blocks(&self.dir.join(LEGACY_BLOCKS_DIR))? {
+            if !self.contains(&block.get_hash()) {
+                self.append(&block)?;
+                imported += 1;
+            }
+        }
+        Ok(imported)
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+pub(crate) enum Record {
+    Complete(u32, Vec<u8>),
+    /// Nothing left to read
+    End,
+    /// A record that was cut short, or garbage where a record should start
+    Partial,
+}
+
+/// The magic bytes, the length and the serialized block, ready to be written
+pub(crate) fn encode_record(serialized_block: &[u8]) -> CleytoResult<Vec<u8>> {
+    let length = u32::try_from(serialized_block.len())
+        .map_err(|_| CleytonError::CorruptBlockStore("block too big to store".to_string()))?;
+
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + serialized_block.len());
+    record.extend_from_slice(&BLOCK_RECORD_MAGIC);
+    record.extend_from_slice(&length.to_le_bytes());
+    record.extend_from_slice(serialized_block);
+    Ok(record)
+}
+
+/// Reads the record starting at the current position
+pub(crate) fn read_record<R: Read>(file: &mut R) -> CleytoResult<Record> {
+    let mut header = Vec::with_capacity(RECORD_HEADER_LEN as usize);
+    Read::by_ref(file)
+        .take(RECORD_HEADER_LEN)
+        .read_to_end(&mut header)?;
+    if header.is_empty() {
+        return Ok(Record::End);
+    }
+    if header.len() < RECORD_HEADER_LEN as usize || header[..4] != BLOCK_RECORD_MAGIC {
+        return Ok(Record::Partial);
+    }
+    let length = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+    // Not allocated up front, the length could be garbage
+    let mut serialized_block = Vec::new();
+    Read::by_ref(file)
+        .take(length as u64)
+        .read_to_end(&mut serialized_block)?;
+    if serialized_block.len() < length as usize {
+        return Ok(Record::Partial);
+    }
+
+    Ok(Record::Complete(length, serialized_block))
+}
+
+// One line of `blocks.idx`
+fn index_line(location: &BlockLocation) -> CleytoResult<Vec<u8>> {
+    let mut line = serde_json::to_vec(location).map_err(CleytonError::BlockSerializationError)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// The blocks written as `block_<number>_<hash>.blk`, in order of number
+fn read_legacy_blocks(legacy_dir: &Path) -> CleytoResult<Vec<Block>> {
+    if !std::fs::exists(legacy_dir)? {
+        return Ok(Vec::new());
+    }
+
+    let mut numbered_paths = Vec::new();
+    for file in std::fs::read_dir(legacy_dir)? {
+        let path = file?.path();
+        if path.extension().is_none_or(|extension| extension != "blk") {
+            continue;
+        }
+
+        let number = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.strip_prefix("block_"))
+            .and_then(|stem| stem.split('_').next())
+            .and_then(|number| number.parse::<u32>().ok());
+        if let Some(number) = number {
+            numbered_paths.push((number, path));
+        }
+    }
+    numbered_paths.sort();
+
+    numbered_paths
+        .into_iter()
+        .map(|(_, path)| {
+            let serialized_block = std::fs::read_to_string(path)?;
+            serde_json::from_str(&serialized_block).map_err(CleytonError::BlockDeserializationError)
+        })
+        .collect()
+}
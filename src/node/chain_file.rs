@@ -0,0 +1,62 @@
+//! A portable file with a stretch of the chain: the blocks one after the other, in the same
+//! length-prefixed records as `blocks.dat`. It's written and read one block at a time, so a whole
+//! chain never has to fit in memory.
+
+use std::io::{Read, Write};
+
+use crate::{
+    chain::block::Block,
+    error_handling::{CleytoResult, CleytonError},
+    node::{
+        block_store::{encode_record, read_record, BlockStore, Record},
+        NodeState,
+    },
+};
+
+/// Writes the blocks from height `from` to `to`, both included, in order. Returns how many were
+/// written
+pub fn export_chain<W: Write>(
+    block_store: &BlockStore,
+    from: u32,
+    to: u32,
+    writer: &mut W,
+) -> CleytoResult<u32> {
+    if from > to || to as usize >= block_store.len() {
+        return Err(CleytonError::BlockNotFound);
+    }
+
+    for height in from..=to {
+        let block = block_store.read_by_height(height)?;
+        let serialized_block =
+            serde_json::to_vec(&block).map_err(CleytonError::BlockSerializationError)?;
+        writer.write_all(&encode_record(&serialized_block)?)?;
+    }
+    writer.flush()?;
+
+    Ok(to - from + 1)
+}
+
+/// Reads the blocks one at a time and has the node state validate and store each of them. Blocks
+/// the node already has are skipped, so exports can overlap. Returns how many were imported
+pub fn import_chain<R: Read>(state: &mut NodeState, reader: &mut R) -> CleytoResult<usize> {
+    let mut imported = 0;
+    loop {
+        let serialized_block = match read_record(reader)? {
+            Record::Complete(_, serialized_block) => serialized_block,
+            Record::End => return Ok(imported),
+            Record::Partial => {
+                return Err(CleytonError::InvalidBlock(
+                    "the chain file ends in the middle of a block".to_string(),
+                ))
+            }
+        };
+        let block: Block = serde_json::from_slice(&serialized_block)
+            .map_err(CleytonError::BlockDeserializationError)?;
+
+        if state.has_block(&block.get_hash()) {
+            continue;
+        }
+        state.accept_block(block)?;
+        imported += 1;
+    }
+}
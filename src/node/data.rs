@@ -1,147 +1,125 @@
-use std::path::PathBuf;
-
 use crate::{
     chain::{block::Block, Chain},
-    configs::{Config, ConfigPaths},
+    configs::ConfigPaths,
     error_handling::{CleytoResult, CleytonError},
+    node::{
+        block_store::BlockStore,
+        chain_file,
+        snapshot::{SnapshotBase, UtxoSnapshot},
+        NodeState,
+    },
+};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
 };
 
-pub fn write_block(block: &Block) -> CleytoResult<()> {
-    let last_block = Config::get().last_block();
-
-    let serialized_block =
-        serde_json::to_string(&block).map_err(CleytonError::BlockSerializationError)?;
-
-    let block_path = PathBuf::from(format!(
-        "{}/block_{}_{}.blk",
-        ConfigPaths::get().block_dir,
-        last_block,
-        block.get_hash(),
-    ));
-
-    if !std::fs::exists(ConfigPaths::get().block_dir)? {
-        std::fs::create_dir(ConfigPaths::get().block_dir)?;
-    }
-
-    // write block
-    std::fs::write(block_path, serialized_block)?;
-    // update last_block
-    std::fs::write(ConfigPaths::get().last_block, (last_block + 1).to_string())?;
+/// Opens the block store in the default data directory. Anything doing many lookups should keep
+/// the `BlockStore` around instead of going through these
+pub fn block_store() -> CleytoResult<BlockStore> {
+    BlockStore::open(ConfigPaths::get().data_dir)
+}
 
+pub fn write_block(block: &Block) -> CleytoResult<()> {
+    block_store()?.append(block)?;
     Ok(())
 }
 
-pub fn read_block_by_hash(hash: &String) -> CleytoResult<Block> {
-    let block_paths =
-        std::fs::read_dir(PathBuf::from(format!("{}/", ConfigPaths::get().block_dir,))).unwrap();
-
-    let regex = regex::Regex::new(format!("block_.*_{hash}\\.blk").as_str()).unwrap();
-
-    let block_path = if let Some(v) = block_paths.map(|v| v.unwrap()).find(|file| {
-        let file_name = file.file_name().into_string().unwrap();
-        regex.is_match(&file_name)
-    }) {
-        v
-    } else {
-        return Err(CleytonError::BlockNotFound);
-    };
-
-    let serialized_block =
-        std::fs::read_to_string(block_path.path()).map_err(|_| CleytonError::BlockNotFound)?;
-
-    serde_json::from_str(&serialized_block).map_err(CleytonError::BlockDeserializationError)
+pub fn read_block_by_hash(hash: &str) -> CleytoResult<Block> {
+    block_store()?.read_by_hash(hash)
 }
 
 pub fn read_block_by_number(block_number: &u32) -> CleytoResult<Block> {
-    let block_paths =
-        std::fs::read_dir(PathBuf::from(format!("{}/", ConfigPaths::get().block_dir,))).unwrap();
-
-    let regex = regex::Regex::new(format!("block_{block_number}_.*\\.blk").as_str()).unwrap();
-
-    let block_path = if let Some(v) = block_paths.map(|v| v.unwrap()).find(|file| {
-        let file_name = file.file_name().into_string().unwrap();
-        regex.is_match(&file_name)
-    }) {
-        v
-    } else {
-        return Err(CleytonError::BlockNotFound);
-    };
-
-    let serialized_block =
-        std::fs::read_to_string(block_path.path()).map_err(|_| CleytonError::BlockNotFound)?;
-
-    serde_json::from_str(&serialized_block).map_err(CleytonError::BlockDeserializationError)
+    block_store()?.read_by_height(*block_number)
 }
 
-// I'm leaving those here to maybe implement a better one later on,
-// right now they are a bit naive
 pub fn check_block_is_registered_by_number(block_number: u32) -> bool {
-    read_block_by_number(&block_number).is_ok()
+    block_store().is_ok_and(|store| store.hash_at(block_number).is_some())
 }
 
-pub fn check_block_is_registered_by_hash(hash: &String) -> bool {
-    read_block_by_hash(hash).is_ok()
+pub fn check_block_is_registered_by_hash(hash: &str) -> bool {
+    block_store().is_ok_and(|store| store.contains(hash))
 }
 
 pub fn write_chain_blocks(chain: &Chain) -> CleytoResult<()> {
-    println!("Checkpoint 2");
+    let mut store = block_store()?;
     for block in &chain.blocks {
-        println!("Checkpoint 3");
-        write_block(block)?;
-        println!("Checkpoint 4");
+        store.append(block)?;
     }
     Ok(())
 }
 
+/// Blocks are appended to a single file, so only the last one can be removed
 pub fn remove_block_by_hash(hash: String) -> CleytoResult<()> {
-    let block_paths =
-        std::fs::read_dir(PathBuf::from(format!("{}/", ConfigPaths::get().block_dir,))).unwrap();
-
-    let regex = regex::Regex::new(format!("block_(.*)_{hash}\\.blk").as_str()).unwrap();
-
-    let (block_path, block_number) = if let Some(v) =
-        block_paths.map(|v| v.unwrap()).find_map(|file| {
-            let file_name = file.file_name().into_string().unwrap();
-            let caps_opt = regex.captures(&file_name);
-            if let Some(caps) = caps_opt {
-                let blk_number: u32 = str::parse(&caps[0]).unwrap();
-                Some((file, blk_number))
-            } else {
-                None
-            }
-        }) {
-        v
-    } else {
-        return Err(CleytonError::BlockNotFound);
-    };
-
-    std::fs::remove_file(block_path.path())?;
-
-    if block_number == Config::get().last_block() {
-        Config::get().update_last_block(-1)?;
+    let mut store = block_store()?;
+    match store.tip() {
+        Some(tip) if tip == hash => {}
+        _ if store.contains(&hash) => return Err(CleytonError::BlockNotAtTip),
+        _ => return Err(CleytonError::BlockNotFound),
     }
-
+    store.pop()?;
     Ok(())
 }
 
 pub fn remove_block_by_number(block_number: u32) -> CleytoResult<()> {
-    let block_paths =
-        std::fs::read_dir(PathBuf::from(format!("{}/", ConfigPaths::get().block_dir,))).unwrap();
+    let mut store = block_store()?;
+    if store.hash_at(block_number).is_none() {
+        return Err(CleytonError::BlockNotFound);
+    }
+    if block_number as usize + 1 != store.len() {
+        return Err(CleytonError::BlockNotAtTip);
+    }
+    store.pop()?;
+    Ok(())
+}
 
-    let regex = regex::Regex::new(format!("block_{block_number}_.*\\.blk").as_str()).unwrap();
+/// Rebuilds the block index from the raw block files. Returns how many blocks are stored
+pub fn reindex_blocks() -> CleytoResult<usize> {
+    block_store()?.reindex()
+}
 
-    let block_path = if let Some(v) = block_paths.map(|v| v.unwrap()).find(|file| {
-        let file_name = file.file_name().into_string().unwrap();
-        regex.is_match(&file_name)
-    }) {
-        v
-    } else {
-        return Err(CleytonError::BlockNotFound);
+/// Writes a snapshot of the UTXO set at `height`, or at the tip, to `path`
+pub fn dump_utxo(height: Option<u32>, path: &Path) -> CleytoResult<UtxoSnapshot> {
+    let snapshot = UtxoSnapshot::create(&block_store()?, height)?;
+    snapshot.write(path)?;
+    Ok(snapshot)
+}
+
+/// Starts the empty default data directory from the snapshot at `path`
+pub fn load_utxo(path: &Path, expected_commitment: Option<&str>) -> CleytoResult<SnapshotBase> {
+    UtxoSnapshot::read(path)?.load_into(&mut block_store()?, expected_commitment)
+}
+
+/// Writes the blocks from height `from`, or the first one, to `to`, or the tip, to `path`. Returns
+/// how many were written
+pub fn export_chain(from: Option<u32>, to: Option<u32>, path: &Path) -> CleytoResult<u32> {
+    let store = block_store()?;
+    let from = from.unwrap_or(store.pruned_height());
+    let to = match to {
+        Some(to) => to,
+        None => store
+            .len()
+            .checked_sub(1)
+            .ok_or(CleytonError::BlockNotFound)? as u32,
     };
 
-    std::fs::remove_file(block_path.path())?;
+    let mut writer = BufWriter::new(File::create(path)?);
+    chain_file::export_chain(&store, from, to, &mut writer)
+}
 
-    Ok(())
+/// Validates the blocks in the file at `path` on top of the chain in the default data directory,
+/// and stores them. The node shouldn't be running meanwhile. Returns how many were imported
+pub fn import_chain(path: &Path) -> CleytoResult<usize> {
+    let mut state = NodeState::load(block_store()?)?;
+    let mut reader = BufReader::new(File::open(path)?);
+    chain_file::import_chain(&mut state, &mut reader)
+}
+
+/// Moves the blocks still kept one per file, from before the block store, into the store of the
+/// default data directory, in the current format. Returns how many blocks were added
+pub fn migrate_blocks() -> CleytoResult<usize> {
+    block_store()?.import_legacy_blocks()
 }
 
 /// If no hash is provided, reads from block number 0
@@ -0,0 +1,174 @@
+//! A few nodes on this machine talking to each other, to try the network without more computers.
+//! Each one gets its own data directory under `devnet` in ours, its own ports, and connects to every
+//! node started before it. What was started is kept in `devnet/devnet.json`, for `devnet down`.
+
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::configs::ConfigPaths;
+use crate::error_handling::{CleytoResult, CleytonError};
+use crate::node::files::write_atomically;
+use crate::node::NodeOptions;
+use crate::{kill_node_at, spawn_node};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DevnetNode {
+    pub name: String,
+    pub data_dir: PathBuf,
+    /// Of the HTTP API
+    pub port: u16,
+    pub p2p_port: u16,
+    /// None until it's started
+    #[serde(default)]
+    pub pid: Option<u32>,
+}
+
+impl DevnetNode {
+    pub fn p2p_address(&self) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], self.p2p_port))
+    }
+}
+
+/// Where the devnet of a data directory lives
+pub fn devnet_dir(paths: &ConfigPaths) -> PathBuf {
+    paths.data_dir().join("devnet")
+}
+
+fn state_file(devnet_dir: &Path) -> PathBuf {
+    devnet_dir.join("devnet.json")
+}
+
+/// The nodes of a devnet of `count` nodes. Their HTTP ports go one after the other from `port`,
+/// and so do their peer ports from `p2p_port`, which defaults to the one after the last HTTP port
+pub fn layout(
+    devnet_dir: &Path,
+    count: u16,
+    port: u16,
+    p2p_port: Option<u16>,
+) -> CleytoResult<Vec<DevnetNode>> {
+    if count == 0 {
+        return Err(CleytonError::InvalidOptions(
+            "a devnet needs at least one node".to_string(),
+        ));
+    }
+    let out_of_ports =
+        || CleytonError::InvalidOptions(format!("not enough ports for {count} nodes"));
+    let p2p_port = match p2p_port {
+        Some(p2p_port) => p2p_port,
+        None => port.checked_add(count).ok_or_else(out_of_ports)?,
+    };
+    port.checked_add(count - 1).ok_or_else(out_of_ports)?;
+    p2p_port.checked_add(count - 1).ok_or_else(out_of_ports)?;
+    let (first, first_p2p, count_u32) = (u32::from(port), u32::from(p2p_port), u32::from(count));
+    if first < first_p2p + count_u32 && first_p2p < first + count_u32 {
+        return Err(CleytonError::InvalidOptions(
+            "the HTTP and peer ports of the devnet overlap".to_string(),
+        ));
+    }
+
+    Ok((0..count)
+        .map(|i| {
+            let name = format!("devnet-{}", i + 1);
+            DevnetNode {
+                data_dir: devnet_dir.join(&name),
+                name,
+                port: port + i,
+                p2p_port: p2p_port + i,
+                pid: None,
+            }
+        })
+        .collect())
+}
+
+/// The nodes of the devnet that's up, if there's one
+pub fn running(paths: &ConfigPaths) -> CleytoResult<Option<Vec<DevnetNode>>> {
+    match fs::read_to_string(state_file(&devnet_dir(paths))) {
+        Ok(serialized) => serde_json::from_str(&serialized)
+            .map(Some)
+            .map_err(CleytonError::BlockDeserializationError),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save(devnet_dir: &Path, nodes: &[DevnetNode]) -> CleytoResult<()> {
+    let serialized =
+        serde_json::to_string_pretty(nodes).map_err(CleytonError::BlockSerializationError)?;
+    write_atomically(&state_file(devnet_dir), serialized.as_bytes())
+}
+
+/// Starts the nodes of `layout`, each in its own process. With `encrypt`, they encrypt the
+/// connections between them
+pub fn up(
+    paths: &ConfigPaths,
+    nodes: u16,
+    port: u16,
+    p2p_port: Option<u16>,
+    encrypt: bool,
+) -> CleytoResult<Vec<DevnetNode>> {
+    if running(paths)?.is_some() {
+        return Err(CleytonError::InvalidOptions(
+            "a devnet is up already, `node devnet down` stops it".to_string(),
+        ));
+    }
+    let devnet_dir = devnet_dir(paths);
+    fs::create_dir_all(&devnet_dir)?;
+
+    let mut started: Vec<DevnetNode> = Vec::new();
+    for mut node in layout(&devnet_dir, nodes, port, p2p_port)? {
+        fs::create_dir_all(&node.data_dir)?;
+        let options = NodeOptions {
+            port: Some(node.port),
+            p2p_port: Some(node.p2p_port),
+            connect: started.iter().map(DevnetNode::p2p_address).collect(),
+            encrypt,
+            ..NodeOptions::default()
+        };
+        // They outlive this process, `down` stops them
+        #[allow(clippy::zombie_processes)]
+        let child = spawn_node(&node.data_dir, &node.name, &options)?;
+        node.pid = Some(child.id());
+        started.push(node);
+        // Saved as they go, so the ones started are stopped by `down` even if the next one fails
+        save(&devnet_dir, &started)?;
+    }
+    Ok(started)
+}
+
+/// Stops every node of the devnet, deleting their data directories too if `clean`. Returns the
+/// nodes that were up
+pub fn down(paths: &ConfigPaths, clean: bool) -> CleytoResult<Vec<DevnetNode>> {
+    let devnet_dir = devnet_dir(paths);
+    let nodes = running(paths)?.unwrap_or_default();
+    for node in &nodes {
+        kill_node_at(&ConfigPaths::new(&node.data_dir), &node.name)?;
+    }
+
+    if clean {
+        // They write their logs and mempools on the way out
+        for pid in nodes.iter().filter_map(|node| node.pid) {
+            wait_for_exit(pid);
+        }
+        match fs::remove_dir_all(&devnet_dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    } else if !nodes.is_empty() {
+        fs::remove_file(state_file(&devnet_dir))?;
+    }
+    Ok(nodes)
+}
+
+// Only where there's a /proc to look at, elsewhere it doesn't wait
+fn wait_for_exit(pid: u32) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let process = Path::new("/proc").join(pid.to_string());
+    while process.exists() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(50));
+    }
+}
@@ -0,0 +1,50 @@
+//! Writing the node's files in a way a crash can't leave half done
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::error_handling::CleytoResult;
+
+/// Replaces the file at `path` in one step: the contents go to a temp file that is synced and then
+/// renamed over it, so a crash leaves either the old file or the new one, never half of each
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> CleytoResult<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    write_atomically_with(path, contents, &options)
+}
+
+/// Like [`write_atomically`], but on unix the file is only readable and writable by its owner
+/// from the moment it's created, for secrets like the node key
+pub(crate) fn write_private_atomically(path: &Path, contents: &[u8]) -> CleytoResult<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    write_atomically_with(path, contents, &options)
+}
+
+fn write_atomically_with(path: &Path, contents: &[u8], options: &OpenOptions) -> CleytoResult<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_owned();
+    temp_name.push(".tmp");
+    let temp_path = path.with_file_name(temp_name);
+
+    // A temp file left by a crash keeps its old permissions if it's opened again, so start afresh
+    match std::fs::remove_file(&temp_path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let mut file = options.open(&temp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)?;
+    sync_parent_dir(path)
+}
+
+/// A rename lives in the directory, so it's only on disk once the directory is synced too
+pub(crate) fn sync_parent_dir(path: &Path) -> CleytoResult<()> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
@@ -1,27 +1,54 @@
+pub mod address_index;
+pub mod block_store;
+pub mod chain_file;
 pub mod data;
+pub mod devnet;
 pub mod logger;
+pub mod p2p;
+pub mod snapshot;
+pub mod tx_index;
 pub mod ui;
 
+mod files;
 mod resolve_requests;
 mod thread_pool;
 mod utils;
 
-use crate::chain::{transaction::Transaction, Chain};
-use crate::configs::ConfigPaths;
+use crate::chain::block::{Block, BlockHeader};
+use crate::chain::utils::{BLOCK_REWARD, PROOF_OF_WORK_DIFFICULTY};
+use crate::chain::utxo::UTXO;
+use crate::chain::{
+    transaction::Transaction,
+    utxo_set::{OutPoint, UtxoSet},
+    Chain,
+};
+use crate::configs::{ConfigPaths, NodeConfig};
+use crate::error_handling::{CleytoResult, CleytonError, TransactionError};
+use crate::node::address_index::AddressIndex;
+use crate::node::block_store::BlockStore;
+use crate::node::files::write_atomically;
 use crate::node::logger::Logger;
+use crate::node::p2p::identity::parse_public_key;
+use crate::node::p2p::message::InvItem;
+use crate::node::p2p::network::EncryptionConfig;
+use crate::node::p2p::sync::SyncProgress;
+use crate::node::p2p::{BanList, Network, NetworkConfig, NodeIdentity, PeerSet};
+use crate::node::snapshot::SnapshotBase;
+use crate::node::tx_index::{TxIndex, TxLocation};
 use crate::remove_name_from_running_servers;
+use chrono::Utc;
 use core::panic;
-use directories::ProjectDirs;
 use once_cell::sync::Lazy;
 use resolve_requests::endpoints::resolve_endpoint;
 use resolve_requests::methods::{HTTPParseError, HTTPRequest};
 use serde::{Deserialize, Serialize};
 use std::fs::{self};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::os::unix::net::UnixListener;
 use std::path::PathBuf;
 use std::time::Duration;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io::{prelude::*, BufReader},
     net::{TcpListener, TcpStream},
     sync::{Arc, Mutex},
@@ -29,26 +56,721 @@ use std::{
 };
 use thread_pool::custom_thread_pool::ThreadPool;
 
+// Kept next to the block store, the pending transactions at the time the node was shut down
+const MEMPOOL_FILE: &str = "mempool.json";
+// Also next to the block store, the UTXO set as of some stored block
+const CHAINSTATE_FILE: &str = "chainstate.json";
+// Blocks accepted between two writes of the chain state. Writing it means serializing the whole
+// UTXO set, and at most this many blocks are replayed on startup
+const CHAINSTATE_INTERVAL: u32 = 100;
+
+/// How a node started from the data directory should behave
+#[derive(Clone, Debug, Default)]
+pub struct NodeOptions {
+    /// Keep only the bodies of the last `prune` blocks on disk. The headers of the others and the
+    /// UTXO set are kept, which is enough to validate new blocks
+    pub prune: Option<u32>,
+
+    /// The data directory of a node with the whole history, to check in the background the UTXO
+    /// snapshot this node was started from
+    pub verify_history: Option<PathBuf>,
+
+    /// Keep an index of where every confirmed transaction is, to look them up by txid
+    pub txindex: bool,
+
+    /// Keep an index of the outputs every address received and spent, for balances and histories
+    pub addrindex: bool,
+
+    /// The port to accept other nodes on. Without it, and without peers to connect to, the node
+    /// doesn't talk to other nodes at all
+    pub p2p_port: Option<u16>,
+
+    /// Nodes to connect to on startup
+    pub connect: Vec<SocketAddr>,
+
+    /// The port of the HTTP API. Wins over the one of the config file, `Node::DEFAULT_PORT` if
+    /// neither has one
+    pub port: Option<u16>,
+
+    /// The address the HTTP API and the peer port listen on. Wins over the one of the config
+    /// file, 127.0.0.1 if neither has one
+    pub bind: Option<IpAddr>,
+
+    /// Encrypt the connections to the nodes this one connects to, like `encrypt_peers` in the
+    /// config file
+    pub encrypt: bool,
+}
+
+// The UTXO set after applying the block `tip`, at `height`. With it, the blocks up to the tip
+// don't need to be replayed on startup, which pruned nodes can't do anyway
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ChainState {
+    pub(crate) tip: String,
+    pub(crate) height: u32,
+    pub(crate) utxo_set: UtxoSet,
+}
+
+impl ChainState {
+    /// The saved chain state, if there's one and it's about a block the store still has
+    pub(crate) fn read(block_store: &BlockStore) -> CleytoResult<Option<Self>> {
+        let chain_state: Option<Self> =
+            match fs::read_to_string(block_store.dir().join(CHAINSTATE_FILE)) {
+                Ok(serialized_state) => serde_json::from_str(&serialized_state).ok(),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+        Ok(chain_state.filter(|saved| block_store.height_of(&saved.tip) == Some(saved.height)))
+    }
+
+    pub(crate) fn write(&self, block_store: &BlockStore) -> CleytoResult<()> {
+        let serialized_state =
+            serde_json::to_string(self).map_err(CleytonError::BlockSerializationError)?;
+        write_atomically(
+            &block_store.dir().join(CHAINSTATE_FILE),
+            serialized_state.as_bytes(),
+        )
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct NodeState {
     status: bool,
     chain: Chain,
+    utxo_set: UtxoSet,
     transactions_pool: Vec<Transaction>,
+
+    // The unspent outputs the pooled transactions spend, so no two of them spend the same one
+    #[serde(skip)]
+    pool_claims: HashSet<OutPoint>,
+
+    // Where accepted blocks are persisted. Nodes built from a chain in memory have none
+    #[serde(skip)]
+    block_store: Option<BlockStore>,
+
+    // Height of the block the chain state on disk is at
+    #[serde(skip)]
+    chain_state_height: Option<u32>,
+
+    #[serde(skip)]
+    prune: Option<u32>,
+
+    #[serde(skip)]
+    tx_index: Option<TxIndex>,
+
+    #[serde(skip)]
+    address_index: Option<AddressIndex>,
+
+    // Leading zeros the hash of a block needs to be accepted
+    #[serde(skip, default = "default_pow_difficulty")]
+    pow_difficulty: u8,
+
+    // Filled in by the network threads, if the node runs one
+    #[serde(skip)]
+    peers: Arc<PeerSet>,
+
+    #[serde(skip)]
+    sync_progress: Arc<SyncProgress>,
 }
-#[derive(Debug, Serialize, Deserialize)]
-struct NodeConfig {
-    log_path: PathBuf,
+
+fn default_pow_difficulty() -> u8 {
+    PROOF_OF_WORK_DIFFICULTY
 }
-impl Default for NodeConfig {
-    fn default() -> Self {
-        let proj_dirs = ProjectDirs::from("", "CleytoCoin Big Mean Corp", "cleyto_coin")
-            .expect("Could not find the config directory");
-        Self {
-            log_path: proj_dirs.data_dir().join("logs.log"),
+
+/// Why `NodeState::add_transaction` didn't pool a transaction
+#[derive(Debug)]
+pub enum PoolRejection {
+    /// Pooled or confirmed already
+    AlreadyKnown,
+    /// Spends an output that isn't unspent, or that a pooled transaction spends already. It may
+    /// only be one the node doesn't have yet, or that a block just spent
+    UnavailableInputs,
+    /// In the old format, where every input is signed separately
+    Legacy,
+    /// Outputs bigger than the inputs, a bad signature, an unknown version and the like
+    Invalid(TransactionError),
+}
+
+impl PoolRejection {
+    /// Whether whoever sent the transaction should have known better. The others can happen to
+    /// honest peers, with a block or another transaction crossing theirs
+    pub fn is_senders_fault(&self) -> bool {
+        match self {
+            PoolRejection::Legacy => true,
+            PoolRejection::Invalid(e) => !matches!(e, TransactionError::OpenSSLError(_)),
+            PoolRejection::AlreadyKnown | PoolRejection::UnavailableInputs => false,
         }
     }
 }
 
+/// A transaction found by `NodeState::find_transaction`
+pub struct FoundTransaction {
+    pub transaction: Transaction,
+    /// None while it's still in the pool
+    pub block_hash: Option<String>,
+    /// How many blocks there are from the one with the transaction to the tip, both included
+    pub confirmations: u32,
+}
+
+impl NodeState {
+    /// A node state that lives only in memory, nothing it accepts is persisted. Fails if the
+    /// blocks of the chain spend outputs that aren't there
+    pub fn in_memory(chain: Chain) -> CleytoResult<Self> {
+        Ok(Self {
+            status: true,
+            utxo_set: UtxoSet::from_chain(&chain).map_err(CleytonError::TransactionError)?,
+            chain,
+            transactions_pool: Vec::new(),
+            pool_claims: HashSet::new(),
+            block_store: None,
+            chain_state_height: None,
+            prune: None,
+            tx_index: None,
+            address_index: None,
+            pow_difficulty: PROOF_OF_WORK_DIFFICULTY,
+            peers: Arc::default(),
+            sync_progress: Arc::default(),
+        })
+    }
+
+    /// Restores the chain and the UTXO set from the block store, and the pending transactions saved
+    /// by `save_mempool` that are still valid. An empty store gets the genesis block, so every node
+    /// starts from the same one
+    pub fn load(block_store: BlockStore) -> CleytoResult<Self> {
+        Self::load_with_options(block_store, &NodeOptions::default())
+    }
+
+    /// Like `load`, but pruning the block store as the options say. Only the blocks that still have
+    /// their bodies are kept in the chain
+    pub fn load_with_options(
+        mut block_store: BlockStore,
+        options: &NodeOptions,
+    ) -> CleytoResult<Self> {
+        let mut chain = Chain { blocks: Vec::new() };
+        if block_store.is_empty() {
+            block_store.append(&chain.create_genesis_block())?;
+        } else {
+            for height in block_store.pruned_height()..block_store.len() as u32 {
+                chain.add_block(block_store.read_by_height(height)?);
+            }
+        }
+
+        // Start from the saved UTXO set if it's about a block we still have, and replay the
+        // blocks after it. Without one, every block has to be replayed
+        let (utxo_set, replay_from) = match ChainState::read(&block_store)? {
+            Some(saved) => (saved.utxo_set, saved.height + 1),
+            None => (UtxoSet::new(), 0),
+        };
+        let chain_state_height = replay_from.checked_sub(1);
+        if (options.txindex || options.addrindex) && options.prune.is_some() {
+            return Err(CleytonError::InvalidOptions(
+                "the transaction and address indexes need every block, they can't be used with \
+                pruning"
+                    .to_string(),
+            ));
+        }
+        let tx_index = match options.txindex {
+            true => Some(TxIndex::open(&block_store)?),
+            false => None,
+        };
+        let address_index = match options.addrindex {
+            true => Some(AddressIndex::open(&block_store)?),
+            false => None,
+        };
+        if replay_from < block_store.pruned_height() {
+            return Err(CleytonError::CorruptBlockStore(
+                "the saved UTXO set is older than the pruned blocks".to_string(),
+            ));
+        }
+        let first_body = block_store.pruned_height();
+
+        let mempool_path = block_store.dir().join(MEMPOOL_FILE);
+        let mut state = Self {
+            status: true,
+            utxo_set,
+            chain,
+            transactions_pool: Vec::new(),
+            pool_claims: HashSet::new(),
+            block_store: Some(block_store),
+            chain_state_height,
+            prune: options.prune,
+            tx_index,
+            address_index,
+            pow_difficulty: PROOF_OF_WORK_DIFFICULTY,
+            peers: Arc::default(),
+            sync_progress: Arc::default(),
+        };
+        for block in &state.chain.blocks[(replay_from - first_body) as usize..] {
+            state.utxo_set.apply_block(block).map_err(|e| {
+                CleytonError::CorruptBlockStore(format!(
+                    "stored block {} can't be applied: {e}",
+                    block.get_hash()
+                ))
+            })?;
+        }
+        state.checkpoint_chain_state()?;
+        state.prune_blocks()?;
+
+        let saved_pool: Vec<Transaction> = match fs::read_to_string(&mempool_path) {
+            Ok(serialized_pool) => serde_json::from_str(&serialized_pool).unwrap_or_else(|e| {
+                eprintln!("Ignoring unreadable mempool file: {e}");
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let saved = saved_pool.len();
+        for transaction in saved_pool {
+            let _ = state.pool_transaction(transaction);
+        }
+        if saved > 0 {
+            println!(
+                "Restored {} of {saved} pending transactions",
+                state.transactions_pool.len()
+            );
+        }
+
+        Ok(state)
+    }
+
+    /// Writes the UTXO set as of the tip next to the block store, so `load` doesn't have to replay
+    /// the blocks before it. Does nothing for nodes that live only in memory
+    pub fn save_chain_state(&mut self) -> CleytoResult<()> {
+        let Some(block_store) = &self.block_store else {
+            return Ok(());
+        };
+        let Some(tip) = block_store.tip() else {
+            return Ok(());
+        };
+        let height = block_store.len() as u32 - 1;
+        if self.chain_state_height == Some(height) {
+            return Ok(());
+        }
+        ChainState {
+            tip: tip.to_string(),
+            height,
+            utxo_set: self.utxo_set.clone(),
+        }
+        .write(block_store)?;
+        self.chain_state_height = Some(height);
+        Ok(())
+    }
+
+    // Only every `CHAINSTATE_INTERVAL` blocks, the rest are replayed on startup
+    fn checkpoint_chain_state(&mut self) -> CleytoResult<()> {
+        let Some(block_store) = &self.block_store else {
+            return Ok(());
+        };
+        let behind = match self.chain_state_height {
+            Some(saved) => (block_store.len() as u32 - 1).saturating_sub(saved),
+            None => u32::MAX,
+        };
+        if behind < CHAINSTATE_INTERVAL {
+            return Ok(());
+        }
+        self.save_chain_state()
+    }
+
+    // Rewriting the block file is expensive, so bodies are only deleted once there are twice as
+    // many as need to be kept
+    fn prune_blocks(&mut self) -> CleytoResult<()> {
+        let (Some(block_store), Some(keep)) = (&self.block_store, self.prune) else {
+            return Ok(());
+        };
+        let keep = keep.max(1);
+        let bodies = block_store.len() as u32 - block_store.pruned_height();
+        if bodies < keep.saturating_mul(2) {
+            return Ok(());
+        }
+
+        // The blocks after the saved chain state have to be there to replay them
+        self.save_chain_state()?;
+        let Some(block_store) = &mut self.block_store else {
+            return Ok(());
+        };
+
+        let pruned = block_store.prune(keep)?;
+        self.chain.blocks.drain(..pruned);
+        Ok(())
+    }
+
+    /// Writes the pending transactions next to the block store, so `load` can bring them back.
+    /// Does nothing for nodes that live only in memory
+    pub fn save_mempool(&self) -> CleytoResult<()> {
+        let Some(block_store) = &self.block_store else {
+            return Ok(());
+        };
+        let serialized_pool = serde_json::to_string(&self.transactions_pool)
+            .map_err(CleytonError::BlockSerializationError)?;
+        write_atomically(
+            &block_store.dir().join(MEMPOOL_FILE),
+            serialized_pool.as_bytes(),
+        )
+    }
+
+    /// Adds the transaction to the pool if it's valid and not pooled or confirmed already, and
+    /// announces it to the peers. Fails with why it wasn't added
+    pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), PoolRejection> {
+        let item = InvItem::transaction(&transaction);
+        self.pool_transaction(transaction)?;
+        self.peers.announce(&[item]);
+        Ok(())
+    }
+
+    // Pools the transaction if it's valid and its inputs are unspent, and not spent by another pooled
+    // one either
+    fn pool_transaction(&mut self, transaction: Transaction) -> Result<(), PoolRejection> {
+        self.check_for_pool(&transaction)?;
+        let claims = self
+            .utxo_set
+            .claim(&transaction.transaction_info.inputs, &self.pool_claims)
+            .ok_or(PoolRejection::UnavailableInputs)?;
+        self.pool_claims.extend(claims);
+        self.transactions_pool.push(transaction);
+        Ok(())
+    }
+
+    // The same checks `/submit-transaction` makes, plus that the transaction isn't pooled or
+    // confirmed already. Things may have changed while the node was down
+    fn check_for_pool(&self, transaction: &Transaction) -> Result<(), PoolRejection> {
+        let already_known = self
+            .transactions_pool
+            .iter()
+            .chain(
+                self.chain
+                    .blocks
+                    .iter()
+                    .flat_map(|block| block.get_transactions()),
+            )
+            .any(|known| known.txid == transaction.txid);
+        if already_known {
+            return Err(PoolRejection::AlreadyKnown);
+        }
+        if transaction.is_legacy() {
+            return Err(PoolRejection::Legacy);
+        }
+        Transaction::check_transaction(transaction)
+            .map_err(|_| PoolRejection::Invalid(TransactionError::InsufficientInputs))?;
+        transaction
+            .verify_signature()
+            .map_err(PoolRejection::Invalid)
+    }
+
+    pub fn chain(&self) -> &Chain {
+        &self.chain
+    }
+
+    /// How many leading zeros the hash of a block needs to be accepted. `PROOF_OF_WORK_DIFFICULTY`
+    /// unless set otherwise, which only tests and networks of test nodes should do
+    pub fn set_pow_difficulty(&mut self, difficulty: u8) {
+        self.pow_difficulty = difficulty;
+    }
+
+    /// Looks a transaction up in the pool and, if the node keeps a transaction index, among the
+    /// confirmed ones. Fails with `TxIndexDisabled` if it isn't pooled and there's no index
+    pub fn find_transaction(&self, txid: &[u8; 32]) -> CleytoResult<Option<FoundTransaction>> {
+        if let Some(pooled) = self.transactions_pool.iter().find(|tx| tx.txid == *txid) {
+            return Ok(Some(FoundTransaction {
+                transaction: pooled.clone(),
+                block_hash: None,
+                confirmations: 0,
+            }));
+        }
+
+        let (Some(tx_index), Some(block_store)) = (&self.tx_index, &self.block_store) else {
+            return Err(CleytonError::TxIndexDisabled);
+        };
+        let Some(TxLocation {
+            block_hash,
+            position,
+        }) = tx_index.get(txid)
+        else {
+            return Ok(None);
+        };
+
+        let block = block_store.read_by_hash(block_hash)?;
+        let height = block_store
+            .height_of(block_hash)
+            .ok_or(CleytonError::BlockNotFound)?;
+        let transaction = block
+            .get_transactions()
+            .get(*position as usize)
+            .cloned()
+            .ok_or_else(|| {
+                CleytonError::CorruptBlockStore(format!("bad index for {block_hash}"))
+            })?;
+
+        Ok(Some(FoundTransaction {
+            transaction,
+            block_hash: Some(block_hash.clone()),
+            confirmations: block_store.len() as u32 - height,
+        }))
+    }
+
+    /// Fails with `AddressIndexDisabled` if the node doesn't keep one
+    pub fn address_index(&self) -> CleytoResult<&AddressIndex> {
+        self.address_index
+            .as_ref()
+            .ok_or(CleytonError::AddressIndexDisabled)
+    }
+
+    /// Whether the block is stored, or in the chain for nodes that live only in memory
+    pub fn has_block(&self, hash: &str) -> bool {
+        match &self.block_store {
+            Some(block_store) => block_store.contains(hash),
+            None => self
+                .chain
+                .blocks
+                .iter()
+                .any(|block| block.get_hash() == hash),
+        }
+    }
+
+    /// The block with that hash, from the store or, for nodes that live only in memory, the chain
+    pub fn read_block(&self, hash: &str) -> CleytoResult<Block> {
+        match &self.block_store {
+            Some(block_store) => block_store.read_by_hash(hash),
+            None => self
+                .chain
+                .blocks
+                .iter()
+                .find(|block| block.get_hash() == hash)
+                .cloned()
+                .ok_or(CleytonError::BlockNotFound),
+        }
+    }
+
+    // The hash of the block at that height, counting from the genesis block at 0, and the height
+    // of the tip. Pruned blocks have hashes too, nodes living in memory only know their chain
+    fn hash_at(&self, height: u32) -> Option<String> {
+        match &self.block_store {
+            Some(block_store) => block_store.hash_at(height).map(str::to_string),
+            None => self
+                .chain
+                .blocks
+                .get(height as usize)
+                .map(|block| block.get_hash()),
+        }
+    }
+
+    fn height_of(&self, hash: &str) -> Option<u32> {
+        match &self.block_store {
+            Some(block_store) => block_store.height_of(hash),
+            None => self
+                .chain
+                .blocks
+                .iter()
+                .position(|block| block.get_hash() == hash)
+                .map(|position| position as u32),
+        }
+    }
+
+    fn tip_height(&self) -> u32 {
+        match &self.block_store {
+            Some(block_store) => block_store.len() as u32 - 1,
+            None => self.chain.blocks.len() as u32 - 1,
+        }
+    }
+
+    /// Hashes of our blocks from the tip back to the genesis block, the first ten in a row and
+    /// then further and further apart, so a peer can find the last block we have in common even
+    /// if our chains went different ways
+    pub fn block_locator(&self) -> Vec<String> {
+        let mut locator = Vec::new();
+        let mut height = self.tip_height() as i64;
+        let mut step = 1;
+        while height > 0 {
+            locator.extend(self.hash_at(height as u32));
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height -= step;
+        }
+        locator.extend(self.hash_at(0));
+        locator
+    }
+
+    /// Up to `max` headers of the blocks after the first one of the locator we have, or after the
+    /// genesis block if we have none of them
+    pub fn headers_after(&self, locator: &[String], max: usize) -> CleytoResult<Vec<BlockHeader>> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.height_of(hash))
+            .unwrap_or(0);
+        let end = self.tip_height().min(start.saturating_add(max as u32));
+        (start + 1..=end)
+            .map(|height| match &self.block_store {
+                Some(block_store) => block_store.read_header(height),
+                None => Ok(self.chain.blocks[height as usize].header()),
+            })
+            .collect()
+    }
+
+    /// How far the node got in downloading the chain its peers have
+    pub fn sync_progress(&self) -> Arc<SyncProgress> {
+        Arc::clone(&self.sync_progress)
+    }
+
+    /// The pooled transaction with that txid, in hex
+    pub fn pooled_transaction(&self, txid: &str) -> Option<&Transaction> {
+        self.transactions_pool
+            .iter()
+            .find(|transaction| hex::encode(transaction.txid) == txid)
+    }
+
+    /// Whether a peer announcing the item has nothing new for us. Transactions count if they're
+    /// pooled or in the blocks still in memory
+    pub fn has_item(&self, item: &InvItem) -> bool {
+        match item {
+            InvItem::Tx(txid) => {
+                self.pooled_transaction(txid).is_some()
+                    || self
+                        .chain
+                        .blocks
+                        .iter()
+                        .flat_map(|block| block.get_transactions())
+                        .any(|transaction| hex::encode(transaction.txid) == *txid)
+            }
+            InvItem::Block(hash) => self.has_block(hash),
+        }
+    }
+
+    /// The other nodes this one is connected to
+    pub fn peers(&self) -> Arc<PeerSet> {
+        Arc::clone(&self.peers)
+    }
+
+    pub fn utxo_set(&self) -> &UtxoSet {
+        &self.utxo_set
+    }
+
+    pub fn transactions_pool(&self) -> &[Transaction] {
+        &self.transactions_pool
+    }
+
+    /// Checks that the block goes on top of the chain, has the proof of work and that its
+    /// transactions are valid and spend outputs that are unspent, then persists it before applying
+    /// it. Transactions in the block, or spending what it spent, leave the pool
+    pub fn accept_block(&mut self, block: Block) -> CleytoResult<()> {
+        if block.get_previous_hash() != self.chain.get_last_hash() {
+            return Err(CleytonError::InvalidBlock(
+                "block doesn't build on the tip of the chain".to_string(),
+            ));
+        }
+        if block.get_index() != self.chain.get_last_index() + 1 {
+            return Err(CleytonError::InvalidBlock(format!(
+                "expected a block with index {}",
+                self.chain.get_last_index() + 1
+            )));
+        }
+        if block.get_hash() != block.calculate_hash() {
+            return Err(CleytonError::InvalidBlock(
+                "block hash doesn't match its contents".to_string(),
+            ));
+        }
+        if !block.header().meets_difficulty(self.pow_difficulty) {
+            return Err(CleytonError::InvalidBlock(format!(
+                "block hash doesn't start with {} zeros",
+                self.pow_difficulty
+            )));
+        }
+        Self::check_transactions(&block)?;
+
+        let spent = self
+            .utxo_set
+            .apply_block(&block)
+            .map_err(CleytonError::TransactionError)?;
+        if let Some(block_store) = &mut self.block_store {
+            if let Err(e) = block_store.append(&block) {
+                self.utxo_set.undo_block(&block, spent);
+                return Err(e);
+            }
+        }
+
+        // Along with the ones spending what the block spent. The rest claim their outputs again,
+        // the block may have spent the ones they had
+        let (utxo_set, pool_claims) = (&self.utxo_set, &mut self.pool_claims);
+        pool_claims.clear();
+        self.transactions_pool.retain(|pooled| {
+            let in_block = block
+                .get_transactions()
+                .iter()
+                .any(|transaction| transaction.txid == pooled.txid);
+            let claims = utxo_set.claim(&pooled.transaction_info.inputs, pool_claims);
+            match (in_block, claims) {
+                (false, Some(claims)) => {
+                    pool_claims.extend(claims);
+                    true
+                }
+                _ => false,
+            }
+        });
+        if let (Some(tx_index), Some(block_store)) = (&mut self.tx_index, &self.block_store) {
+            tx_index.connect_block(&block);
+            tx_index.save(block_store.dir())?;
+        }
+        if let (Some(address_index), Some(block_store)) =
+            (&mut self.address_index, &self.block_store)
+        {
+            address_index.connect_block(&block, &spent);
+            address_index.save(block_store.dir())?;
+        }
+        self.peers.announce_block(&block);
+        self.chain.add_block(block);
+        self.checkpoint_chain_state()?;
+        self.prune_blocks()
+    }
+
+    // Everything about the transactions that doesn't need the UTXO set. Only the first one can be
+    // a coinbase, and it can't create more than the block reward and the fees of the others
+    fn check_transactions(block: &Block) -> CleytoResult<()> {
+        let mut fees: u64 = 0;
+        for (position, transaction) in block.get_transactions().iter().enumerate() {
+            if transaction.is_legacy() {
+                return Err(CleytonError::InvalidBlock(
+                    "block has a transaction in the old format".to_string(),
+                ));
+            }
+            if transaction.is_coinbase() {
+                if position != 0
+                    || transaction.transaction_info.coinbase_height != Some(block.get_index())
+                    || !transaction.transaction_info.inputs.is_empty()
+                {
+                    return Err(CleytonError::InvalidBlock(
+                        "only the first transaction can be a coinbase, for the block's index and \
+                        without inputs"
+                            .to_string(),
+                    ));
+                }
+                continue;
+            }
+            transaction
+                .verify_signature()
+                .map_err(CleytonError::TransactionError)?;
+
+            let input_sum = UTXO::sum(&transaction.transaction_info.inputs);
+            let output_sum = UTXO::sum(&transaction.transaction_info.outputs);
+            fees += input_sum
+                .checked_sub(output_sum)
+                .ok_or(CleytonError::TransactionError(
+                    TransactionError::InsufficientInputs,
+                ))?;
+        }
+
+        let coinbase_sum = match block.get_transactions().first() {
+            Some(coinbase) if coinbase.is_coinbase() => {
+                UTXO::sum(&coinbase.transaction_info.outputs)
+            }
+            _ => 0,
+        };
+        if coinbase_sum > BLOCK_REWARD + fees {
+            return Err(CleytonError::InvalidBlock(format!(
+                "the coinbase creates {coinbase_sum}, more than the block reward and fees of {}",
+                BLOCK_REWARD + fees
+            )));
+        }
+        Ok(())
+    }
+}
 #[derive(Serialize, Deserialize)]
 pub struct Node {
     state: Arc<Mutex<NodeState>>,
@@ -57,9 +779,13 @@ pub struct Node {
     #[serde(skip)]
     logger: Arc<logger::Logger>,
 
-    // The configs are best reloaded with every initialization
+    // The paths are best reloaded with every initialization
+    #[serde(skip)]
+    paths: ConfigPaths,
+
+    // What it was started with, the peer options are needed when it starts running
     #[serde(skip)]
-    config: NodeConfig,
+    options: NodeOptions,
 
     // The name, for when you finally need to kill it
     pub name: String,
@@ -75,35 +801,57 @@ static NUMBER_OF_THREADS_IN_THREAD_POOL: Lazy<usize> = Lazy::new(num_cpus::get);
 // 2 = Debug
 pub const LOG_LEVEL: u8 = 2;
 
-fn load_config() -> NodeConfig {
-    let proj_dirs = ProjectDirs::from("", "CleytoCoin Big Mean Corp", "cleyto_coin")
-        .expect("Could not find the config directory");
-    let config_path = proj_dirs.config_dir().join("config.toml");
-
-    if let Ok(contents) = fs::read_to_string(&config_path) {
-        toml::from_str(&contents).expect("Invalid config format")
-    } else {
-        fs::create_dir_all(proj_dirs.config_dir()).expect("Could not create config directories");
-
-        let default_cfg = NodeConfig::default();
-        let toml_str = toml::to_string_pretty(&default_cfg).unwrap();
-        fs::write(&config_path, &toml_str).expect("Couldn't write default config");
-        default_cfg
-    }
-}
-
 impl Node {
     // these configurations should be moved to a file
     pub const DEFAULT_PORT: u16 = 9473;
     pub const REFRESH_RATE_SERVER_IN_MS: u64 = 50;
 
-    pub fn new(chain: Chain, name: String) -> (Node, Arc<Logger>) {
-        let config = load_config();
+    pub fn new(chain: Chain, name: String) -> CleytoResult<(Node, Arc<Logger>)> {
+        Ok(Self::with_state(
+            NodeState::in_memory(chain)?,
+            name,
+            ConfigPaths::get(),
+        ))
+    }
+
+    /// A node that restores its chain from the block store in the data directory of `paths`, and
+    /// persists the blocks it accepts there
+    pub fn open(
+        name: String,
+        paths: ConfigPaths,
+        options: NodeOptions,
+    ) -> CleytoResult<(Node, Arc<Logger>)> {
+        let state = NodeState::load_with_options(BlockStore::open(paths.data_dir())?, &options)?;
+        let snapshot_base = SnapshotBase::read(paths.data_dir())?;
+        let (mut node, logger) = Self::with_state(state, name, paths);
+
+        if let (Some(base), Some(history_dir)) = (snapshot_base, options.verify_history.clone()) {
+            let data_dir = node.paths.data_dir().to_path_buf();
+            let logger = Arc::clone(&logger);
+            thread::spawn(move || {
+                let verified = BlockStore::open(history_dir)
+                    .and_then(|history| base.verify_history(&data_dir, &history));
+                match verified {
+                    Ok(()) => logger.log(format!(
+                        "Verified the history up to the UTXO snapshot at height {}",
+                        base.height
+                    )),
+                    Err(e) => logger.log_error(format!(
+                        "Couldn't verify the history behind the UTXO snapshot: {e:?}"
+                    )),
+                }
+            });
+        }
+
+        node.options = options;
+        Ok((node, logger))
+    }
+
+    fn with_state(state: NodeState, name: String, paths: ConfigPaths) -> (Node, Arc<Logger>) {
         let logger =
-            Arc::new(Logger::read_logs_file(&config.log_path).unwrap_or_else(|_| Logger::new()));
+            Arc::new(Logger::read_logs_file(&paths.log_file).unwrap_or_else(|_| Logger::new()));
         let logger_clone = Arc::clone(&logger);
-        let socket_location =
-            PathBuf::from(format!("{}/{}.sock:", ConfigPaths::get().sockets_dir, name));
+        let socket_location = paths.socket_path(&name);
 
         println!(
             "Creating node with name {name} and socket {}",
@@ -111,13 +859,10 @@ impl Node {
         );
         (
             Node {
-                state: Arc::new(Mutex::new(NodeState {
-                    status: true,
-                    chain,
-                    transactions_pool: Vec::new(),
-                })),
+                state: Arc::new(Mutex::new(state)),
                 logger,
-                config,
+                paths,
+                options: NodeOptions::default(),
                 name,
                 socket_location,
             },
@@ -245,25 +990,152 @@ impl Node {
         resolve_endpoint(state, request_object)
     }
 
-    pub fn run(&mut self, default: bool, selected_port: u16) {
-        let port: u16 = if default {
-            Self::DEFAULT_PORT
-        } else {
-            match selected_port {
-                port if (1..=65535).contains(&port) => port,
-                _ => {
-                    println!("Invalid port! Using default: {}", Self::DEFAULT_PORT);
-                    Self::DEFAULT_PORT
+    // Only nodes given a peer port, peers to connect to or seeds take part in the network
+    fn start_network(&self) -> Option<Arc<Network>> {
+        let node_config = NodeConfig::load();
+        if self.options.p2p_port.is_none()
+            && self.options.connect.is_empty()
+            && node_config.seed_peers.is_empty()
+        {
+            return None;
+        }
+        let encryption = self.encryption_config(&node_config);
+        let config = NetworkConfig {
+            listen: self
+                .options
+                .p2p_port
+                .map(|port| SocketAddr::new(self.bind_address(&node_config), port)),
+            connect: self.options.connect.clone(),
+            seeds: node_config.seed_peers,
+            target_outbound: node_config.outbound_peers,
+            address_book: Some(self.paths.peers_file.clone()),
+            ban_list: Some(self.paths.bans_file.clone()),
+            ban_duration: Duration::from_secs(node_config.ban_duration),
+            encryption: Some(encryption),
+            ..NetworkConfig::default()
+        };
+        match Network::start(config, Arc::clone(&self.state), Arc::clone(&self.logger)) {
+            Ok(network) => {
+                if let Some(address) = network.local_addr() {
+                    println!("Accepting peers on {address}");
+                }
+                Some(network)
+            }
+            Err(e) => panic!("Couldn't start the peer network: {e:?}"),
+        }
+    }
+
+    // Every node on the network has a key, so peers that want to can always encrypt
+    fn encryption_config(&self, node_config: &NodeConfig) -> EncryptionConfig {
+        let identity = match NodeIdentity::open_or_create(&self.paths.node_key_file) {
+            Ok(identity) => identity,
+            Err(e) => panic!("Couldn't read the node key: {e:?}"),
+        };
+        println!("Node key {}", hex::encode(identity.public_key()));
+        let mut encryption = EncryptionConfig::new(identity);
+        encryption.encrypt_outbound = self.options.encrypt || node_config.encrypt_peers;
+        encryption.required = node_config.require_encrypted_peers;
+        for (address, key) in &node_config.pinned_peers {
+            match parse_public_key(key) {
+                Some(key) => encryption.pinned.insert(*address, key),
+                None => panic!("Invalid pinned key for {address}: {key}"),
+            };
+        }
+        encryption
+    }
+
+    // The commands of `node ban`, `node unban` and `node bans`. Nodes off the network keep the ban
+    // list too, for when they join it
+    fn admin_command(&self, network: Option<&Network>, command: &str) -> String {
+        self.run_admin_command(network, command)
+            .unwrap_or_else(|e| format!("Couldn't change the ban list: {e:?}"))
+    }
+
+    fn run_admin_command(&self, network: Option<&Network>, command: &str) -> CleytoResult<String> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words.as_slice() {
+            ["ban", ip, duration @ ..] => {
+                let Ok(ip) = ip.parse::<IpAddr>() else {
+                    return Ok(format!("Invalid address {ip}"));
+                };
+                let seconds = match duration {
+                    [] => NodeConfig::load().ban_duration,
+                    [seconds] => match seconds.parse() {
+                        Ok(seconds) => seconds,
+                        Err(_) => return Ok(format!("Invalid duration {seconds}")),
+                    },
+                    _ => return Ok(format!("Unknown command: {command}")),
+                };
+                let duration = Duration::from_secs(seconds);
+                match network {
+                    Some(network) => network.ban(ip, duration, "banned by hand"),
+                    None => {
+                        self.change_ban_list(|bans| bans.ban_for(ip, duration, "banned by hand"))?
+                    }
                 }
+                Ok(format!("Banned {ip} for {seconds} seconds"))
             }
+            ["unban", ip] => {
+                let Ok(ip) = ip.parse::<IpAddr>() else {
+                    return Ok(format!("Invalid address {ip}"));
+                };
+                let unbanned = match network {
+                    Some(network) => network.unban(&ip),
+                    None => self.change_ban_list(|bans| bans.unban(&ip))?,
+                };
+                match unbanned {
+                    true => Ok(format!("Unbanned {ip}")),
+                    false => Ok(format!("{ip} wasn't banned")),
+                }
+            }
+            ["bans"] => {
+                let bans = match network {
+                    Some(network) => network.bans(),
+                    None => self.change_ban_list(|bans| bans.list(Utc::now()))?,
+                };
+                serde_json::to_string_pretty(&bans).map_err(CleytonError::BlockSerializationError)
+            }
+            _ => Ok(format!("Unknown command: {command}")),
+        }
+    }
+
+    fn change_ban_list<T>(&self, change: impl FnOnce(&mut BanList) -> T) -> CleytoResult<T> {
+        let mut bans = BanList::open(&self.paths.bans_file)?;
+        let result = change(&mut bans);
+        bans.save()?;
+        Ok(result)
+    }
+
+    fn bind_address(&self, node_config: &NodeConfig) -> IpAddr {
+        self.options
+            .bind
+            .or(node_config.bind)
+            .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+    }
+
+    /// Where the HTTP API listens, from the options or else the config file
+    pub fn http_address(&self) -> SocketAddr {
+        let node_config = NodeConfig::load();
+        let port = match self.options.port.or(node_config.port) {
+            Some(0) => {
+                println!("Invalid port! Using default: {}", Self::DEFAULT_PORT);
+                Self::DEFAULT_PORT
+            }
+            Some(port) => port,
+            None => Self::DEFAULT_PORT,
         };
+        SocketAddr::new(self.bind_address(&node_config), port)
+    }
 
+    pub fn run(&mut self) {
         println!("Running node of name {}", self.name);
 
-        let tcp_listener = match TcpListener::bind(format!("127.0.0.1:{port}")) {
+        let address = self.http_address();
+        let tcp_listener = match TcpListener::bind(address) {
             Ok(l) => l,
             Err(_) => panic!("Trying to create another node in the same port"),
         };
+        println!("Accepting HTTP requests on {address}");
 
         tcp_listener
             .set_nonblocking(true)
@@ -280,6 +1152,8 @@ impl Node {
 
         let mut read_buffer: [u8; 100] = [0u8; 100];
 
+        let network = self.start_network();
+
         let unix_listener =
             UnixListener::bind(self.socket_location.clone()).expect("Could not bind to socket");
         unix_listener
@@ -301,7 +1175,10 @@ impl Node {
                     Some("kill") => {
                         break;
                     }
-                    Some(&_) => {}
+                    Some(command) => {
+                        let reply = self.admin_command(network.as_deref(), command);
+                        let _ = listener.write_all(reply.as_bytes());
+                    }
                     None => {}
                 }
             }
@@ -331,6 +1208,9 @@ impl Node {
                 }
             }
         }
+        if let Some(network) = network {
+            network.stop();
+        }
         let _ = std::fs::remove_file(self.socket_location.clone());
         remove_name_from_running_servers(self.name.clone());
         println!("Dropping thread pool");
@@ -341,9 +1221,25 @@ impl Drop for Node {
     fn drop(&mut self) {
         println!("Deleting socket file and closing connection");
 
-        match self.logger.write_logs_file(&self.config.log_path) {
+        match self.logger.write_logs_file(&self.paths.log_file) {
             Ok(_) => {}
             Err(e) => eprintln!("Error saving log file: {e:?}"),
         }
+
+        // Unconfirmed payments would be lost on every restart otherwise, and the blocks since the
+        // last chain state would have to be replayed
+        match self.state.lock() {
+            Ok(mut state) => {
+                if let Err(e) = state.save_mempool() {
+                    eprintln!("Error saving the pending transactions: {e:?}")
+                }
+                if let Err(e) = state.save_chain_state() {
+                    eprintln!("Error saving the chain state: {e:?}")
+                }
+            }
+            Err(_) => eprintln!(
+                "Couldn't save the pending transactions and the chain state, the state was poisoned"
+            ),
+        }
     }
 }
@@ -0,0 +1,219 @@
+//! Every peer address the node heard of, kept in `peers.json` so it doesn't depend on the seeds
+//! after the first start. Addresses come from the config, from `--connect` and from other peers,
+//! and the ones that keep failing are tried less and less often until they're forgotten.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error_handling::{CleytoResult, CleytonError};
+use crate::node::files::write_atomically;
+
+/// Most addresses sent in one addr message
+pub const MAX_ADDRS: usize = 1_000;
+// Addresses learned from peers are forgotten after failing this many times in a row
+const MAX_FAILURES: u32 = 10;
+// The wait before retrying an address doubles with every failure, up to the maximum
+const RETRY_DELAY: i64 = 1;
+const MAX_RETRY_DELAY: i64 = 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressSource {
+    /// The `seed_peers` of the config file
+    Seed,
+    /// Given with `--connect`
+    Manual,
+    /// Sent by a peer, or the address a peer said it listens on
+    Peer,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeerAddress {
+    pub address: SocketAddr,
+    pub source: AddressSource,
+    /// The last time a handshake with it went through
+    pub last_seen: Option<DateTime<Utc>>,
+    pub last_attempt: Option<DateTime<Utc>>,
+    /// Failed connections since the last one that worked
+    pub failures: u32,
+}
+
+impl PeerAddress {
+    // Whether enough time went by since the last failed attempt
+    fn can_retry(&self, now: DateTime<Utc>) -> bool {
+        let Some(last_attempt) = self.last_attempt else {
+            return true;
+        };
+        if self.failures == 0 {
+            return true;
+        }
+        let delay = RETRY_DELAY
+            .saturating_mul(1 << self.failures.min(20))
+            .min(MAX_RETRY_DELAY);
+        now - last_attempt >= Duration::seconds(delay)
+    }
+}
+
+// --------------------------------------------- AddressBook definition --------------------------------------------
+pub struct AddressBook {
+    // None for books that live only in memory
+    path: Option<PathBuf>,
+    addresses: HashMap<SocketAddr, PeerAddress>,
+}
+
+impl AddressBook {
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            addresses: HashMap::new(),
+        }
+    }
+
+    /// The book saved at `path`, or an empty one if there's none yet
+    pub fn open(path: impl Into<PathBuf>) -> CleytoResult<Self> {
+        let path = path.into();
+        let addresses: Vec<PeerAddress> = match fs::read_to_string(&path) {
+            Ok(serialized) => serde_json::from_str(&serialized).unwrap_or_else(|e| {
+                eprintln!("Ignoring unreadable peer address book: {e}");
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            addresses: addresses
+                .into_iter()
+                .map(|entry| (entry.address, entry))
+                .collect(),
+        })
+    }
+
+    pub fn save(&self) -> CleytoResult<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let serialized = serde_json::to_string_pretty(&self.list())
+            .map_err(CleytonError::BlockSerializationError)?;
+        write_atomically(path, serialized.as_bytes())
+    }
+
+    /// Every address, the most recently seen first
+    pub fn list(&self) -> Vec<PeerAddress> {
+        let mut addresses: Vec<PeerAddress> = self.addresses.values().cloned().collect();
+        addresses.sort_by(|a, b| {
+            b.last_seen
+                .cmp(&a.last_seen)
+                .then(a.address.cmp(&b.address))
+        });
+        addresses
+    }
+
+    pub fn get(&self, address: &SocketAddr) -> Option<&PeerAddress> {
+        self.addresses.get(address)
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    /// Adds the address if it's new. Seeds and manual addresses are never forgotten, so they take
+    /// over as the source of addresses that peers sent first. Returns whether it was new
+    pub fn add(&mut self, address: SocketAddr, source: AddressSource) -> bool {
+        if address.port() == 0 || address.ip().is_unspecified() {
+            return false;
+        }
+        match self.addresses.get_mut(&address) {
+            Some(known) => {
+                if source != AddressSource::Peer {
+                    known.source = source;
+                }
+                false
+            }
+            None => {
+                self.addresses.insert(
+                    address,
+                    PeerAddress {
+                        address,
+                        source,
+                        last_seen: None,
+                        last_attempt: None,
+                        failures: 0,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    pub fn mark_attempt(&mut self, address: &SocketAddr, now: DateTime<Utc>) {
+        if let Some(known) = self.addresses.get_mut(address) {
+            known.last_attempt = Some(now);
+        }
+    }
+
+    pub fn mark_connected(&mut self, address: &SocketAddr, now: DateTime<Utc>) {
+        if let Some(known) = self.addresses.get_mut(address) {
+            known.last_seen = Some(now);
+            known.failures = 0;
+        }
+    }
+
+    /// Counts a failed connection. Addresses from peers that failed too many times are dropped
+    pub fn mark_failed(&mut self, address: &SocketAddr) {
+        let Some(known) = self.addresses.get_mut(address) else {
+            return;
+        };
+        known.failures += 1;
+        if known.source == AddressSource::Peer && known.failures >= MAX_FAILURES {
+            self.addresses.remove(address);
+        }
+    }
+
+    /// Up to `count` addresses to connect to, leaving out the ones in `exclude` and the ones that
+    /// failed too recently. The ones that worked before go first
+    pub fn candidates(
+        &self,
+        exclude: &HashSet<SocketAddr>,
+        now: DateTime<Utc>,
+        count: usize,
+    ) -> Vec<SocketAddr> {
+        let mut candidates: Vec<&PeerAddress> = self
+            .addresses
+            .values()
+            .filter(|known| !exclude.contains(&known.address) && known.can_retry(now))
+            .collect();
+        candidates.sort_by(|a, b| {
+            a.failures
+                .cmp(&b.failures)
+                .then(b.last_seen.cmp(&a.last_seen))
+                .then(a.address.cmp(&b.address))
+        });
+        candidates
+            .into_iter()
+            .take(count)
+            .map(|known| known.address)
+            .collect()
+    }
+
+    /// What to answer a getaddr with: the addresses a handshake went through with, the most
+    /// recent first
+    pub fn sample(&self, max: usize) -> Vec<SocketAddr> {
+        self.list()
+            .into_iter()
+            .filter(|known| known.last_seen.is_some())
+            .take(max)
+            .map(|known| known.address)
+            .collect()
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
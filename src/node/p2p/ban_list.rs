@@ -0,0 +1,109 @@
+//! The peers that aren't let back in for a while, kept in `bans.json` so restarting the node doesn't
+//! let them in either. Peers get here by misbehaving too much, or by hand with `node ban`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::error_handling::{CleytoResult, CleytonError};
+use crate::node::files::write_atomically;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Ban {
+    pub ip: IpAddr,
+    pub until: DateTime<Utc>,
+    pub reason: String,
+}
+
+// ---------------------------------------------- BanList definition -----------------------------------------------
+pub struct BanList {
+    // None for lists that live only in memory
+    path: Option<PathBuf>,
+    bans: HashMap<IpAddr, Ban>,
+}
+
+impl BanList {
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            bans: HashMap::new(),
+        }
+    }
+
+    /// The list saved at `path`, or an empty one if there's none yet
+    pub fn open(path: impl Into<PathBuf>) -> CleytoResult<Self> {
+        let path = path.into();
+        let bans: Vec<Ban> = match fs::read_to_string(&path) {
+            Ok(serialized) => serde_json::from_str(&serialized).unwrap_or_else(|e| {
+                eprintln!("Ignoring unreadable ban list: {e}");
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Some(path),
+            bans: bans.into_iter().map(|ban| (ban.ip, ban)).collect(),
+        })
+    }
+
+    pub fn save(&self) -> CleytoResult<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut bans: Vec<&Ban> = self.bans.values().collect();
+        bans.sort_by_key(|ban| ban.ip);
+        let serialized =
+            serde_json::to_string_pretty(&bans).map_err(CleytonError::BlockSerializationError)?;
+        write_atomically(path, serialized.as_bytes())
+    }
+
+    /// Bans the address until `until`, or keeps the ban it had if that one lasts longer
+    pub fn ban(&mut self, ip: IpAddr, until: DateTime<Utc>, reason: impl Into<String>) {
+        match self.bans.get_mut(&ip) {
+            Some(ban) if ban.until >= until => {}
+            _ => {
+                self.bans.insert(
+                    ip,
+                    Ban {
+                        ip,
+                        until,
+                        reason: reason.into(),
+                    },
+                );
+            }
+        }
+    }
+
+    /// Bans the address for `duration` from now
+    pub fn ban_for(&mut self, ip: IpAddr, duration: Duration, reason: impl Into<String>) {
+        let duration = chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::MAX);
+        let until = Utc::now()
+            .checked_add_signed(duration)
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        self.ban(ip, until, reason);
+    }
+
+    /// Returns whether the address was banned
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.bans.remove(ip).is_some()
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr, now: DateTime<Utc>) -> bool {
+        self.bans.get(ip).is_some_and(|ban| ban.until > now)
+    }
+
+    /// The bans still going, the ones ending first first. Expired ones are forgotten
+    pub fn list(&mut self, now: DateTime<Utc>) -> Vec<Ban> {
+        self.bans.retain(|_, ban| ban.until > now);
+        let mut bans: Vec<Ban> = self.bans.values().cloned().collect();
+        bans.sort_by(|a, b| a.until.cmp(&b.until).then(a.ip.cmp(&b.ip)));
+        bans
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
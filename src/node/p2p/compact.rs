@@ -0,0 +1,192 @@
+//! Compact blocks, for relaying new blocks to peers that already have most of their transactions in
+//! the mempool. Instead of the whole block they get its header and a short id for every
+//! transaction, rebuild the block with what they have and only ask for the ones they're missing.
+//! Like `peer`, there's no IO here, `network` sends what this makes.
+//!
+//! Short ids are the first 6 bytes of SHA-256(block hash || txid), so they change with every block
+//! and nobody can make transactions that collide with others' ahead of time. When they collide
+//! anyway the rebuilt block has the wrong hash, and the whole block is asked for instead.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::chain::block::{Block, BlockHeader};
+use crate::chain::transaction::Transaction;
+
+const SHORT_ID_LEN: usize = 6;
+
+/// The short id of a transaction in the block with hash `block_hash`
+pub fn short_id(block_hash: &str, txid: &[u8; 32]) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(block_hash.as_bytes())
+        .chain_update(txid)
+        .finalize();
+    let mut bytes = [0u8; 8];
+    bytes[..SHORT_ID_LEN].copy_from_slice(&digest[..SHORT_ID_LEN]);
+    u64::from_le_bytes(bytes)
+}
+
+/// A transaction sent whole in a compact block, at its place in the block
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PrefilledTransaction {
+    pub index: u32,
+    pub transaction: Transaction,
+}
+
+// ---------------------------------------------- CompactBlock definition ------------------------------------------
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    /// Of the transactions not prefilled, in the order they have in the block
+    pub short_ids: Vec<u64>,
+    /// Sorted by index
+    pub prefilled: Vec<PrefilledTransaction>,
+}
+
+impl CompactBlock {
+    /// The block for a peer that has the transactions `peer_has` says it has. The rest are sent
+    /// whole, and so are repeated ones, since their short ids would be too
+    pub fn new(block: &Block, peer_has: impl Fn(&Transaction) -> bool) -> Self {
+        let hash = block.get_hash();
+        let mut short_ids = Vec::new();
+        let mut seen = HashSet::new();
+        let mut prefilled = Vec::new();
+        for (index, transaction) in block.get_transactions().iter().enumerate() {
+            let id = short_id(&hash, &transaction.txid);
+            if peer_has(transaction) && seen.insert(id) {
+                short_ids.push(id);
+            } else {
+                prefilled.push(PrefilledTransaction {
+                    index: index as u32,
+                    transaction: transaction.clone(),
+                });
+            }
+        }
+        Self {
+            header: block.header(),
+            short_ids,
+            prefilled,
+        }
+    }
+
+    pub fn transaction_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+/// What a `getblocktxn` asks for: the transactions at these places of the block
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockTxnRequest {
+    pub block_hash: String,
+    /// Sorted, no repeats
+    pub indexes: Vec<u32>,
+}
+
+/// The answer to a `getblocktxn`, the transactions in the order they were asked for
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BlockTransactions {
+    pub block_hash: String,
+    pub transactions: Vec<Transaction>,
+}
+
+// ---------------------------------------------- PartialBlock definition ------------------------------------------
+/// A compact block being rebuilt, with the transactions found so far
+pub struct PartialBlock {
+    header: BlockHeader,
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    /// Fills in the prefilled transactions and the ones of `mempool` matching a short id. Errors if
+    /// the compact block makes no sense: prefilled indexes out of order or out of the block, or
+    /// short ids repeated
+    pub fn new(compact: CompactBlock, mempool: &[Transaction]) -> Result<Self, String> {
+        let count = compact.transaction_count();
+        let mut transactions: Vec<Option<Transaction>> = vec![None; count];
+        let mut previous: Option<u32> = None;
+        for prefilled in compact.prefilled {
+            let index = prefilled.index;
+            if previous.is_some_and(|previous| index <= previous) || index as usize >= count {
+                return Err(format!("prefilled transaction at {index} is out of place"));
+            }
+            transactions[index as usize] = Some(prefilled.transaction);
+            previous = Some(index);
+        }
+
+        let hash = &compact.header.hash;
+        let mut by_short_id: HashMap<u64, Option<&Transaction>> = HashMap::new();
+        for transaction in mempool {
+            // Two of ours with the same id, can't tell which it is so it's asked for
+            by_short_id
+                .entry(short_id(hash, &transaction.txid))
+                .and_modify(|found| *found = None)
+                .or_insert(Some(transaction));
+        }
+
+        let mut seen = HashSet::new();
+        let empty = transactions
+            .iter_mut()
+            .filter(|transaction| transaction.is_none());
+        for (slot, id) in empty.zip(&compact.short_ids) {
+            if !seen.insert(*id) {
+                return Err(format!("short id {id} is there twice"));
+            }
+            *slot = by_short_id.get(id).copied().flatten().cloned();
+        }
+
+        Ok(Self {
+            header: compact.header,
+            transactions,
+        })
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.header.hash
+    }
+
+    /// Indexes of the transactions still missing, to ask for in a `getblocktxn`
+    pub fn missing(&self) -> Vec<u32> {
+        self.transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, transaction)| transaction.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+
+    /// Fills in the missing transactions, given in the order `missing` returned them
+    pub fn fill(&mut self, transactions: Vec<Transaction>) -> Result<(), String> {
+        let missing = self.missing();
+        if transactions.len() != missing.len() {
+            return Err(format!(
+                "{} transactions missing but got {}",
+                missing.len(),
+                transactions.len()
+            ));
+        }
+        for (index, transaction) in missing.into_iter().zip(transactions) {
+            self.transactions[index as usize] = Some(transaction);
+        }
+        Ok(())
+    }
+
+    /// The block, once nothing is missing. Errors if something is, or if the rebuilt block doesn't
+    /// have the header's hash, because of a short id collision or transactions that weren't the
+    /// ones asked for
+    pub fn into_block(self) -> Result<Block, String> {
+        let missing = self.transactions.iter().filter(|tx| tx.is_none()).count();
+        if missing > 0 {
+            return Err(format!("{missing} transactions still missing"));
+        }
+        let transactions = self.transactions.into_iter().flatten().collect();
+        let block = Block::from_header(self.header, transactions);
+        if block.calculate_hash() != block.get_hash() {
+            return Err("the transactions don't match the header".to_string());
+        }
+        Ok(block)
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
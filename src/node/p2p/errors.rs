@@ -0,0 +1,63 @@
+use std::fmt;
+
+use super::identity::PublicKey;
+
+#[derive(Debug)]
+pub enum P2PError {
+    /// The frame doesn't start with our network's magic bytes
+    WrongNetwork([u8; 4]),
+    BadChecksum,
+    PayloadTooBig(u32),
+    UnknownCommand(String),
+    MalformedPayload(serde_json::Error),
+    /// The peer broke the protocol, like sending messages before the handshake
+    ProtocolViolation(String),
+    /// The encrypted handshake didn't go through, like with a node of another network
+    Handshake(String),
+    /// A transport message that wasn't encrypted with the key of the connection
+    DecryptionFailed,
+    /// The peer has another key than the one pinned for its address
+    UnexpectedKey(PublicKey),
+    /// The peer didn't start a handshake, and we only take encrypted connections
+    NotEncrypted,
+    Io(std::io::Error),
+}
+
+impl fmt::Display for P2PError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            P2PError::WrongNetwork(magic) => {
+                write!(
+                    f,
+                    "Frame from another network, magic {}",
+                    hex::encode(magic)
+                )
+            }
+            P2PError::BadChecksum => write!(f, "Frame payload doesn't match its checksum"),
+            P2PError::PayloadTooBig(length) => write!(f, "Frame payload of {length} bytes"),
+            P2PError::UnknownCommand(command) => write!(f, "Unknown command {command}"),
+            P2PError::MalformedPayload(e) => write!(f, "Malformed payload: {e}"),
+            P2PError::ProtocolViolation(reason) => write!(f, "Protocol violation: {reason}"),
+            P2PError::Handshake(reason) => write!(f, "Encrypted handshake failed: {reason}"),
+            P2PError::DecryptionFailed => write!(f, "Couldn't decrypt a transport message"),
+            P2PError::UnexpectedKey(key) => {
+                write!(f, "Peer has key {}, not the pinned one", hex::encode(key))
+            }
+            P2PError::NotEncrypted => write!(f, "Peer didn't start an encrypted handshake"),
+            P2PError::Io(e) => write!(f, "IO error: {e}"),
+        }
+    }
+}
+impl std::error::Error for P2PError {}
+
+impl From<std::io::Error> for P2PError {
+    fn from(value: std::io::Error) -> Self {
+        P2PError::Io(value)
+    }
+}
+
+impl From<serde_json::Error> for P2PError {
+    fn from(value: serde_json::Error) -> Self {
+        P2PError::MalformedPayload(value)
+    }
+}
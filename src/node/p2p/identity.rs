@@ -0,0 +1,88 @@
+//! The key a node is known by to the peers it encrypts its connections with. It's an X25519 key,
+//! made the first time the node needs one and kept in `node_key.pem` in the data directory, so it
+//! stays the same across restarts and other nodes can pin it.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use openssl::pkey::{Id, PKey, Private};
+
+use crate::error_handling::{CleytoResult, CleytonError};
+use crate::node::files::write_private_atomically;
+
+pub const KEY_LEN: usize = 32;
+pub type PublicKey = [u8; KEY_LEN];
+
+pub struct NodeIdentity {
+    key: PKey<Private>,
+    public: PublicKey,
+}
+
+impl NodeIdentity {
+    pub fn generate() -> Self {
+        let key = PKey::generate_x25519().expect("Couldn't generate an X25519 key");
+        Self::from_key(key).expect("A new X25519 key has a public key")
+    }
+
+    /// The identity kept at `path`, or a new one saved there if there's none yet. The file is only
+    /// readable by the node's user, since whoever has the key can pose as the node
+    pub fn open_or_create(path: &Path) -> CleytoResult<Self> {
+        match fs::read(path) {
+            Ok(pem) => Self::from_pem(&pem),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let identity = Self::generate();
+                write_private_atomically(path, &identity.to_pem())?;
+                Ok(identity)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn from_pem(pem: &[u8]) -> CleytoResult<Self> {
+        let key = PKey::private_key_from_pem(pem)
+            .map_err(|e| CleytonError::InvalidNodeKey(e.to_string()))?;
+        if key.id() != Id::X25519 {
+            return Err(CleytonError::InvalidNodeKey(
+                "the node key isn't an X25519 key".to_string(),
+            ));
+        }
+        Self::from_key(key)
+    }
+
+    pub fn to_pem(&self) -> Vec<u8> {
+        self.key
+            .private_key_to_pem_pkcs8()
+            .expect("Couldn't serialize the node key")
+    }
+
+    fn from_key(key: PKey<Private>) -> CleytoResult<Self> {
+        let raw = key
+            .raw_public_key()
+            .map_err(|e| CleytonError::InvalidNodeKey(e.to_string()))?;
+        let public = raw
+            .try_into()
+            .map_err(|_| CleytonError::InvalidNodeKey("wrong public key length".to_string()))?;
+        Ok(Self { key, public })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public
+    }
+
+    pub(crate) fn private_key(&self) -> &PKey<Private> {
+        &self.key
+    }
+}
+
+// The private key stays out of logs and panics
+impl fmt::Debug for NodeIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "NodeIdentity({})", hex::encode(self.public))
+    }
+}
+
+/// A public key written in hex, like `node identity` prints them
+pub fn parse_public_key(hex_key: &str) -> Option<PublicKey> {
+    hex::decode(hex_key.trim()).ok()?.try_into().ok()
+}
@@ -0,0 +1,289 @@
+//! Every message goes in a frame:
+//!
+//! | bytes | what                                                        |
+//! |-------|-------------------------------------------------------------|
+//! | 4     | network magic                                               |
+//! | 12    | command, ASCII padded with zeros                            |
+//! | 4     | payload length, little endian u32                           |
+//! | 4     | checksum, the first bytes of SHA-256(SHA-256(payload))      |
+//! | ...   | payload, the message serialized as JSON                     |
+
+use std::fmt;
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::address_book::MAX_ADDRS;
+use super::compact::{BlockTransactions, BlockTxnRequest, CompactBlock};
+use super::errors::P2PError;
+use crate::chain::block::{Block, BlockHeader};
+use crate::chain::transaction::Transaction;
+
+pub const NETWORK_MAGIC: [u8; 4] = [0xc1, 0xe7, 0x70, 0xc0];
+pub const HEADER_LEN: usize = 24;
+const COMMAND_LEN: usize = 12;
+// Big enough for any block we make
+pub const MAX_PAYLOAD_LEN: u32 = 32 * 1024 * 1024;
+/// Most items an inv, getdata or notfound can have
+pub const MAX_INV_ITEMS: usize = 50_000;
+/// Most headers sent in one headers message. Getting exactly this many means there are more
+pub const MAX_HEADERS: usize = 2_000;
+// A locator has a few hashes near the tip and then doubles the step, this is plenty
+const MAX_LOCATOR_LEN: usize = 101;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Version {
+    pub protocol_version: u32,
+    /// Index of the last block of the sender's chain
+    pub best_height: u64,
+    /// Random for every connection, so a node can tell when it connected to itself
+    pub nonce: u64,
+    /// The port the sender accepts peers on, if any
+    pub listen_port: Option<u16>,
+    pub user_agent: String,
+}
+
+/// Something a node can announce and others can ask for, by its hash in hex
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", content = "hash", rename_all = "lowercase")]
+pub enum InvItem {
+    Tx(String),
+    Block(String),
+}
+
+impl InvItem {
+    pub fn transaction(transaction: &Transaction) -> Self {
+        InvItem::Tx(hex::encode(transaction.txid))
+    }
+
+    pub fn block(block: &Block) -> Self {
+        InvItem::Block(block.get_hash())
+    }
+}
+
+#[derive(Clone)]
+pub enum Message {
+    Version(Version),
+    Verack,
+    Ping(u64),
+    Pong(u64),
+    /// Items the sender has, so the peer can ask for the ones it doesn't
+    Inv(Vec<InvItem>),
+    GetData(Vec<InvItem>),
+    /// The answer to the items of a getdata the sender doesn't have
+    NotFound(Vec<InvItem>),
+    Tx(Box<Transaction>),
+    Block(Box<Block>),
+    /// A block locator, asking for the headers after the last block in it the peer has
+    GetHeaders(Vec<String>),
+    Headers(Vec<BlockHeader>),
+    /// Asks for addresses of other nodes
+    GetAddr,
+    Addr(Vec<SocketAddr>),
+    /// The sender wants new blocks as compact blocks instead of inv
+    SendCmpct,
+    CmpctBlock(Box<CompactBlock>),
+    /// Asks for the transactions of a compact block the sender couldn't find in its mempool
+    GetBlockTxn(BlockTxnRequest),
+    BlockTxn(Box<BlockTransactions>),
+}
+
+// Blocks and transactions don't implement Debug, so only their hashes are shown
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Message::Version(version) => f.debug_tuple("Version").field(version).finish(),
+            Message::Verack => write!(f, "Verack"),
+            Message::Ping(nonce) => f.debug_tuple("Ping").field(nonce).finish(),
+            Message::Pong(nonce) => f.debug_tuple("Pong").field(nonce).finish(),
+            Message::Inv(items) => f.debug_tuple("Inv").field(items).finish(),
+            Message::GetData(items) => f.debug_tuple("GetData").field(items).finish(),
+            Message::NotFound(items) => f.debug_tuple("NotFound").field(items).finish(),
+            Message::Tx(transaction) => write!(f, "Tx({})", hex::encode(transaction.txid)),
+            Message::Block(block) => write!(f, "Block({})", block.get_hash()),
+            Message::GetHeaders(locator) => f.debug_tuple("GetHeaders").field(locator).finish(),
+            Message::Headers(headers) => write!(f, "Headers({} headers)", headers.len()),
+            Message::GetAddr => write!(f, "GetAddr"),
+            Message::Addr(addresses) => f.debug_tuple("Addr").field(addresses).finish(),
+            Message::SendCmpct => write!(f, "SendCmpct"),
+            Message::CmpctBlock(compact) => write!(f, "CmpctBlock({})", compact.header.hash),
+            Message::GetBlockTxn(request) => f.debug_tuple("GetBlockTxn").field(request).finish(),
+            Message::BlockTxn(response) => write!(f, "BlockTxn({})", response.block_hash),
+        }
+    }
+}
+
+impl Message {
+    pub fn command(&self) -> &'static str {
+        match self {
+            Message::Version(_) => "version",
+            Message::Verack => "verack",
+            Message::Ping(_) => "ping",
+            Message::Pong(_) => "pong",
+            Message::Inv(_) => "inv",
+            Message::GetData(_) => "getdata",
+            Message::NotFound(_) => "notfound",
+            Message::Tx(_) => "tx",
+            Message::Block(_) => "block",
+            Message::GetHeaders(_) => "getheaders",
+            Message::Headers(_) => "headers",
+            Message::GetAddr => "getaddr",
+            Message::Addr(_) => "addr",
+            Message::SendCmpct => "sendcmpct",
+            Message::CmpctBlock(_) => "cmpctblock",
+            Message::GetBlockTxn(_) => "getblocktxn",
+            Message::BlockTxn(_) => "blocktxn",
+        }
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let payload = match self {
+            Message::Version(version) => serde_json::to_vec(version),
+            Message::Verack | Message::GetAddr | Message::SendCmpct => Ok(Vec::new()),
+            Message::Ping(nonce) | Message::Pong(nonce) => serde_json::to_vec(nonce),
+            Message::Inv(items) | Message::GetData(items) | Message::NotFound(items) => {
+                serde_json::to_vec(items)
+            }
+            Message::Tx(transaction) => serde_json::to_vec(transaction),
+            Message::Block(block) => serde_json::to_vec(block),
+            Message::GetHeaders(locator) => serde_json::to_vec(locator),
+            Message::Headers(headers) => serde_json::to_vec(headers),
+            Message::Addr(addresses) => serde_json::to_vec(addresses),
+            Message::CmpctBlock(compact) => serde_json::to_vec(compact),
+            Message::GetBlockTxn(request) => serde_json::to_vec(request),
+            Message::BlockTxn(response) => serde_json::to_vec(response),
+        };
+        payload.expect("Couldn't serialize a peer message")
+    }
+
+    fn from_payload(command: &str, payload: &[u8]) -> Result<Self, P2PError> {
+        let message = match command {
+            "version" => Message::Version(serde_json::from_slice(payload)?),
+            "verack" => Message::Verack,
+            "ping" => Message::Ping(serde_json::from_slice(payload)?),
+            "pong" => Message::Pong(serde_json::from_slice(payload)?),
+            "inv" => Message::Inv(at_most(payload, MAX_INV_ITEMS, "inventory items")?),
+            "getdata" => Message::GetData(at_most(payload, MAX_INV_ITEMS, "inventory items")?),
+            "notfound" => Message::NotFound(at_most(payload, MAX_INV_ITEMS, "inventory items")?),
+            "tx" => Message::Tx(serde_json::from_slice(payload)?),
+            "block" => Message::Block(serde_json::from_slice(payload)?),
+            "getheaders" => Message::GetHeaders(at_most(payload, MAX_LOCATOR_LEN, "locator")?),
+            "headers" => Message::Headers(at_most(payload, MAX_HEADERS, "headers")?),
+            "getaddr" => Message::GetAddr,
+            "addr" => Message::Addr(at_most(payload, MAX_ADDRS, "addresses")?),
+            "sendcmpct" => Message::SendCmpct,
+            "cmpctblock" => {
+                let compact: CompactBlock = serde_json::from_slice(payload)?;
+                too_many(
+                    compact.transaction_count(),
+                    MAX_INV_ITEMS,
+                    "compact block transactions",
+                )?;
+                Message::CmpctBlock(Box::new(compact))
+            }
+            "getblocktxn" => {
+                let request: BlockTxnRequest = serde_json::from_slice(payload)?;
+                too_many(request.indexes.len(), MAX_INV_ITEMS, "transaction indexes")?;
+                Message::GetBlockTxn(request)
+            }
+            "blocktxn" => Message::BlockTxn(serde_json::from_slice(payload)?),
+            _ => return Err(P2PError::UnknownCommand(command.to_string())),
+        };
+        Ok(message)
+    }
+
+    /// The message in its frame, ready to be written to the peer
+    pub fn encode(&self) -> Vec<u8> {
+        let payload = self.payload();
+        let mut command = [0u8; COMMAND_LEN];
+        command[..self.command().len()].copy_from_slice(self.command().as_bytes());
+
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.extend_from_slice(&NETWORK_MAGIC);
+        frame.extend_from_slice(&command);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&checksum(&payload));
+        frame.extend_from_slice(&payload);
+        frame
+    }
+}
+
+// A list of at most `max` things, or the peer is breaking the protocol
+fn at_most<T: serde::de::DeserializeOwned>(
+    payload: &[u8],
+    max: usize,
+    what: &str,
+) -> Result<Vec<T>, P2PError> {
+    let items: Vec<T> = serde_json::from_slice(payload)?;
+    too_many(items.len(), max, what)?;
+    Ok(items)
+}
+
+fn too_many(count: usize, max: usize, what: &str) -> Result<(), P2PError> {
+    if count > max {
+        return Err(P2PError::ProtocolViolation(format!(
+            "{count} {what} in one message"
+        )));
+    }
+    Ok(())
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(Sha256::digest(payload));
+    digest[..4].try_into().unwrap()
+}
+
+// ---------------------------------------------- FrameDecoder definition ------------------------------------------
+/// Collects the bytes read from a peer and takes whole messages out of them. Reads can end in the
+/// middle of a frame, the rest just stays buffered until the next ones
+#[derive(Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next whole message, if there's one buffered. After an error the connection should be
+    /// dropped, there's no telling where the next frame starts
+    pub fn next_message(&mut self) -> Result<Option<Message>, P2PError> {
+        if self.buffer.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let magic: [u8; 4] = self.buffer[..4].try_into().unwrap();
+        if magic != NETWORK_MAGIC {
+            return Err(P2PError::WrongNetwork(magic));
+        }
+        let length = u32::from_le_bytes(self.buffer[16..20].try_into().unwrap());
+        if length > MAX_PAYLOAD_LEN {
+            return Err(P2PError::PayloadTooBig(length));
+        }
+        let frame_len = HEADER_LEN + length as usize;
+        if self.buffer.len() < frame_len {
+            return Ok(None);
+        }
+
+        let frame: Vec<u8> = self.buffer.drain(..frame_len).collect();
+        let payload = &frame[HEADER_LEN..];
+        if frame[20..24] != checksum(payload) {
+            return Err(P2PError::BadChecksum);
+        }
+        let command = &frame[4..4 + COMMAND_LEN];
+        let command_len = command
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(COMMAND_LEN);
+        let command = String::from_utf8_lossy(&command[..command_len]);
+
+        Message::from_payload(&command, payload).map(Some)
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
@@ -0,0 +1,56 @@
+//! What peers can do wrong, and how much each thing counts. Every address collects the points of
+//! what its peers did, and reaching `BAN_THRESHOLD` gets it banned.
+
+use std::fmt;
+
+use super::errors::P2PError;
+
+/// Points that get an address banned
+pub const BAN_THRESHOLD: u32 = 100;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Misbehaviour {
+    /// Headers that don't form a chain, have the wrong hash or lack the proof of work
+    InvalidHeaders(String),
+    /// A block that builds on our tip but doesn't validate, or whose body doesn't match its hash
+    InvalidBlock(String),
+    /// A transaction with a bad signature or that doesn't add up. Not counting the ones we already had
+    InvalidTransaction(String),
+    /// A frame that can't be read: bad checksum, unknown command, payload too big or not parsing
+    MalformedMessage(String),
+}
+
+impl Misbehaviour {
+    pub fn score(&self) -> u32 {
+        match self {
+            Misbehaviour::InvalidHeaders(_) | Misbehaviour::InvalidBlock(_) => BAN_THRESHOLD,
+            // A few could be honest mistakes, a flood of them isn't
+            Misbehaviour::InvalidTransaction(_) => 10,
+            Misbehaviour::MalformedMessage(_) => 50,
+        }
+    }
+
+    /// What the error says about the peer that sent the frame. None for errors that aren't its
+    /// fault, like connections to nodes of another network
+    pub fn from_error(error: &P2PError) -> Option<Self> {
+        match error {
+            P2PError::WrongNetwork(_)
+            | P2PError::Handshake(_)
+            | P2PError::UnexpectedKey(_)
+            | P2PError::NotEncrypted
+            | P2PError::Io(_) => None,
+            _ => Some(Misbehaviour::MalformedMessage(error.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Misbehaviour {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Misbehaviour::InvalidHeaders(reason) => write!(f, "invalid headers: {reason}"),
+            Misbehaviour::InvalidBlock(reason) => write!(f, "invalid block: {reason}"),
+            Misbehaviour::InvalidTransaction(txid) => write!(f, "invalid transaction {txid}"),
+            Misbehaviour::MalformedMessage(reason) => write!(f, "malformed message: {reason}"),
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! How nodes talk to each other, over plain TCP next to the HTTP server.
+//!
+//! `message` has the framing, `peer` the handshake and keepalive of a single connection, without
+//! any IO so it can be tested on its own, `sync` how the blocks we're missing are downloaded, and
+//! `network` the sockets and threads that run them. `compact` is how new blocks are relayed to peers
+//! that have their transactions already. `noise` encrypts connections between nodes that
+//! want to, with the keys of `identity`. `address_book` and `ban_list` are what the
+//! node remembers about other nodes between restarts. `sim` runs several nodes in one process, over
+//! links in memory, for tests.
+
+pub mod address_book;
+pub mod ban_list;
+pub mod compact;
+pub mod errors;
+pub mod identity;
+pub mod message;
+pub mod misbehaviour;
+pub mod network;
+pub mod noise;
+pub mod peer;
+pub mod sim;
+pub mod sync;
+
+pub use address_book::{AddressBook, AddressSource, PeerAddress};
+pub use ban_list::{Ban, BanList};
+pub use identity::{NodeIdentity, PublicKey};
+pub use misbehaviour::Misbehaviour;
+pub use network::{Link, Network, NetworkConfig, PeerInfo, PeerSet};
+pub use sim::{SimConfig, SimNode, Simulation};
+
+pub const DEFAULT_P2P_PORT: u16 = 9474;
@@ -0,0 +1,1313 @@
+//! The TCP side of the peer protocol. Every connection gets its own thread, which reads frames
+//! into a `FrameDecoder`, feeds the messages to its `Peer` and writes back whatever it says.
+//! What a connection does with what arrives doesn't depend on TCP, so `sim` runs the same code over
+//! links in memory.
+//!
+//! With an `EncryptionConfig`, connections can start with a `noise` handshake, and then everything
+//! read is decrypted before the `FrameDecoder` sees it, and everything written goes through an
+//! `EncryptedLink`.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+use super::address_book::{AddressBook, AddressSource, PeerAddress, MAX_ADDRS};
+use super::ban_list::{Ban, BanList};
+use super::compact::{BlockTransactions, BlockTxnRequest, CompactBlock, PartialBlock};
+use super::errors::P2PError;
+use super::identity::{NodeIdentity, PublicKey};
+use super::message::{FrameDecoder, InvItem, Message, Version, MAX_HEADERS};
+use super::misbehaviour::{Misbehaviour, BAN_THRESHOLD};
+use super::noise::{self, CipherState, Handshake, NoiseDecoder};
+use super::peer::{
+    Direction, Peer, PeerEvent, PeerTimings, COMPACT_BLOCKS_VERSION, PROTOCOL_VERSION,
+};
+use super::sync::{BlockSync, Outgoing};
+use crate::chain::block::Block;
+use crate::chain::transaction::Transaction;
+use crate::chain::utils::PROOF_OF_WORK_DIFFICULTY;
+use crate::error_handling::CleytoResult;
+use crate::node::logger::Logger;
+use crate::node::NodeState;
+
+// How long a read waits for data before the peer gets a tick
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+// How often the outbound connections are topped up
+const CONNECTION_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+pub const DEFAULT_TARGET_OUTBOUND: usize = 8;
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+// Past this, what a peer is known to have is forgotten. At worst it gets a few announcements again
+const MAX_KNOWN_INVENTORY: usize = 100_000;
+// Compact blocks waiting for the transactions asked for. Past this, new ones are asked for whole
+const MAX_PARTIAL_BLOCKS: usize = 16;
+
+/// Where to listen and who to connect to
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    /// None to only make outbound connections
+    pub listen: Option<SocketAddr>,
+    pub connect: Vec<SocketAddr>,
+    pub timings: PeerTimings,
+    /// Leading zeros the hash of a block needs for its header to be accepted from peers. The node
+    /// state gets the same, for the blocks it accepts whole
+    pub pow_difficulty: u8,
+    /// Addresses to start from when the address book has nothing better, from the config file
+    pub seeds: Vec<SocketAddr>,
+    /// How many outbound connections to keep, counting the ones to `connect`
+    pub target_outbound: usize,
+    /// Where the address book is kept, None to keep it only in memory
+    pub address_book: Option<PathBuf>,
+    /// Where the banned addresses are kept, None to keep them only in memory
+    pub ban_list: Option<PathBuf>,
+    /// How long addresses that misbehave too much stay banned
+    pub ban_duration: Duration,
+    /// None to only talk to peers in plaintext
+    pub encryption: Option<EncryptionConfig>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            connect: Vec::new(),
+            timings: PeerTimings::default(),
+            pow_difficulty: PROOF_OF_WORK_DIFFICULTY,
+            seeds: Vec::new(),
+            target_outbound: DEFAULT_TARGET_OUTBOUND,
+            address_book: None,
+            ban_list: None,
+            ban_duration: DEFAULT_BAN_DURATION,
+            encryption: None,
+        }
+    }
+}
+
+/// Which connections are encrypted, and with what key. Peers that start a handshake always get
+/// an encrypted connection, the rest is up to this
+#[derive(Clone, Debug)]
+pub struct EncryptionConfig {
+    pub identity: Arc<NodeIdentity>,
+    /// Whether to start a handshake with every peer we connect to
+    pub encrypt_outbound: bool,
+    /// Whether to refuse peers that don't, and only connect encrypted
+    pub required: bool,
+    /// The keys the peers at these addresses must have. Connections to them are always encrypted
+    pub pinned: HashMap<SocketAddr, PublicKey>,
+}
+
+impl EncryptionConfig {
+    pub fn new(identity: NodeIdentity) -> Self {
+        Self {
+            identity: Arc::new(identity),
+            encrypt_outbound: false,
+            required: false,
+            pinned: HashMap::new(),
+        }
+    }
+}
+
+/// Where the frames for a peer go: a TCP stream, or a link of the simulator
+pub trait Link: Send {
+    fn send(&mut self, frame: &[u8]) -> std::io::Result<()>;
+    /// Closes the connection, the other side and the one reading this side notice
+    fn close(&mut self);
+}
+
+impl Link for TcpStream {
+    fn send(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        self.write_all(frame)
+    }
+
+    fn close(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+/// A TCP stream that encrypts the frames with the key of its handshake
+pub struct EncryptedLink {
+    stream: TcpStream,
+    cipher: CipherState,
+}
+
+impl Link for EncryptedLink {
+    fn send(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        self.stream.write_all(&noise::seal(&mut self.cipher, frame))
+    }
+
+    fn close(&mut self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+// What's left of the handshake for the rest of a connection
+struct SecureChannel {
+    send: CipherState,
+    decoder: NoiseDecoder,
+    remote_key: PublicKey,
+}
+
+// Every frame is written whole while holding the lock, so they never interleave
+pub(crate) type Writer = Arc<Mutex<dyn Link>>;
+
+/// A connected peer, as `/peers` shows it
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    pub id: u64,
+    pub address: SocketAddr,
+    pub direction: Direction,
+    /// None until the handshake is done
+    pub version: Option<Version>,
+    pub latency: Option<Duration>,
+    /// The identity key of the peer, for encrypted connections
+    pub key: Option<PublicKey>,
+}
+
+struct PeerHandle {
+    info: PeerInfo,
+    writer: Writer,
+    // What the peer announced, sent us or was announced already, so it isn't announced again
+    known: HashSet<InvItem>,
+    // Whether it asked for new blocks as compact blocks
+    compact: bool,
+}
+
+/// One connection to a peer, whatever carries its frames. The network feeds it what arrives and
+/// ticks it, and leaves in `dropped` why to close it once there's a reason to
+pub(crate) struct Connection {
+    id: u64,
+    address: SocketAddr,
+    peer: Peer,
+    decoder: FrameDecoder,
+    writer: Writer,
+    pub(crate) dropped: Option<String>,
+}
+
+// ---------------------------------------------- PeerSet definition -----------------------------------------------
+/// The peers a node is connected to, shared by the threads serving them
+#[derive(Default)]
+pub struct PeerSet {
+    peers: Mutex<HashMap<u64, PeerHandle>>,
+    next_id: AtomicU64,
+}
+
+impl PeerSet {
+    /// How many peers finished the handshake
+    pub fn len(&self) -> usize {
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|handle| handle.info.version.is_some())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn list(&self) -> Vec<PeerInfo> {
+        let mut peers: Vec<PeerInfo> = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|handle| handle.info.clone())
+            .collect();
+        peers.sort_by_key(|info| info.id);
+        peers
+    }
+
+    /// Sends the message to every peer that finished the handshake
+    pub fn broadcast(&self, message: &Message) {
+        let writers: Vec<_> = self
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|handle| handle.info.version.is_some())
+            .map(|handle| Arc::clone(&handle.writer))
+            .collect();
+        let frame = message.encode();
+        for writer in writers {
+            // A peer that can't be written to is dropped by its own thread
+            let _ = writer.lock().unwrap().send(&frame);
+        }
+    }
+
+    /// Announces the items to every peer that finished the handshake and doesn't have them yet
+    pub fn announce(&self, items: &[InvItem]) {
+        let mut announcements = Vec::new();
+        for handle in self.peers.lock().unwrap().values_mut() {
+            if handle.info.version.is_none() {
+                continue;
+            }
+            let unknown: Vec<InvItem> = items
+                .iter()
+                .filter(|item| !handle.known.contains(*item))
+                .cloned()
+                .collect();
+            if unknown.is_empty() {
+                continue;
+            }
+            remember(&mut handle.known, &unknown);
+            announcements.push((Arc::clone(&handle.writer), Message::Inv(unknown)));
+        }
+
+        for (writer, message) in announcements {
+            let _ = writer.lock().unwrap().send(&message.encode());
+        }
+    }
+
+    /// Relays a new block to every peer that finished the handshake and doesn't have it. The ones
+    /// that asked for compact blocks get one, with the transactions they may not have sent whole,
+    /// the rest get an inv
+    pub fn announce_block(&self, block: &Block) {
+        let item = InvItem::block(block);
+        let transactions: Vec<InvItem> = block
+            .get_transactions()
+            .iter()
+            .map(InvItem::transaction)
+            .collect();
+        let mut announcements = Vec::new();
+        for handle in self.peers.lock().unwrap().values_mut() {
+            if handle.info.version.is_none() || handle.known.contains(&item) {
+                continue;
+            }
+            let message = match handle.compact {
+                true => {
+                    let known = &handle.known;
+                    let compact = CompactBlock::new(block, |transaction: &Transaction| {
+                        known.contains(&InvItem::transaction(transaction))
+                    });
+                    Message::CmpctBlock(Box::new(compact))
+                }
+                false => Message::Inv(vec![item.clone()]),
+            };
+            remember(&mut handle.known, std::slice::from_ref(&item));
+            remember(&mut handle.known, &transactions);
+            announcements.push((Arc::clone(&handle.writer), message));
+        }
+
+        for (writer, message) in announcements {
+            let _ = writer.lock().unwrap().send(&message.encode());
+        }
+    }
+
+    /// Sends the message to one peer, if it's still connected
+    pub fn send(&self, id: u64, message: &Message) {
+        let writer = match self.peers.lock().unwrap().get(&id) {
+            Some(handle) => Arc::clone(&handle.writer),
+            None => return,
+        };
+        // A peer that can't be written to is dropped by its own thread
+        let _ = writer.lock().unwrap().send(&message.encode());
+    }
+
+    // The addresses the connected peers listen on, as far as we know them, so they aren't dialed
+    // again
+    fn listen_addresses(&self) -> HashSet<SocketAddr> {
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|handle| match handle.info.direction {
+                Direction::Outbound => Some(handle.info.address),
+                Direction::Inbound => handle
+                    .info
+                    .version
+                    .as_ref()
+                    .and_then(|version| version.listen_port)
+                    .map(|port| SocketAddr::new(handle.info.address.ip(), port)),
+            })
+            .collect()
+    }
+
+    // Shuts the connections from the address down, their threads notice and drop them
+    fn disconnect(&self, ip: IpAddr) {
+        for handle in self.peers.lock().unwrap().values() {
+            if handle.info.address.ip() == ip {
+                handle.writer.lock().unwrap().close();
+            }
+        }
+    }
+
+    fn address(&self, id: u64) -> Option<SocketAddr> {
+        let peers = self.peers.lock().unwrap();
+        peers.get(&id).map(|handle| handle.info.address)
+    }
+
+    fn set_compact(&self, id: u64) {
+        if let Some(handle) = self.peers.lock().unwrap().get_mut(&id) {
+            handle.compact = true;
+        }
+    }
+
+    fn mark_known(&self, id: u64, items: &[InvItem]) {
+        if let Some(handle) = self.peers.lock().unwrap().get_mut(&id) {
+            remember(&mut handle.known, items);
+        }
+    }
+
+    fn insert(
+        &self,
+        address: SocketAddr,
+        direction: Direction,
+        writer: Writer,
+        key: Option<PublicKey>,
+    ) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = PeerInfo {
+            id,
+            address,
+            direction,
+            version: None,
+            latency: None,
+            key,
+        };
+        self.peers.lock().unwrap().insert(
+            id,
+            PeerHandle {
+                info,
+                writer,
+                known: HashSet::new(),
+                compact: false,
+            },
+        );
+        id
+    }
+
+    fn update(&self, id: u64, peer: &Peer) {
+        if let Some(handle) = self.peers.lock().unwrap().get_mut(&id) {
+            handle.info.version = peer.is_ready().then(|| peer.remote().cloned()).flatten();
+            handle.info.latency = peer.latency();
+        }
+    }
+
+    fn remove(&self, id: u64) {
+        self.peers.lock().unwrap().remove(&id);
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+fn remember(known: &mut HashSet<InvItem>, items: &[InvItem]) {
+    if known.len() + items.len() > MAX_KNOWN_INVENTORY {
+        known.clear();
+    }
+    known.extend(items.iter().cloned());
+}
+
+// ---------------------------------------------- Network definition -----------------------------------------------
+/// The running peer protocol of a node. Dropping it doesn't stop the threads, `stop` does
+pub struct Network {
+    state: Arc<Mutex<NodeState>>,
+    logger: Arc<Logger>,
+    peers: Arc<PeerSet>,
+    sync: Mutex<BlockSync>,
+    book: Mutex<AddressBook>,
+    // Addresses we're connecting or connected to
+    outbound: Mutex<HashSet<SocketAddr>>,
+    target_outbound: usize,
+    bans: Mutex<BanList>,
+    // Misbehaviour points of every address that didn't get banned yet
+    scores: Mutex<HashMap<IpAddr, u32>>,
+    ban_duration: Duration,
+    pow_difficulty: u8,
+    // Compact blocks waiting for the transactions asked for, by hash, and the peer asked
+    partial_blocks: Mutex<HashMap<String, (u64, PartialBlock)>>,
+    timings: PeerTimings,
+    encryption: Option<EncryptionConfig>,
+    listen_port: Option<u16>,
+    local_addr: Option<SocketAddr>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Network {
+    /// Starts listening, if the config says so, and connecting to the configured peers
+    pub fn start(
+        config: NetworkConfig,
+        state: Arc<Mutex<NodeState>>,
+        logger: Arc<Logger>,
+    ) -> CleytoResult<Arc<Network>> {
+        let listener = match config.listen {
+            Some(address) => Some(TcpListener::bind(address)?),
+            None => None,
+        };
+        let local_addr = match &listener {
+            Some(listener) => Some(listener.local_addr()?),
+            None => None,
+        };
+        let connect = config.connect.clone();
+        let network = Arc::new(Network::new(config, state, logger, local_addr)?);
+
+        if let Some(listener) = listener {
+            listener.set_nonblocking(true)?;
+            let network = Arc::clone(&network);
+            thread::spawn(move || network.accept_peers(listener));
+        }
+        for address in connect {
+            network.connect(address);
+        }
+        {
+            let network = Arc::clone(&network);
+            thread::spawn(move || network.manage_connections());
+        }
+
+        Ok(network)
+    }
+
+    // Everything but the sockets and threads, which the simulator does without. `local_addr` is
+    // where it says it listens
+    pub(crate) fn new(
+        config: NetworkConfig,
+        state: Arc<Mutex<NodeState>>,
+        logger: Arc<Logger>,
+        local_addr: Option<SocketAddr>,
+    ) -> CleytoResult<Network> {
+        let mut book = match config.address_book {
+            Some(path) => AddressBook::open(path)?,
+            None => AddressBook::in_memory(),
+        };
+        for seed in config.seeds {
+            book.add(seed, AddressSource::Seed);
+        }
+        for address in &config.connect {
+            book.add(*address, AddressSource::Manual);
+        }
+        book.save()?;
+        let bans = match config.ban_list {
+            Some(path) => BanList::open(path)?,
+            None => BanList::in_memory(),
+        };
+
+        let (peers, progress) = {
+            let mut state = state.lock().unwrap();
+            state.set_pow_difficulty(config.pow_difficulty);
+            (state.peers(), state.sync_progress())
+        };
+        Ok(Network {
+            state,
+            logger,
+            peers,
+            sync: Mutex::new(BlockSync::new(config.pow_difficulty, progress)),
+            book: Mutex::new(book),
+            outbound: Mutex::new(HashSet::new()),
+            target_outbound: config.target_outbound,
+            bans: Mutex::new(bans),
+            scores: Mutex::new(HashMap::new()),
+            ban_duration: config.ban_duration,
+            pow_difficulty: config.pow_difficulty,
+            partial_blocks: Mutex::new(HashMap::new()),
+            timings: config.timings,
+            encryption: config.encryption,
+            listen_port: local_addr.map(|address| address.port()),
+            local_addr,
+            stop: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Where it's listening, with the actual port if it was started on port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+
+    /// The key other nodes know this one by, if it encrypts connections
+    pub fn identity_key(&self) -> Option<PublicKey> {
+        self.encryption
+            .as_ref()
+            .map(|encryption| encryption.identity.public_key())
+    }
+
+    pub fn peers(&self) -> Arc<PeerSet> {
+        Arc::clone(&self.peers)
+    }
+
+    /// Every address the node knows of, the most recently seen first
+    pub fn known_addresses(&self) -> Vec<PeerAddress> {
+        self.book.lock().unwrap().list()
+    }
+
+    /// Bans the address for `duration`, dropping the peers connected from it
+    pub fn ban(&self, ip: IpAddr, duration: Duration, reason: impl Into<String>) {
+        {
+            let mut bans = self.bans.lock().unwrap();
+            bans.ban_for(ip, duration, reason);
+            if let Err(e) = bans.save() {
+                self.logger
+                    .log_error(format!("Couldn't save the ban list: {e:?}"));
+            }
+        }
+        self.peers.disconnect(ip);
+    }
+
+    /// Returns whether the address was banned
+    pub fn unban(&self, ip: &IpAddr) -> bool {
+        let mut bans = self.bans.lock().unwrap();
+        let unbanned = bans.unban(ip);
+        if let Err(e) = bans.save() {
+            self.logger
+                .log_error(format!("Couldn't save the ban list: {e:?}"));
+        }
+        unbanned
+    }
+
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.lock().unwrap().list(Utc::now())
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.bans.lock().unwrap().is_banned(ip, Utc::now())
+    }
+
+    /// The misbehaviour points of the address, 0 once it's banned
+    pub fn misbehaviour_score(&self, ip: &IpAddr) -> u32 {
+        self.scores.lock().unwrap().get(ip).copied().unwrap_or(0)
+    }
+
+    // Adds what the peer did to the points of its address. Returns why to drop it if that got
+    // the address banned
+    fn misbehaved(&self, address: SocketAddr, misbehaviour: Misbehaviour) -> Result<(), String> {
+        self.logger
+            .log_error(format!("Peer {address} misbehaved: {misbehaviour}"));
+        let ip = address.ip();
+        let score = {
+            let mut scores = self.scores.lock().unwrap();
+            let score = scores.entry(ip).or_default();
+            *score += misbehaviour.score();
+            let total = *score;
+            if total >= BAN_THRESHOLD {
+                scores.remove(&ip);
+            }
+            total
+        };
+        if score < BAN_THRESHOLD {
+            return Ok(());
+        }
+        self.logger.log(format!("Banning {ip} for {misbehaviour}"));
+        self.ban(ip, self.ban_duration, misbehaviour.to_string());
+        Err(format!("banned for {misbehaviour}"))
+    }
+
+    /// Connects to the peer in the background, unless it's connected or being connected to, or
+    /// banned
+    pub fn connect(self: &Arc<Self>, address: SocketAddr) {
+        if self.is_banned(&address.ip()) {
+            return;
+        }
+        if !self.outbound.lock().unwrap().insert(address) {
+            return;
+        }
+        self.update_book(|book| book.mark_attempt(&address, Utc::now()));
+
+        let network = Arc::clone(self);
+        thread::spawn(move || {
+            match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+                Ok(stream) => network.serve_peer(stream, address, Direction::Outbound),
+                Err(e) => {
+                    network
+                        .logger
+                        .log_error(format!("Couldn't connect to peer {address}: {e}"));
+                    network.update_book(|book| book.mark_failed(&address));
+                }
+            }
+            network.outbound.lock().unwrap().remove(&address);
+        });
+    }
+
+    // Dials addresses from the book while there are fewer outbound peers than the target
+    fn manage_connections(self: Arc<Self>) {
+        while !self.stopped() {
+            let missing = self
+                .target_outbound
+                .saturating_sub(self.outbound.lock().unwrap().len());
+            if missing > 0 {
+                let mut exclude = self.peers.listen_addresses();
+                exclude.extend(self.outbound.lock().unwrap().iter().copied());
+                exclude.extend(self.local_addr);
+                let candidates =
+                    self.book
+                        .lock()
+                        .unwrap()
+                        .candidates(&exclude, Utc::now(), usize::MAX);
+                let allowed = candidates
+                    .into_iter()
+                    .filter(|address| !self.is_banned(&address.ip()))
+                    .take(missing);
+                for address in allowed {
+                    self.connect(address);
+                }
+            }
+            thread::sleep(CONNECTION_CHECK_INTERVAL);
+        }
+    }
+
+    // Changes the address book and saves it
+    fn update_book(&self, change: impl FnOnce(&mut AddressBook)) {
+        let mut book = self.book.lock().unwrap();
+        change(&mut book);
+        if let Err(e) = book.save() {
+            self.logger
+                .log_error(format!("Couldn't save the peer address book: {e:?}"));
+        }
+    }
+
+    /// Stops accepting peers and drops the connected ones
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    fn accept_peers(self: Arc<Self>, listener: TcpListener) {
+        while !self.stopped() {
+            match listener.accept() {
+                Ok((stream, address)) if self.is_banned(&address.ip()) => {
+                    self.logger
+                        .log(format!("Refused peer {address}, it's banned"));
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                Ok((stream, address)) => {
+                    let network = Arc::clone(&self);
+                    thread::spawn(move || network.serve_peer(stream, address, Direction::Inbound));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(ACCEPT_INTERVAL),
+                Err(e) => {
+                    self.logger.log_error(format!("Error accepting peer: {e}"));
+                    thread::sleep(ACCEPT_INTERVAL);
+                }
+            }
+        }
+    }
+
+    fn local_version(&self) -> Version {
+        Version {
+            protocol_version: PROTOCOL_VERSION,
+            best_height: self.state.lock().unwrap().chain().get_last_index(),
+            nonce: rand::random(),
+            listen_port: self.listen_port,
+            user_agent: format!("cleyto_coin/{}", env!("CARGO_PKG_VERSION")),
+        }
+    }
+
+    fn serve_peer(&self, stream: TcpStream, address: SocketAddr, direction: Direction) {
+        let (reason, handshake_done) =
+            match self.serve_peer_until_disconnect(&stream, address, direction) {
+                Ok(disconnected) => disconnected,
+                Err(e) => (e.to_string(), false),
+            };
+        if direction == Direction::Outbound && !handshake_done {
+            self.update_book(|book| book.mark_failed(&address));
+        }
+        self.logger
+            .log(format!("Disconnected from peer {address}: {reason}"));
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    // Returns why the peer was dropped, and whether the handshake went through before
+    fn serve_peer_until_disconnect(
+        &self,
+        stream: &TcpStream,
+        address: SocketAddr,
+        direction: Direction,
+    ) -> std::io::Result<(String, bool)> {
+        stream.set_nodelay(true)?;
+        let secure = match self.secure_channel(stream, address, direction) {
+            Ok(secure) => secure,
+            Err(e) => return Ok((e.to_string(), false)),
+        };
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let (writer, mut decoder, key): (Writer, _, _) = match secure {
+            Some(secure) => {
+                let link = EncryptedLink {
+                    stream: stream.try_clone()?,
+                    cipher: secure.send,
+                };
+                (
+                    Arc::new(Mutex::new(link)),
+                    Some(secure.decoder),
+                    Some(secure.remote_key),
+                )
+            }
+            None => (Arc::new(Mutex::new(stream.try_clone()?)), None, None),
+        };
+        let mut connection = self.open_connection(address, direction, writer, key, Instant::now());
+        let mut reader = stream;
+        let mut read_buffer = [0u8; 64 * 1024];
+        // What came right after the handshake, in the same read
+        let mut received = Vec::new();
+
+        let reason = loop {
+            if let Some(decoder) = &mut decoder {
+                match decoder.decrypt(&received) {
+                    Ok(plaintext) if plaintext.is_empty() => {}
+                    Ok(plaintext) => {
+                        self.receive_bytes(&mut connection, &plaintext, Instant::now())
+                    }
+                    Err(e) => {
+                        if let Some(misbehaviour) = Misbehaviour::from_error(&e) {
+                            let _ = self.misbehaved(address, misbehaviour);
+                        }
+                        break e.to_string();
+                    }
+                }
+            } else if !received.is_empty() {
+                self.receive_bytes(&mut connection, &received, Instant::now());
+            }
+            received.clear();
+            if let Some(reason) = connection.dropped.take() {
+                break reason;
+            }
+            if self.stopped() {
+                break "the node is shutting down".to_string();
+            }
+
+            match reader.read(&mut read_buffer) {
+                Ok(0) => break "connection closed".to_string(),
+                Ok(n) => received.extend_from_slice(&read_buffer[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    self.tick_connection(&mut connection, Instant::now())
+                }
+                Err(e) => break e.to_string(),
+            }
+        };
+        Ok((reason, self.close_connection(connection)))
+    }
+
+    // Runs the handshake if the connection is to be encrypted. None for plaintext ones
+    fn secure_channel(
+        &self,
+        stream: &TcpStream,
+        address: SocketAddr,
+        direction: Direction,
+    ) -> Result<Option<SecureChannel>, P2PError> {
+        let Some(encryption) = &self.encryption else {
+            return Ok(None);
+        };
+        stream.set_read_timeout(Some(self.timings.handshake_timeout))?;
+        let pinned = encryption.pinned.get(&address).copied();
+        let mut handshake = match direction {
+            Direction::Outbound => {
+                if !(encryption.encrypt_outbound || encryption.required || pinned.is_some()) {
+                    return Ok(None);
+                }
+                Handshake::initiator(&encryption.identity)
+            }
+            // Whoever connects says how, with the first byte it sends
+            Direction::Inbound => {
+                let mut first = [0u8; 1];
+                let encrypted = stream.peek(&mut first)? == 1 && noise::starts_handshake(first[0]);
+                if !encrypted && encryption.required {
+                    return Err(P2PError::NotEncrypted);
+                }
+                if !encrypted {
+                    return Ok(None);
+                }
+                Handshake::responder(&encryption.identity)
+            }
+        };
+
+        let mut decoder = NoiseDecoder::default();
+        let mut read_buffer = [0u8; 1024];
+        let mut stream = stream;
+        while !handshake.is_finished() {
+            if handshake.is_our_turn() {
+                stream.write_all(&noise::length_prefixed(&handshake.write_message()?))?;
+                continue;
+            }
+            let message = loop {
+                if let Some(message) = decoder.next_message() {
+                    break message;
+                }
+                match stream.read(&mut read_buffer)? {
+                    0 => return Err(P2PError::Handshake("connection closed".to_string())),
+                    n => decoder.push(&read_buffer[..n]),
+                }
+            };
+            handshake.read_message(&message)?;
+        }
+
+        let transport = handshake.into_transport()?;
+        if pinned.is_some_and(|key| key != transport.remote_key) {
+            return Err(P2PError::UnexpectedKey(transport.remote_key));
+        }
+        decoder.start_transport(transport.receive);
+        Ok(Some(SecureChannel {
+            send: transport.send,
+            decoder,
+            remote_key: transport.remote_key,
+        }))
+    }
+
+    /// Starts the handshake on a new connection, whose frames go to `writer`
+    pub(crate) fn open_connection(
+        &self,
+        address: SocketAddr,
+        direction: Direction,
+        writer: Writer,
+        key: Option<PublicKey>,
+        now: Instant,
+    ) -> Connection {
+        let id = self
+            .peers
+            .insert(address, direction, Arc::clone(&writer), key);
+        let (peer, events) = Peer::new(direction, self.local_version(), self.timings, now);
+        let mut connection = Connection {
+            id,
+            address,
+            peer,
+            decoder: FrameDecoder::new(),
+            writer,
+            dropped: None,
+        };
+        self.handle_events(&mut connection, events, now);
+        connection
+    }
+
+    /// Handles the messages in what arrived from the peer
+    pub(crate) fn receive_bytes(&self, connection: &mut Connection, bytes: &[u8], now: Instant) {
+        connection.decoder.push(bytes);
+        let mut events = Vec::new();
+        loop {
+            match connection.decoder.next_message() {
+                Ok(Some(message)) => events.extend(connection.peer.receive(message, now)),
+                Ok(None) => break,
+                Err(e) => {
+                    if let Some(misbehaviour) = Misbehaviour::from_error(&e) {
+                        let _ = self.misbehaved(connection.address, misbehaviour);
+                    }
+                    events.push(PeerEvent::Disconnect(e.to_string()));
+                    break;
+                }
+            }
+        }
+        self.handle_events(connection, events, now);
+    }
+
+    /// Called every so often, for the pings and the requests that time out
+    pub(crate) fn tick_connection(&self, connection: &mut Connection, now: Instant) {
+        let outgoing = self.with_sync(|sync, state| sync.schedule(now, state));
+        self.send_outgoing(outgoing);
+        let events = connection.peer.tick(now);
+        self.handle_events(connection, events, now);
+    }
+
+    /// Forgets the peer of a connection that's gone. Returns whether the handshake went through
+    pub(crate) fn close_connection(&self, connection: Connection) -> bool {
+        let id = connection.id;
+        self.peers.remove(id);
+        self.sync.lock().unwrap().peer_disconnected(id);
+        self.partial_blocks
+            .lock()
+            .unwrap()
+            .retain(|_, (peer, _)| *peer != id);
+        connection.peer.is_ready()
+    }
+
+    // Leaves in `dropped` why to disconnect, if there's a reason to
+    fn handle_events(&self, connection: &mut Connection, events: Vec<PeerEvent>, now: Instant) {
+        if connection.dropped.is_none() {
+            connection.dropped = self.handle_events_until_dropped(connection, events, now);
+        }
+    }
+
+    fn handle_events_until_dropped(
+        &self,
+        connection: &Connection,
+        events: Vec<PeerEvent>,
+        now: Instant,
+    ) -> Option<String> {
+        let (id, address) = (connection.id, connection.address);
+        let (peer, writer) = (&connection.peer, &connection.writer);
+        for event in events {
+            match event {
+                PeerEvent::Send(message) => {
+                    if let Err(e) = writer.lock().unwrap().send(&message.encode()) {
+                        return Some(e.to_string());
+                    }
+                }
+                PeerEvent::Ready(version) => {
+                    self.logger.log(format!(
+                        "Connected to peer {address} ({:?}), {} at height {}",
+                        peer.direction(),
+                        version.user_agent,
+                        version.best_height
+                    ));
+                    match peer.direction() {
+                        Direction::Outbound => {
+                            self.update_book(|book| book.mark_connected(&address, Utc::now()));
+                            if let Err(e) = writer.lock().unwrap().send(&Message::GetAddr.encode())
+                            {
+                                return Some(e.to_string());
+                            }
+                        }
+                        // It can be dialed where it listens, later or by the peers we tell about it
+                        Direction::Inbound => {
+                            if let Some(port) = version.listen_port {
+                                let listening = SocketAddr::new(address.ip(), port);
+                                self.update_book(|book| {
+                                    book.add(listening, AddressSource::Peer);
+                                });
+                            }
+                        }
+                    }
+                    if version.protocol_version >= COMPACT_BLOCKS_VERSION {
+                        if let Err(e) = writer.lock().unwrap().send(&Message::SendCmpct.encode()) {
+                            return Some(e.to_string());
+                        }
+                    }
+                    // Before the sync asks it for anything
+                    self.peers.update(id, peer);
+                    let outgoing = self.with_sync(|sync, state| {
+                        sync.peer_connected(id, version.best_height, now, state)
+                    });
+                    self.send_outgoing(outgoing);
+                }
+                PeerEvent::Received(message) => {
+                    let replies = match self.handle_message(id, address, message, now) {
+                        Ok(replies) => replies,
+                        Err(reason) => return Some(reason),
+                    };
+                    for reply in replies {
+                        if let Err(e) = writer.lock().unwrap().send(&reply.encode()) {
+                            return Some(e.to_string());
+                        }
+                    }
+                }
+                PeerEvent::Disconnect(reason) => return Some(reason),
+            }
+        }
+        self.peers.update(id, peer);
+        None
+    }
+
+    // The sync is always locked before the state, never the other way around
+    fn with_sync<T>(&self, f: impl FnOnce(&mut BlockSync, &mut NodeState) -> T) -> T {
+        let mut sync = self.sync.lock().unwrap();
+        let mut state = self.state.lock().unwrap();
+        f(&mut sync, &mut state)
+    }
+
+    fn send_outgoing(&self, outgoing: Outgoing) {
+        for (id, message) in outgoing {
+            self.peers.send(id, &message);
+        }
+    }
+
+    // A block from the peer, asked for by the sync or not. Returns why to drop the peer if it
+    // should be
+    fn receive_block(
+        &self,
+        id: u64,
+        address: SocketAddr,
+        block: Block,
+        now: Instant,
+    ) -> Result<(), String> {
+        let hash = block.get_hash();
+        self.peers.mark_known(id, &[InvItem::Block(hash.clone())]);
+        // Otherwise a peer could make the sync wait on a body that never connects
+        if block.calculate_hash() != hash {
+            let misbehaviour =
+                Misbehaviour::InvalidBlock(format!("the body of {hash} doesn't match its hash"));
+            return self.misbehaved(address, misbehaviour);
+        }
+        // Nor make us validate blocks that took no work, on our tip or not
+        if !block.header().meets_difficulty(self.pow_difficulty) {
+            let misbehaviour =
+                Misbehaviour::InvalidBlock(format!("{hash} doesn't have the proof of work"));
+            return self.misbehaved(address, misbehaviour);
+        }
+        let mut invalid = None;
+        let mut invalid_body = None;
+        let outgoing = self.with_sync(|sync, state| {
+            // Blocks the sync didn't ask for are fine too, if they go on our tip
+            if let Some(block) = sync.block_received(id, block) {
+                let on_tip = block.get_previous_hash() == state.chain().get_last_hash();
+                if !state.has_block(&hash) {
+                    if let Err(e) = state.accept_block(block) {
+                        self.logger
+                            .log_error(format!("Rejected block {hash} from peer {address}: {e:?}"));
+                        // Ones that don't go on our tip may just be late
+                        if on_tip {
+                            invalid = Some(format!("{hash}: {e:?}"));
+                        }
+                    }
+                }
+                return Vec::new();
+            }
+            invalid_body = sync.connect_blocks(state, &self.logger);
+            sync.schedule(now, state)
+        });
+        self.send_outgoing(outgoing);
+        if let Some(reason) = invalid {
+            self.misbehaved(address, Misbehaviour::InvalidBlock(reason))?;
+        }
+        // The body that didn't validate may have come from another peer, before this one's
+        if let Some((sender, reason)) = invalid_body {
+            let misbehaviour = Misbehaviour::InvalidBlock(reason);
+            if sender == id {
+                self.misbehaved(address, misbehaviour)?;
+            } else if let Some(sender_address) = self.peers.address(sender) {
+                // Banning it drops its connection, this one goes on
+                let _ = self.misbehaved(sender_address, misbehaviour);
+            }
+        }
+        Ok(())
+    }
+
+    // Asks the peer for what the compact block is missing, or takes the block if nothing is
+    fn rebuild_block(
+        &self,
+        id: u64,
+        address: SocketAddr,
+        partial: PartialBlock,
+        now: Instant,
+    ) -> Result<Vec<Message>, String> {
+        let hash = partial.hash().to_string();
+        let missing = partial.missing();
+        if !missing.is_empty() {
+            let mut partials = self.partial_blocks.lock().unwrap();
+            if partials.len() >= MAX_PARTIAL_BLOCKS && !partials.contains_key(&hash) {
+                return Ok(vec![Message::GetData(vec![InvItem::Block(hash)])]);
+            }
+            partials.insert(hash.clone(), (id, partial));
+            return Ok(vec![Message::GetBlockTxn(BlockTxnRequest {
+                block_hash: hash,
+                indexes: missing,
+            })]);
+        }
+        match partial.into_block() {
+            Ok(block) => {
+                self.receive_block(id, address, block, now)?;
+                Ok(Vec::new())
+            }
+            // Most likely two transactions with the same short id, the whole block settles it
+            Err(reason) => {
+                self.logger.log_error(format!(
+                    "Couldn't rebuild block {hash} from peer {address}: {reason}"
+                ));
+                Ok(vec![Message::GetData(vec![InvItem::Block(hash)])])
+            }
+        }
+    }
+
+    // What the node makes of announcements, requests, transactions and blocks. Returns the
+    // replies for the peer, or why it should be dropped
+    fn handle_message(
+        &self,
+        id: u64,
+        address: SocketAddr,
+        message: Message,
+        now: Instant,
+    ) -> Result<Vec<Message>, String> {
+        match message {
+            Message::Inv(items) => {
+                self.peers.mark_known(id, &items);
+                let (transactions, blocks): (Vec<InvItem>, Vec<InvItem>) = {
+                    let state = self.state.lock().unwrap();
+                    items
+                        .into_iter()
+                        .filter(|item| !state.has_item(item))
+                        .partition(|item| matches!(item, InvItem::Tx(_)))
+                };
+                // New blocks come headers first, like everything the sync downloads
+                if !blocks.is_empty() {
+                    let outgoing = self.with_sync(|sync, state| sync.block_announced(id, state));
+                    self.send_outgoing(outgoing);
+                }
+                match transactions.is_empty() {
+                    true => Ok(Vec::new()),
+                    false => Ok(vec![Message::GetData(transactions)]),
+                }
+            }
+            Message::GetData(items) => {
+                let state = self.state.lock().unwrap();
+                let mut replies = Vec::new();
+                let mut not_found = Vec::new();
+                for item in items {
+                    match &item {
+                        InvItem::Tx(txid) => match state.pooled_transaction(txid) {
+                            Some(transaction) => {
+                                replies.push(Message::Tx(Box::new(transaction.clone())))
+                            }
+                            None => not_found.push(item),
+                        },
+                        InvItem::Block(hash) => match state.read_block(hash) {
+                            Ok(block) => replies.push(Message::Block(Box::new(block))),
+                            Err(_) => not_found.push(item),
+                        },
+                    }
+                }
+                if !not_found.is_empty() {
+                    replies.push(Message::NotFound(not_found));
+                }
+                Ok(replies)
+            }
+            Message::GetHeaders(locator) => {
+                let headers = self
+                    .state
+                    .lock()
+                    .unwrap()
+                    .headers_after(&locator, MAX_HEADERS);
+                match headers {
+                    Ok(headers) => Ok(vec![Message::Headers(headers)]),
+                    Err(e) => {
+                        self.logger
+                            .log_error(format!("Couldn't read headers for peer {address}: {e:?}"));
+                        Ok(Vec::new())
+                    }
+                }
+            }
+            Message::Headers(headers) => {
+                let outgoing = self
+                    .with_sync(|sync, state| sync.headers_received(id, headers, now, state))
+                    .map_err(|reason| {
+                        let misbehaviour = Misbehaviour::InvalidHeaders(reason.clone());
+                        self.misbehaved(address, misbehaviour)
+                            .err()
+                            .unwrap_or(reason)
+                    })?;
+                self.send_outgoing(outgoing);
+                Ok(Vec::new())
+            }
+            Message::Tx(transaction) => {
+                let (item, transaction_id) = (InvItem::transaction(&transaction), transaction.txid);
+                self.peers.mark_known(id, std::slice::from_ref(&item));
+                // Accepted transactions are announced to the other peers by the node state
+                let rejected = {
+                    let mut state = self.state.lock().unwrap();
+                    match state.has_item(&item) {
+                        true => None,
+                        false => state.add_transaction(*transaction).err(),
+                    }
+                };
+                // Inputs we don't have yet, or that a block just spent, aren't the peer's fault
+                if rejected.is_some_and(|rejection| rejection.is_senders_fault()) {
+                    let txid = hex::encode(transaction_id);
+                    self.misbehaved(address, Misbehaviour::InvalidTransaction(txid))?;
+                }
+                Ok(Vec::new())
+            }
+            Message::Block(block) => {
+                self.receive_block(id, address, *block, now)?;
+                Ok(Vec::new())
+            }
+            Message::SendCmpct => {
+                self.peers.set_compact(id);
+                Ok(Vec::new())
+            }
+            Message::CmpctBlock(compact) => {
+                let hash = compact.header.hash.clone();
+                self.peers.mark_known(id, &[InvItem::Block(hash.clone())]);
+                let header = &compact.header;
+                if header.calculate_hash() != hash || !header.meets_difficulty(self.pow_difficulty)
+                {
+                    let misbehaviour = Misbehaviour::InvalidHeaders(format!(
+                        "compact block {hash} has the wrong hash or no proof of work"
+                    ));
+                    return self.misbehaved(address, misbehaviour).map(|_| Vec::new());
+                }
+                let (known, on_tip) = {
+                    let state = self.state.lock().unwrap();
+                    let on_tip = header.previous_hash == state.chain().get_last_hash();
+                    (state.has_block(&hash), on_tip)
+                };
+                if known {
+                    return Ok(Vec::new());
+                }
+                // One that doesn't go on our tip is taken like an inv, and the sync gets it
+                if !on_tip {
+                    let outgoing = self.with_sync(|sync, state| sync.block_announced(id, state));
+                    self.send_outgoing(outgoing);
+                    return Ok(Vec::new());
+                }
+                let partial = {
+                    let state = self.state.lock().unwrap();
+                    PartialBlock::new(*compact, state.transactions_pool())
+                };
+                match partial {
+                    Ok(partial) => self.rebuild_block(id, address, partial, now),
+                    Err(reason) => self
+                        .misbehaved(address, Misbehaviour::MalformedMessage(reason))
+                        .map(|_| Vec::new()),
+                }
+            }
+            Message::GetBlockTxn(request) => {
+                let hash = request.block_hash;
+                let block = match self.state.lock().unwrap().read_block(&hash) {
+                    Ok(block) => block,
+                    Err(_) => return Ok(vec![Message::NotFound(vec![InvItem::Block(hash)])]),
+                };
+                let transactions: Option<Vec<Transaction>> = request
+                    .indexes
+                    .iter()
+                    .map(|index| block.get_transactions().get(*index as usize).cloned())
+                    .collect();
+                match transactions {
+                    Some(transactions) => {
+                        Ok(vec![Message::BlockTxn(Box::new(BlockTransactions {
+                            block_hash: hash,
+                            transactions,
+                        }))])
+                    }
+                    None => {
+                        let misbehaviour = Misbehaviour::MalformedMessage(format!(
+                            "asked for transactions {hash} doesn't have"
+                        ));
+                        self.misbehaved(address, misbehaviour).map(|_| Vec::new())
+                    }
+                }
+            }
+            Message::BlockTxn(response) => {
+                let hash = response.block_hash;
+                let partial = {
+                    let mut partials = self.partial_blocks.lock().unwrap();
+                    match partials.get(&hash) {
+                        Some((peer, _)) if *peer == id => partials.remove(&hash),
+                        _ => None,
+                    }
+                };
+                // Not asked for, or asked to someone else
+                let Some((_, mut partial)) = partial else {
+                    return Ok(Vec::new());
+                };
+                if let Err(reason) = partial.fill(response.transactions) {
+                    self.misbehaved(address, Misbehaviour::MalformedMessage(reason))?;
+                    return Ok(vec![Message::GetData(vec![InvItem::Block(hash)])]);
+                }
+                self.rebuild_block(id, address, partial, now)
+            }
+            Message::GetAddr => {
+                let addresses = self.book.lock().unwrap().sample(MAX_ADDRS);
+                Ok(vec![Message::Addr(addresses)])
+            }
+            Message::Addr(addresses) => {
+                self.update_book(|book| {
+                    for address in addresses {
+                        if Some(address) != self.local_addr {
+                            book.add(address, AddressSource::Peer);
+                        }
+                    }
+                });
+                Ok(Vec::new())
+            }
+            Message::NotFound(items) => {
+                let outgoing = self.with_sync(|sync, state| sync.not_found(id, &items, now, state));
+                self.send_outgoing(outgoing);
+                Ok(Vec::new())
+            }
+            // The connection messages never get here
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
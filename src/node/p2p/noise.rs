@@ -0,0 +1,451 @@
+//! Encrypted peer connections, with the XX handshake of the Noise protocol framework, as
+//! `Noise_XX_25519_ChaChaPoly_SHA256`. Both nodes send their identity key encrypted, so the
+//! connection is confidential and each knows the key of the other, which is what pinning checks:
+//!
+//! ```text
+//!  initiator                       responder
+//!     | ---------- e ----------------> |
+//!     | <--------- e, ee, s, es ------ |
+//!     | ---------- s, se ------------> |
+//! ```
+//!
+//! The network magic is the prologue, so nodes of different networks fail the handshake. Every
+//! handshake and transport message goes on the wire after its length, a big endian u16. After the
+//! handshake, the frames of `message` go in transport messages encrypted with ChaCha20-Poly1305,
+//! several of them for frames bigger than one can be. Like `peer`, there's no IO here.
+
+use openssl::derive::Deriver;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Private};
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use sha2::{Digest, Sha256};
+
+use super::errors::P2PError;
+use super::identity::{NodeIdentity, PublicKey, KEY_LEN};
+use super::message::NETWORK_MAGIC;
+
+const PROTOCOL_NAME: &[u8; 32] = b"Noise_XX_25519_ChaChaPoly_SHA256";
+const TAG_LEN: usize = 16;
+/// Longest message Noise allows, tag included
+pub const MAX_NOISE_MESSAGE_LEN: usize = 65535;
+const MAX_PLAINTEXT_LEN: usize = MAX_NOISE_MESSAGE_LEN - TAG_LEN;
+const LENGTH_LEN: usize = 2;
+
+/// Whether the first byte a peer sends starts a handshake instead of a plaintext frame. Frames
+/// start with the network magic, handshakes with the length of their first message
+pub fn starts_handshake(first_byte: u8) -> bool {
+    first_byte != NETWORK_MAGIC[0]
+}
+
+/// The message after its length, ready to be written to the peer
+pub fn length_prefixed(message: &[u8]) -> Vec<u8> {
+    let mut prefixed = Vec::with_capacity(LENGTH_LEN + message.len());
+    prefixed.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    prefixed.extend_from_slice(message);
+    prefixed
+}
+
+// ---------------------------------------------- CipherState definition -------------------------------------------
+/// A key and the number of messages it encrypted or decrypted so far, which is their nonce
+pub struct CipherState {
+    key: [u8; 32],
+    nonce: u64,
+}
+
+impl CipherState {
+    fn new(key: [u8; 32]) -> Self {
+        Self { key, nonce: 0 }
+    }
+
+    // 32 bits of zeros and then the counter, little endian
+    fn nonce_bytes(&self) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_le_bytes());
+        nonce
+    }
+
+    pub fn encrypt(&mut self, ad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut tag = [0u8; TAG_LEN];
+        let mut ciphertext = encrypt_aead(
+            Cipher::chacha20_poly1305(),
+            &self.key,
+            Some(&self.nonce_bytes()),
+            ad,
+            plaintext,
+            &mut tag,
+        )
+        .expect("ChaCha20-Poly1305 couldn't encrypt");
+        ciphertext.extend_from_slice(&tag);
+        self.nonce += 1;
+        ciphertext
+    }
+
+    pub fn decrypt(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, P2PError> {
+        if ciphertext.len() < TAG_LEN {
+            return Err(P2PError::DecryptionFailed);
+        }
+        let (data, tag) = ciphertext.split_at(ciphertext.len() - TAG_LEN);
+        let plaintext = decrypt_aead(
+            Cipher::chacha20_poly1305(),
+            &self.key,
+            Some(&self.nonce_bytes()),
+            ad,
+            data,
+            tag,
+        )
+        .map_err(|_| P2PError::DecryptionFailed)?;
+        self.nonce += 1;
+        Ok(plaintext)
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+fn hmac(key: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let key = PKey::hmac(key).expect("Couldn't make an HMAC key");
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("Couldn't start an HMAC");
+    for part in data {
+        signer.update(part).expect("Couldn't HMAC");
+    }
+    let mac = signer.sign_to_vec().expect("Couldn't HMAC");
+    mac.try_into().expect("HMAC-SHA256 is 32 bytes")
+}
+
+// HKDF with the chaining key as salt, giving two outputs
+fn hkdf(chaining_key: &[u8; 32], input_key_material: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let temp_key = hmac(chaining_key, &[input_key_material]);
+    let first = hmac(&temp_key, &[&[1]]);
+    let second = hmac(&temp_key, &[&first, &[2]]);
+    (first, second)
+}
+
+fn dh(private: &PKey<Private>, public: &PublicKey) -> Result<[u8; 32], P2PError> {
+    let failed = |e: openssl::error::ErrorStack| P2PError::Handshake(e.to_string());
+    let public = PKey::public_key_from_raw_bytes(public, Id::X25519).map_err(failed)?;
+    let mut deriver = Deriver::new(private).map_err(failed)?;
+    deriver.set_peer(&public).map_err(failed)?;
+    // OpenSSL refuses keys that make an all zero secret
+    let secret = deriver.derive_to_vec().map_err(failed)?;
+    secret
+        .try_into()
+        .map_err(|_| P2PError::Handshake("wrong shared secret length".to_string()))
+}
+
+fn public_key(key: &PKey<Private>) -> PublicKey {
+    key.raw_public_key()
+        .expect("An X25519 key has a public key")
+        .try_into()
+        .expect("X25519 public keys are 32 bytes")
+}
+
+// ---------------------------------------------- SymmetricState definition ----------------------------------------
+struct SymmetricState {
+    chaining_key: [u8; 32],
+    hash: [u8; 32],
+    cipher: Option<CipherState>,
+}
+
+impl SymmetricState {
+    fn new() -> Self {
+        // The name is exactly as long as a hash, so it's used as it is
+        Self {
+            chaining_key: *PROTOCOL_NAME,
+            hash: *PROTOCOL_NAME,
+            cipher: None,
+        }
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.hash = Sha256::new()
+            .chain_update(self.hash)
+            .chain_update(data)
+            .finalize()
+            .into();
+    }
+
+    fn mix_key(&mut self, input_key_material: &[u8]) {
+        let (chaining_key, key) = hkdf(&self.chaining_key, input_key_material);
+        self.chaining_key = chaining_key;
+        self.cipher = Some(CipherState::new(key));
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let hash = self.hash;
+        let ciphertext = match &mut self.cipher {
+            Some(cipher) => cipher.encrypt(&hash, plaintext),
+            None => plaintext.to_vec(),
+        };
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, P2PError> {
+        let hash = self.hash;
+        let plaintext = match &mut self.cipher {
+            Some(cipher) => cipher
+                .decrypt(&hash, ciphertext)
+                .map_err(|_| P2PError::Handshake("couldn't decrypt".to_string()))?,
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    // The keys of both directions, the initiator's first
+    fn split(&self) -> (CipherState, CipherState) {
+        let (initiator, responder) = hkdf(&self.chaining_key, &[]);
+        (CipherState::new(initiator), CipherState::new(responder))
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Initiator,
+    Responder,
+}
+
+/// What's left of a finished handshake: a key for each direction, and who's on the other side
+pub struct Transport {
+    pub send: CipherState,
+    pub receive: CipherState,
+    pub remote_key: PublicKey,
+}
+
+// ---------------------------------------------- Handshake definition ---------------------------------------------
+pub struct Handshake {
+    role: Role,
+    symmetric: SymmetricState,
+    identity: PKey<Private>,
+    ephemeral: PKey<Private>,
+    remote_identity: Option<PublicKey>,
+    remote_ephemeral: Option<PublicKey>,
+    // Messages written or read so far
+    step: usize,
+}
+
+impl Handshake {
+    /// The side that connected, which writes first
+    pub fn initiator(identity: &NodeIdentity) -> Self {
+        Self::new(Role::Initiator, identity)
+    }
+
+    pub fn responder(identity: &NodeIdentity) -> Self {
+        Self::new(Role::Responder, identity)
+    }
+
+    fn new(role: Role, identity: &NodeIdentity) -> Self {
+        let mut symmetric = SymmetricState::new();
+        symmetric.mix_hash(&NETWORK_MAGIC);
+        Self {
+            role,
+            symmetric,
+            identity: identity.private_key().clone(),
+            ephemeral: PKey::generate_x25519().expect("Couldn't generate an X25519 key"),
+            remote_identity: None,
+            remote_ephemeral: None,
+            step: 0,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.step == 3
+    }
+
+    /// Whether the next message is ours to write, or theirs to read
+    pub fn is_our_turn(&self) -> bool {
+        self.step.is_multiple_of(2) == (self.role == Role::Initiator)
+    }
+
+    /// The identity key of the peer, once it sent it
+    pub fn remote_key(&self) -> Option<PublicKey> {
+        self.remote_identity
+    }
+
+    pub fn write_message(&mut self) -> Result<Vec<u8>, P2PError> {
+        if self.is_finished() || !self.is_our_turn() {
+            return Err(P2PError::Handshake("not our turn to write".to_string()));
+        }
+        let mut message = Vec::new();
+        match self.step {
+            // -> e
+            0 => {
+                let ephemeral = public_key(&self.ephemeral);
+                self.symmetric.mix_hash(&ephemeral);
+                message.extend_from_slice(&ephemeral);
+            }
+            // <- e, ee, s, es
+            1 => {
+                let ephemeral = public_key(&self.ephemeral);
+                self.symmetric.mix_hash(&ephemeral);
+                message.extend_from_slice(&ephemeral);
+                let remote_ephemeral = self.remote_ephemeral()?;
+                self.symmetric
+                    .mix_key(&dh(&self.ephemeral, &remote_ephemeral)?);
+                let identity = public_key(&self.identity);
+                message.extend(self.symmetric.encrypt_and_hash(&identity));
+                self.symmetric
+                    .mix_key(&dh(&self.identity, &remote_ephemeral)?);
+            }
+            // -> s, se
+            _ => {
+                let identity = public_key(&self.identity);
+                message.extend(self.symmetric.encrypt_and_hash(&identity));
+                let remote_ephemeral = self.remote_ephemeral()?;
+                self.symmetric
+                    .mix_key(&dh(&self.identity, &remote_ephemeral)?);
+            }
+        }
+        // No payload, the version message comes after the handshake
+        message.extend(self.symmetric.encrypt_and_hash(&[]));
+        self.step += 1;
+        Ok(message)
+    }
+
+    pub fn read_message(&mut self, message: &[u8]) -> Result<(), P2PError> {
+        if self.is_finished() || self.is_our_turn() {
+            return Err(P2PError::Handshake("not their turn to write".to_string()));
+        }
+        let payload = match self.step {
+            // -> e
+            0 => {
+                let (ephemeral, payload) = split_key(message)?;
+                self.symmetric.mix_hash(&ephemeral);
+                self.remote_ephemeral = Some(ephemeral);
+                payload
+            }
+            // <- e, ee, s, es
+            1 => {
+                let (ephemeral, rest) = split_key(message)?;
+                self.symmetric.mix_hash(&ephemeral);
+                self.remote_ephemeral = Some(ephemeral);
+                self.symmetric.mix_key(&dh(&self.ephemeral, &ephemeral)?);
+                let (identity, payload) = self.read_identity(rest)?;
+                self.symmetric.mix_key(&dh(&self.ephemeral, &identity)?);
+                payload
+            }
+            // -> s, se
+            _ => {
+                let (identity, payload) = self.read_identity(message)?;
+                self.symmetric.mix_key(&dh(&self.ephemeral, &identity)?);
+                payload
+            }
+        };
+        self.symmetric.decrypt_and_hash(payload)?;
+        self.step += 1;
+        Ok(())
+    }
+
+    // The encrypted identity key at the start of `message`, and what's after it
+    fn read_identity<'a>(&mut self, message: &'a [u8]) -> Result<(PublicKey, &'a [u8]), P2PError> {
+        if message.len() < KEY_LEN + TAG_LEN {
+            return Err(P2PError::Handshake("message too short".to_string()));
+        }
+        let (encrypted, rest) = message.split_at(KEY_LEN + TAG_LEN);
+        let identity: PublicKey = self
+            .symmetric
+            .decrypt_and_hash(encrypted)?
+            .try_into()
+            .map_err(|_| P2PError::Handshake("wrong key length".to_string()))?;
+        self.remote_identity = Some(identity);
+        Ok((identity, rest))
+    }
+
+    fn remote_ephemeral(&self) -> Result<PublicKey, P2PError> {
+        self.remote_ephemeral
+            .ok_or_else(|| P2PError::Handshake("no ephemeral key from the peer".to_string()))
+    }
+
+    /// The keys to encrypt the rest of the connection with. Errors if the handshake isn't finished
+    pub fn into_transport(self) -> Result<Transport, P2PError> {
+        let Some(remote_key) = self.remote_identity.filter(|_| self.is_finished()) else {
+            return Err(P2PError::Handshake(
+                "the handshake isn't finished".to_string(),
+            ));
+        };
+        let (initiator, responder) = self.symmetric.split();
+        let (send, receive) = match self.role {
+            Role::Initiator => (initiator, responder),
+            Role::Responder => (responder, initiator),
+        };
+        Ok(Transport {
+            send,
+            receive,
+            remote_key,
+        })
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+fn split_key(message: &[u8]) -> Result<(PublicKey, &[u8]), P2PError> {
+    if message.len() < KEY_LEN {
+        return Err(P2PError::Handshake("message too short".to_string()));
+    }
+    let (key, rest) = message.split_at(KEY_LEN);
+    Ok((key.try_into().unwrap(), rest))
+}
+
+/// Encrypts `plaintext` into as many transport messages as it takes, each after its length
+pub fn seal(cipher: &mut CipherState, plaintext: &[u8]) -> Vec<u8> {
+    let mut sealed = Vec::new();
+    for chunk in plaintext.chunks(MAX_PLAINTEXT_LEN) {
+        sealed.extend(length_prefixed(&cipher.encrypt(&[], chunk)));
+    }
+    sealed
+}
+
+// ---------------------------------------------- NoiseDecoder definition ------------------------------------------
+/// Puts the length prefixed messages of a connection back together as their bytes arrive, and
+/// decrypts them once the handshake is done
+#[derive(Default)]
+pub struct NoiseDecoder {
+    buffer: Vec<u8>,
+    cipher: Option<CipherState>,
+}
+
+impl NoiseDecoder {
+    /// A decoder for the transport messages of a finished handshake
+    pub fn new(cipher: CipherState) -> Self {
+        Self {
+            buffer: Vec::new(),
+            cipher: Some(cipher),
+        }
+    }
+
+    /// Decrypts what comes after the handshake, including what's buffered already
+    pub fn start_transport(&mut self, cipher: CipherState) {
+        self.cipher = Some(cipher);
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next whole message, as it came
+    pub fn next_message(&mut self) -> Option<Vec<u8>> {
+        if self.buffer.len() < LENGTH_LEN {
+            return None;
+        }
+        let length = u16::from_be_bytes([self.buffer[0], self.buffer[1]]) as usize;
+        if self.buffer.len() < LENGTH_LEN + length {
+            return None;
+        }
+        let message = self.buffer[LENGTH_LEN..LENGTH_LEN + length].to_vec();
+        self.buffer.drain(..LENGTH_LEN + length);
+        Some(message)
+    }
+
+    /// Pushes the bytes and decrypts every whole transport message there is
+    pub fn decrypt(&mut self, bytes: &[u8]) -> Result<Vec<u8>, P2PError> {
+        self.push(bytes);
+        let mut plaintext = Vec::new();
+        while let Some(message) = self.next_message() {
+            let cipher = self.cipher.as_mut().ok_or_else(|| {
+                P2PError::Handshake("transport message before the handshake".to_string())
+            })?;
+            plaintext.extend(cipher.decrypt(&[], &message)?);
+        }
+        Ok(plaintext)
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
@@ -0,0 +1,199 @@
+//! What to do with one peer, without any of the IO: messages and clock ticks go in, messages to
+//! send and what happened to the connection come out. The network feeds it from the socket, and the
+//! tests can feed it from another `Peer`.
+//!
+//! The handshake goes like this, and nothing else is allowed before it's done:
+//!
+//! ```text
+//! outbound                 inbound
+//!    | ------ version ------> |
+//!    | <----- version ------- |
+//!    | <----- verack -------- |
+//!    | ------ verack -------> |
+//! ```
+
+use std::time::{Duration, Instant};
+
+use super::message::{Message, Version};
+
+pub const PROTOCOL_VERSION: u32 = 2;
+// Oldest version we can still talk to
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// First version that knows compact blocks. Older peers get new blocks announced with inv
+pub const COMPACT_BLOCKS_VERSION: u32 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// They connected to us
+    Inbound,
+    /// We connected to them
+    Outbound,
+}
+
+/// How long things may take before the peer is dropped
+#[derive(Clone, Copy, Debug)]
+pub struct PeerTimings {
+    pub handshake_timeout: Duration,
+    /// How long after the last pong to ping again
+    pub ping_interval: Duration,
+    pub ping_timeout: Duration,
+}
+
+impl Default for PeerTimings {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: Duration::from_secs(10),
+            ping_interval: Duration::from_secs(30),
+            ping_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PeerEvent {
+    Send(Message),
+    /// The handshake is done, with the peer's version
+    Ready(Version),
+    /// A message for the node rather than the connection, like announcements and blocks
+    Received(Message),
+    /// Drop the connection, for the reason given
+    Disconnect(String),
+}
+
+// --------------------------------------------------- Peer definition ---------------------------------------------
+pub struct Peer {
+    direction: Direction,
+    local: Version,
+    remote: Option<Version>,
+    verack_received: bool,
+    timings: PeerTimings,
+    connected_at: Instant,
+    next_ping_at: Instant,
+    // The nonce of the ping waiting for a pong, and when it was sent
+    pending_ping: Option<(u64, Instant)>,
+    latency: Option<Duration>,
+}
+
+impl Peer {
+    /// A fresh connection. Outbound peers start the handshake, so they get their version to send
+    pub fn new(
+        direction: Direction,
+        local: Version,
+        timings: PeerTimings,
+        now: Instant,
+    ) -> (Self, Vec<PeerEvent>) {
+        let events = match direction {
+            Direction::Outbound => vec![PeerEvent::Send(Message::Version(local.clone()))],
+            Direction::Inbound => Vec::new(),
+        };
+        let peer = Self {
+            direction,
+            local,
+            remote: None,
+            verack_received: false,
+            timings,
+            connected_at: now,
+            next_ping_at: now,
+            pending_ping: None,
+            latency: None,
+        };
+        (peer, events)
+    }
+
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    /// The version the peer sent, once it sent it
+    pub fn remote(&self) -> Option<&Version> {
+        self.remote.as_ref()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.remote.is_some() && self.verack_received
+    }
+
+    /// How long the last ping took to be answered
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    pub fn receive(&mut self, message: Message, now: Instant) -> Vec<PeerEvent> {
+        match message {
+            Message::Version(version) => self.receive_version(version, now),
+            Message::Verack => {
+                if self.remote.is_none() || self.verack_received {
+                    return disconnect("verack out of order");
+                }
+                self.verack_received = true;
+                vec![PeerEvent::Ready(self.remote.clone().unwrap())]
+            }
+            _ if !self.is_ready() => {
+                disconnect(&format!("{} before the handshake", message.command()))
+            }
+            Message::Ping(nonce) => vec![PeerEvent::Send(Message::Pong(nonce))],
+            Message::Pong(nonce) => {
+                if let Some((pending, sent_at)) = self.pending_ping {
+                    if pending == nonce {
+                        self.latency = Some(now.duration_since(sent_at));
+                        self.pending_ping = None;
+                        self.next_ping_at = now + self.timings.ping_interval;
+                    }
+                }
+                Vec::new()
+            }
+            message => vec![PeerEvent::Received(message)],
+        }
+    }
+
+    fn receive_version(&mut self, version: Version, now: Instant) -> Vec<PeerEvent> {
+        if self.remote.is_some() {
+            return disconnect("sent its version twice");
+        }
+        if version.protocol_version < MIN_PROTOCOL_VERSION {
+            return disconnect(&format!(
+                "protocol version {} is too old",
+                version.protocol_version
+            ));
+        }
+        if version.nonce == self.local.nonce {
+            return disconnect("connected to ourselves");
+        }
+
+        self.remote = Some(version);
+        self.next_ping_at = now + self.timings.ping_interval;
+        let mut events = Vec::new();
+        if self.direction == Direction::Inbound {
+            events.push(PeerEvent::Send(Message::Version(self.local.clone())));
+        }
+        events.push(PeerEvent::Send(Message::Verack));
+        events
+    }
+
+    /// Called every so often, to ping the peer and drop it if it stopped answering
+    pub fn tick(&mut self, now: Instant) -> Vec<PeerEvent> {
+        if !self.is_ready() {
+            if now.duration_since(self.connected_at) > self.timings.handshake_timeout {
+                return disconnect("handshake timed out");
+            }
+            return Vec::new();
+        }
+
+        match self.pending_ping {
+            Some((_, sent_at)) if now.duration_since(sent_at) > self.timings.ping_timeout => {
+                disconnect("ping timed out")
+            }
+            None if now >= self.next_ping_at => {
+                let nonce = rand::random();
+                self.pending_ping = Some((nonce, now));
+                vec![PeerEvent::Send(Message::Ping(nonce))]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+fn disconnect(reason: &str) -> Vec<PeerEvent> {
+    vec![PeerEvent::Disconnect(reason.to_string())]
+}
@@ -0,0 +1,525 @@
+//! Several nodes in one process, talking through links in memory instead of sockets, for tests of
+//! what a network of nodes does: forks, partitions, double spends. Each node is the `NodeState` and
+//! `Network` a real node runs, only fed by the simulation instead of TCP threads.
+//!
+//! That's all of a node it has, though, not a `Node`: there's no HTTP server, data directory,
+//! block store or saved logs, and the TCP listening, dialing and peer threads are never run. So it
+//! tests what the nodes decide, not how they're started, stored or reached from outside.
+//!
+//! Time is simulated too. Nothing happens until `run_for` or `run_until`, which move the clock
+//! from one event to the next: a message arriving, or the tick every connection gets for its pings
+//! and timeouts. So a minute of network goes by in however long the nodes take to handle it, and
+//! runs the same every time. Messages take `latency` to arrive, can be dropped at random with a
+//! seeded RNG, and don't cross partitions.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::network::{Connection, Link, Network, NetworkConfig, Writer};
+use super::peer::{Direction, PeerTimings};
+use super::DEFAULT_P2P_PORT;
+use crate::chain::block::Block;
+use crate::chain::transaction::Transaction;
+use crate::chain::utils::BLOCK_REWARD;
+use crate::chain::utxo::UTXO;
+use crate::chain::wallet::{Wallet, WalletPK};
+use crate::chain::Chain;
+use crate::error_handling::CleytoResult;
+use crate::node::logger::Logger;
+use crate::node::NodeState;
+
+// Like the read timeout of the TCP threads
+const TICK_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    /// Of the RNG dropping messages. The same seed drops the same messages
+    pub seed: u64,
+    /// How long messages take between nodes, unless `set_latency` says otherwise
+    pub latency: Duration,
+    /// Leading zeros the blocks of `mine` get, and the nodes ask of headers
+    pub pow_difficulty: u8,
+    pub timings: PeerTimings,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            latency: Duration::from_millis(50),
+            pow_difficulty: 0,
+            timings: PeerTimings::default(),
+        }
+    }
+}
+
+// What nodes write to their links, picked up after every step
+enum Sent {
+    Frame {
+        wire: u64,
+        from: usize,
+        frame: Vec<u8>,
+    },
+    Close {
+        wire: u64,
+        from: usize,
+    },
+}
+
+type Outbox = Arc<Mutex<Vec<Sent>>>;
+
+// One end of a wire
+struct SimLink {
+    wire: u64,
+    from: usize,
+    outbox: Outbox,
+}
+
+impl Link for SimLink {
+    fn send(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        self.outbox.lock().unwrap().push(Sent::Frame {
+            wire: self.wire,
+            from: self.from,
+            frame: frame.to_vec(),
+        });
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.outbox.lock().unwrap().push(Sent::Close {
+            wire: self.wire,
+            from: self.from,
+        });
+    }
+}
+
+enum Event {
+    Deliver {
+        wire: u64,
+        to: usize,
+        frame: Vec<u8>,
+    },
+    Close {
+        wire: u64,
+        to: usize,
+    },
+    Tick,
+}
+
+// Events come out of the heap earliest first, and in the order they were scheduled at the same time
+struct Scheduled {
+    at: Instant,
+    seq: u64,
+    event: Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        (self.at, self.seq) == (other.at, other.seq)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.at, other.seq).cmp(&(self.at, self.seq))
+    }
+}
+
+// ---------------------------------------------- SimNode definition -----------------------------------------------
+pub struct SimNode {
+    state: Arc<Mutex<NodeState>>,
+    network: Network,
+    address: SocketAddr,
+    // By wire, in order so they're ticked the same way every run
+    connections: BTreeMap<u64, Connection>,
+    // Where the coinbases of the blocks it mines pay to
+    wallet: (Wallet, WalletPK),
+}
+
+impl SimNode {
+    pub fn state(&self) -> &Arc<Mutex<NodeState>> {
+        &self.state
+    }
+
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+
+    /// Where the other nodes see it listening
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The wallet the coinbases of its blocks pay to, and its key to spend them
+    pub fn wallet(&self) -> (&Wallet, &WalletPK) {
+        (&self.wallet.0, &self.wallet.1)
+    }
+
+    /// Index of the last block of its chain
+    pub fn height(&self) -> u64 {
+        self.state.lock().unwrap().chain().get_last_index()
+    }
+
+    pub fn tip(&self) -> String {
+        self.state.lock().unwrap().chain().get_last_hash()
+    }
+
+    pub fn has_transaction(&self, transaction: &Transaction) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .transactions_pool()
+            .iter()
+            .any(|pooled| pooled.txid == transaction.txid)
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+// ---------------------------------------------- Simulation definition --------------------------------------------
+pub struct Simulation {
+    config: SimConfig,
+    started: Instant,
+    now: Instant,
+    nodes: Vec<SimNode>,
+    // The node that opened each wire and the one it connected to
+    wires: HashMap<u64, (usize, usize)>,
+    next_wire: u64,
+    outbox: Outbox,
+    events: BinaryHeap<Scheduled>,
+    next_seq: u64,
+    rng: StdRng,
+    // The side of the partition each node is on, None while there's no partition
+    sides: Option<Vec<usize>>,
+    latencies: HashMap<(usize, usize), Duration>,
+    drop_rate: f64,
+    logger: Arc<Logger>,
+}
+
+impl Simulation {
+    pub fn new(config: SimConfig) -> Self {
+        let now = Instant::now();
+        let mut simulation = Self {
+            rng: StdRng::seed_from_u64(config.seed),
+            config,
+            started: now,
+            now,
+            nodes: Vec::new(),
+            wires: HashMap::new(),
+            next_wire: 0,
+            outbox: Arc::new(Mutex::new(Vec::new())),
+            events: BinaryHeap::new(),
+            next_seq: 0,
+            sides: None,
+            latencies: HashMap::new(),
+            drop_rate: 0.0,
+            logger: Arc::new(Logger::new()),
+        };
+        simulation.schedule(now + TICK_INTERVAL, Event::Tick);
+        simulation
+    }
+
+    /// Adds a node with only the genesis block. Returns its index
+    pub fn add_node(&mut self) -> CleytoResult<usize> {
+        self.add_node_with_chain(Chain::new())
+    }
+
+    /// Adds a node starting from `chain`. Returns its index
+    pub fn add_node_with_chain(&mut self, chain: Chain) -> CleytoResult<usize> {
+        let index = self.nodes.len();
+        let address = SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(
+                10,
+                0,
+                (index / 250) as u8,
+                (index % 250 + 1) as u8,
+            )),
+            DEFAULT_P2P_PORT,
+        );
+        let state = Arc::new(Mutex::new(NodeState::in_memory(chain)?));
+        let config = NetworkConfig {
+            timings: self.config.timings,
+            pow_difficulty: self.config.pow_difficulty,
+            target_outbound: 0,
+            ..NetworkConfig::default()
+        };
+        let network = Network::new(
+            config,
+            Arc::clone(&state),
+            Arc::clone(&self.logger),
+            Some(address),
+        )?;
+        self.nodes.push(SimNode {
+            state,
+            network,
+            address,
+            connections: BTreeMap::new(),
+            wallet: Wallet::new(),
+        });
+        Ok(index)
+    }
+
+    pub fn node(&self, index: usize) -> &SimNode {
+        &self.nodes[index]
+    }
+
+    pub fn nodes(&self) -> &[SimNode] {
+        &self.nodes
+    }
+
+    /// The simulated time
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Simulated time since the simulation started
+    pub fn elapsed(&self) -> Duration {
+        self.now - self.started
+    }
+
+    /// Opens a connection from one node to the other, which starts the handshake
+    pub fn connect(&mut self, from: usize, to: usize) {
+        let wire = self.next_wire;
+        self.next_wire += 1;
+        self.wires.insert(wire, (from, to));
+
+        // The port of the connecting side is an ephemeral one, like with TCP
+        let ephemeral = SocketAddr::new(self.nodes[from].address.ip(), 40_000 + wire as u16);
+        let ends = [
+            (from, self.nodes[to].address, Direction::Outbound),
+            (to, ephemeral, Direction::Inbound),
+        ];
+        for (node, address, direction) in ends {
+            let writer: Writer = Arc::new(Mutex::new(SimLink {
+                wire,
+                from: node,
+                outbox: Arc::clone(&self.outbox),
+            }));
+            let connection = self.nodes[node]
+                .network
+                .open_connection(address, direction, writer, None, self.now);
+            self.nodes[node].connections.insert(wire, connection);
+        }
+        self.flush();
+    }
+
+    /// Splits the nodes into sides that can't reach each other. Nodes not in any side are on one
+    /// more side, together. Messages already on their way still arrive
+    pub fn partition(&mut self, sides: &[&[usize]]) {
+        let mut assigned = vec![sides.len(); self.nodes.len()];
+        for (side, nodes) in sides.iter().enumerate() {
+            for node in nodes.iter() {
+                assigned[*node] = side;
+            }
+        }
+        self.sides = Some(assigned);
+    }
+
+    /// Ends the partition
+    pub fn heal(&mut self) {
+        self.sides = None;
+    }
+
+    /// Each message has this chance, from 0 to 1, of never arriving
+    pub fn set_drop_rate(&mut self, rate: f64) {
+        self.drop_rate = rate.clamp(0.0, 1.0);
+    }
+
+    /// How long messages take between the two nodes, both ways
+    pub fn set_latency(&mut self, a: usize, b: usize, latency: Duration) {
+        self.latencies.insert((a.min(b), a.max(b)), latency);
+    }
+
+    /// Gives the node a transaction, like `/submit-transaction` would. Returns whether it took it
+    pub fn submit_transaction(&mut self, node: usize, transaction: Transaction) -> bool {
+        let added = self.nodes[node]
+            .state
+            .lock()
+            .unwrap()
+            .add_transaction(transaction)
+            .is_ok();
+        self.flush();
+        added
+    }
+
+    /// Makes the node find a block with every transaction in its pool, and relay it. The coinbase
+    /// pays the block reward to the node's wallet. Returns the block's hash
+    pub fn mine(&mut self, node: usize) -> CleytoResult<String> {
+        let hash = {
+            let miner = self.nodes[node].wallet.0.clone();
+            let mut state = self.nodes[node].state.lock().unwrap();
+            let coinbase = Transaction::coinbase(
+                state.chain().get_last_index() + 1,
+                vec![UTXO::new(BLOCK_REWARD, miner)],
+            );
+            let transactions = std::iter::once(coinbase)
+                .chain(state.transactions_pool().iter().cloned())
+                .collect();
+            // Block::new only looks at the tip, so a chain of just the tip is enough to build on
+            let mut tip = Chain {
+                blocks: state.chain().blocks.last().cloned().into_iter().collect(),
+            };
+            let block =
+                Block::new(&mut tip, transactions).mine_with_difficulty(self.config.pow_difficulty);
+            let hash = block.get_hash();
+            state.accept_block(block)?;
+            hash
+        };
+        self.flush();
+        Ok(hash)
+    }
+
+    /// Runs the network for `duration` of simulated time
+    pub fn run_for(&mut self, duration: Duration) {
+        self.run_until(duration, |_| false);
+    }
+
+    /// Runs the network until `condition` holds, for at most `limit` of simulated time. Returns
+    /// whether it held
+    pub fn run_until(&mut self, limit: Duration, condition: impl Fn(&Simulation) -> bool) -> bool {
+        let deadline = self.now + limit;
+        loop {
+            if condition(self) {
+                return true;
+            }
+            match self.events.peek() {
+                Some(next) if next.at <= deadline => {}
+                _ => {
+                    self.now = deadline;
+                    return condition(self);
+                }
+            }
+            let Scheduled { at, event, .. } = self.events.pop().unwrap();
+            self.now = at;
+            self.handle(event);
+            self.flush();
+        }
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Deliver { wire, to, frame } => {
+                let node = &mut self.nodes[to];
+                if let Some(connection) = node.connections.get_mut(&wire) {
+                    node.network.receive_bytes(connection, &frame, self.now);
+                    self.close_if_dropped(to, wire);
+                }
+            }
+            Event::Close { wire, to } => self.close(to, wire, "connection closed"),
+            Event::Tick => {
+                for index in 0..self.nodes.len() {
+                    let wires: Vec<u64> = self.nodes[index].connections.keys().copied().collect();
+                    for wire in wires {
+                        let node = &mut self.nodes[index];
+                        if let Some(connection) = node.connections.get_mut(&wire) {
+                            node.network.tick_connection(connection, self.now);
+                            self.close_if_dropped(index, wire);
+                        }
+                    }
+                }
+                self.schedule(self.now + TICK_INTERVAL, Event::Tick);
+            }
+        }
+    }
+
+    fn close_if_dropped(&mut self, node: usize, wire: u64) {
+        let dropped = self.nodes[node]
+            .connections
+            .get_mut(&wire)
+            .and_then(|connection| connection.dropped.take());
+        if let Some(reason) = dropped {
+            self.close(node, wire, &reason);
+            // The other side finds out when it would read the closed connection
+            let other = self.other_end(wire, node);
+            self.schedule(
+                self.now + self.latency(node, other),
+                Event::Close { wire, to: other },
+            );
+        }
+    }
+
+    fn close(&mut self, node: usize, wire: u64, reason: &str) {
+        let node = &mut self.nodes[node];
+        if let Some(connection) = node.connections.remove(&wire) {
+            self.logger.log(format!(
+                "Simulated node {} disconnected from a peer: {reason}",
+                node.address
+            ));
+            node.network.close_connection(connection);
+        }
+    }
+
+    // Puts what the nodes wrote on their way, or drops it
+    fn flush(&mut self) {
+        let mut sent = std::mem::take(&mut *self.outbox.lock().unwrap());
+        // Nodes relay to their peers in no particular order, this keeps the drops the same
+        sent.sort_by_key(|sent| match sent {
+            Sent::Frame { wire, .. } | Sent::Close { wire, .. } => *wire,
+        });
+        for sent in sent {
+            match sent {
+                Sent::Frame { wire, from, frame } => {
+                    let to = self.other_end(wire, from);
+                    if self.partitioned(from, to) || self.dropped() {
+                        continue;
+                    }
+                    let at = self.now + self.latency(from, to);
+                    self.schedule(at, Event::Deliver { wire, to, frame });
+                }
+                // Like shutting a socket down, the reading side of the node notices right away
+                Sent::Close { wire, from } => {
+                    let to = self.other_end(wire, from);
+                    self.schedule(self.now, Event::Close { wire, to: from });
+                    self.schedule(self.now + self.latency(from, to), Event::Close { wire, to });
+                }
+            }
+        }
+    }
+
+    fn other_end(&self, wire: u64, node: usize) -> usize {
+        let (from, to) = self.wires[&wire];
+        if node == from {
+            to
+        } else {
+            from
+        }
+    }
+
+    fn partitioned(&self, a: usize, b: usize) -> bool {
+        self.sides
+            .as_ref()
+            .is_some_and(|sides| sides[a] != sides[b])
+    }
+
+    // Only asks the RNG when messages can be dropped, so the same seed drops the same ones
+    fn dropped(&mut self) -> bool {
+        self.drop_rate > 0.0 && self.rng.random_bool(self.drop_rate)
+    }
+
+    fn latency(&self, a: usize, b: usize) -> Duration {
+        self.latencies
+            .get(&(a.min(b), a.max(b)))
+            .copied()
+            .unwrap_or(self.config.latency)
+    }
+
+    fn schedule(&mut self, at: Instant, event: Event) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.events.push(Scheduled { at, seq, event });
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
@@ -0,0 +1,333 @@
+//! Headers-first download of the blocks peers have and we don't. The headers come first, from one
+//! peer at a time, and are checked to form a chain with the proof of work on top of ours. Only then
+//! are the bodies asked for, spread over every peer that has them, and connected in order as they
+//! arrive.
+//!
+//! New blocks announced after the sync go the same way, just one header at a time.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::message::{InvItem, Message, MAX_HEADERS};
+use crate::chain::block::{Block, BlockHeader};
+use crate::node::logger::Logger;
+use crate::node::NodeState;
+
+/// Most blocks asked to one peer and not received yet
+pub const MAX_IN_FLIGHT_PER_PEER: usize = 16;
+// Only blocks this close to the tip are asked for, so the ones received out of order and waiting
+// for the ones before them can't pile up
+const DOWNLOAD_WINDOW: usize = 1024;
+// After this long, headers or blocks asked for are asked to someone else
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(20);
+const PROGRESS_LOG_INTERVAL: u64 = 500;
+
+/// How far the sync got, for `/status`
+#[derive(Default)]
+pub struct SyncProgress {
+    best_header: AtomicU64,
+}
+
+impl SyncProgress {
+    /// Index of the last block whose header was checked, whether its body arrived or not. 0 until
+    /// a peer sends headers
+    pub fn best_header(&self) -> u64 {
+        self.best_header.load(Ordering::Relaxed)
+    }
+}
+
+// ---------------------------------------------- BlockSync definition ---------------------------------------------
+/// Messages to send, and the id of the peer to send each to
+pub type Outgoing = Vec<(u64, Message)>;
+
+pub struct BlockSync {
+    difficulty: u8,
+    progress: Arc<SyncProgress>,
+
+    // Checked headers after our tip, in order, and their hashes
+    headers: VecDeque<BlockHeader>,
+    queued: HashSet<String>,
+    // Block hash to the peer it was asked to and when
+    in_flight: HashMap<String, (u64, Instant)>,
+    // Bodies that arrived before the ones they build on, and the peer that sent each
+    received: HashMap<String, (u64, Block)>,
+
+    // The highest block each peer is known to have
+    peer_heights: HashMap<u64, u64>,
+    headers_request: Option<(u64, Instant)>,
+    connected_since_log: u64,
+}
+
+impl BlockSync {
+    /// Headers need `difficulty` leading zeros in their hash to be accepted
+    pub fn new(difficulty: u8, progress: Arc<SyncProgress>) -> Self {
+        Self {
+            difficulty,
+            progress,
+            headers: VecDeque::new(),
+            queued: HashSet::new(),
+            in_flight: HashMap::new(),
+            received: HashMap::new(),
+            peer_heights: HashMap::new(),
+            headers_request: None,
+            connected_since_log: 0,
+        }
+    }
+
+    /// Whether there are headers whose blocks didn't arrive yet
+    pub fn is_syncing(&self) -> bool {
+        !self.headers.is_empty()
+    }
+
+    // Index and hash of the last block we have the header of
+    fn best_header(&self, state: &NodeState) -> (u64, String) {
+        match self.headers.back() {
+            Some(header) => (header.index, header.hash.clone()),
+            None => (
+                state.chain().get_last_index(),
+                state.chain().get_last_hash(),
+            ),
+        }
+    }
+
+    fn get_headers(&self, state: &NodeState) -> Message {
+        let mut locator = state.block_locator();
+        if let Some(header) = self.headers.back() {
+            locator.insert(0, header.hash.clone());
+        }
+        Message::GetHeaders(locator)
+    }
+
+    pub fn peer_connected(
+        &mut self,
+        id: u64,
+        best_height: u64,
+        now: Instant,
+        state: &NodeState,
+    ) -> Outgoing {
+        self.peer_heights.insert(id, best_height);
+        self.schedule(now, state)
+    }
+
+    pub fn peer_disconnected(&mut self, id: u64) {
+        self.peer_heights.remove(&id);
+        self.in_flight.retain(|_, (peer, _)| *peer != id);
+        if matches!(self.headers_request, Some((peer, _)) if peer == id) {
+            self.headers_request = None;
+        }
+    }
+
+    /// A peer announced a block we don't know, so it asks for the headers leading to it
+    pub fn block_announced(&mut self, id: u64, state: &NodeState) -> Outgoing {
+        vec![(id, self.get_headers(state))]
+    }
+
+    /// Checks the headers and queues their blocks for download. Fails with the reason to drop the
+    /// peer if they don't form a chain or don't have the proof of work
+    pub fn headers_received(
+        &mut self,
+        id: u64,
+        headers: Vec<BlockHeader>,
+        now: Instant,
+        state: &NodeState,
+    ) -> Result<Outgoing, String> {
+        if matches!(self.headers_request, Some((peer, _)) if peer == id) {
+            self.headers_request = None;
+        }
+        let full = headers.len() == MAX_HEADERS;
+        let known = headers
+            .iter()
+            .take_while(|header| {
+                state.has_block(&header.hash) || self.queued.contains(&header.hash)
+            })
+            .count();
+        let new = &headers[known..];
+
+        let (mut index, mut hash) = self.best_header(state);
+        match new.first() {
+            // Nothing new, the peer has what we have
+            None => {
+                self.peer_heights.insert(id, index);
+                return Ok(self.schedule(now, state));
+            }
+            // A chain that went another way. Without reorgs there's nothing to do with it
+            Some(first) if first.previous_hash != hash => {
+                self.peer_heights
+                    .insert(id, first.index.saturating_sub(1).min(index));
+                return Ok(self.schedule(now, state));
+            }
+            Some(_) => {}
+        }
+
+        for header in new {
+            if header.previous_hash != hash || header.index != index + 1 {
+                return Err("sent headers that don't form a chain".to_string());
+            }
+            if header.calculate_hash() != header.hash {
+                return Err(format!("sent header {} with the wrong hash", header.hash));
+            }
+            if !header.meets_difficulty(self.difficulty) {
+                return Err(format!("sent header {} without proof of work", header.hash));
+            }
+            (index, hash) = (header.index, header.hash.clone());
+        }
+
+        self.queued
+            .extend(new.iter().map(|header| header.hash.clone()));
+        self.headers.extend(new.iter().cloned());
+        self.peer_heights.insert(id, index);
+        self.progress.best_header.store(index, Ordering::Relaxed);
+
+        let mut outgoing = Vec::new();
+        if full {
+            outgoing.push((id, self.get_headers(state)));
+            self.headers_request = Some((id, now));
+        }
+        outgoing.extend(self.schedule(now, state));
+        Ok(outgoing)
+    }
+
+    /// Takes the block the peer sent if it's one the sync is waiting for, gives it back otherwise
+    pub fn block_received(&mut self, id: u64, block: Block) -> Option<Block> {
+        let hash = block.get_hash();
+        if !self.queued.contains(&hash) {
+            return Some(block);
+        }
+        self.in_flight.remove(&hash);
+        self.received.insert(hash, (id, block));
+        None
+    }
+
+    /// The peer doesn't have these, so they're asked to someone else
+    pub fn not_found(
+        &mut self,
+        id: u64,
+        items: &[InvItem],
+        now: Instant,
+        state: &NodeState,
+    ) -> Outgoing {
+        for item in items {
+            let InvItem::Block(hash) = item else {
+                continue;
+            };
+            if !matches!(self.in_flight.get(hash), Some((peer, _)) if *peer == id) {
+                continue;
+            }
+            self.in_flight.remove(hash);
+            if let Some(header) = self.headers.iter().find(|header| header.hash == *hash) {
+                self.peer_heights.insert(id, header.index - 1);
+            }
+        }
+        self.schedule(now, state)
+    }
+
+    /// Connects the blocks that arrived, in the order of their headers, as far as they go. A body
+    /// that doesn't validate stops them, and is asked to another peer. Returns the peer that sent
+    /// it, and why it's invalid
+    pub fn connect_blocks(
+        &mut self,
+        state: &mut NodeState,
+        logger: &Logger,
+    ) -> Option<(u64, String)> {
+        while let Some((sender, block)) = self
+            .headers
+            .front()
+            .and_then(|header| self.received.remove(&header.hash))
+        {
+            let header = self.headers.front().unwrap();
+            let already_had = state.has_block(&header.hash);
+            if !already_had {
+                if let Err(e) = state.accept_block(block) {
+                    logger.log_error(format!(
+                        "Block {} from peer {sender} doesn't validate, asking another peer: {e:?}",
+                        header.hash
+                    ));
+                    // Like a peer that doesn't have it, so it isn't asked again
+                    self.peer_heights.insert(sender, header.index - 1);
+                    return Some((sender, format!("{}: {e:?}", header.hash)));
+                }
+            }
+            let header = self.headers.pop_front().unwrap();
+            self.queued.remove(&header.hash);
+            if already_had {
+                continue;
+            }
+
+            self.connected_since_log += 1;
+            if self.connected_since_log >= PROGRESS_LOG_INTERVAL || self.headers.is_empty() {
+                logger.log(format!(
+                    "Synced up to block {} of {}",
+                    header.index,
+                    self.progress.best_header()
+                ));
+                self.connected_since_log = 0;
+            }
+        }
+        None
+    }
+
+    /// Asks for headers if some peer has blocks past ours, and for the bodies of the next blocks
+    /// to the peers that have them and aren't too busy. Also forgets requests that took too long,
+    /// so they're asked again
+    pub fn schedule(&mut self, now: Instant, state: &NodeState) -> Outgoing {
+        self.in_flight
+            .retain(|_, (_, sent_at)| now.duration_since(*sent_at) < REQUEST_TIMEOUT);
+        if matches!(self.headers_request, Some((_, sent_at)) if now.duration_since(sent_at) >= REQUEST_TIMEOUT)
+        {
+            self.headers_request = None;
+        }
+
+        let mut outgoing = Vec::new();
+        let (best_index, _) = self.best_header(state);
+        if self.headers_request.is_none() {
+            let ahead = self
+                .peer_heights
+                .iter()
+                .filter(|(_, height)| **height > best_index)
+                .max_by_key(|(id, height)| (**height, std::cmp::Reverse(**id)));
+            if let Some((&id, _)) = ahead {
+                outgoing.push((id, self.get_headers(state)));
+                self.headers_request = Some((id, now));
+            }
+        }
+
+        let mut load: HashMap<u64, usize> = self.peer_heights.keys().map(|id| (*id, 0)).collect();
+        for (peer, _) in self.in_flight.values() {
+            if let Some(count) = load.get_mut(peer) {
+                *count += 1;
+            }
+        }
+        let mut requests: HashMap<u64, Vec<InvItem>> = HashMap::new();
+        for header in self.headers.iter().take(DOWNLOAD_WINDOW) {
+            if self.in_flight.contains_key(&header.hash) || self.received.contains_key(&header.hash)
+            {
+                continue;
+            }
+            let least_busy = load
+                .iter()
+                .filter(|(id, count)| {
+                    **count < MAX_IN_FLIGHT_PER_PEER && self.peer_heights[*id] >= header.index
+                })
+                .min_by_key(|(id, count)| (**count, **id))
+                .map(|(id, _)| *id);
+            let Some(peer) = least_busy else {
+                continue;
+            };
+            *load.get_mut(&peer).unwrap() += 1;
+            self.in_flight.insert(header.hash.clone(), (peer, now));
+            requests
+                .entry(peer)
+                .or_default()
+                .push(InvItem::Block(header.hash.clone()));
+        }
+        outgoing.extend(
+            requests
+                .into_iter()
+                .map(|(id, items)| (id, Message::GetData(items))),
+        );
+        outgoing
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
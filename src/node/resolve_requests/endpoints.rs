@@ -5,13 +5,16 @@ use super::helpers::{
     HTTPResult, Handler, POSTFunc,
 };
 use super::methods::{Content, GETData, HTTPRequest, HTTPResponse, ImageType, Method, POSTData};
+use crate::chain::address::Address;
+use crate::chain::block::Block;
 use crate::chain::transaction::Transaction;
-use crate::error_handling::{TransactionDeserializeError, TransactionError};
-use crate::node::NodeState;
+use crate::error_handling::{CleytonError, TransactionDeserializeError, TransactionError};
+use crate::node::{NodeState, PoolRejection};
 use chrono::Utc;
 use core::panic;
 use serde_json::json;
 use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 
 // pub type POSTFunc = fn(&POSTData, Arc<Mutex<NodeState>>) -> HTTPResult;
@@ -47,6 +50,13 @@ pub fn submit_transaction(data: &POSTData, state: Arc<Mutex<NodeState>>) -> HTTP
             }
         }
     }
+    if transaction.is_legacy() {
+        return Err(HTTPResponseError::BadRequest(Some(
+            "Transaction submitted in the old format, every input has to be signed separately"
+                .to_string(),
+        )));
+    }
+
     match transaction.verify_signature() {
         Ok(()) => {}
         Err(e) => {
@@ -59,17 +69,60 @@ pub fn submit_transaction(data: &POSTData, state: Arc<Mutex<NodeState>>) -> HTTP
                     invalid signature"
                         .to_string(),
                 ))),
+                TransactionError::SignatureCountMismatch => Err(HTTPResponseError::BadRequest(
+                    Some("Transaction submitted without one signature per input".to_string()),
+                )),
+                TransactionError::InvalidSigHash => Err(HTTPResponseError::BadRequest(Some(
+                    "Transaction submitted with sighash flags that don't fit its inputs \
+                    and outputs"
+                        .to_string(),
+                ))),
+                TransactionError::MissingPublicKey => Err(HTTPResponseError::BadRequest(Some(
+                    "Transaction submitted with an input that doesn't reveal its owner's \
+                    public key"
+                        .to_string(),
+                ))),
+                TransactionError::UnsupportedKeyType => Err(HTTPResponseError::BadRequest(Some(
+                    "Transaction submitted with a key type its version doesn't allow".to_string(),
+                ))),
+                TransactionError::UnsupportedVersion(version) => {
+                    Err(HTTPResponseError::BadRequest(Some(format!(
+                        "Transaction submitted with unknown version {version}"
+                    ))))
+                }
                 TransactionError::InsufficientInputs => Err(HTTPResponseError::BadRequest(Some(
                     "Transaction's outputs are bigger that its inputs".to_string(),
                 ))),
+                TransactionError::UnknownInput | TransactionError::DoubleSpend => {
+                    Err(HTTPResponseError::BadRequest(Some(
+                        "Transaction spends an output that isn't unspent".to_string(),
+                    )))
+                }
                 // TODO Should move both of those to another error enum, maybe client and server errors
                 TransactionError::InsufficientFunds => panic!("Not the server's problem"),
                 TransactionError::ConnectionError(_) => panic!("Not the server's problem"),
+                TransactionError::AddressIndexRequired => Err(address_index_disabled()),
+                TransactionError::InvalidAddress(e) => Err(HTTPResponseError::BadRequest(Some(
+                    format!("Transaction submitted with an invalid address: {e}"),
+                ))),
+                TransactionError::InvalidPublicKey(_) => Err(HTTPResponseError::BadRequest(Some(
+                    "Transaction submitted with a public key that can't be read".to_string(),
+                ))),
             };
         }
     };
 
-    state.lock().unwrap().transactions_pool.push(transaction);
+    if let Err(rejection) = state.lock().unwrap().add_transaction(transaction) {
+        let reason = match rejection {
+            PoolRejection::AlreadyKnown => "Transaction is already pooled or in the chain",
+            PoolRejection::UnavailableInputs => {
+                "Transaction spends outputs that aren't unspent, or that a pooled transaction \
+                spends already"
+            }
+            _ => "Transaction isn't valid",
+        };
+        return Err(HTTPResponseError::BadRequest(Some(reason.to_string())));
+    }
 
     Ok(HTTPResponse::OK(Some(Content::JSON(json!({
         "msg": "The transaction was added to the pool.",
@@ -77,12 +130,190 @@ pub fn submit_transaction(data: &POSTData, state: Arc<Mutex<NodeState>>) -> HTTP
     })))))
 }
 
+pub fn submit_block(data: &POSTData, state: Arc<Mutex<NodeState>>) -> HTTPResult {
+    let body = data.body.clone().unwrap();
+    let block: Block = match serde_json::from_str(&body) {
+        Ok(block) => block,
+        Err(_) => return Err(HTTPResponseError::InvalidBody(None)),
+    };
+    let hash = block.get_hash();
+
+    match state.lock().unwrap().accept_block(block) {
+        Ok(()) => {}
+        Err(CleytonError::InvalidBlock(reason)) => {
+            return Err(HTTPResponseError::BadRequest(Some(format!(
+                "Block {hash} rejected: {reason}"
+            ))))
+        }
+        Err(CleytonError::TransactionError(e)) => {
+            return Err(HTTPResponseError::BadRequest(Some(format!(
+                "Block {hash} rejected, it has an invalid transaction: {e}"
+            ))))
+        }
+        Err(e) => {
+            return Err(HTTPResponseError::InternalServerError(Some(format!(
+                "Couldn't store block {hash}: {e:?}"
+            ))))
+        }
+    }
+
+    Ok(HTTPResponse::OK(Some(Content::JSON(json!({
+        "msg": "The block was added to the chain.",
+        "status_code": "200"
+    })))))
+}
+
 pub fn get_transaction_pool(_: &GETData, state: Arc<Mutex<NodeState>>) -> HTTPResult {
     let transaction_pool: Vec<Transaction> = state.lock().unwrap().transactions_pool.clone();
     let response = serde_json::to_value(transaction_pool).unwrap();
     Ok(HTTPResponse::OK(Some(Content::JSON(response))))
 }
 
+/// `/tx/<txid>`, a pooled transaction or a confirmed one if the node keeps a transaction index
+pub fn get_transaction(data: &GETData, state: Arc<Mutex<NodeState>>) -> HTTPResult {
+    let requested = data
+        .path
+        .file_name()
+        .and_then(|txid| txid.to_str())
+        .unwrap_or_default();
+    let txid: [u8; 32] = match hex::decode(requested)
+        .ok()
+        .and_then(|txid| txid.try_into().ok())
+    {
+        Some(txid) => txid,
+        None => {
+            return Err(HTTPResponseError::BadRequest(Some(format!(
+                "{requested} is not a txid"
+            ))))
+        }
+    };
+
+    let found = match state.lock().unwrap().find_transaction(&txid) {
+        Ok(Some(found)) => found,
+        Ok(None) => return path_not_found(Some(&format!("/tx/{requested}"))),
+        Err(CleytonError::TxIndexDisabled) => {
+            return Err(HTTPResponseError::BadRequest(Some(
+                "Only pooled transactions can be looked up, the node has no transaction index"
+                    .to_string(),
+            )))
+        }
+        Err(e) => {
+            return Err(HTTPResponseError::InternalServerError(Some(format!(
+                "Couldn't read transaction {requested}: {e:?}"
+            ))))
+        }
+    };
+
+    return_json(json!({
+        "txid": requested,
+        "blockHash": found.block_hash,
+        "confirmations": found.confirmations,
+        "transaction": found.transaction,
+    }))
+}
+
+// The `:address` of `/address/:address/...`
+fn address_from_path(data: &GETData) -> Result<Address, HTTPResponseError> {
+    let requested = data
+        .path
+        .iter()
+        .nth(2)
+        .and_then(|address| address.to_str())
+        .unwrap_or_default();
+    Address::from_str(requested)
+        .map_err(|_| HTTPResponseError::BadRequest(Some(format!("{requested} is not an address"))))
+}
+
+fn address_index_disabled() -> HTTPResponseError {
+    HTTPResponseError::BadRequest(Some(
+        "The node doesn't keep an address index, start it with --addrindex".to_string(),
+    ))
+}
+
+/// `/address/<address>/balance`, the sum of what the address has unspent
+pub fn get_address_balance(data: &GETData, state: Arc<Mutex<NodeState>>) -> HTTPResult {
+    let address = address_from_path(data)?;
+    let state = state.lock().unwrap();
+    let address_index = state
+        .address_index()
+        .map_err(|_| address_index_disabled())?;
+
+    return_json(json!({
+        "address": address.to_string(),
+        "balance": address_index.balance(&address),
+        "utxos": address_index.unspent(&address).count(),
+    }))
+}
+
+/// `/address/<address>/utxos`, what the address has unspent, ready to be used as inputs
+pub fn get_address_utxos(data: &GETData, state: Arc<Mutex<NodeState>>) -> HTTPResult {
+    let address = address_from_path(data)?;
+    let state = state.lock().unwrap();
+    let address_index = state
+        .address_index()
+        .map_err(|_| address_index_disabled())?;
+
+    let utxos: Vec<serde_json::Value> = address_index
+        .unspent(&address)
+        .map(|entry| {
+            json!({
+                "outpoint": entry.outpoint.to_string(),
+                "blockHash": entry.block_hash,
+                "utxo": entry.utxo,
+            })
+        })
+        .collect();
+    return_json(json!(utxos))
+}
+
+/// `/address/<address>/history`, everything the address received and what spent it
+pub fn get_address_history(data: &GETData, state: Arc<Mutex<NodeState>>) -> HTTPResult {
+    let address = address_from_path(data)?;
+    let state = state.lock().unwrap();
+    let address_index = state
+        .address_index()
+        .map_err(|_| address_index_disabled())?;
+
+    let history: Vec<serde_json::Value> = address_index
+        .history(&address)
+        .iter()
+        .map(|entry| {
+            json!({
+                "outpoint": entry.outpoint.to_string(),
+                "blockHash": entry.block_hash,
+                "value": entry.utxo.value(),
+                "spentBy": entry.spent_by.map(hex::encode),
+            })
+        })
+        .collect();
+    return_json(json!(history))
+}
+
+/// `/peers`, the nodes this one is connected to
+pub fn get_peers(_: &GETData, state: Arc<Mutex<NodeState>>) -> HTTPResult {
+    let peers = match state.lock() {
+        Ok(guard) => guard.peers(),
+        Err(_) => panic!("Mutex lock was poisoned in function get_peers on endpoints"),
+    };
+
+    let peers: Vec<_> = peers
+        .list()
+        .into_iter()
+        .map(|peer| {
+            json!({
+                "address": peer.address.to_string(),
+                "direction": format!("{:?}", peer.direction).to_lowercase(),
+                "handshakeDone": peer.version.is_some(),
+                "bestHeight": peer.version.as_ref().map(|version| version.best_height),
+                "userAgent": peer.version.as_ref().map(|version| version.user_agent.clone()),
+                "latencyMs": peer.latency.map(|latency| latency.as_millis() as u64),
+                "key": peer.key.map(hex::encode),
+            })
+        })
+        .collect();
+    return_json(json!(peers))
+}
+
 pub fn favicon(_: &GETData, _: Arc<Mutex<NodeState>>) -> HTTPResult {
     return_image("fav.ico", ImageType::ICO)
 }
@@ -93,10 +324,15 @@ pub fn status(_: &GETData, state: Arc<Mutex<NodeState>>) -> HTTPResult {
         Err(_) => panic!("Mutex lock was poisoned in function status on endpoints"),
     };
 
+    // Headers run ahead of the blocks while the node is catching up with its peers
+    let block_height = state.chain.get_last_index();
+    let header_height = state.sync_progress().best_header().max(block_height);
     return_json(json!({
         "status": state.status,
-        "blockHeight": state.chain.get_last_index(),
-        "peers": 100000000,
+        "blockHeight": block_height,
+        "headerHeight": header_height,
+        "syncing": header_height > block_height,
+        "peers": state.peers().len(),
         "timestamp": Utc::now()
     }))
 }
@@ -134,12 +370,35 @@ pub fn resolve_endpoint(
             add_endpoints("/", Some(index), None);
             add_endpoints("/favicon.ico", Some(favicon), None);
             add_endpoints("/status", Some(status), None);
+            add_endpoints("/peers", Some(get_peers), None);
             add_endpoints("/submit-transaction", None, Some(submit_transaction));
+            add_endpoints("/submit-block", None, Some(submit_block));
             add_endpoints("/get-transaction-pool", Some(get_transaction_pool), None);
+            add_endpoints("/tx/:txid", Some(get_transaction), None);
+            add_endpoints("/address/:address/balance", Some(get_address_balance), None);
+            add_endpoints("/address/:address/utxos", Some(get_address_utxos), None);
+            add_endpoints("/address/:address/history", Some(get_address_history), None);
         }
         endpoints
     }
 
+    // Segments starting with ':' match any single segment, the handler reads them from the path
+    fn matches_pattern(pattern: &str, path: &str) -> bool {
+        let (mut pattern, mut path) = (pattern.split('/'), path.split('/'));
+        loop {
+            match (pattern.next(), path.next()) {
+                (None, None) => return true,
+                (Some(expected), Some(segment)) if expected.starts_with(':') => {
+                    if segment.is_empty() {
+                        return false;
+                    }
+                }
+                (Some(expected), Some(segment)) if expected == segment => {}
+                _ => return false,
+            }
+        }
+    }
+
     let endpoints = initialize_endpoints();
 
     let (path, method) = match request.get_method() {
@@ -147,7 +406,13 @@ pub fn resolve_endpoint(
         Method::POST(data) => (data.path.clone(), "POST"),
     };
 
-    let r = match endpoints.get(path.to_str().unwrap()) {
+    let methods = endpoints.get(path.to_str().unwrap()).or_else(|| {
+        endpoints
+            .iter()
+            .find(|(pattern, _)| matches_pattern(pattern, path.to_str().unwrap()))
+            .map(|(_, methods)| methods)
+    });
+    let r = match methods {
         Some(methods) => match methods.get(method) {
             Some(handler) => handler.call(&request, state),
             None => method_not_allowed(Some(path.to_str().unwrap())),
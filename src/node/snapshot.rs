@@ -0,0 +1,234 @@
+//! A UTXO snapshot is the set of unspent outputs as of some block, plus what a node needs to build
+//! on top of that block: the headers before it and the block itself. A fresh node loaded from one
+//! starts validating new blocks right away, as if everything before the snapshot was pruned.
+//!
+//! The snapshot commits to its UTXO set with a hash, which should be checked against one from a
+//! trusted source. Until the history behind it is verified by replaying it from a node that has
+//! it, the data directory remembers which snapshot it came from in `snapshot_base.json`.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chain::{
+        block::{Block, BlockHeader},
+        utxo_set::UtxoSet,
+    },
+    error_handling::{CleytoResult, CleytonError},
+    node::{block_store::BlockStore, files::write_atomically, ChainState},
+};
+
+const SNAPSHOT_BASE_FILE: &str = "snapshot_base.json";
+
+// ------------------------------------------- UtxoSnapshot definition ---------------------------------------------
+#[derive(Serialize, Deserialize)]
+pub struct UtxoSnapshot {
+    /// Height of the block the UTXO set is at
+    pub height: u32,
+    /// Hex SHA-256 of the UTXO set
+    pub commitment: String,
+    /// Headers of every block before the base one
+    pub headers: Vec<BlockHeader>,
+    pub base_block: Block,
+    pub utxo_set: UtxoSet,
+}
+
+impl UtxoSnapshot {
+    /// The UTXO set at `height`, or at the tip if there's none. The blocks up to it are replayed
+    /// from the saved chain state if it's not after `height`, from the genesis block otherwise, so
+    /// they can't have been pruned
+    pub fn create(block_store: &BlockStore, height: Option<u32>) -> CleytoResult<Self> {
+        let tip_height = block_store
+            .len()
+            .checked_sub(1)
+            .ok_or(CleytonError::BlockNotFound)? as u32;
+        let height = height.unwrap_or(tip_height);
+        if height > tip_height {
+            return Err(CleytonError::BlockNotFound);
+        }
+
+        let (mut utxo_set, replay_from) = match ChainState::read(block_store)? {
+            Some(saved) if saved.height <= height => (saved.utxo_set, saved.height + 1),
+            _ => (UtxoSet::new(), 0),
+        };
+        for replayed in replay_from..=height {
+            let block = block_store.read_by_height(replayed)?;
+            utxo_set.apply_block(&block).map_err(|e| {
+                CleytonError::CorruptBlockStore(format!(
+                    "stored block {} can't be applied: {e}",
+                    block.get_hash()
+                ))
+            })?;
+        }
+        let headers = (0..height)
+            .map(|header_height| block_store.read_header(header_height))
+            .collect::<CleytoResult<_>>()?;
+
+        Ok(Self {
+            height,
+            commitment: hex::encode(utxo_set.commitment()),
+            headers,
+            base_block: block_store.read_by_height(height)?,
+            utxo_set,
+        })
+    }
+
+    pub fn read(path: &Path) -> CleytoResult<Self> {
+        let serialized_snapshot = std::fs::read_to_string(path)?;
+        serde_json::from_str(&serialized_snapshot)
+            .map_err(|e| CleytonError::InvalidSnapshot(e.to_string()))
+    }
+
+    pub fn write(&self, path: &Path) -> CleytoResult<()> {
+        let serialized_snapshot =
+            serde_json::to_string(self).map_err(CleytonError::BlockSerializationError)?;
+        write_atomically(path, serialized_snapshot.as_bytes())
+    }
+
+    /// Checks that the snapshot is consistent with itself: the UTXO set matches the commitment and
+    /// the headers lead to the base block. Whether it's the right chain is up to the commitment
+    pub fn verify(&self) -> CleytoResult<()> {
+        if hex::encode(self.utxo_set.commitment()) != self.commitment {
+            return Err(CleytonError::InvalidSnapshot(
+                "the UTXO set doesn't match its commitment".to_string(),
+            ));
+        }
+        if self.headers.len() != self.height as usize {
+            return Err(CleytonError::InvalidSnapshot(format!(
+                "expected {} headers before the base block",
+                self.height
+            )));
+        }
+        if self.height > 0 && self.base_block.get_hash() != self.base_block.calculate_hash() {
+            return Err(CleytonError::InvalidSnapshot(
+                "the base block hash doesn't match its contents".to_string(),
+            ));
+        }
+
+        let mut previous_hash = None;
+        for header in self.headers.iter().chain([&self.base_block.header()]) {
+            if previous_hash.is_some_and(|previous_hash| previous_hash != &header.previous_hash) {
+                return Err(CleytonError::InvalidSnapshot(format!(
+                    "header {} doesn't build on the one before it",
+                    header.hash
+                )));
+            }
+            previous_hash = Some(&header.hash);
+        }
+
+        Ok(())
+    }
+
+    /// Starts the empty block store from the snapshot, once it's verified and, if given, its
+    /// commitment matches the expected one
+    pub fn load_into(
+        self,
+        block_store: &mut BlockStore,
+        expected_commitment: Option<&str>,
+    ) -> CleytoResult<SnapshotBase> {
+        self.verify()?;
+        if expected_commitment.is_some_and(|expected| expected != self.commitment) {
+            return Err(CleytonError::InvalidSnapshot(
+                "the snapshot commits to a different UTXO set than expected".to_string(),
+            ));
+        }
+        if !block_store.is_empty() {
+            return Err(CleytonError::InvalidSnapshot(
+                "snapshots can only be loaded into an empty data directory".to_string(),
+            ));
+        }
+
+        block_store.import_headers(self.headers)?;
+        block_store.append(&self.base_block)?;
+        ChainState {
+            tip: self.base_block.get_hash(),
+            height: self.height,
+            utxo_set: self.utxo_set,
+        }
+        .write(block_store)?;
+
+        let base = SnapshotBase {
+            height: self.height,
+            hash: self.base_block.get_hash(),
+            commitment: self.commitment,
+        };
+        base.write(block_store.dir())?;
+        Ok(base)
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
+
+// ------------------------------------------- SnapshotBase definition ---------------------------------------------
+/// The snapshot a data directory was started from, while its history is still unverified
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotBase {
+    pub height: u32,
+    pub hash: String,
+    pub commitment: String,
+}
+
+impl SnapshotBase {
+    pub fn read(data_dir: &Path) -> CleytoResult<Option<Self>> {
+        match std::fs::read_to_string(data_dir.join(SNAPSHOT_BASE_FILE)) {
+            Ok(serialized_base) => serde_json::from_str(&serialized_base)
+                .map(Some)
+                .map_err(|e| CleytonError::InvalidSnapshot(e.to_string())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, data_dir: &Path) -> CleytoResult<()> {
+        let serialized_base =
+            serde_json::to_string(self).map_err(CleytonError::BlockSerializationError)?;
+        write_atomically(
+            &data_dir.join(SNAPSHOT_BASE_FILE),
+            serialized_base.as_bytes(),
+        )
+    }
+
+    /// Replays the blocks up to the snapshot from a store that has all of them, and checks they
+    /// end at the snapshot's block with the same UTXO set. If they do, the data directory stops
+    /// remembering the snapshot
+    pub fn verify_history(&self, data_dir: &Path, history: &BlockStore) -> CleytoResult<()> {
+        let mut utxo_set = UtxoSet::new();
+        let mut previous_hash: Option<String> = None;
+        for height in 0..=self.height {
+            let block = history.read_by_height(height)?;
+            // The genesis block hash is fixed, not calculated
+            if height > 0 && block.get_hash() != block.calculate_hash() {
+                return Err(CleytonError::InvalidBlock(format!(
+                    "block {height} of the history doesn't match its hash"
+                )));
+            }
+            if previous_hash.is_some_and(|previous_hash| previous_hash != block.get_previous_hash())
+            {
+                return Err(CleytonError::InvalidBlock(format!(
+                    "block {height} of the history doesn't build on the one before it"
+                )));
+            }
+            utxo_set.apply_block(&block).map_err(|e| {
+                CleytonError::InvalidBlock(format!(
+                    "block {height} of the history spends outputs it can't: {e}"
+                ))
+            })?;
+            previous_hash = Some(block.get_hash());
+        }
+
+        if previous_hash.as_deref() != Some(self.hash.as_str()) {
+            return Err(CleytonError::InvalidSnapshot(
+                "the history leads to a different block than the snapshot".to_string(),
+            ));
+        }
+        if hex::encode(utxo_set.commitment()) != self.commitment {
+            return Err(CleytonError::InvalidSnapshot(
+                "the history leads to a different UTXO set than the snapshot".to_string(),
+            ));
+        }
+
+        std::fs::remove_file(data_dir.join(SNAPSHOT_BASE_FILE))?;
+        Ok(())
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
@@ -0,0 +1,103 @@
+//! Where every confirmed transaction is: the block it's in and its position there. It's saved to
+//! `txindex.json` in the data directory after every block, along with the last block indexed, so on
+//! startup only the blocks after that one are indexed again.
+//!
+//! The index points into block bodies, so it doesn't go together with pruning.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chain::block::Block,
+    error_handling::{CleytoResult, CleytonError},
+    node::{block_store::BlockStore, files::write_atomically},
+};
+
+const TX_INDEX_FILE: &str = "txindex.json";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TxLocation {
+    pub block_hash: String,
+    /// Position of the transaction in the block
+    pub position: u32,
+}
+
+// ---------------------------------------------- TxIndex definition -----------------------------------------------
+#[derive(Default, Serialize, Deserialize)]
+pub struct TxIndex {
+    // The last block indexed
+    tip: Option<String>,
+    // By hex txid
+    transactions: HashMap<String, TxLocation>,
+}
+
+impl TxIndex {
+    /// The index saved next to the block store, brought up to date with it. A missing or unreadable
+    /// index is rebuilt from every block
+    pub fn open(block_store: &BlockStore) -> CleytoResult<Self> {
+        let mut index: Self = match std::fs::read_to_string(Self::path(block_store.dir())) {
+            Ok(serialized_index) => serde_json::from_str(&serialized_index).unwrap_or_default(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        // Blocks stored after the index was last saved. If the index is about a block the store
+        // doesn't have, it can't be trusted at all
+        let from = match index.tip.as_deref().map(|tip| block_store.height_of(tip)) {
+            None => 0,
+            Some(Some(height)) => height + 1,
+            Some(None) => {
+                index = Self::default();
+                0
+            }
+        };
+        if from < block_store.pruned_height() {
+            return Err(CleytonError::BlockPruned);
+        }
+        for height in from..block_store.len() as u32 {
+            index.connect_block(&block_store.read_by_height(height)?);
+        }
+        index.save(block_store.dir())?;
+
+        Ok(index)
+    }
+
+    fn path(dir: &Path) -> std::path::PathBuf {
+        dir.join(TX_INDEX_FILE)
+    }
+
+    pub fn save(&self, dir: &Path) -> CleytoResult<()> {
+        let serialized_index =
+            serde_json::to_string(self).map_err(CleytonError::BlockSerializationError)?;
+        write_atomically(&Self::path(dir), serialized_index.as_bytes())
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    pub fn get(&self, txid: &[u8; 32]) -> Option<&TxLocation> {
+        self.transactions.get(&hex::encode(txid))
+    }
+
+    pub fn connect_block(&mut self, block: &Block) {
+        let block_hash = block.get_hash();
+        for (position, transaction) in block.get_transactions().iter().enumerate() {
+            self.transactions.insert(
+                hex::encode(transaction.txid),
+                TxLocation {
+                    block_hash: block_hash.clone(),
+                    position: position as u32,
+                },
+            );
+        }
+        self.tip = Some(block_hash);
+    }
+}
+// -----------------------------------------------------------------------------------------------------------------
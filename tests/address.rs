@@ -0,0 +1,102 @@
+use std::str::FromStr;
+
+use cleyto_coin::chain::address::{Address, AddressError, ADDRESS_VERSION};
+use cleyto_coin::chain::transaction::{Transaction, TransactionInfo};
+use cleyto_coin::chain::utxo::UTXO;
+use cleyto_coin::chain::wallet::Wallet;
+use cleyto_coin::error_handling::TransactionError;
+
+#[test]
+fn address_round_trip() {
+    let (wallet, _) = Wallet::new();
+
+    let address = wallet.address();
+    let encoded = address.to_string();
+    println!("address: {encoded}");
+
+    assert!(encoded.starts_with('C'));
+    assert_eq!(address.version(), ADDRESS_VERSION);
+    assert_eq!(Address::from_str(&encoded).unwrap(), address);
+
+    // Same key, same address
+    assert_eq!(
+        Wallet::from(String::from_utf8(wallet.to_pem()).unwrap()).address(),
+        address
+    );
+}
+
+#[test]
+fn address_with_typo_fails_checksum() {
+    let (wallet, _) = Wallet::new();
+    let encoded = wallet.address().to_string();
+
+    // Swap one character for another valid Base58 one
+    let mut chars: Vec<char> = encoded.chars().collect();
+    chars[10] = if chars[10] == '2' { '3' } else { '2' };
+    let typo: String = chars.into_iter().collect();
+
+    assert_eq!(Address::from_str(&typo), Err(AddressError::InvalidChecksum));
+    assert_eq!(Address::from_str("C0OIl"), Err(AddressError::InvalidBase58));
+    assert!(matches!(
+        Address::from_str("Cabc"),
+        Err(AddressError::InvalidLength(_))
+    ));
+}
+
+#[test]
+fn pay_to_address_and_spend_it() {
+    let (sender, sender_pk) = Wallet::new();
+    let (receiver, receiver_pk) = Wallet::new();
+    let (shop, _) = Wallet::new();
+
+    // The sender only knows the receiver's address
+    let transaction_info = TransactionInfo::new(
+        vec![UTXO::new(1000, sender.clone())],
+        vec![UTXO::new(1000, receiver.address())],
+    );
+    let signatures = sender_pk.sign_transaction(&transaction_info).unwrap();
+    let payment = Transaction::new(transaction_info, signatures).unwrap();
+
+    let serialized = payment.serialize();
+    let payment: Transaction = serde_json::from_str(&serialized).unwrap();
+    let received = payment.transaction_info.outputs[0].clone();
+    assert!(received.owner().public_key().is_none());
+
+    // To spend it the receiver reveals the public key behind the address
+    let spent = UTXO::new(1000, receiver.clone());
+    assert_eq!(spent, received);
+
+    let transaction_info = TransactionInfo::new(vec![spent], vec![UTXO::new(1000, shop.address())]);
+    let signatures = receiver_pk.sign_transaction(&transaction_info).unwrap();
+    Transaction::new(transaction_info, signatures).unwrap();
+
+    // But an input with just the address can't be verified
+    let transaction_info = TransactionInfo::new(vec![received], vec![UTXO::new(1000, shop)]);
+    let signatures = receiver_pk.sign_transaction(&transaction_info).unwrap();
+    assert!(Transaction::new(transaction_info, signatures).is_err());
+}
+
+#[tokio::test]
+async fn malformed_public_keys_are_errors() {
+    let malformed =
+        Some("-----BEGIN PUBLIC KEY-----\nnot a key\n-----END PUBLIC KEY-----\n".to_string());
+    assert!(matches!(
+        cleyto_coin::address(&malformed, &None),
+        Err(TransactionError::InvalidPublicKey(_))
+    ));
+
+    // Before the node is asked for anything
+    let (_, sender) = Wallet::new();
+    let sender_key = String::from_utf8(sender.to_pem()).unwrap();
+    let sent = cleyto_coin::send(
+        malformed,
+        None,
+        Some(sender_key),
+        None,
+        None,
+        10,
+        "http://127.0.0.1:1",
+    )
+    .await;
+    assert!(matches!(sent, Err(TransactionError::InvalidPublicKey(_))));
+}
@@ -0,0 +1,142 @@
+mod common;
+
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use cleyto_coin::chain::Chain;
+use cleyto_coin::node::logger::Logger;
+use cleyto_coin::node::p2p::{AddressBook, AddressSource, Network, NetworkConfig};
+use cleyto_coin::node::NodeState;
+use common::empty_dir;
+
+fn address(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+#[test]
+fn the_book_is_saved_and_read_back() {
+    let path = empty_dir("address_book_roundtrip").join("peers.json");
+    let mut book = AddressBook::open(&path).unwrap();
+    assert!(book.is_empty());
+
+    book.add(address(1000), AddressSource::Seed);
+    book.add(address(1001), AddressSource::Peer);
+    book.mark_connected(&address(1001), Utc::now());
+    book.mark_failed(&address(1000));
+    book.save().unwrap();
+
+    let reopened = AddressBook::open(&path).unwrap();
+    assert_eq!(reopened.len(), 2);
+    let seed = reopened.get(&address(1000)).unwrap();
+    assert_eq!(seed.source, AddressSource::Seed);
+    assert_eq!(seed.failures, 1);
+    assert!(reopened.get(&address(1001)).unwrap().last_seen.is_some());
+}
+
+#[test]
+fn unusable_addresses_are_not_added() {
+    let mut book = AddressBook::in_memory();
+    assert!(!book.add(address(0), AddressSource::Peer));
+    assert!(!book.add("0.0.0.0:9474".parse().unwrap(), AddressSource::Peer));
+    assert!(book.add(address(9474), AddressSource::Peer));
+    assert!(!book.add(address(9474), AddressSource::Manual));
+    assert_eq!(
+        book.get(&address(9474)).unwrap().source,
+        AddressSource::Manual
+    );
+}
+
+#[test]
+fn failing_addresses_wait_longer_and_are_forgotten() {
+    let mut book = AddressBook::in_memory();
+    book.add(address(1000), AddressSource::Peer);
+    book.add(address(1001), AddressSource::Seed);
+    let now = Utc::now();
+
+    book.mark_attempt(&address(1000), now);
+    book.mark_failed(&address(1000));
+    let none = HashSet::new();
+    assert_eq!(book.candidates(&none, now, 10), vec![address(1001)]);
+    assert_eq!(
+        book.candidates(&none, now + chrono::Duration::seconds(5), 10),
+        vec![address(1001), address(1000)]
+    );
+
+    // Seeds are kept however much they fail
+    for _ in 0..20 {
+        book.mark_failed(&address(1000));
+        book.mark_failed(&address(1001));
+    }
+    assert!(book.get(&address(1000)).is_none());
+    assert!(book.get(&address(1001)).is_some());
+}
+
+#[test]
+fn only_addresses_that_worked_are_shared() {
+    let mut book = AddressBook::in_memory();
+    book.add(address(1000), AddressSource::Peer);
+    book.add(address(1001), AddressSource::Peer);
+    book.mark_connected(&address(1001), Utc::now());
+    assert_eq!(book.sample(10), vec![address(1001)]);
+}
+
+fn start_node(config: NetworkConfig) -> Arc<Network> {
+    let state = Arc::new(Mutex::new(NodeState::in_memory(Chain::new()).unwrap()));
+    Network::start(config, state, Arc::new(Logger::new())).unwrap()
+}
+
+fn wait_until(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "Timed out waiting until {what}");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn nodes_find_each_other_through_their_peers() {
+    let listening = || NetworkConfig {
+        listen: Some("127.0.0.1:0".parse().unwrap()),
+        target_outbound: 0,
+        ..Default::default()
+    };
+    let a = start_node(listening());
+    let b = start_node(NetworkConfig {
+        connect: vec![a.local_addr().unwrap()],
+        ..listening()
+    });
+    wait_until("b is connected to a", || {
+        b.known_addresses()
+            .iter()
+            .any(|known| known.last_seen.is_some())
+    });
+
+    // c only knows b, and learns about a from it
+    let path = empty_dir("address_book_discovery").join("peers.json");
+    let c = start_node(NetworkConfig {
+        connect: vec![b.local_addr().unwrap()],
+        target_outbound: 2,
+        address_book: Some(path.clone()),
+        ..listening()
+    });
+    wait_until("c is connected to a and b", || {
+        c.peers().len() == 2 && a.peers().len() == 2
+    });
+
+    let saved = AddressBook::open(&path).unwrap();
+    let a_address = saved.get(&a.local_addr().unwrap()).unwrap();
+    assert_eq!(a_address.source, AddressSource::Peer);
+    assert!(a_address.last_seen.is_some());
+    assert_eq!(
+        saved.get(&b.local_addr().unwrap()).unwrap().source,
+        AddressSource::Manual
+    );
+
+    for network in [a, b, c] {
+        network.stop();
+    }
+}
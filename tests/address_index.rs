@@ -0,0 +1,82 @@
+mod common;
+
+use std::path::PathBuf;
+
+use cleyto_coin::chain::block::Block;
+use cleyto_coin::chain::transaction::{Transaction, TransactionInfo};
+use cleyto_coin::chain::utxo::UTXO;
+use cleyto_coin::chain::wallet::Wallet;
+use cleyto_coin::chain::Chain;
+use cleyto_coin::error_handling::CleytonError;
+use cleyto_coin::node::block_store::BlockStore;
+use cleyto_coin::node::{NodeOptions, NodeState};
+use common::empty_dir;
+
+fn with_address_index(dir: &PathBuf) -> NodeState {
+    let options = NodeOptions {
+        addrindex: true,
+        ..Default::default()
+    };
+    let mut state = NodeState::load_with_options(BlockStore::open(dir).unwrap(), &options).unwrap();
+    state.set_pow_difficulty(0);
+    state
+}
+
+#[test]
+fn balances_and_history_follow_the_chain() {
+    let dir = empty_dir("address_index");
+    let mut state = with_address_index(&dir);
+
+    let (alice, alice_pk) = Wallet::new();
+    let (bob, _) = Wallet::new();
+
+    let funding = Transaction::coinbase(
+        2,
+        vec![
+            UTXO::new(100, alice.clone()),
+            UTXO::new(50, alice.address()),
+        ],
+    );
+
+    // Pays to bob's address, and spends the output locked to alice's address with her key
+    let info = TransactionInfo::new(
+        vec![UTXO::new(50, alice.clone())],
+        vec![UTXO::new(40, bob.address())],
+    );
+    let signatures = alice_pk.sign_transaction(&info).unwrap();
+    let payment = Transaction::new(info, signatures).unwrap();
+
+    let mut chain = Chain::new();
+    for transactions in [vec![funding.clone()], vec![payment.clone()]] {
+        let block = Block::new(&mut chain, transactions);
+        chain.add_block(block.clone());
+        state.accept_block(block).unwrap();
+    }
+
+    let check = |state: &NodeState| {
+        let index = state.address_index().unwrap();
+        assert_eq!(index.balance(&alice.address()), 100);
+        assert_eq!(index.balance(&bob.address()), 40);
+        assert_eq!(index.unspent(&alice.address()).count(), 1);
+
+        let history = index.history(&alice.address());
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].spent_by, Some(payment.txid));
+        assert_eq!(history[0].block_hash, chain.blocks[1].get_hash());
+    };
+    check(&state);
+    drop(state);
+
+    // Saved across restarts, and rebuilt from the blocks if it's lost or behind
+    check(&with_address_index(&dir));
+    std::fs::remove_file(dir.join("addrindex.json")).unwrap();
+    check(&with_address_index(&dir));
+
+    let state = NodeState::load(BlockStore::open(&dir).unwrap()).unwrap();
+    assert!(matches!(
+        state.address_index(),
+        Err(CleytonError::AddressIndexDisabled)
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,267 @@
+mod common;
+
+use cleyto_coin::chain::block::Block;
+use cleyto_coin::chain::testing::test_chain;
+use cleyto_coin::node::block_store::BlockStore;
+use common::empty_dir;
+
+#[test]
+fn lookups_by_hash_and_height() {
+    let dir = empty_dir("lookups");
+    let chain = test_chain();
+
+    let mut store = BlockStore::open(&dir).unwrap();
+    for (height, block) in chain.blocks.iter().enumerate() {
+        assert_eq!(store.append(block).unwrap(), height as u32);
+    }
+    // Appending a block twice doesn't store it twice
+    assert_eq!(store.append(&chain.blocks[1]).unwrap(), 1);
+
+    // The index survives reopening the store
+    let store = BlockStore::open(&dir).unwrap();
+    assert_eq!(store.len(), chain.blocks.len());
+    assert_eq!(store.tip(), Some(chain.get_last_hash().as_str()));
+
+    for (height, block) in chain.blocks.iter().enumerate() {
+        let hash = block.get_hash();
+        assert_eq!(store.height_of(&hash), Some(height as u32));
+        assert_eq!(store.read_by_hash(&hash).unwrap().get_hash(), hash);
+        assert_eq!(
+            store.read_by_height(height as u32).unwrap().get_hash(),
+            hash
+        );
+    }
+    assert!(store.read_by_hash("not a hash").is_err());
+    assert!(store.read_by_height(chain.blocks.len() as u32).is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn reindex_rebuilds_lost_index() {
+    let dir = empty_dir("reindex");
+    let chain = test_chain();
+
+    let mut store = BlockStore::open(&dir).unwrap();
+    for block in &chain.blocks {
+        store.append(block).unwrap();
+    }
+
+    std::fs::remove_file(dir.join("blocks.idx")).unwrap();
+    let mut store = BlockStore::open(&dir).unwrap();
+    assert_eq!(store.len(), chain.blocks.len());
+    assert_eq!(store.reindex().unwrap(), chain.blocks.len());
+    assert_eq!(store.tip(), Some(chain.get_last_hash().as_str()));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn reindex_imports_blocks_in_old_layout() {
+    let dir = empty_dir("legacy");
+    let chain = test_chain();
+
+    // One file per block, named after its number and hash
+    std::fs::create_dir_all(dir.join("blocks")).unwrap();
+    for (number, block) in chain.blocks.iter().enumerate().rev() {
+        std::fs::write(
+            dir.join(format!("blocks/block_{number}_{}.blk", block.get_hash())),
+            serde_json::to_string(block).unwrap(),
+        )
+        .unwrap();
+    }
+
+    let store = BlockStore::open(&dir).unwrap();
+    let stored: Vec<String> = (0..store.len() as u32)
+        .map(|height| store.read_by_height(height).unwrap())
+        .map(|block: Block| block.get_hash())
+        .collect();
+    let expected: Vec<String> = chain.blocks.iter().map(|block| block.get_hash()).collect();
+    assert_eq!(stored, expected);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn recovers_from_a_block_cut_short() {
+    let dir = empty_dir("partial_write");
+    let chain = test_chain();
+
+    let mut store = BlockStore::open(&dir).unwrap();
+    for block in &chain.blocks {
+        store.append(block).unwrap();
+    }
+    let complete_length = std::fs::metadata(dir.join("blocks.dat")).unwrap().len();
+
+    // A crash in the middle of writing the next block
+    let serialized_block = serde_json::to_vec(&chain.blocks[0]).unwrap();
+    let mut partial_record = b"CLBK".to_vec();
+    partial_record.extend((serialized_block.len() as u32).to_le_bytes());
+    partial_record.extend(&serialized_block[..serialized_block.len() / 2]);
+    let mut blocks_file = std::fs::read(dir.join("blocks.dat")).unwrap();
+    blocks_file.extend(partial_record);
+    std::fs::write(dir.join("blocks.dat"), blocks_file).unwrap();
+
+    let store = BlockStore::open(&dir).unwrap();
+    assert_eq!(store.len(), chain.blocks.len());
+    assert_eq!(
+        std::fs::metadata(dir.join("blocks.dat")).unwrap().len(),
+        complete_length
+    );
+    store.read_by_height(store.len() as u32 - 1).unwrap();
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn recovers_blocks_written_before_the_index() {
+    let dir = empty_dir("stale_index");
+    let chain = test_chain();
+    let (last, rest) = chain.blocks.split_last().unwrap();
+
+    let mut store = BlockStore::open(&dir).unwrap();
+    for block in rest {
+        store.append(block).unwrap();
+    }
+    let stale_index = std::fs::read(dir.join("blocks.idx")).unwrap();
+
+    // A crash after the block was written, but before the index was updated
+    store.append(last).unwrap();
+    std::fs::write(dir.join("blocks.idx"), stale_index).unwrap();
+
+    let store = BlockStore::open(&dir).unwrap();
+    assert_eq!(store.len(), chain.blocks.len());
+    assert_eq!(store.tip(), Some(last.get_hash().as_str()));
+    store.read_by_hash(&last.get_hash()).unwrap();
+
+    // And an index pointing past the data is rebuilt from it
+    let blocks_file = std::fs::read(dir.join("blocks.dat")).unwrap();
+    let first_record_length =
+        8 + u32::from_le_bytes(blocks_file[4..8].try_into().unwrap()) as usize;
+    std::fs::write(
+        dir.join("blocks.dat"),
+        &blocks_file[..first_record_length + 3],
+    )
+    .unwrap();
+
+    let store = BlockStore::open(&dir).unwrap();
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.tip(), Some(chain.blocks[0].get_hash().as_str()));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn pruning_keeps_headers_of_deleted_bodies() {
+    let dir = empty_dir("prune");
+    let chain = test_chain();
+
+    let mut store = BlockStore::open(&dir).unwrap();
+    for block in &chain.blocks {
+        store.append(block).unwrap();
+    }
+    let keep = 2;
+    let pruned = chain.blocks.len() - keep;
+    assert_eq!(store.prune(keep as u32).unwrap(), pruned);
+    // Nothing left to prune
+    assert_eq!(store.prune(keep as u32).unwrap(), 0);
+
+    // Heights don't change, and survive reopening the store
+    let store = BlockStore::open(&dir).unwrap();
+    assert_eq!(store.len(), chain.blocks.len());
+    assert_eq!(store.pruned_height(), pruned as u32);
+    assert_eq!(store.tip(), Some(chain.get_last_hash().as_str()));
+
+    for (height, block) in chain.blocks.iter().enumerate() {
+        let hash = block.get_hash();
+        assert_eq!(store.height_of(&hash), Some(height as u32));
+        assert_eq!(store.read_header(height as u32).unwrap(), block.header());
+        if height < pruned {
+            assert!(store.read_by_hash(&hash).is_err());
+        } else {
+            assert_eq!(store.read_by_hash(&hash).unwrap().get_hash(), hash);
+        }
+    }
+
+    // The pruned blocks don't come back from the raw block file either
+    let mut store = BlockStore::open(&dir).unwrap();
+    std::fs::remove_file(dir.join("blocks.idx")).unwrap();
+    store.reindex().unwrap();
+    assert_eq!(store.len(), chain.blocks.len());
+    assert_eq!(store.pruned_height(), pruned as u32);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn storing_a_block_appends_one_line_to_the_index() {
+    let dir = empty_dir("index_lines");
+    let chain = test_chain();
+    let (last, rest) = chain.blocks.split_last().unwrap();
+
+    let mut store = BlockStore::open(&dir).unwrap();
+    for block in rest {
+        store.append(block).unwrap();
+    }
+    let index = std::fs::read_to_string(dir.join("blocks.idx")).unwrap();
+    assert_eq!(index.lines().count(), rest.len());
+
+    store.append(last).unwrap();
+    let appended = std::fs::read_to_string(dir.join("blocks.idx")).unwrap();
+    assert!(appended.starts_with(&index));
+    assert_eq!(appended.lines().count(), chain.blocks.len());
+
+    // A crash in the middle of the last line leaves the block to be found in the raw file
+    std::fs::write(dir.join("blocks.idx"), &appended[..appended.len() - 10]).unwrap();
+    let store = BlockStore::open(&dir).unwrap();
+    assert_eq!(store.len(), chain.blocks.len());
+    assert_eq!(store.tip(), Some(last.get_hash().as_str()));
+    assert_eq!(
+        std::fs::read_to_string(dir.join("blocks.idx")).unwrap(),
+        appended
+    );
+
+    // Indexes in the old format, one JSON object, are still read
+    let locations: Vec<serde_json::Value> = appended
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let old_index = serde_json::json!({ "blocks": locations }).to_string();
+    std::fs::write(dir.join("blocks.idx"), old_index).unwrap();
+    let store = BlockStore::open(&dir).unwrap();
+    assert_eq!(store.len(), chain.blocks.len());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("blocks.idx")).unwrap(),
+        appended
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn migrating_adds_the_blocks_in_old_layout_to_an_existing_store() {
+    let dir = empty_dir("migrate");
+    let chain = test_chain();
+
+    let mut store = BlockStore::open(&dir).unwrap();
+    store.append(&chain.blocks[0]).unwrap();
+    std::fs::create_dir_all(dir.join("blocks")).unwrap();
+    for (number, block) in chain.blocks.iter().enumerate() {
+        std::fs::write(
+            dir.join(format!("blocks/block_{number}_{}.blk", block.get_hash())),
+            serde_json::to_string(block).unwrap(),
+        )
+        .unwrap();
+    }
+
+    assert_eq!(
+        store.import_legacy_blocks().unwrap(),
+        chain.blocks.len() - 1
+    );
+    assert_eq!(store.import_legacy_blocks().unwrap(), 0);
+    let store = BlockStore::open(&dir).unwrap();
+    assert_eq!(store.len(), chain.blocks.len());
+    assert_eq!(store.tip(), Some(chain.get_last_hash().as_str()));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,264 @@
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cleyto_coin::chain::block::{Block, BlockHeader};
+use cleyto_coin::chain::Chain;
+use cleyto_coin::node::logger::Logger;
+use cleyto_coin::node::p2p::message::{InvItem, Message};
+use cleyto_coin::node::p2p::sync::{BlockSync, MAX_IN_FLIGHT_PER_PEER};
+use cleyto_coin::node::p2p::{Network, NetworkConfig};
+use cleyto_coin::node::NodeState;
+use common::payment;
+
+const DIFFICULTY: u8 = 1;
+
+// Mined blocks on top of the genesis block, the same for every node
+fn mined_blocks(count: usize) -> Vec<Block> {
+    let mut chain = Chain::new();
+    (0..count)
+        .map(|_| {
+            let block = Block::test_block(&chain).mine_with_difficulty(DIFFICULTY);
+            chain.add_block(block.clone());
+            block
+        })
+        .collect()
+}
+
+fn state_with(blocks: &[Block]) -> NodeState {
+    let mut chain = Chain::new();
+    for block in blocks {
+        chain.add_block(block.clone());
+    }
+    let mut state = NodeState::in_memory(chain).unwrap();
+    state.set_pow_difficulty(DIFFICULTY);
+    state
+}
+
+fn headers(blocks: &[Block]) -> Vec<BlockHeader> {
+    blocks.iter().map(Block::header).collect()
+}
+
+fn new_sync(state: &NodeState) -> BlockSync {
+    BlockSync::new(DIFFICULTY, state.sync_progress())
+}
+
+// The blocks each peer was asked for
+fn requested_blocks(outgoing: &[(u64, Message)], peer: u64) -> Vec<String> {
+    outgoing
+        .iter()
+        .filter(|(id, _)| *id == peer)
+        .flat_map(|(_, message)| match message {
+            Message::GetData(items) => items.clone(),
+            _ => Vec::new(),
+        })
+        .map(|item| match item {
+            InvItem::Block(hash) => hash,
+            InvItem::Tx(txid) => panic!("Asked for transaction {txid}"),
+        })
+        .collect()
+}
+
+#[test]
+fn peers_ahead_are_asked_for_headers() {
+    let state = state_with(&[]);
+    let mut sync = new_sync(&state);
+    let outgoing = sync.peer_connected(7, 10, Instant::now(), &state);
+    assert!(matches!(
+        outgoing.as_slice(),
+        [(7, Message::GetHeaders(locator))] if *locator == state.block_locator()
+    ));
+
+    // Nothing to ask one that's behind
+    let mut sync = new_sync(&state);
+    let tip = state.chain().get_last_index();
+    assert!(sync
+        .peer_connected(7, tip, Instant::now(), &state)
+        .is_empty());
+}
+
+#[test]
+fn headers_without_proof_of_work_are_rejected() {
+    let state = state_with(&[]);
+    let mut chain = Chain::new();
+    let mut unmined = Block::new(&mut chain, Vec::new());
+    while unmined.header().meets_difficulty(DIFFICULTY) {
+        unmined = Block::new(&mut chain, Vec::new());
+    }
+
+    let mut sync = new_sync(&state);
+    let now = Instant::now();
+    assert!(sync
+        .headers_received(1, vec![unmined.header()], now, &state)
+        .is_err());
+}
+
+#[test]
+fn headers_have_to_form_a_chain() {
+    let state = state_with(&[]);
+    let blocks = mined_blocks(3);
+    let mut sync = new_sync(&state);
+    let now = Instant::now();
+
+    let mut skipping = headers(&blocks);
+    skipping.remove(1);
+    assert!(sync.headers_received(1, skipping, now, &state).is_err());
+
+    let mut tampered = headers(&blocks);
+    tampered[2].nonce += 1;
+    assert!(sync.headers_received(1, tampered, now, &state).is_err());
+
+    assert!(sync
+        .headers_received(1, headers(&blocks), now, &state)
+        .is_ok());
+    assert_eq!(
+        state.sync_progress().best_header(),
+        blocks.last().unwrap().get_index()
+    );
+}
+
+#[test]
+fn bodies_are_downloaded_from_several_peers() {
+    let mut state = state_with(&[]);
+    let blocks = mined_blocks(2 * MAX_IN_FLIGHT_PER_PEER + 4);
+    let mut sync = new_sync(&state);
+    let now = Instant::now();
+    let tip = blocks.last().unwrap().get_index();
+    sync.peer_connected(1, tip, now, &state);
+    sync.peer_connected(2, tip, now, &state);
+
+    let outgoing = sync
+        .headers_received(1, headers(&blocks), now, &state)
+        .unwrap();
+    let (first, second) = (
+        requested_blocks(&outgoing, 1),
+        requested_blocks(&outgoing, 2),
+    );
+    assert_eq!(first.len(), MAX_IN_FLIGHT_PER_PEER);
+    assert_eq!(second.len(), MAX_IN_FLIGHT_PER_PEER);
+    assert!(first.iter().all(|hash| !second.contains(hash)));
+
+    // Out of order, the blocks wait for the ones before them
+    for block in blocks.iter().rev() {
+        if first.contains(&block.get_hash()) {
+            assert!(sync.block_received(1, block.clone()).is_none());
+        } else if second.contains(&block.get_hash()) {
+            assert!(sync.block_received(2, block.clone()).is_none());
+        }
+    }
+    sync.connect_blocks(&mut state, &Logger::new());
+    assert_eq!(
+        state.chain().get_last_index(),
+        blocks[2 * MAX_IN_FLIGHT_PER_PEER - 1].get_index()
+    );
+
+    // The rest are asked for now there's room
+    let outgoing = sync.schedule(now, &state);
+    let rest = [
+        requested_blocks(&outgoing, 1),
+        requested_blocks(&outgoing, 2),
+    ]
+    .concat();
+    assert_eq!(rest.len(), 4);
+    for block in &blocks[2 * MAX_IN_FLIGHT_PER_PEER..] {
+        assert!(sync.block_received(1, block.clone()).is_none());
+    }
+    sync.connect_blocks(&mut state, &Logger::new());
+    assert_eq!(state.chain().get_last_index(), tip);
+    assert!(!sync.is_syncing());
+}
+
+#[test]
+fn blocks_a_peer_doesnt_have_are_asked_to_another() {
+    let state = state_with(&[]);
+    let blocks = mined_blocks(1);
+    let mut sync = new_sync(&state);
+    let now = Instant::now();
+    let tip = blocks[0].get_index();
+    sync.peer_connected(1, tip, now, &state);
+    let outgoing = sync
+        .headers_received(1, headers(&blocks), now, &state)
+        .unwrap();
+    assert_eq!(requested_blocks(&outgoing, 1), vec![blocks[0].get_hash()]);
+
+    sync.peer_connected(2, tip, now, &state);
+    let outgoing = sync.not_found(1, &[InvItem::block(&blocks[0])], now, &state);
+    assert_eq!(requested_blocks(&outgoing, 2), vec![blocks[0].get_hash()]);
+}
+
+#[test]
+fn blocks_that_dont_validate_are_asked_to_another_peer() {
+    let mut state = state_with(&[]);
+    // The header checks out, but the block spends an output that doesn't exist
+    let mut chain = Chain::new();
+    let invalid = Block::new(&mut chain, vec![payment()]).mine_with_difficulty(DIFFICULTY);
+    let mut sync = new_sync(&state);
+    let now = Instant::now();
+    let tip = invalid.get_index();
+    sync.peer_connected(1, tip, now, &state);
+    sync.headers_received(1, vec![invalid.header()], now, &state)
+        .unwrap();
+    sync.peer_connected(2, tip, now, &state);
+
+    assert!(sync.block_received(1, invalid.clone()).is_none());
+    let (sender, _) = sync.connect_blocks(&mut state, &Logger::new()).unwrap();
+    assert_eq!(sender, 1);
+    // Only that block is asked again, the headers are kept
+    assert!(sync.is_syncing());
+    let outgoing = sync.schedule(now, &state);
+    assert_eq!(requested_blocks(&outgoing, 2), vec![invalid.get_hash()]);
+    assert!(requested_blocks(&outgoing, 1).is_empty());
+}
+
+fn wait_until(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while !condition() {
+        assert!(Instant::now() < deadline, "Timed out waiting until {what}");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn new_nodes_download_the_chain_from_their_peers() {
+    let blocks = mined_blocks(40);
+    let config = NetworkConfig {
+        listen: Some("127.0.0.1:0".parse().unwrap()),
+        pow_difficulty: DIFFICULTY,
+        ..Default::default()
+    };
+    let start = |state: NodeState, connect| {
+        let state = Arc::new(Mutex::new(state));
+        let config = NetworkConfig {
+            connect,
+            ..config.clone()
+        };
+        let network = Network::start(config, Arc::clone(&state), Arc::new(Logger::new())).unwrap();
+        (state, network)
+    };
+
+    let (_, first) = start(state_with(&blocks), Vec::new());
+    let (_, second) = start(state_with(&blocks), Vec::new());
+    let (fresh, fresh_network) = start(
+        state_with(&[]),
+        vec![first.local_addr().unwrap(), second.local_addr().unwrap()],
+    );
+
+    let tip = blocks.last().unwrap();
+    wait_until("the new node has every block", || {
+        fresh.lock().unwrap().chain().get_last_hash() == tip.get_hash()
+    });
+    let state = fresh.lock().unwrap();
+    assert_eq!(state.chain().blocks.len(), blocks.len() + 1);
+    assert_eq!(state.sync_progress().best_header(), tip.get_index());
+    assert_eq!(
+        state.utxo_set().commitment(),
+        state_with(&blocks).utxo_set().commitment()
+    );
+    drop(state);
+
+    for network in [first, second, fresh_network] {
+        network.stop();
+    }
+}
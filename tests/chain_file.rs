@@ -0,0 +1,113 @@
+mod common;
+
+use cleyto_coin::chain::block::Block;
+use cleyto_coin::chain::Chain;
+use cleyto_coin::error_handling::{CleytonError, TransactionError};
+use cleyto_coin::node::block_store::BlockStore;
+use cleyto_coin::node::chain_file::{export_chain, import_chain};
+use cleyto_coin::node::NodeState;
+use common::{empty_dir, payment};
+
+#[test]
+fn exported_chain_imports_into_another_node() {
+    let dir = empty_dir("export_chain");
+    let mut state = NodeState::load(BlockStore::open(&dir).unwrap()).unwrap();
+    state.set_pow_difficulty(0);
+    for _ in 0..4 {
+        let block = Block::test_block(state.chain());
+        state.accept_block(block).unwrap();
+    }
+    let tip = state.chain().get_last_hash();
+    drop(state);
+
+    let store = BlockStore::open(&dir).unwrap();
+    let mut first_half = Vec::new();
+    assert_eq!(export_chain(&store, 0, 2, &mut first_half).unwrap(), 3);
+    let mut second_half = Vec::new();
+    assert_eq!(export_chain(&store, 1, 4, &mut second_half).unwrap(), 4);
+    assert!(export_chain(&store, 0, 5, &mut Vec::new()).is_err());
+
+    // The genesis block and the overlap between the two files are already there
+    let mut other = NodeState::in_memory(Chain::new()).unwrap();
+    other.set_pow_difficulty(0);
+    assert_eq!(
+        import_chain(&mut other, &mut first_half.as_slice()).unwrap(),
+        2
+    );
+    assert_eq!(
+        import_chain(&mut other, &mut second_half.as_slice()).unwrap(),
+        2
+    );
+    assert_eq!(other.chain().get_last_hash(), tip);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn import_stops_at_blocks_that_dont_check_out() {
+    let mut chain = Chain::new();
+    let block = Block::test_block(&chain);
+    chain.add_block(block);
+    let block = Block::test_block(&chain);
+    chain.add_block(block);
+
+    let dir = empty_dir("import_invalid");
+    let mut store = BlockStore::open(&dir).unwrap();
+    for block in &chain.blocks {
+        store.append(block).unwrap();
+    }
+    let mut exported = Vec::new();
+    export_chain(&store, 0, 2, &mut exported).unwrap();
+
+    // Cut off in the middle of the last block
+    let mut state = NodeState::in_memory(Chain::new()).unwrap();
+    state.set_pow_difficulty(0);
+    let mut truncated = &exported[..exported.len() - 10];
+    assert!(import_chain(&mut state, &mut truncated).is_err());
+    assert_eq!(state.chain().blocks.len(), 2);
+
+    // Without the block in the middle, the last one doesn't fit
+    let mut state = NodeState::in_memory(Chain::new()).unwrap();
+    state.set_pow_difficulty(0);
+    let mut gap = Vec::new();
+    export_chain(&store, 2, 2, &mut gap).unwrap();
+    assert!(import_chain(&mut state, &mut gap.as_slice()).is_err());
+    assert_eq!(state.chain().blocks.len(), 1);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn import_rejects_blocks_that_spend_outputs_that_dont_exist() {
+    // Stored without being checked, the way a file from anywhere could have it
+    let mut chain = Chain::new();
+    let block = Block::test_block(&chain);
+    chain.add_block(block);
+    let block = Block::new(&mut chain, vec![payment()]);
+    chain.add_block(block);
+    let block = Block::test_block(&chain);
+    chain.add_block(block);
+
+    let dir = empty_dir("import_bad_spend");
+    let mut store = BlockStore::open(&dir).unwrap();
+    for block in &chain.blocks {
+        store.append(block).unwrap();
+    }
+    let mut exported = Vec::new();
+    export_chain(&store, 0, 3, &mut exported).unwrap();
+
+    let mut state = NodeState::in_memory(Chain::new()).unwrap();
+    state.set_pow_difficulty(0);
+    assert!(matches!(
+        import_chain(&mut state, &mut exported.as_slice()),
+        Err(CleytonError::TransactionError(
+            TransactionError::UnknownInput
+        ))
+    ));
+    // The block before it stays, the bad one and the ones after it don't. Only the coinbase of the
+    // first one is unspent
+    assert_eq!(state.chain().get_last_hash(), chain.blocks[1].get_hash());
+    assert_eq!(state.utxo_set().len(), 1);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,66 @@
+// Helpers shared by the integration tests. Each test file only uses some of them
+#![allow(dead_code)]
+
+use cleyto_coin::chain::block::Block;
+use cleyto_coin::chain::transaction::{Transaction, TransactionInfo};
+use cleyto_coin::chain::utxo::UTXO;
+use cleyto_coin::chain::wallet::Wallet;
+use cleyto_coin::chain::Chain;
+use cleyto_coin::node::NodeState;
+use std::path::PathBuf;
+
+/// A directory in the temp dir that exists and is empty, with the name and the id of the test
+/// process in it so runs don't step on each other
+pub fn empty_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cleyto_coin_{name}_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A signed payment of 99 with a fee of 1, between two new wallets. Nothing created its input,
+/// so no block with it is valid
+pub fn payment() -> Transaction {
+    let (sender, sender_pk) = Wallet::new();
+    let (receiver, _) = Wallet::new();
+    let info = TransactionInfo::new(vec![UTXO::new(100, sender)], vec![UTXO::new(99, receiver)]);
+    let signatures = sender_pk.sign_transaction(&info).unwrap();
+    Transaction::new(info, signatures).unwrap()
+}
+
+/// The block with `transactions` on top of the node's chain. It isn't mined
+pub fn next_block(state: &NodeState, transactions: Vec<Transaction>) -> Block {
+    // Block::new only looks at the tip, so a chain of just the tip is enough to build on
+    let mut tip = Chain {
+        blocks: state.chain().blocks.last().cloned().into_iter().collect(),
+    };
+    Block::new(&mut tip, transactions)
+}
+
+/// A block on top of the node's chain whose coinbase pays 100 `N` times to a new wallet, and that
+/// many signed payments of 99 with a fee of 1, each spending one of those outputs. The block isn't
+/// mined
+pub fn funded_payments<const N: usize>(state: &NodeState) -> (Block, [Transaction; N]) {
+    let (sender, sender_pk) = Wallet::new();
+    let funding = vec![UTXO::new(100, sender.clone()); N];
+    let height = state.chain().get_last_index() + 1;
+    let block = next_block(state, vec![Transaction::coinbase(height, funding)]);
+
+    let payments = std::array::from_fn(|_| {
+        let (receiver, _) = Wallet::new();
+        let info = TransactionInfo::new(
+            vec![UTXO::new(100, sender.clone())],
+            vec![UTXO::new(99, receiver)],
+        );
+        let signatures = sender_pk.sign_transaction(&info).unwrap();
+        Transaction::new(info, signatures).unwrap()
+    });
+    (block, payments)
+}
+
+/// Has the node accept the block funding the payments from `funded_payments`, and returns them
+pub fn fund_payments<const N: usize>(state: &mut NodeState) -> [Transaction; N] {
+    let (funding, payments) = funded_payments(state);
+    state.accept_block(funding).unwrap();
+    payments
+}
@@ -0,0 +1,242 @@
+mod common;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cleyto_coin::chain::block::Block;
+use cleyto_coin::chain::transaction::Transaction;
+use cleyto_coin::chain::Chain;
+use cleyto_coin::node::logger::Logger;
+use cleyto_coin::node::p2p::compact::{
+    BlockTransactions, BlockTxnRequest, CompactBlock, PartialBlock,
+};
+use cleyto_coin::node::p2p::message::{FrameDecoder, Message, Version};
+use cleyto_coin::node::p2p::peer::PROTOCOL_VERSION;
+use cleyto_coin::node::p2p::{Network, NetworkConfig};
+use cleyto_coin::node::NodeState;
+use common::{fund_payments, next_block, payment};
+
+fn block_of(chain: &mut Chain, transactions: &[Transaction]) -> Block {
+    Block::new(chain, transactions.to_vec())
+}
+
+#[test]
+fn blocks_are_rebuilt_from_the_mempool() {
+    let transactions = [payment(), payment(), payment()];
+    let block = block_of(&mut Chain::new(), &transactions);
+    let compact = CompactBlock::new(&block, |_| true);
+    assert!(compact.prefilled.is_empty());
+    assert_eq!(compact.short_ids.len(), 3);
+
+    // In another order, and with transactions that aren't in the block
+    let mempool = vec![
+        payment(),
+        transactions[2].clone(),
+        transactions[0].clone(),
+        transactions[1].clone(),
+    ];
+    let partial = PartialBlock::new(compact, &mempool).unwrap();
+    assert!(partial.missing().is_empty());
+    assert_eq!(partial.into_block().unwrap().get_hash(), block.get_hash());
+}
+
+#[test]
+fn missing_transactions_are_asked_for_and_filled_in() {
+    let transactions = [payment(), payment(), payment()];
+    let block = block_of(&mut Chain::new(), &transactions);
+    let compact = CompactBlock::new(&block, |_| true);
+
+    let mut partial = PartialBlock::new(compact, &[transactions[1].clone()]).unwrap();
+    assert_eq!(partial.missing(), vec![0, 2]);
+    assert!(partial.fill(vec![transactions[0].clone()]).is_err());
+    partial
+        .fill(vec![transactions[0].clone(), transactions[2].clone()])
+        .unwrap();
+    assert_eq!(partial.into_block().unwrap().get_hash(), block.get_hash());
+}
+
+#[test]
+fn transactions_the_peer_may_lack_are_sent_whole() {
+    let transactions = [payment(), payment(), payment()];
+    let block = block_of(&mut Chain::new(), &transactions);
+    let first = transactions[0].txid;
+    let compact = CompactBlock::new(&block, |transaction| transaction.txid == first);
+    let prefilled: Vec<u32> = compact.prefilled.iter().map(|tx| tx.index).collect();
+    assert_eq!(prefilled, vec![1, 2]);
+
+    let partial = PartialBlock::new(compact, &[]).unwrap();
+    assert_eq!(partial.missing(), vec![0]);
+}
+
+#[test]
+fn wrong_transactions_dont_rebuild_the_block() {
+    let transactions = [payment(), payment()];
+    let block = block_of(&mut Chain::new(), &transactions);
+    let compact = CompactBlock::new(&block, |_| true);
+
+    let mut partial = PartialBlock::new(compact, &[transactions[0].clone()]).unwrap();
+    partial.fill(vec![payment()]).unwrap();
+    assert!(partial.into_block().is_err());
+}
+
+#[test]
+fn nonsense_compact_blocks_are_refused() {
+    let transactions = [payment(), payment()];
+    let block = block_of(&mut Chain::new(), &transactions);
+
+    let mut out_of_the_block = CompactBlock::new(&block, |_| false);
+    out_of_the_block.prefilled[1].index = 7;
+    assert!(PartialBlock::new(out_of_the_block, &[]).is_err());
+
+    let mut repeated = CompactBlock::new(&block, |_| true);
+    repeated.short_ids[1] = repeated.short_ids[0];
+    assert!(PartialBlock::new(repeated, &[]).is_err());
+}
+
+fn start_node() -> (Arc<Mutex<NodeState>>, Arc<Network>) {
+    let state = Arc::new(Mutex::new(NodeState::in_memory(Chain::new()).unwrap()));
+    let config = NetworkConfig {
+        listen: Some("127.0.0.1:0".parse().unwrap()),
+        // Test blocks aren't mined
+        pow_difficulty: 0,
+        target_outbound: 0,
+        ..Default::default()
+    };
+    let network = Network::start(config, Arc::clone(&state), Arc::new(Logger::new())).unwrap();
+    (state, network)
+}
+
+fn wait_until(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "Timed out waiting until {what}");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// A connection that went through the handshake and asked for compact blocks
+fn compact_peer(network: &Network) -> TcpStream {
+    let mut stream = TcpStream::connect(network.local_addr().unwrap()).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    let version = Version {
+        protocol_version: PROTOCOL_VERSION,
+        best_height: 1,
+        nonce: rand::random(),
+        listen_port: None,
+        user_agent: "test".to_string(),
+    };
+    for message in [
+        Message::Version(version),
+        Message::Verack,
+        Message::SendCmpct,
+    ] {
+        stream.write_all(&message.encode()).unwrap();
+    }
+    stream
+}
+
+// Reads from the node until it sends a message `wanted` picks, skipping the rest
+fn receive<T>(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+    wanted: impl Fn(Message) -> Option<T>,
+) -> T {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        while let Some(message) = decoder.next_message().unwrap() {
+            if let Some(found) = wanted(message) {
+                return found;
+            }
+        }
+        assert!(Instant::now() < deadline, "Timed out waiting for a message");
+        match stream.read(&mut buffer) {
+            Ok(0) => panic!("The node closed the connection"),
+            Ok(n) => decoder.push(&buffer[..n]),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => panic!("{e}"),
+        }
+    }
+}
+
+#[test]
+fn new_blocks_go_to_peers_that_ask_as_compact_blocks() {
+    let (state, network) = start_node();
+    let [announced, unannounced] = fund_payments(&mut state.lock().unwrap());
+    let mut stream = compact_peer(&network);
+    let mut decoder = FrameDecoder::new();
+    wait_until("the handshake is done", || network.peers().len() == 1);
+
+    state
+        .lock()
+        .unwrap()
+        .add_transaction(announced.clone())
+        .unwrap();
+    receive(&mut stream, &mut decoder, |message| match message {
+        Message::Inv(items) => Some(items),
+        _ => None,
+    });
+
+    let block = next_block(&state.lock().unwrap(), vec![announced, unannounced.clone()]);
+    let hash = block.get_hash();
+    state.lock().unwrap().accept_block(block).unwrap();
+
+    let compact = receive(&mut stream, &mut decoder, |message| match message {
+        Message::CmpctBlock(compact) => Some(compact),
+        _ => None,
+    });
+    assert_eq!(compact.header.hash, hash);
+    assert_eq!(compact.short_ids.len(), 1);
+    assert_eq!(compact.prefilled.len(), 1);
+    assert_eq!(compact.prefilled[0].transaction.txid, unannounced.txid);
+    network.stop();
+}
+
+#[test]
+fn compact_blocks_missing_transactions_are_completed_by_the_peer() {
+    let (state, network) = start_node();
+    let [pooled, missing] = fund_payments(&mut state.lock().unwrap());
+    let mut stream = compact_peer(&network);
+    let mut decoder = FrameDecoder::new();
+    wait_until("the handshake is done", || network.peers().len() == 1);
+
+    state
+        .lock()
+        .unwrap()
+        .add_transaction(pooled.clone())
+        .unwrap();
+    let block = next_block(&state.lock().unwrap(), vec![pooled, missing.clone()]);
+    let hash = block.get_hash();
+    let compact = CompactBlock::new(&block, |_| true);
+    stream
+        .write_all(&Message::CmpctBlock(Box::new(compact)).encode())
+        .unwrap();
+
+    let request = receive(&mut stream, &mut decoder, |message| match message {
+        Message::GetBlockTxn(request) => Some(request),
+        _ => None,
+    });
+    assert_eq!(
+        request,
+        BlockTxnRequest {
+            block_hash: hash.clone(),
+            indexes: vec![1],
+        }
+    );
+    let response = BlockTransactions {
+        block_hash: hash.clone(),
+        transactions: vec![missing],
+    };
+    stream
+        .write_all(&Message::BlockTxn(Box::new(response)).encode())
+        .unwrap();
+    wait_until("the block is accepted", || {
+        state.lock().unwrap().has_block(&hash)
+    });
+    network.stop();
+}
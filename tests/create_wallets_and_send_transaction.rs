@@ -1,38 +1,57 @@
+use std::io::{Read, Write};
+use std::net::TcpListener;
 use std::path::PathBuf;
+use std::thread;
 
-use cleyto_coin::{generate, kill_node, new_server_name, run_server_thread, send};
+use cleyto_coin::{
+    chain::key_type::KeyType, error_handling::TransactionError, generate, kill_node,
+    new_server_name, run_server_thread, send, DEFAULT_NODE_URL,
+};
 
-const SENDER_PUBLIC_KEY_PATH: &str = "./wallets/sender/public.pem";
-const SENDER_PRIVATE_KEY_PATH: &str = "./wallets/sender/private.pem";
+const SENDER_PUBLIC_KEY_PATH: &str = "sender/public.pem";
+const SENDER_PRIVATE_KEY_PATH: &str = "sender/private.pem";
 const SENDER_PASSWORD: &str = "palmeiras";
 
-const RECEIVER_PUBLIC_KEY_PATH: &str = "./wallets/receiver/public.pem";
-const RECEIVER_PRIVATE_KEY_PATH: &str = "./wallets/receiver/private.pem";
+const RECEIVER_PUBLIC_KEY_PATH: &str = "receiver/public.pem";
+const RECEIVER_PRIVATE_KEY_PATH: &str = "receiver/private.pem";
+
+// The wallets go to a temporary directory, not over the ones in the repo
+fn wallet_file(path: &str) -> PathBuf {
+    std::env::temp_dir()
+        .join(format!("cleyto_coin_wallets_{}", std::process::id()))
+        .join(path)
+}
 
 #[test]
 fn test_wallet_creation() {
-    let sender_private_key_file = PathBuf::from(SENDER_PRIVATE_KEY_PATH);
-    let sender_public_key_file = PathBuf::from(SENDER_PUBLIC_KEY_PATH);
+    let sender_private_key_file = wallet_file(SENDER_PRIVATE_KEY_PATH);
+    let sender_public_key_file = wallet_file(SENDER_PUBLIC_KEY_PATH);
     let sender_password = Some(String::from(SENDER_PASSWORD));
     generate(
         &sender_private_key_file,
         &sender_public_key_file,
         &sender_password,
+        KeyType::Rsa,
     );
 
-    let receiver_private_key_file = PathBuf::from(RECEIVER_PRIVATE_KEY_PATH);
-    let receiver_public_key_file = PathBuf::from(RECEIVER_PUBLIC_KEY_PATH);
-    generate(&receiver_private_key_file, &receiver_public_key_file, &None);
+    let receiver_private_key_file = wallet_file(RECEIVER_PRIVATE_KEY_PATH);
+    let receiver_public_key_file = wallet_file(RECEIVER_PUBLIC_KEY_PATH);
+    generate(
+        &receiver_private_key_file,
+        &receiver_public_key_file,
+        &None,
+        KeyType::Ed25519,
+    );
 }
 
 #[tokio::test]
 #[ignore = "Failing because of insufficient funds, which is correct. I'm not sure how to test it though lol"]
 async fn test_send_transaction() {
     test_wallet_creation();
-    let sender_private_key_file = PathBuf::from(SENDER_PRIVATE_KEY_PATH);
+    let sender_private_key_file = wallet_file(SENDER_PRIVATE_KEY_PATH);
     let sender_password = Some(String::from(SENDER_PASSWORD));
 
-    let receiver_public_key_file = PathBuf::from(RECEIVER_PUBLIC_KEY_PATH);
+    let receiver_public_key_file = wallet_file(RECEIVER_PUBLIC_KEY_PATH);
 
     let server_name = new_server_name();
     run_server_thread(server_name.clone());
@@ -44,9 +63,38 @@ async fn test_send_transaction() {
         Some(sender_private_key_file),
         sender_password,
         100,
+        DEFAULT_NODE_URL,
     )
     .await
     .unwrap();
 
     kill_node(server_name).unwrap();
 }
+
+#[tokio::test]
+async fn sending_through_a_node_without_an_address_index_says_it_needs_one() {
+    let private_key_file = wallet_file("no_addrindex/private.pem");
+    let public_key_file = wallet_file("no_addrindex/public.pem");
+    generate(&private_key_file, &public_key_file, &None, KeyType::Ed25519);
+
+    // Answers like a node started without --addrindex
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let node_url = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let _ = stream.read(&mut [0; 1024]);
+        let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n");
+    });
+
+    let sent = send(
+        None,
+        Some(public_key_file),
+        None,
+        Some(private_key_file),
+        None,
+        100,
+        &node_url,
+    )
+    .await;
+    assert!(matches!(sent, Err(TransactionError::AddressIndexRequired)));
+}
@@ -0,0 +1,30 @@
+use cleyto_coin::node::{Node, NodeOptions};
+use cleyto_coin::ConfigPaths;
+
+#[test]
+fn every_path_comes_from_the_data_dir() {
+    let dir = std::env::temp_dir().join(format!("cleyto_coin_datadir_{}", std::process::id()));
+    let paths = ConfigPaths::new(&dir);
+
+    assert_eq!(paths.data_dir(), dir.as_path());
+    assert!(paths.socket_path("Canada").starts_with(&dir));
+}
+
+#[test]
+fn nodes_with_different_data_dirs_dont_share_blocks() {
+    let base = std::env::temp_dir().join(format!("cleyto_coin_two_nodes_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&base);
+
+    for name in ["first", "second"] {
+        let paths = ConfigPaths::new(base.join(name));
+        let (node, _) = Node::open(name.to_string(), paths, NodeOptions::default()).unwrap();
+        assert!(node.socket_location.starts_with(base.join(name)));
+        drop(node);
+
+        // Each one got its own block store, and its logs went next to it
+        assert!(base.join(name).join("blocks.dat").exists());
+        assert!(base.join(name).join("logs.log").exists());
+    }
+
+    std::fs::remove_dir_all(base).unwrap();
+}
@@ -0,0 +1,38 @@
+mod common;
+
+use std::path::Path;
+
+use cleyto_coin::node::devnet;
+use cleyto_coin::ConfigPaths;
+use common::empty_dir;
+
+#[test]
+fn nodes_get_consecutive_ports_and_their_own_data_dirs() {
+    let nodes = devnet::layout(Path::new("devnet"), 3, 9473, None).unwrap();
+    let ports: Vec<(u16, u16)> = nodes
+        .iter()
+        .map(|node| (node.port, node.p2p_port))
+        .collect();
+    assert_eq!(ports, vec![(9473, 9476), (9474, 9477), (9475, 9478)]);
+    assert_eq!(nodes[1].name, "devnet-2");
+    assert_eq!(nodes[1].data_dir, Path::new("devnet").join("devnet-2"));
+
+    let nodes = devnet::layout(Path::new("devnet"), 2, 8000, Some(9000)).unwrap();
+    assert_eq!(nodes[1].p2p_port, 9001);
+}
+
+#[test]
+fn impossible_layouts_are_refused() {
+    let dir = Path::new("devnet");
+    assert!(devnet::layout(dir, 0, 9473, None).is_err());
+    assert!(devnet::layout(dir, 3, 65534, None).is_err());
+    assert!(devnet::layout(dir, 3, 9473, Some(9475)).is_err());
+}
+
+#[test]
+fn stopping_without_a_devnet_does_nothing() {
+    let paths = ConfigPaths::new(empty_dir("devnet_down"));
+    assert!(devnet::running(&paths).unwrap().is_none());
+    assert!(devnet::down(&paths, false).unwrap().is_empty());
+    assert!(devnet::down(&paths, true).unwrap().is_empty());
+}
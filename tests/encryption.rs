@@ -0,0 +1,247 @@
+mod common;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cleyto_coin::chain::Chain;
+use cleyto_coin::node::logger::Logger;
+use cleyto_coin::node::p2p::errors::P2PError;
+use cleyto_coin::node::p2p::identity::parse_public_key;
+use cleyto_coin::node::p2p::network::EncryptionConfig;
+use cleyto_coin::node::p2p::noise::{self, Handshake, NoiseDecoder, Transport};
+use cleyto_coin::node::p2p::{Network, NetworkConfig, NodeIdentity};
+use cleyto_coin::node::NodeState;
+use common::{empty_dir, fund_payments};
+
+// Runs the three messages of the handshake, passing each through `tamper` on the way
+fn handshake_with(
+    initiator: &NodeIdentity,
+    responder: &NodeIdentity,
+    tamper: impl Fn(usize, &mut Vec<u8>),
+) -> Result<(Transport, Transport), P2PError> {
+    let mut sides = [
+        Handshake::initiator(initiator),
+        Handshake::responder(responder),
+    ];
+    for step in 0..3 {
+        let (writer, reader) = if step % 2 == 0 { (0, 1) } else { (1, 0) };
+        assert!(sides[writer].is_our_turn() && !sides[reader].is_our_turn());
+        let mut message = sides[writer].write_message()?;
+        tamper(step, &mut message);
+        sides[reader].read_message(&message)?;
+    }
+    let [initiator, responder] = sides;
+    assert!(initiator.is_finished() && responder.is_finished());
+    Ok((initiator.into_transport()?, responder.into_transport()?))
+}
+
+#[test]
+fn the_handshake_tells_each_side_the_key_of_the_other() {
+    let (a, b) = (NodeIdentity::generate(), NodeIdentity::generate());
+    let (mut initiator, mut responder) = handshake_with(&a, &b, |_, _| {}).unwrap();
+    assert_eq!(initiator.remote_key, b.public_key());
+    assert_eq!(responder.remote_key, a.public_key());
+
+    let sealed = initiator.send.encrypt(&[], b"hello");
+    assert_ne!(&sealed[..5], b"hello");
+    assert_eq!(responder.receive.decrypt(&[], &sealed).unwrap(), b"hello");
+    let reply = responder.send.encrypt(&[], b"hi");
+    assert_eq!(initiator.receive.decrypt(&[], &reply).unwrap(), b"hi");
+}
+
+#[test]
+fn tampered_handshakes_fail() {
+    let (a, b) = (NodeIdentity::generate(), NodeIdentity::generate());
+    for step in 0..3 {
+        let result = handshake_with(&a, &b, |at, message| {
+            if at == step {
+                let last = message.len() - 1;
+                message[last] ^= 1;
+            }
+        });
+        assert!(
+            matches!(result, Err(P2PError::Handshake(_))),
+            "Message {step} was tampered with"
+        );
+    }
+}
+
+#[test]
+fn frames_bigger_than_a_transport_message_are_split_and_put_back_together() {
+    let (a, b) = (NodeIdentity::generate(), NodeIdentity::generate());
+    let (mut initiator, responder) = handshake_with(&a, &b, |_, _| {}).unwrap();
+    let frame: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+    let sealed = noise::seal(&mut initiator.send, &frame);
+    assert!(sealed.len() > frame.len());
+
+    let mut decoder = NoiseDecoder::new(responder.receive);
+    let mut received = Vec::new();
+    for chunk in sealed.chunks(1000) {
+        received.extend(decoder.decrypt(chunk).unwrap());
+    }
+    assert_eq!(received, frame);
+}
+
+#[test]
+fn tampered_transport_messages_fail() {
+    let (a, b) = (NodeIdentity::generate(), NodeIdentity::generate());
+    let (mut initiator, responder) = handshake_with(&a, &b, |_, _| {}).unwrap();
+    let mut sealed = noise::seal(&mut initiator.send, b"some frame");
+    sealed[5] ^= 1;
+    let mut decoder = NoiseDecoder::new(responder.receive);
+    assert!(matches!(
+        decoder.decrypt(&sealed),
+        Err(P2PError::DecryptionFailed)
+    ));
+}
+
+#[test]
+fn the_node_key_is_kept_across_restarts() {
+    let path = empty_dir("node_key").join("node_key.pem");
+    let created = NodeIdentity::open_or_create(&path).unwrap();
+    let reopened = NodeIdentity::open_or_create(&path).unwrap();
+    assert_eq!(created.public_key(), reopened.public_key());
+
+    let printed = hex::encode(created.public_key());
+    assert_eq!(parse_public_key(&printed), Some(created.public_key()));
+    assert_eq!(parse_public_key("abcd"), None);
+    assert!(!format!("{created:?}").contains("PRIVATE"));
+}
+
+#[cfg(unix)]
+#[test]
+fn only_the_owner_can_read_the_node_key() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = empty_dir("node_key_mode").join("node_key.pem");
+    NodeIdentity::open_or_create(&path).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+fn start_node(config: NetworkConfig) -> (Arc<Mutex<NodeState>>, Arc<Network>) {
+    let state = Arc::new(Mutex::new(NodeState::in_memory(Chain::new()).unwrap()));
+    let network = Network::start(config, Arc::clone(&state), Arc::new(Logger::new())).unwrap();
+    (state, network)
+}
+
+fn wait_until(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "Timed out waiting until {what}");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn listening_on_localhost(encryption: Option<EncryptionConfig>) -> NetworkConfig {
+    NetworkConfig {
+        listen: Some("127.0.0.1:0".parse().unwrap()),
+        pow_difficulty: 0,
+        target_outbound: 0,
+        encryption,
+        ..Default::default()
+    }
+}
+
+fn encrypting(pinned: HashMap<SocketAddr, [u8; 32]>) -> EncryptionConfig {
+    let mut encryption = EncryptionConfig::new(NodeIdentity::generate());
+    encryption.encrypt_outbound = true;
+    encryption.pinned = pinned;
+    encryption
+}
+
+fn connection_failed(network: &Network, address: SocketAddr) -> bool {
+    network
+        .known_addresses()
+        .iter()
+        .any(|known| known.address == address && known.failures > 0)
+}
+
+#[test]
+fn encrypted_peers_know_each_others_key_and_relay() {
+    let (a, a_network) = start_node(listening_on_localhost(Some(EncryptionConfig::new(
+        NodeIdentity::generate(),
+    ))));
+    let a_address = a_network.local_addr().unwrap();
+    let a_key = a_network.identity_key().unwrap();
+    let [transaction] = fund_payments(&mut a.lock().unwrap());
+    let funding = a.lock().unwrap().chain().get_last_hash();
+    let (b, b_network) = start_node(NetworkConfig {
+        connect: vec![a_address],
+        ..listening_on_localhost(Some(encrypting(HashMap::from([(a_address, a_key)]))))
+    });
+    wait_until("the nodes are connected", || {
+        a_network.peers().len() == 1 && b_network.peers().len() == 1
+    });
+    wait_until("the funding block is downloaded", || {
+        b.lock().unwrap().has_block(&funding)
+    });
+    assert_eq!(b_network.peers().list()[0].key, Some(a_key));
+    assert_eq!(a_network.peers().list()[0].key, b_network.identity_key());
+
+    a.lock()
+        .unwrap()
+        .add_transaction(transaction.clone())
+        .unwrap();
+    wait_until("the transaction is relayed", || {
+        b.lock()
+            .unwrap()
+            .transactions_pool()
+            .iter()
+            .any(|pooled| pooled.txid == transaction.txid)
+    });
+
+    for network in [a_network, b_network] {
+        network.stop();
+    }
+}
+
+#[test]
+fn peers_with_another_key_than_the_pinned_one_are_dropped() {
+    let (_, a_network) = start_node(listening_on_localhost(Some(EncryptionConfig::new(
+        NodeIdentity::generate(),
+    ))));
+    let a_address = a_network.local_addr().unwrap();
+    let other_key = NodeIdentity::generate().public_key();
+    let (_, b_network) = start_node(NetworkConfig {
+        connect: vec![a_address],
+        ..listening_on_localhost(Some(encrypting(HashMap::from([(a_address, other_key)]))))
+    });
+    wait_until("the connection fails", || {
+        connection_failed(&b_network, a_address)
+    });
+    assert!(b_network.peers().is_empty());
+
+    for network in [a_network, b_network] {
+        network.stop();
+    }
+}
+
+#[test]
+fn plaintext_peers_are_refused_only_when_encryption_is_required() {
+    let mut required = EncryptionConfig::new(NodeIdentity::generate());
+    required.required = true;
+    let (_, strict) = start_node(listening_on_localhost(Some(required)));
+    let (_, lenient) = start_node(listening_on_localhost(Some(EncryptionConfig::new(
+        NodeIdentity::generate(),
+    ))));
+    let strict_address = strict.local_addr().unwrap();
+    let (_, plaintext) = start_node(NetworkConfig {
+        connect: vec![strict_address, lenient.local_addr().unwrap()],
+        ..listening_on_localhost(None)
+    });
+
+    wait_until("the lenient node accepts the plaintext one", || {
+        lenient.peers().len() == 1 && connection_failed(&plaintext, strict_address)
+    });
+    assert_eq!(plaintext.peers().len(), 1);
+    assert!(strict.peers().is_empty());
+    assert_eq!(lenient.peers().list()[0].key, None);
+
+    for network in [strict, lenient, plaintext] {
+        network.stop();
+    }
+}
@@ -0,0 +1,122 @@
+use cleyto_coin::chain::key_type::{is_low_s, KeyType};
+use cleyto_coin::chain::transaction::{Transaction, TransactionInfo};
+use cleyto_coin::chain::utxo::UTXO;
+use cleyto_coin::chain::wallet::Wallet;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::EcGroup;
+use openssl::ecdsa::EcdsaSig;
+use openssl::nid::Nid;
+
+const KEY_TYPES: [KeyType; 3] = [KeyType::Rsa, KeyType::Secp256k1, KeyType::Ed25519];
+
+#[test]
+fn sign_and_verify_with_every_key_type() {
+    for key_type in KEY_TYPES {
+        let (sender, sender_pk) = Wallet::new_with_key_type(key_type);
+        let (receiver, _) = Wallet::new_with_key_type(key_type);
+        assert_eq!(sender.key_type(), Some(key_type));
+        assert_eq!(sender_pk.public_wallet(), sender);
+
+        let transaction_info = TransactionInfo::new(
+            vec![
+                UTXO::new(1000, sender.clone()),
+                UTXO::new(500, sender.clone()),
+            ],
+            vec![UTXO::new(1500, receiver.clone())],
+        );
+        let signatures = sender_pk.sign_transaction(&transaction_info).unwrap();
+        println!(
+            "{key_type} signature is {} bytes long",
+            signatures[0].signature.len()
+        );
+
+        Transaction::new(transaction_info, signatures).unwrap();
+    }
+}
+
+#[test]
+fn secp256k1_signatures_with_the_other_s_are_rejected() {
+    let (sender, sender_pk) = Wallet::new_with_key_type(KeyType::Secp256k1);
+    let transaction_info = TransactionInfo::new(
+        vec![UTXO::new(1000, sender.clone())],
+        vec![UTXO::new(1000, sender.clone())],
+    );
+    let mut signatures = sender_pk.sign_transaction(&transaction_info).unwrap();
+    assert!(is_low_s(&signatures[0].signature));
+
+    // (r, n - s) checks out as plain ECDSA, but would give the transaction another txid
+    let signature = EcdsaSig::from_der(&signatures[0].signature).unwrap();
+    let group = EcGroup::from_curve_name(Nid::SECP256K1).unwrap();
+    let mut order = BigNum::new().unwrap();
+    group
+        .order(&mut order, &mut BigNumContext::new().unwrap())
+        .unwrap();
+    let mut high_s = BigNum::new().unwrap();
+    high_s.checked_sub(&order, signature.s()).unwrap();
+    let flipped = EcdsaSig::from_private_components(signature.r().to_owned().unwrap(), high_s);
+    signatures[0].signature = flipped.unwrap().to_der().unwrap();
+
+    assert!(!is_low_s(&signatures[0].signature));
+    assert!(Transaction::new(transaction_info, signatures).is_err());
+}
+
+#[test]
+fn compact_public_keys_round_trip() {
+    for key_type in KEY_TYPES {
+        let (wallet, _) = Wallet::new_with_key_type(key_type);
+        let compact = wallet.to_compact_bytes();
+        println!("{key_type} public key is {} bytes long", compact.len());
+
+        match key_type {
+            KeyType::Secp256k1 => assert_eq!(compact.len(), 34),
+            KeyType::Ed25519 => assert_eq!(compact.len(), 33),
+            KeyType::Rsa => assert!(compact.starts_with(b"-----BEGIN")),
+        }
+
+        let serialized = serde_json::to_string(&wallet).unwrap();
+        let deserialized: Wallet = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, wallet);
+        assert_eq!(deserialized.address(), wallet.address());
+    }
+}
+
+#[test]
+fn old_transaction_version_only_allows_rsa() {
+    let (rsa_wallet, rsa_pk) = Wallet::new_with_key_type(KeyType::Rsa);
+    let (ec_wallet, ec_pk) = Wallet::new_with_key_type(KeyType::Secp256k1);
+
+    // Version 1 transactions between RSA keys are still valid
+    let mut transaction_info = TransactionInfo::new(
+        vec![UTXO::new(1000, rsa_wallet.clone())],
+        vec![UTXO::new(1000, rsa_wallet.clone())],
+    );
+    transaction_info.version = 1;
+    let signatures = rsa_pk.sign_transaction(&transaction_info).unwrap();
+    Transaction::new(transaction_info, signatures).unwrap();
+
+    // But they can't have elliptic curve keys
+    let mut transaction_info = TransactionInfo::new(
+        vec![UTXO::new(1000, ec_wallet.clone())],
+        vec![UTXO::new(1000, rsa_wallet.clone())],
+    );
+    transaction_info.version = 1;
+    let signatures = ec_pk.sign_transaction(&transaction_info).unwrap();
+    assert!(Transaction::new(transaction_info, signatures).is_err());
+
+    // Old RSA outputs can be spent to elliptic curve keys by the current version
+    let transaction_info = TransactionInfo::new(
+        vec![UTXO::new(1000, rsa_wallet.clone())],
+        vec![UTXO::new(1000, ec_wallet.clone())],
+    );
+    let signatures = rsa_pk.sign_transaction(&transaction_info).unwrap();
+    Transaction::new(transaction_info, signatures).unwrap();
+
+    // And versions from the future are refused
+    let mut transaction_info = TransactionInfo::new(
+        vec![UTXO::new(1000, ec_wallet.clone())],
+        vec![UTXO::new(1000, ec_wallet)],
+    );
+    transaction_info.version = 1000;
+    let signatures = ec_pk.sign_transaction(&transaction_info).unwrap();
+    assert!(Transaction::new(transaction_info, signatures).is_err());
+}
@@ -0,0 +1,248 @@
+mod common;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use cleyto_coin::chain::block::Block;
+use cleyto_coin::chain::Chain;
+use cleyto_coin::node::logger::Logger;
+use cleyto_coin::node::p2p::errors::P2PError;
+use cleyto_coin::node::p2p::message::{Message, Version, HEADER_LEN};
+use cleyto_coin::node::p2p::misbehaviour::BAN_THRESHOLD;
+use cleyto_coin::node::p2p::peer::PROTOCOL_VERSION;
+use cleyto_coin::node::p2p::{BanList, Misbehaviour, Network, NetworkConfig};
+use cleyto_coin::node::NodeState;
+use common::{empty_dir, payment};
+
+fn localhost() -> IpAddr {
+    "127.0.0.1".parse().unwrap()
+}
+
+#[test]
+fn bans_are_saved_and_expire() {
+    let path = empty_dir("ban_list").join("bans.json");
+    let mut bans = BanList::open(&path).unwrap();
+    let now = Utc::now();
+    let other: IpAddr = "10.0.0.7".parse().unwrap();
+
+    bans.ban(localhost(), now + chrono::Duration::hours(1), "spam");
+    // A shorter ban doesn't cut short the one there is
+    bans.ban(localhost(), now + chrono::Duration::minutes(1), "more spam");
+    bans.ban(other, now + chrono::Duration::minutes(1), "spam");
+    bans.save().unwrap();
+
+    let mut reopened = BanList::open(&path).unwrap();
+    assert!(reopened.is_banned(&localhost(), now));
+    assert_eq!(reopened.list(now)[1].reason, "spam");
+    assert_eq!(reopened.list(now + chrono::Duration::minutes(2)).len(), 1);
+    assert!(!reopened.is_banned(&other, now));
+
+    assert!(reopened.unban(&localhost()));
+    assert!(!reopened.unban(&localhost()));
+    assert!(!reopened.is_banned(&localhost(), now));
+}
+
+#[test]
+fn only_errors_of_the_peer_count() {
+    assert!(Misbehaviour::from_error(&P2PError::WrongNetwork([0; 4])).is_none());
+    let malformed = Misbehaviour::from_error(&P2PError::BadChecksum).unwrap();
+    assert!(malformed.score() < BAN_THRESHOLD);
+    assert_eq!(
+        Misbehaviour::InvalidBlock(String::new()).score(),
+        BAN_THRESHOLD
+    );
+}
+
+fn start_node(config: NetworkConfig) -> Arc<Network> {
+    let state = Arc::new(Mutex::new(NodeState::in_memory(Chain::new()).unwrap()));
+    let config = NetworkConfig {
+        listen: Some("127.0.0.1:0".parse().unwrap()),
+        target_outbound: 0,
+        pow_difficulty: 1,
+        ..config
+    };
+    Network::start(config, state, Arc::new(Logger::new())).unwrap()
+}
+
+fn wait_until(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "Timed out waiting until {what}");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Whether the node closes the connection without saying anything
+fn closed_right_away(mut stream: TcpStream) -> bool {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut buffer = [0u8; 1024];
+    loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => return true,
+            Ok(_) => return false,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => return true,
+        }
+    }
+}
+
+// A connection that went through the handshake
+fn connected_peer(network: &Network) -> TcpStream {
+    let mut stream = TcpStream::connect(network.local_addr().unwrap()).unwrap();
+    let version = Version {
+        protocol_version: PROTOCOL_VERSION,
+        best_height: 1,
+        nonce: rand::random(),
+        listen_port: None,
+        user_agent: "test".to_string(),
+    };
+    stream
+        .write_all(&Message::Version(version).encode())
+        .unwrap();
+    stream.write_all(&Message::Verack.encode()).unwrap();
+    wait_until("the handshake is done", || network.peers().len() == 1);
+    stream
+}
+
+#[test]
+fn peers_sending_garbage_are_banned() {
+    let network = start_node(NetworkConfig::default());
+    let address: SocketAddr = network.local_addr().unwrap();
+
+    let mut corrupted = Message::Ping(1).encode();
+    corrupted[HEADER_LEN] ^= 0xff;
+    for _ in 0..2 {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(&corrupted).unwrap();
+        assert!(closed_right_away(stream));
+    }
+    wait_until("the address is banned", || network.is_banned(&localhost()));
+    assert_eq!(network.bans()[0].ip, localhost());
+
+    // Not even the handshake with it now
+    let stream = TcpStream::connect(address).unwrap();
+    assert!(closed_right_away(stream));
+
+    assert!(network.unban(&localhost()));
+    let stream = connected_peer(&network);
+    stream.shutdown(Shutdown::Both).unwrap();
+    network.stop();
+}
+
+#[test]
+fn headers_without_proof_of_work_get_the_peer_banned() {
+    let network = start_node(NetworkConfig::default());
+    let mut chain = Chain::new();
+    let mut unmined = Block::new(&mut chain, Vec::new());
+    while unmined.header().meets_difficulty(1) {
+        unmined = Block::new(&mut chain, Vec::new());
+    }
+
+    let mut stream = connected_peer(&network);
+    stream
+        .write_all(&Message::Headers(vec![unmined.header()]).encode())
+        .unwrap();
+    wait_until("the address is banned", || network.is_banned(&localhost()));
+    wait_until("the peer is dropped", || network.peers().is_empty());
+    network.stop();
+}
+
+#[test]
+fn blocks_without_proof_of_work_get_the_peer_banned() {
+    let state = Arc::new(Mutex::new(NodeState::in_memory(Chain::new()).unwrap()));
+    let config = NetworkConfig {
+        listen: Some("127.0.0.1:0".parse().unwrap()),
+        target_outbound: 0,
+        pow_difficulty: 1,
+        ..Default::default()
+    };
+    let network = Network::start(config, Arc::clone(&state), Arc::new(Logger::new())).unwrap();
+    // Builds on the tip, so it would be taken without being asked for
+    let mut unmined = Block::test_block(state.lock().unwrap().chain());
+    while unmined.header().meets_difficulty(1) {
+        unmined = Block::test_block(state.lock().unwrap().chain());
+    }
+
+    let mut stream = connected_peer(&network);
+    stream
+        .write_all(&Message::Block(Box::new(unmined)).encode())
+        .unwrap();
+    wait_until("the address is banned", || network.is_banned(&localhost()));
+    assert_eq!(state.lock().unwrap().chain().blocks.len(), 1);
+    network.stop();
+}
+
+#[test]
+fn floods_of_invalid_transactions_get_the_peer_banned() {
+    let network = start_node(NetworkConfig::default());
+    let mut forged = payment();
+    // Signed by someone else, for something else
+    forged.signatures = payment().signatures;
+
+    let mut stream = connected_peer(&network);
+    let frame = Message::Tx(Box::new(forged)).encode();
+    stream.write_all(&frame).unwrap();
+    wait_until("the transaction is counted", || {
+        network.misbehaviour_score(&localhost()) > 0
+    });
+    assert!(!network.is_banned(&localhost()));
+
+    for _ in 1..BAN_THRESHOLD / 10 {
+        // The connection may be gone before the last ones
+        let _ = stream.write_all(&frame);
+    }
+    wait_until("the address is banned", || network.is_banned(&localhost()));
+    assert_eq!(network.misbehaviour_score(&localhost()), 0);
+    network.stop();
+}
+
+#[test]
+fn transactions_spending_outputs_we_dont_have_dont_count() {
+    let network = start_node(NetworkConfig::default());
+    let mut stream = connected_peer(&network);
+    // Signed right, but what they spend may just not have reached us yet
+    for _ in 0..BAN_THRESHOLD / 10 {
+        let frame = Message::Tx(Box::new(payment())).encode();
+        stream.write_all(&frame).unwrap();
+    }
+    // Taken after the others, so once it counts they were all looked at
+    let mut forged = payment();
+    forged.signatures = payment().signatures;
+    stream
+        .write_all(&Message::Tx(Box::new(forged)).encode())
+        .unwrap();
+
+    wait_until("the forged transaction is counted", || {
+        network.misbehaviour_score(&localhost()) > 0
+    });
+    assert_eq!(
+        network.misbehaviour_score(&localhost()),
+        Misbehaviour::InvalidTransaction(String::new()).score()
+    );
+    assert!(!network.is_banned(&localhost()));
+    network.stop();
+}
+
+#[test]
+fn bans_are_kept_across_restarts() {
+    let path = empty_dir("ban_restart").join("bans.json");
+    let config = NetworkConfig {
+        ban_list: Some(path.clone()),
+        ..Default::default()
+    };
+    let network = start_node(config.clone());
+    network.ban(localhost(), Duration::from_secs(60), "testing");
+    network.stop();
+
+    let restarted = start_node(config);
+    assert!(restarted.is_banned(&localhost()));
+    let stream = TcpStream::connect(restarted.local_addr().unwrap()).unwrap();
+    assert!(closed_right_away(stream));
+    restarted.stop();
+}
@@ -1,6 +1,7 @@
 use cleyto_coin::{
     chain::{block::Block, Chain},
-    node::Node,
+    node::{Node, NodeOptions},
+    ConfigPaths,
 };
 
 #[test]
@@ -12,8 +13,23 @@ fn serialize_and_deserialize_node() {
     chain.add_block(Block::test_block(&chain));
     chain.add_block(Block::test_block(&chain));
 
-    let (node1, _) = Node::new(chain, "test".to_string());
+    let (node1, _) = Node::new(chain, "test".to_string()).unwrap();
     let node_json = serde_json::to_string(&node1).expect("Could not serialize node");
     println!("{}", node_json);
     let _: Node = serde_json::from_str(&node_json).expect("Could not deserialize node");
 }
+
+#[test]
+fn options_choose_where_the_http_api_listens() {
+    let dir = std::env::temp_dir().join(format!("cleyto_coin_bind_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let options = NodeOptions {
+        port: Some(18473),
+        bind: Some("0.0.0.0".parse().unwrap()),
+        ..NodeOptions::default()
+    };
+    let (node, _) = Node::open("bind".to_string(), ConfigPaths::new(&dir), options).unwrap();
+    assert_eq!(node.http_address(), "0.0.0.0:18473".parse().unwrap());
+    drop(node);
+    std::fs::remove_dir_all(dir).unwrap();
+}
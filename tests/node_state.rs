@@ -0,0 +1,371 @@
+mod common;
+
+use cleyto_coin::chain::block::Block;
+use cleyto_coin::chain::transaction::{Transaction, TransactionInfo};
+use cleyto_coin::chain::utils::BLOCK_REWARD;
+use cleyto_coin::chain::utxo::UTXO;
+use cleyto_coin::chain::utxo_set::{OutPoint, UtxoSet};
+use cleyto_coin::chain::wallet::Wallet;
+use cleyto_coin::chain::Chain;
+use cleyto_coin::error_handling::{CleytonError, TransactionError};
+use cleyto_coin::node::block_store::BlockStore;
+use cleyto_coin::node::{NodeOptions, NodeState, PoolRejection};
+use common::{empty_dir, fund_payments, next_block, payment};
+
+#[test]
+fn node_state_survives_a_restart() {
+    let dir = empty_dir("node_state_restart");
+
+    let mut state = NodeState::load(BlockStore::open(&dir).unwrap()).unwrap();
+    state.set_pow_difficulty(0);
+    assert_eq!(state.chain().blocks.len(), 1);
+
+    for _ in 0..3 {
+        let block = Block::test_block(state.chain());
+        state.accept_block(block).unwrap();
+    }
+    let hashes: Vec<String> = state.chain().blocks.iter().map(|b| b.get_hash()).collect();
+    let utxos = state.utxo_set().len();
+    assert_eq!(utxos, 3);
+    drop(state);
+
+    let state = NodeState::load(BlockStore::open(&dir).unwrap()).unwrap();
+    let restored: Vec<String> = state.chain().blocks.iter().map(|b| b.get_hash()).collect();
+    assert_eq!(restored, hashes);
+    assert_eq!(state.utxo_set().len(), utxos);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+// Height of the block the UTXO set in `chainstate.json` is at
+fn chain_state_height(dir: &std::path::Path) -> u64 {
+    let saved = std::fs::read_to_string(dir.join("chainstate.json")).unwrap();
+    serde_json::from_str::<serde_json::Value>(&saved).unwrap()["height"]
+        .as_u64()
+        .unwrap()
+}
+
+#[test]
+fn chain_state_isnt_rewritten_for_every_block() {
+    let dir = empty_dir("chain_state_interval");
+
+    let mut state = NodeState::load(BlockStore::open(&dir).unwrap()).unwrap();
+    state.set_pow_difficulty(0);
+    for _ in 0..3 {
+        let block = Block::test_block(state.chain());
+        state.accept_block(block).unwrap();
+    }
+    let utxos = state.utxo_set().len();
+    assert_eq!(chain_state_height(&dir), 0);
+    drop(state);
+
+    // The blocks after the saved one are replayed
+    let mut state = NodeState::load(BlockStore::open(&dir).unwrap()).unwrap();
+    assert_eq!(state.utxo_set().len(), utxos);
+    state.save_chain_state().unwrap();
+    assert_eq!(chain_state_height(&dir), 3);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn pruned_node_validates_new_blocks_after_a_restart() {
+    let dir = empty_dir("pruned_restart");
+    let options = NodeOptions {
+        prune: Some(2),
+        ..Default::default()
+    };
+
+    let mut state =
+        NodeState::load_with_options(BlockStore::open(&dir).unwrap(), &options).unwrap();
+    state.set_pow_difficulty(0);
+    for _ in 0..5 {
+        let block = Block::test_block(state.chain());
+        state.accept_block(block).unwrap();
+    }
+    let tip = state.chain().get_last_hash();
+    let tip_index = state.chain().get_last_index();
+    let utxos = state.utxo_set().len();
+    // Only the bodies of the last blocks stay in memory and on disk
+    assert!(state.chain().blocks.len() < 6);
+    drop(state);
+
+    let store = BlockStore::open(&dir).unwrap();
+    assert_eq!(store.len(), 6);
+    assert!(store.pruned_height() > 0);
+
+    let mut state = NodeState::load_with_options(store, &options).unwrap();
+    state.set_pow_difficulty(0);
+    assert_eq!(state.chain().get_last_hash(), tip);
+    assert_eq!(state.utxo_set().len(), utxos);
+
+    let block = Block::test_block(state.chain());
+    state.accept_block(block).unwrap();
+    assert_eq!(state.chain().get_last_index(), tip_index + 1);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn pending_transactions_survive_a_restart() {
+    let dir = empty_dir("mempool_restart");
+
+    let mut state = NodeState::load(BlockStore::open(&dir).unwrap()).unwrap();
+    state.set_pow_difficulty(0);
+    let [confirmed, pending] = fund_payments(&mut state);
+    state.add_transaction(confirmed.clone()).unwrap();
+    state.add_transaction(pending.clone()).unwrap();
+    assert!(matches!(
+        state.add_transaction(pending.clone()),
+        Err(PoolRejection::AlreadyKnown)
+    ));
+    state.save_mempool().unwrap();
+
+    // The block confirming one of them is stored, but the node goes down before saving the pool
+    let block = next_block(&state, vec![confirmed]);
+    state.accept_block(block).unwrap();
+    drop(state);
+
+    let state = NodeState::load(BlockStore::open(&dir).unwrap()).unwrap();
+    let txids: Vec<[u8; 32]> = state.transactions_pool().iter().map(|tx| tx.txid).collect();
+    assert_eq!(txids, vec![pending.txid]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn pending_transactions_whose_inputs_got_spent_are_dropped_on_restart() {
+    let dir = empty_dir("mempool_spent_inputs");
+
+    let mut state = NodeState::load(BlockStore::open(&dir).unwrap()).unwrap();
+    state.set_pow_difficulty(0);
+    let (alice, alice_pk) = Wallet::new();
+    let coinbase = Transaction::coinbase(2, vec![UTXO::new(100, alice.clone())]);
+    state
+        .accept_block(next_block(&state, vec![coinbase]))
+        .unwrap();
+
+    // Two payments out of alice's only output, one pooled and the other mined
+    let [pooled, mined] = [(); 2].map(|_| {
+        let (receiver, _) = Wallet::new();
+        let info = TransactionInfo::new(
+            vec![UTXO::new(100, alice.clone())],
+            vec![UTXO::new(99, receiver)],
+        );
+        let signatures = alice_pk.sign_transaction(&info).unwrap();
+        Transaction::new(info, signatures).unwrap()
+    });
+    state.add_transaction(pooled.clone()).unwrap();
+    state.save_mempool().unwrap();
+
+    // Stored before the node goes down, without saving the pool again
+    let block = next_block(&state, vec![mined]);
+    state.accept_block(block).unwrap();
+    assert!(state.transactions_pool().is_empty());
+    drop(state);
+
+    let mut state = NodeState::load(BlockStore::open(&dir).unwrap()).unwrap();
+    assert!(state.transactions_pool().is_empty());
+    assert!(matches!(
+        state.add_transaction(pooled),
+        Err(PoolRejection::UnavailableInputs)
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn pooled_transactions_cant_spend_the_same_output() {
+    let mut state = NodeState::in_memory(Chain::new()).unwrap();
+    state.set_pow_difficulty(0);
+    let (alice, alice_pk) = Wallet::new();
+    let coinbase = Transaction::coinbase(2, vec![UTXO::new(100, alice.clone())]);
+    state
+        .accept_block(next_block(&state, vec![coinbase]))
+        .unwrap();
+
+    let [first, second] = [(); 2].map(|_| {
+        let (receiver, _) = Wallet::new();
+        let info = TransactionInfo::new(
+            vec![UTXO::new(100, alice.clone())],
+            vec![UTXO::new(99, receiver)],
+        );
+        let signatures = alice_pk.sign_transaction(&info).unwrap();
+        Transaction::new(info, signatures).unwrap()
+    });
+    state.add_transaction(first.clone()).unwrap();
+    assert!(matches!(
+        state.add_transaction(second.clone()),
+        Err(PoolRejection::UnavailableInputs)
+    ));
+    assert_eq!(state.transactions_pool().len(), 1);
+
+    // Once the first is mined the output is gone for good
+    let block = next_block(&state, vec![first]);
+    state.accept_block(block).unwrap();
+    assert!(matches!(
+        state.add_transaction(second),
+        Err(PoolRejection::UnavailableInputs)
+    ));
+}
+
+#[test]
+fn blocks_that_dont_fit_the_chain_are_rejected() {
+    let mut state = NodeState::in_memory(Chain::new()).unwrap();
+    state.set_pow_difficulty(0);
+
+    let other_chain = {
+        let mut chain = Chain::new();
+        chain.add_block(Block::test_block(&chain));
+        chain
+    };
+    // Builds on a block this node doesn't have
+    assert!(state.accept_block(Block::test_block(&other_chain)).is_err());
+
+    // Fits, but was tampered with after its hash was calculated
+    let block = Block::test_block(state.chain());
+    let mut serialized: serde_json::Value = serde_json::to_value(&block).unwrap();
+    serialized["nonce"] = serde_json::json!(42);
+    let tampered: Block = serde_json::from_value(serialized).unwrap();
+    assert!(state.accept_block(tampered).is_err());
+
+    state.accept_block(block).unwrap();
+    assert_eq!(state.chain().blocks.len(), 2);
+}
+
+#[test]
+fn blocks_without_the_proof_of_work_are_rejected() {
+    let mut state = NodeState::in_memory(Chain::new()).unwrap();
+    state.set_pow_difficulty(2);
+
+    let mut block = Block::test_block(state.chain());
+    while block.header().meets_difficulty(2) {
+        block = Block::test_block(state.chain());
+    }
+    assert!(matches!(
+        state.accept_block(block.clone()),
+        Err(CleytonError::InvalidBlock(_))
+    ));
+
+    state.accept_block(block.mine_with_difficulty(2)).unwrap();
+    assert_eq!(state.chain().blocks.len(), 2);
+}
+
+#[test]
+fn blocks_spending_outputs_that_dont_exist_are_rejected() {
+    let mut state = NodeState::in_memory(Chain::new()).unwrap();
+    state.set_pow_difficulty(0);
+
+    let block = next_block(&state, vec![payment()]);
+    assert!(matches!(
+        state.accept_block(block),
+        Err(CleytonError::TransactionError(
+            TransactionError::UnknownInput
+        ))
+    ));
+    assert_eq!(state.chain().blocks.len(), 1);
+    assert!(state.utxo_set().is_empty());
+}
+
+#[test]
+fn outputs_spent_twice_in_a_block_are_rejected() {
+    let mut state = NodeState::in_memory(Chain::new()).unwrap();
+    state.set_pow_difficulty(0);
+    let (alice, alice_pk) = Wallet::new();
+    let coinbase = Transaction::coinbase(2, vec![UTXO::new(100, alice.clone())]);
+    state
+        .accept_block(next_block(&state, vec![coinbase]))
+        .unwrap();
+    let utxos = state.utxo_set().commitment();
+
+    // Two payments to different wallets out of the one output alice has
+    let payments: Vec<Transaction> = (0..2)
+        .map(|_| {
+            let (receiver, _) = Wallet::new();
+            let info = TransactionInfo::new(
+                vec![UTXO::new(100, alice.clone())],
+                vec![UTXO::new(99, receiver)],
+            );
+            let signatures = alice_pk.sign_transaction(&info).unwrap();
+            Transaction::new(info, signatures).unwrap()
+        })
+        .collect();
+
+    let block = next_block(&state, payments);
+    assert!(matches!(
+        state.accept_block(block),
+        Err(CleytonError::TransactionError(
+            TransactionError::DoubleSpend
+        ))
+    ));
+    assert_eq!(state.chain().blocks.len(), 2);
+    assert_eq!(state.utxo_set().commitment(), utxos);
+}
+
+#[test]
+fn coinbases_creating_more_than_the_reward_and_fees_are_rejected() {
+    let mut state = NodeState::in_memory(Chain::new()).unwrap();
+    state.set_pow_difficulty(0);
+    let [payment] = fund_payments(&mut state);
+
+    // The payment leaves a fee of 1
+    let (miner, _) = Wallet::new();
+    let height = state.chain().get_last_index() + 1;
+    let greedy = Transaction::coinbase(height, vec![UTXO::new(BLOCK_REWARD + 2, miner.clone())]);
+    let block = next_block(&state, vec![greedy, payment.clone()]);
+    assert!(matches!(
+        state.accept_block(block),
+        Err(CleytonError::InvalidBlock(_))
+    ));
+
+    let coinbase = Transaction::coinbase(height, vec![UTXO::new(BLOCK_REWARD + 1, miner)]);
+    let block = next_block(&state, vec![coinbase, payment]);
+    state.accept_block(block).unwrap();
+}
+
+#[test]
+fn utxo_set_follows_spends() {
+    let (alice, alice_pk) = Wallet::new();
+    let (bob, _) = Wallet::new();
+
+    let mut set = UtxoSet::new();
+    let coinbase = Transaction::coinbase(2, vec![UTXO::new(100, alice.clone())]);
+    assert!(set.apply_transaction(&coinbase).unwrap().is_empty());
+
+    let info = TransactionInfo::new(
+        vec![UTXO::new(100, alice.clone())],
+        vec![UTXO::new(60, alice.clone()), UTXO::new(40, bob.clone())],
+    );
+    let signatures = alice_pk.sign_transaction(&info).unwrap();
+    let first = Transaction::new(info, signatures).unwrap();
+
+    let spent = set.apply_transaction(&first).unwrap();
+    assert_eq!(spent[0].0, OutPoint::new(coinbase.txid, 0));
+    assert_eq!(set.len(), 2);
+    assert_eq!(
+        set.get(&OutPoint::new(first.txid, 1)).unwrap(),
+        &UTXO::new(40, bob.clone())
+    );
+
+    let info = TransactionInfo::new(
+        vec![UTXO::new(60, alice.clone())],
+        vec![UTXO::new(60, bob.address())],
+    );
+    let signatures = alice_pk.sign_transaction(&info).unwrap();
+    let second = Transaction::new(info, signatures).unwrap();
+
+    let spent = set.apply_transaction(&second).unwrap();
+    assert_eq!(spent.len(), 1);
+    assert_eq!(spent[0].0, OutPoint::new(first.txid, 0));
+    assert_eq!(set.len(), 2);
+    assert!(set.find(&UTXO::new(60, alice.clone())).is_none());
+    assert!(set.find(&UTXO::new(60, bob)).is_some());
+
+    // The output is gone, and a failed spend leaves the set as it was
+    let commitment = set.commitment();
+    assert!(matches!(
+        set.apply_transaction(&second),
+        Err(TransactionError::UnknownInput)
+    ));
+    assert_eq!(set.commitment(), commitment);
+}
@@ -0,0 +1,408 @@
+mod common;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cleyto_coin::chain::block::Block;
+use cleyto_coin::chain::Chain;
+use cleyto_coin::node::logger::Logger;
+use cleyto_coin::node::p2p::errors::P2PError;
+use cleyto_coin::node::p2p::message::{
+    FrameDecoder, InvItem, Message, Version, HEADER_LEN, MAX_INV_ITEMS,
+};
+use cleyto_coin::node::p2p::peer::{Direction, Peer, PeerEvent, PeerTimings, PROTOCOL_VERSION};
+use cleyto_coin::node::p2p::{Network, NetworkConfig};
+use cleyto_coin::node::NodeState;
+use common::{fund_payments, payment};
+
+fn version(nonce: u64, best_height: u64) -> Version {
+    Version {
+        protocol_version: PROTOCOL_VERSION,
+        best_height,
+        nonce,
+        listen_port: None,
+        user_agent: "test".to_string(),
+    }
+}
+
+fn decode_all(bytes: &[u8]) -> Vec<Message> {
+    let mut decoder = FrameDecoder::new();
+    decoder.push(bytes);
+    let mut messages = Vec::new();
+    while let Some(message) = decoder.next_message().unwrap() {
+        messages.push(message);
+    }
+    messages
+}
+
+// Delivers every Send to the other peer until neither has anything left to say
+fn exchange(
+    a: &mut Peer,
+    b: &mut Peer,
+    mut to_b: Vec<PeerEvent>,
+    now: Instant,
+) -> (Vec<PeerEvent>, Vec<PeerEvent>) {
+    let (mut seen_by_a, mut seen_by_b) = (Vec::new(), Vec::new());
+    let mut to_a = Vec::new();
+    while !to_a.is_empty() || !to_b.is_empty() {
+        for event in std::mem::take(&mut to_b) {
+            match event {
+                PeerEvent::Send(message) => to_a.extend(b.receive(message, now)),
+                other => seen_by_a.push(other),
+            }
+        }
+        let mut next_to_b = Vec::new();
+        for event in std::mem::take(&mut to_a) {
+            match event {
+                PeerEvent::Send(message) => next_to_b.extend(a.receive(message, now)),
+                other => seen_by_b.push(other),
+            }
+        }
+        to_b = next_to_b;
+    }
+    (seen_by_a, seen_by_b)
+}
+
+fn handshake(now: Instant) -> (Peer, Peer) {
+    let (mut outbound, events) = Peer::new(
+        Direction::Outbound,
+        version(1, 5),
+        PeerTimings::default(),
+        now,
+    );
+    let (mut inbound, _) = Peer::new(
+        Direction::Inbound,
+        version(2, 7),
+        PeerTimings::default(),
+        now,
+    );
+    let (outbound_events, inbound_events) = exchange(&mut outbound, &mut inbound, events, now);
+    assert!(
+        matches!(outbound_events.as_slice(), [PeerEvent::Ready(remote)] if *remote == version(2, 7))
+    );
+    assert!(
+        matches!(inbound_events.as_slice(), [PeerEvent::Ready(remote)] if *remote == version(1, 5))
+    );
+    (outbound, inbound)
+}
+
+#[test]
+fn frames_roundtrip() {
+    let mut chain = Chain::new();
+    let block = Block::new(&mut chain, vec![payment()]);
+    let items = vec![InvItem::block(&block), InvItem::transaction(&payment())];
+    let messages = vec![
+        Message::Version(version(42, 3)),
+        Message::Verack,
+        Message::Ping(7),
+        Message::Pong(7),
+        Message::Inv(items.clone()),
+        Message::GetData(items.clone()),
+        Message::NotFound(items),
+        Message::Tx(Box::new(payment())),
+        Message::Block(Box::new(block)),
+    ];
+    // Blocks and transactions can't be compared, their frames can
+    let bytes: Vec<u8> = messages.iter().flat_map(Message::encode).collect();
+    let decoded: Vec<u8> = decode_all(&bytes)
+        .iter()
+        .flat_map(Message::encode)
+        .collect();
+    assert_eq!(decoded, bytes);
+    assert_eq!(decode_all(&bytes).len(), messages.len());
+}
+
+#[test]
+fn frames_split_across_reads_are_put_back_together() {
+    let bytes = [
+        Message::Version(version(1, 2)).encode(),
+        Message::Ping(9).encode(),
+    ]
+    .concat();
+    let mut decoder = FrameDecoder::new();
+    let mut messages = Vec::new();
+    for byte in bytes {
+        decoder.push(&[byte]);
+        if let Some(message) = decoder.next_message().unwrap() {
+            messages.push(message);
+        }
+    }
+    assert!(matches!(
+        messages.as_slice(),
+        [Message::Version(sent), Message::Ping(9)] if *sent == version(1, 2)
+    ));
+}
+
+#[test]
+fn corrupted_payloads_fail_the_checksum() {
+    let mut bytes = Message::Ping(1234).encode();
+    bytes[HEADER_LEN] ^= 0xff;
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&bytes);
+    assert!(matches!(decoder.next_message(), Err(P2PError::BadChecksum)));
+}
+
+#[test]
+fn frames_from_other_networks_are_rejected() {
+    let mut bytes = Message::Verack.encode();
+    bytes[0] = 0;
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&bytes);
+    assert!(matches!(
+        decoder.next_message(),
+        Err(P2PError::WrongNetwork(_))
+    ));
+}
+
+#[test]
+fn peers_finish_the_handshake() {
+    let (outbound, inbound) = handshake(Instant::now());
+    assert!(outbound.is_ready() && inbound.is_ready());
+    assert_eq!(outbound.remote().unwrap().best_height, 7);
+    assert_eq!(inbound.remote().unwrap().best_height, 5);
+}
+
+#[test]
+fn pings_are_answered_and_measured() {
+    let start = Instant::now();
+    let (mut outbound, mut inbound) = handshake(start);
+
+    let later = start + PeerTimings::default().ping_interval;
+    let ping = outbound.tick(later);
+    assert!(matches!(
+        ping.as_slice(),
+        [PeerEvent::Send(Message::Ping(_))]
+    ));
+    let PeerEvent::Send(ping) = ping.into_iter().next().unwrap() else {
+        unreachable!()
+    };
+    let pong = inbound.receive(ping, later);
+    let PeerEvent::Send(pong) = pong.into_iter().next().unwrap() else {
+        panic!("The ping wasn't answered")
+    };
+
+    let answered = later + Duration::from_millis(15);
+    assert!(outbound.receive(pong, answered).is_empty());
+    assert_eq!(outbound.latency(), Some(Duration::from_millis(15)));
+    assert!(outbound.tick(answered).is_empty());
+}
+
+#[test]
+fn unanswered_pings_drop_the_peer() {
+    let start = Instant::now();
+    let (mut outbound, _) = handshake(start);
+    let timings = PeerTimings::default();
+
+    let sent = start + timings.ping_interval;
+    assert_eq!(outbound.tick(sent).len(), 1);
+    let events = outbound.tick(sent + timings.ping_timeout + Duration::from_secs(1));
+    assert!(matches!(events.as_slice(), [PeerEvent::Disconnect(_)]));
+}
+
+#[test]
+fn messages_before_the_handshake_drop_the_peer() {
+    let now = Instant::now();
+    for message in [Message::Ping(1), Message::Inv(Vec::new())] {
+        let (mut inbound, _) = Peer::new(
+            Direction::Inbound,
+            version(1, 0),
+            PeerTimings::default(),
+            now,
+        );
+        let events = inbound.receive(message, now);
+        assert!(matches!(events.as_slice(), [PeerEvent::Disconnect(_)]));
+    }
+}
+
+#[test]
+fn oversized_inventories_are_rejected() {
+    let items = vec![InvItem::Tx("00".to_string()); MAX_INV_ITEMS + 1];
+    let mut decoder = FrameDecoder::new();
+    decoder.push(&Message::Inv(items).encode());
+    assert!(matches!(
+        decoder.next_message(),
+        Err(P2PError::ProtocolViolation(_))
+    ));
+}
+
+#[test]
+fn gossip_is_handed_to_the_node_after_the_handshake() {
+    let (mut outbound, _) = handshake(Instant::now());
+    let events = outbound.receive(Message::Inv(Vec::new()), Instant::now());
+    assert!(matches!(
+        events.as_slice(),
+        [PeerEvent::Received(Message::Inv(_))]
+    ));
+}
+
+#[test]
+fn connecting_to_ourselves_is_detected() {
+    let now = Instant::now();
+    let (mut outbound, events) = Peer::new(
+        Direction::Outbound,
+        version(3, 0),
+        PeerTimings::default(),
+        now,
+    );
+    let PeerEvent::Send(own_version) = events.into_iter().next().unwrap() else {
+        unreachable!()
+    };
+    let events = outbound.receive(own_version, now);
+    assert!(matches!(events.as_slice(), [PeerEvent::Disconnect(_)]));
+}
+
+#[test]
+fn slow_handshakes_time_out() {
+    let now = Instant::now();
+    let timings = PeerTimings::default();
+    let (mut inbound, _) = Peer::new(Direction::Inbound, version(1, 0), timings, now);
+    assert!(inbound.tick(now + timings.handshake_timeout / 2).is_empty());
+    let events = inbound.tick(now + timings.handshake_timeout + Duration::from_secs(1));
+    assert!(matches!(events.as_slice(), [PeerEvent::Disconnect(_)]));
+}
+
+fn start_node(blocks: usize, config: NetworkConfig) -> (Arc<Mutex<NodeState>>, Arc<Network>) {
+    let mut chain = Chain::new();
+    for _ in 0..blocks {
+        let block = Block::new(&mut chain, Vec::new());
+        chain.add_block(block);
+    }
+    let state = Arc::new(Mutex::new(NodeState::in_memory(chain).unwrap()));
+    let network = Network::start(config, Arc::clone(&state), Arc::new(Logger::new())).unwrap();
+    (state, network)
+}
+
+fn wait_until(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "Timed out waiting until {what}");
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+fn nodes_on_localhost_connect_and_keep_alive() {
+    let timings = PeerTimings {
+        ping_interval: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let localhost: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let (_, listening) = start_node(
+        2,
+        NetworkConfig {
+            listen: Some(localhost),
+            timings,
+            ..listening_on_localhost()
+        },
+    );
+    let (_, connecting) = start_node(
+        0,
+        NetworkConfig {
+            listen: Some(localhost),
+            connect: vec![listening.local_addr().unwrap()],
+            timings,
+            ..listening_on_localhost()
+        },
+    );
+
+    wait_until("both nodes see each other", || {
+        listening.peers().len() == 1 && connecting.peers().len() == 1
+    });
+    let outbound = connecting.peers().list().remove(0);
+    assert_eq!(outbound.direction, Direction::Outbound);
+    // The heights at the handshake, the connecting node syncs the blocks it's missing after it
+    let genesis = Chain::new().get_last_index();
+    assert_eq!(outbound.version.as_ref().unwrap().best_height, genesis + 2);
+    let inbound = listening.peers().list().remove(0);
+    assert_eq!(inbound.direction, Direction::Inbound);
+    assert_eq!(inbound.version.as_ref().unwrap().best_height, genesis);
+    assert_eq!(
+        inbound.version.as_ref().unwrap().listen_port,
+        Some(connecting.local_addr().unwrap().port())
+    );
+
+    wait_until("a ping is answered", || {
+        connecting.peers().list()[0].latency.is_some()
+    });
+
+    connecting.stop();
+    wait_until("the peers are dropped", || {
+        listening.peers().is_empty() && connecting.peers().is_empty()
+    });
+    listening.stop();
+}
+
+// Test blocks aren't mined
+fn listening_on_localhost() -> NetworkConfig {
+    NetworkConfig {
+        listen: Some("127.0.0.1:0".parse().unwrap()),
+        pow_difficulty: 0,
+        // Only the connections each test asks for
+        target_outbound: 0,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn transactions_and_blocks_are_relayed_through_the_network() {
+    // a <- b <- c, so what a accepts has to go through b to get to c
+    let (a, a_network) = start_node(0, listening_on_localhost());
+    // Something to spend, in a block the others download when they connect
+    let [transaction] = fund_payments(&mut a.lock().unwrap());
+    let funding = a.lock().unwrap().chain().get_last_hash();
+    let (b, b_network) = start_node(
+        0,
+        NetworkConfig {
+            connect: vec![a_network.local_addr().unwrap()],
+            ..listening_on_localhost()
+        },
+    );
+    // Had c connected first, b could download it in the middle of their handshake, too early to
+    // announce it to c and too late to tell c about it in its version
+    wait_until("b downloads the funding block", || {
+        b.lock().unwrap().has_block(&funding)
+    });
+    let (c, c_network) = start_node(
+        0,
+        NetworkConfig {
+            connect: vec![b_network.local_addr().unwrap()],
+            ..listening_on_localhost()
+        },
+    );
+    wait_until("the nodes are connected", || {
+        a_network.peers().len() == 1 && b_network.peers().len() == 2 && c_network.peers().len() == 1
+    });
+    wait_until("c downloads the funding block", || {
+        c.lock().unwrap().has_block(&funding)
+    });
+
+    a.lock()
+        .unwrap()
+        .add_transaction(transaction.clone())
+        .unwrap();
+    for state in [&b, &c] {
+        wait_until("the transaction is pooled everywhere", || {
+            state
+                .lock()
+                .unwrap()
+                .transactions_pool()
+                .iter()
+                .any(|pooled| pooled.txid == transaction.txid)
+        });
+    }
+
+    let block = Block::test_block(a.lock().unwrap().chain());
+    let hash = block.get_hash();
+    a.lock().unwrap().accept_block(block).unwrap();
+    for state in [&b, &c] {
+        wait_until("the block is accepted everywhere", || {
+            state.lock().unwrap().has_block(&hash)
+        });
+    }
+
+    for network in [a_network, b_network, c_network] {
+        network.stop();
+    }
+}
@@ -30,7 +30,7 @@ fn thread_post(n: u16) {
         Err(e) => panic!("Error creating signed message: {e}"),
     };
 
-    let new_transaction = Transaction::new(wallet1, wallet2, transaction_info, signature).unwrap();
+    let new_transaction = Transaction::new(transaction_info, signature).unwrap();
     let json_transaction = new_transaction.serialize();
     println!("json_transaction is:\n{}", json_transaction);
 
@@ -0,0 +1,139 @@
+use cleyto_coin::chain::key_type::KeyType;
+use cleyto_coin::chain::schnorr::{self, SchnorrNonce};
+use cleyto_coin::chain::transaction::{Transaction, TransactionInfo};
+use cleyto_coin::chain::utxo::UTXO;
+use cleyto_coin::chain::wallet::Wallet;
+
+#[test]
+fn aggregated_signature_covers_inputs_from_cooperating_keys() {
+    let owners: Vec<_> = (0..3).map(|_| Wallet::new()).collect();
+    let (receiver, _) = Wallet::new();
+
+    // Two inputs from every owner
+    let inputs = owners
+        .iter()
+        .flat_map(|(wallet, _)| {
+            vec![
+                UTXO::new(100, wallet.clone()),
+                UTXO::new(50, wallet.clone()),
+            ]
+        })
+        .collect();
+    let transaction_info = TransactionInfo::new(inputs, vec![UTXO::new(450, receiver)]);
+    assert_eq!(transaction_info.signers().unwrap().len(), 3);
+
+    // Keys in any order
+    let private_keys = vec![&owners[2].1, &owners[0].1, &owners[1].1];
+    let signature = schnorr::sign_transaction(&transaction_info, &private_keys).unwrap();
+    assert_eq!(signature.len(), 65);
+
+    let transaction = Transaction::new_aggregated(transaction_info.clone(), signature).unwrap();
+    assert!(transaction.signatures.is_empty());
+
+    // Survives a round trip, and is smaller than signing every input
+    let serialized = transaction.serialize();
+    let deserialized: Transaction = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(deserialized.txid, transaction.txid);
+    assert_eq!(
+        deserialized.aggregate_signature,
+        transaction.aggregate_signature
+    );
+
+    let signatures = (0..transaction_info.inputs.len())
+        .map(|index| {
+            owners[index / 2]
+                .1
+                .sign_input(&transaction_info, index, Default::default())
+                .unwrap()
+        })
+        .collect();
+    let separately_signed = Transaction::new(transaction_info, signatures).unwrap();
+    assert!(serialized.len() < separately_signed.serialize().len());
+
+    // Without one of the owners there's no signature
+    assert!(schnorr::sign_transaction(
+        &deserialized.transaction_info,
+        &[&owners[0].1, &owners[1].1]
+    )
+    .is_err());
+}
+
+#[test]
+fn aggregated_signature_is_rejected_when_tampered_with() {
+    let (sender, sender_pk) = Wallet::new();
+    let (receiver, _) = Wallet::new();
+    let (thief, _) = Wallet::new();
+
+    let transaction_info = TransactionInfo::new(
+        vec![UTXO::new(1000, sender.clone())],
+        vec![UTXO::new(1000, receiver)],
+    );
+    let signature = schnorr::sign_transaction(&transaction_info, &[&sender_pk]).unwrap();
+
+    let mut stolen = transaction_info.clone();
+    stolen.outputs = vec![UTXO::new(1000, thief)];
+    assert!(Transaction::new_aggregated(stolen, signature.clone()).is_err());
+
+    let mut redated = transaction_info.clone();
+    redated.date += chrono::Duration::seconds(1);
+    assert!(Transaction::new_aggregated(redated, signature.clone()).is_err());
+
+    // Older versions don't know about aggregated signatures
+    let mut old = transaction_info.clone();
+    old.version = 2;
+    let old_signature = schnorr::sign_transaction(&old, &[&sender_pk]).unwrap();
+    assert!(Transaction::new_aggregated(old, old_signature).is_err());
+
+    let mut bad_s = signature.clone();
+    bad_s.s[31] ^= 1;
+    assert!(Transaction::new_aggregated(transaction_info.clone(), bad_s).is_err());
+
+    Transaction::new_aggregated(transaction_info, signature).unwrap();
+}
+
+#[test]
+fn aggregation_needs_secp256k1_keys() {
+    let (ed_wallet, ed_pk) = Wallet::new_with_key_type(KeyType::Ed25519);
+    let (ec_wallet, ec_pk) = Wallet::new_with_key_type(KeyType::Secp256k1);
+
+    let transaction_info = TransactionInfo::new(
+        vec![
+            UTXO::new(1000, ed_wallet),
+            UTXO::new(1000, ec_wallet.clone()),
+        ],
+        vec![UTXO::new(2000, ec_wallet)],
+    );
+    assert!(schnorr::sign_transaction(&transaction_info, &[&ed_pk, &ec_pk]).is_err());
+}
+
+#[test]
+fn signing_in_rounds() {
+    let (alice, alice_pk) = Wallet::new();
+    let (bob, bob_pk) = Wallet::new();
+    let signers = vec![alice, bob];
+    let message = b"pay the rent";
+
+    let alice_nonce = SchnorrNonce::new().unwrap();
+    let bob_nonce = SchnorrNonce::new().unwrap();
+    let nonce = schnorr::aggregate_nonces(&[alice_nonce.public(), bob_nonce.public()]).unwrap();
+
+    let alice_part = schnorr::partial_sign(&alice_pk, alice_nonce, &nonce, &signers, 0, message)
+        .unwrap()
+        .unwrap();
+    let bob_part = schnorr::partial_sign(&bob_pk, bob_nonce, &nonce, &signers, 1, message)
+        .unwrap()
+        .unwrap();
+
+    let signature = schnorr::aggregate_partial_signatures(&nonce, &[alice_part, bob_part]).unwrap();
+    assert!(schnorr::verify(&signers, message, &signature).unwrap());
+    assert!(!schnorr::verify(&signers, b"pay the bills", &signature).unwrap());
+    assert!(!schnorr::verify(&signers[..1], message, &signature).unwrap());
+
+    // A key can't sign for someone else's place
+    let stray_nonce = SchnorrNonce::new().unwrap();
+    assert!(
+        schnorr::partial_sign(&alice_pk, stray_nonce, &nonce, &signers, 1, message)
+            .unwrap()
+            .is_none()
+    );
+}
@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use cleyto_coin::chain::transaction::{Transaction, TransactionInfo};
+use cleyto_coin::chain::utils::BLOCK_REWARD;
+use cleyto_coin::chain::utxo::UTXO;
+use cleyto_coin::chain::wallet::Wallet;
+use cleyto_coin::node::p2p::peer::PeerTimings;
+use cleyto_coin::node::p2p::{SimConfig, Simulation};
+
+// `count` nodes, each connected to the one before it
+fn line(config: SimConfig, count: usize) -> Simulation {
+    let mut simulation = Simulation::new(config);
+    for i in 0..count {
+        simulation.add_node().unwrap();
+        if i > 0 {
+            simulation.connect(i, i - 1);
+        }
+    }
+    let connected = simulation.run_until(Duration::from_secs(5), |simulation| {
+        simulation
+            .nodes()
+            .iter()
+            .enumerate()
+            .all(|(i, node)| node.network().peers().len() == expected_peers(i, count))
+    });
+    assert!(connected, "The nodes didn't finish their handshakes");
+    simulation
+}
+
+fn expected_peers(i: usize, count: usize) -> usize {
+    usize::from(i > 0) + usize::from(i + 1 < count)
+}
+
+// Has the node mine a block, waits for every node to have it, and returns a payment out of its
+// coinbase
+fn mined_payment(simulation: &mut Simulation, node: usize) -> Transaction {
+    let hash = simulation.mine(node).unwrap();
+    let synced = simulation.run_until(Duration::from_secs(10), |simulation| {
+        simulation.nodes().iter().all(|node| node.tip() == hash)
+    });
+    assert!(synced);
+
+    let (miner, miner_pk) = simulation.node(node).wallet();
+    let (receiver, _) = Wallet::new();
+    let info = TransactionInfo::new(
+        vec![UTXO::new(BLOCK_REWARD, miner.clone())],
+        vec![UTXO::new(BLOCK_REWARD - 1, receiver)],
+    );
+    let signatures = miner_pk.sign_transaction(&info).unwrap();
+    Transaction::new(info, signatures).unwrap()
+}
+
+#[test]
+fn gossip_takes_the_simulated_latency_of_every_hop() {
+    let latency = Duration::from_millis(100);
+    let config = SimConfig {
+        latency,
+        ..Default::default()
+    };
+    let mut simulation = line(config, 4);
+
+    let transaction = mined_payment(&mut simulation, 0);
+    let submitted_at = simulation.elapsed();
+    assert!(simulation.submit_transaction(0, transaction.clone()));
+    let everywhere = simulation.run_until(Duration::from_secs(10), |simulation| {
+        simulation
+            .nodes()
+            .iter()
+            .all(|node| node.has_transaction(&transaction))
+    });
+    assert!(everywhere);
+    // inv, getdata and tx on each of the 3 hops
+    assert!(simulation.elapsed() - submitted_at >= latency * 9);
+
+    let hash = simulation.mine(0).unwrap();
+    let synced = simulation.run_until(Duration::from_secs(10), |simulation| {
+        simulation.nodes().iter().all(|node| node.tip() == hash)
+    });
+    assert!(synced);
+    assert!(!simulation.node(3).has_transaction(&transaction));
+}
+
+#[test]
+fn blocks_mined_during_a_partition_arrive_once_it_heals() {
+    let mut simulation = line(SimConfig::default(), 2);
+
+    simulation.partition(&[&[0], &[1]]);
+    simulation.mine(0).unwrap();
+    simulation.run_for(Duration::from_secs(5));
+    assert_eq!(simulation.node(1).height(), simulation.node(0).height() - 1);
+
+    // The next block doesn't build on the tip of the other side, which catches up headers first
+    simulation.heal();
+    let tip = simulation.mine(0).unwrap();
+    let caught_up = simulation.run_until(Duration::from_secs(10), |simulation| {
+        simulation.node(1).tip() == tip
+    });
+    assert!(caught_up);
+}
+
+#[test]
+fn peers_that_stop_answering_time_out_in_simulated_time() {
+    let timings = PeerTimings::default();
+    let config = SimConfig {
+        timings,
+        ..Default::default()
+    };
+    let mut simulation = line(config, 2);
+
+    simulation.set_drop_rate(1.0);
+    let timeout = timings.ping_interval + timings.ping_timeout + Duration::from_secs(1);
+    let dropped = simulation.run_until(timeout, |simulation| {
+        simulation
+            .nodes()
+            .iter()
+            .all(|node| node.network().peers().is_empty())
+    });
+    assert!(dropped);
+    assert!(simulation.elapsed() > timings.ping_interval);
+}
+
+#[test]
+fn the_same_seed_drops_the_same_messages() {
+    let run = || {
+        let mut simulation = line(SimConfig::default(), 3);
+        let transactions: Vec<Transaction> = (0..4)
+            .map(|i| mined_payment(&mut simulation, i % 3))
+            .collect();
+        simulation.set_drop_rate(0.5);
+        for (i, transaction) in transactions.iter().enumerate() {
+            simulation.submit_transaction(i % 3, transaction.clone());
+        }
+        simulation.run_for(Duration::from_secs(5));
+        let pooled: Vec<Vec<bool>> = simulation
+            .nodes()
+            .iter()
+            .map(|node| {
+                transactions
+                    .iter()
+                    .map(|transaction| node.has_transaction(transaction))
+                    .collect()
+            })
+            .collect();
+        (pooled, simulation.elapsed())
+    };
+    assert_eq!(run(), run());
+}
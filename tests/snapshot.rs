@@ -0,0 +1,96 @@
+mod common;
+
+use std::path::PathBuf;
+
+use cleyto_coin::chain::block::Block;
+use cleyto_coin::node::block_store::BlockStore;
+use cleyto_coin::node::snapshot::{SnapshotBase, UtxoSnapshot};
+use cleyto_coin::node::NodeState;
+use common::empty_dir;
+
+// A node with a few blocks on top of the genesis one
+fn full_node(dir: &PathBuf) -> NodeState {
+    let mut state = NodeState::load(BlockStore::open(dir).unwrap()).unwrap();
+    state.set_pow_difficulty(0);
+    for _ in 0..4 {
+        let block = Block::test_block(state.chain());
+        state.accept_block(block).unwrap();
+    }
+    state
+}
+
+#[test]
+fn fresh_node_starts_from_a_snapshot() {
+    let full_dir = empty_dir("snapshot_full");
+    let fresh_dir = empty_dir("snapshot_fresh");
+    let snapshot_dir = empty_dir("snapshot_file");
+    let snapshot_path = snapshot_dir.join("snapshot.json");
+
+    let state = full_node(&full_dir);
+    let tip = state.chain().get_last_hash();
+    let utxos = state.utxo_set().len();
+    drop(state);
+
+    let snapshot = UtxoSnapshot::create(&BlockStore::open(&full_dir).unwrap(), None).unwrap();
+    assert_eq!(snapshot.height, 4);
+    snapshot.write(&snapshot_path).unwrap();
+    let commitment = snapshot.commitment.clone();
+
+    let mut fresh_store = BlockStore::open(&fresh_dir).unwrap();
+    let base = UtxoSnapshot::read(&snapshot_path)
+        .unwrap()
+        .load_into(&mut fresh_store, Some(&commitment))
+        .unwrap();
+    assert_eq!(base.hash, tip);
+    assert_eq!(fresh_store.len(), 5);
+
+    // The fresh node has no history, but builds on the snapshot right away
+    let mut state = NodeState::load(fresh_store).unwrap();
+    state.set_pow_difficulty(0);
+    assert_eq!(state.chain().get_last_hash(), tip);
+    assert_eq!(state.utxo_set().len(), utxos);
+    let block = Block::test_block(state.chain());
+    state.accept_block(block).unwrap();
+    drop(state);
+
+    // Once the history checks out, the data directory forgets about the snapshot
+    let history = BlockStore::open(&full_dir).unwrap();
+    base.verify_history(&fresh_dir, &history).unwrap();
+    assert_eq!(SnapshotBase::read(&fresh_dir).unwrap(), None);
+
+    for path in [full_dir, fresh_dir, snapshot_dir] {
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
+
+#[test]
+fn snapshots_that_dont_check_out_are_rejected() {
+    let full_dir = empty_dir("snapshot_reject_full");
+    let fresh_dir = empty_dir("snapshot_reject_fresh");
+
+    drop(full_node(&full_dir));
+    let store = BlockStore::open(&full_dir).unwrap();
+    // Not at the tip, so the blocks up to it are replayed
+    let snapshot = UtxoSnapshot::create(&store, Some(2)).unwrap();
+    assert_eq!(snapshot.headers.len(), 2);
+    snapshot.verify().unwrap();
+
+    let mut fresh_store = BlockStore::open(&fresh_dir).unwrap();
+    let mut tampered = UtxoSnapshot::create(&store, Some(2)).unwrap();
+    tampered.utxo_set = UtxoSnapshot::create(&store, Some(3)).unwrap().utxo_set;
+    assert!(tampered.load_into(&mut fresh_store, None).is_err());
+
+    let unexpected = UtxoSnapshot::create(&store, Some(2)).unwrap();
+    assert!(unexpected
+        .load_into(&mut fresh_store, Some("not the commitment"))
+        .is_err());
+    assert!(fresh_store.is_empty());
+
+    // The data directory has to be empty
+    let mut full_store = BlockStore::open(&full_dir).unwrap();
+    assert!(snapshot.load_into(&mut full_store, None).is_err());
+
+    for path in [full_dir, fresh_dir] {
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
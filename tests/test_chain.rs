@@ -1,5 +1,5 @@
 use cleyto_coin::chain::testing::test_chain;
-use cleyto_coin::node::data::{remove_block_by_hash, write_chain_blocks};
+use cleyto_coin::node::block_store::BlockStore;
 
 #[test]
 fn creating_test_chain() {
@@ -11,13 +11,23 @@ fn writing_test_chain() {
     use cleyto_coin::chain::testing::test_chain;
     let chain = test_chain();
 
+    let dir = std::env::temp_dir().join(format!("cleyto_coin_test_chain_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let mut store = BlockStore::open(&dir).unwrap();
+
     let hashes: Vec<String> = chain.blocks.iter().map(|block| block.get_hash()).collect();
 
-    write_chain_blocks(&chain).unwrap();
+    for block in &chain.blocks {
+        store.append(block).unwrap();
+    }
 
-    for hash in hashes {
-        remove_block_by_hash(hash).unwrap();
+    // Blocks are only removed from the tip down
+    for hash in hashes.into_iter().rev() {
+        assert_eq!(store.pop().unwrap().unwrap().get_hash(), hash);
     }
+    assert!(store.is_empty());
+
+    std::fs::remove_dir_all(dir).unwrap();
 }
 
 #[test]
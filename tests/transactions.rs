@@ -1,3 +1,4 @@
+use cleyto_coin::chain::sighash::{SigHash, SigHashType};
 use cleyto_coin::chain::transaction::{Transaction, TransactionInfo};
 use cleyto_coin::chain::utxo::UTXO;
 use cleyto_coin::chain::wallet::Wallet;
@@ -27,17 +28,18 @@ fn create_transaction() {
     );
 
     // this will also be verified by the Transaction::new();
-    if wallet_sender
-        .verify_transaction_info(&transaction_info, &signature)
-        .unwrap()
-    {
-        println!("transaction verified (by the wallet)");
-    } else {
-        println!("transaction not verified");
+    for (index, input_signature) in signature.iter().enumerate() {
+        if wallet_sender
+            .verify_transaction_info(&transaction_info, index, input_signature)
+            .unwrap()
+        {
+            println!("input {index} verified (by the wallet)");
+        } else {
+            println!("input {index} not verified");
+        }
     }
 
-    let transaction: Transaction =
-        Transaction::new(wallet_sender, wallet_receiver, transaction_info, signature).unwrap();
+    let transaction: Transaction = Transaction::new(transaction_info, signature).unwrap();
 
     println!("transaction.to_string(): {}", transaction);
 }
@@ -84,13 +86,10 @@ fn sign_and_verify_transaction_info() {
         signature
     );
 
-    if wallet_sender
-        .verify_transaction_info(&transaction_info, &signature)
-        .unwrap()
-    {
-        println!("transaction verified (by the wallet)");
-    } else {
-        println!("transaction not verified");
+    for (index, input_signature) in signature.iter().enumerate() {
+        assert!(wallet_sender
+            .verify_transaction_info(&transaction_info, index, input_signature)
+            .unwrap());
     }
 }
 
@@ -114,10 +113,183 @@ fn serialize_and_deserialize_transaction() {
         _ => panic!("error while signing transaction"),
     };
 
-    let transaction = Transaction::new(wallet, mallet, transaction_info, signature).unwrap();
+    let transaction = Transaction::new(transaction_info, signature).unwrap();
 
     let serialized_transaction = transaction.serialize();
     println!("serialized_transaction: \n{serialized_transaction}");
 
     let _: Transaction = serde_json::from_str(&serialized_transaction).unwrap();
 }
+
+#[test]
+fn transaction_with_inputs_from_two_owners() {
+    let (alice, alice_pk) = Wallet::new();
+    let (bob, bob_pk) = Wallet::new();
+    let (shop, _) = Wallet::new();
+
+    let input_utxos = vec![UTXO::new(1000, alice.clone()), UTXO::new(2000, bob.clone())];
+    let output_utxos = vec![UTXO::new(3000, shop.clone())];
+    let transaction_info = TransactionInfo::new(input_utxos, output_utxos);
+
+    // Each owner only signs the input they own
+    let signatures = vec![
+        alice_pk
+            .sign_input(&transaction_info, 0, SigHash::ALL)
+            .unwrap(),
+        bob_pk
+            .sign_input(&transaction_info, 1, SigHash::ALL)
+            .unwrap(),
+    ];
+    Transaction::new(transaction_info.clone(), signatures).unwrap();
+
+    // Alice can't sign for Bob's input
+    let signatures = alice_pk.sign_transaction(&transaction_info).unwrap();
+    assert!(Transaction::new(transaction_info, signatures).is_err());
+}
+
+#[test]
+fn sighash_flags_select_what_is_committed() {
+    let (alice, alice_pk) = Wallet::new();
+    let (bob, bob_pk) = Wallet::new();
+    let (shop, _) = Wallet::new();
+    let (thief, _) = Wallet::new();
+
+    // SIGHASH_ALL breaks as soon as an output changes
+    let transaction_info = TransactionInfo::new(
+        vec![UTXO::new(1000, alice.clone())],
+        vec![UTXO::new(1000, shop.clone())],
+    );
+    let signatures = alice_pk.sign_transaction(&transaction_info).unwrap();
+    let mut tampered_info = transaction_info.clone();
+    tampered_info.outputs = vec![UTXO::new(1000, thief.clone())];
+    assert!(Transaction::new(tampered_info, signatures.clone()).is_err());
+
+    // And so does the date
+    let mut redated_info = transaction_info.clone();
+    redated_info.date += chrono::Duration::seconds(1);
+    assert!(Transaction::new(redated_info, signatures).is_err());
+
+    // SIGHASH_NONE lets whoever finishes the transaction pick the outputs
+    let signatures = vec![alice_pk
+        .sign_input(&transaction_info, 0, SigHash::NONE)
+        .unwrap()];
+    let mut finished_info = transaction_info.clone();
+    finished_info.outputs = vec![UTXO::new(1000, thief.clone())];
+    Transaction::new(finished_info, signatures).unwrap();
+
+    // SIGHASH_SINGLE needs an output with the same index as the input
+    let transaction_info = TransactionInfo::new(
+        vec![
+            UTXO::new(1000, alice.clone()),
+            UTXO::new(500, alice.clone()),
+        ],
+        vec![UTXO::new(1200, shop.clone())],
+    );
+    assert!(alice_pk
+        .sign_input(&transaction_info, 1, SigHash::SINGLE)
+        .is_err());
+
+    // SIGHASH_ALL|ANYONECANPAY lets other people add inputs afterwards
+    let crowdfunding = SigHash::new(SigHashType::All, true);
+    let transaction_info = TransactionInfo::new(
+        vec![UTXO::new(1000, alice.clone())],
+        vec![UTXO::new(3000, shop.clone())],
+    );
+    let alice_signature = alice_pk
+        .sign_input(&transaction_info, 0, crowdfunding)
+        .unwrap();
+
+    let mut funded_info = transaction_info.clone();
+    funded_info.inputs.push(UTXO::new(2000, bob.clone()));
+    let bob_signature = bob_pk.sign_input(&funded_info, 1, crowdfunding).unwrap();
+    Transaction::new(funded_info, vec![alice_signature.clone(), bob_signature]).unwrap();
+
+    // Without ANYONECANPAY, adding an input invalidates the first signature
+    let alice_signature = alice_pk
+        .sign_input(&transaction_info, 0, SigHash::ALL)
+        .unwrap();
+    let mut funded_info = transaction_info.clone();
+    funded_info.inputs.push(UTXO::new(2000, bob.clone()));
+    let bob_signature = bob_pk.sign_input(&funded_info, 1, SigHash::ALL).unwrap();
+    assert!(Transaction::new(funded_info, vec![alice_signature, bob_signature]).is_err());
+}
+
+#[test]
+fn legacy_sighash_commits_to_the_whole_transaction_info() {
+    let (wallet, _) = Wallet::new();
+    let transaction_info = TransactionInfo::new(
+        vec![UTXO::new(1000, wallet.clone())],
+        vec![UTXO::new(1000, wallet.clone())],
+    );
+    // Even with flags no old transaction had
+    for anyone_can_pay in [false, true] {
+        let sighash = SigHash::new(SigHashType::Legacy, anyone_can_pay);
+        assert_eq!(
+            transaction_info.sighash_preimage(0, sighash),
+            Some(transaction_info.to_string())
+        );
+    }
+    assert_eq!(transaction_info.sighash_preimage(1, SigHash::LEGACY), None);
+}
+
+#[test]
+fn transaction_needs_one_signature_per_input() {
+    let (wallet, wallet_pk) = Wallet::new();
+    let (receiver, _) = Wallet::new();
+
+    let transaction_info = TransactionInfo::new(
+        vec![
+            UTXO::new(1000, wallet.clone()),
+            UTXO::new(2000, wallet.clone()),
+        ],
+        vec![UTXO::new(3000, receiver.clone())],
+    );
+    let mut signatures = wallet_pk.sign_transaction(&transaction_info).unwrap();
+    signatures.pop();
+
+    assert!(Transaction::new(transaction_info, signatures).is_err());
+}
+
+#[test]
+fn deserialize_transaction_in_old_format() {
+    let (sender, sender_pk) = Wallet::new();
+    let (receiver, _) = Wallet::new();
+
+    let transaction_info = TransactionInfo::new(
+        vec![
+            UTXO::new(1000, sender.clone()),
+            UTXO::new(2000, sender.clone()),
+        ],
+        vec![UTXO::new(3000, receiver.clone())],
+    );
+    // Old transactions had a single signature over the whole TransactionInfo
+    let old_signature = sender_pk
+        .sign_input(&transaction_info, 0, SigHash::LEGACY)
+        .unwrap()
+        .signature;
+    let old_txid = [7u8; 32];
+
+    let old_transaction = serde_json::json!({
+        "sender": sender,
+        "receiver": receiver,
+        "signature": old_signature,
+        "transaction_info": transaction_info,
+        "txid": old_txid,
+    });
+
+    let transaction: Transaction = serde_json::from_value(old_transaction).unwrap();
+
+    assert!(transaction.is_legacy());
+    assert_eq!(transaction.txid, old_txid);
+    assert_eq!(transaction.signatures.len(), 2);
+    for (index, signature) in transaction.signatures.iter().enumerate() {
+        assert!(sender
+            .verify_transaction_info(&transaction.transaction_info, index, signature)
+            .unwrap());
+    }
+
+    // Once read, it's written in the current format
+    let reserialized: serde_json::Value = serde_json::from_str(&transaction.serialize()).unwrap();
+    assert!(reserialized.get("sender").is_none());
+    assert!(reserialized.get("signatures").is_some());
+}
@@ -0,0 +1,60 @@
+mod common;
+
+use std::path::PathBuf;
+
+use cleyto_coin::error_handling::CleytonError;
+use cleyto_coin::node::block_store::BlockStore;
+use cleyto_coin::node::{NodeOptions, NodeState};
+use common::{empty_dir, fund_payments, next_block};
+
+fn with_tx_index(dir: &PathBuf) -> NodeState {
+    let options = NodeOptions {
+        txindex: true,
+        ..Default::default()
+    };
+    let mut state = NodeState::load_with_options(BlockStore::open(dir).unwrap(), &options).unwrap();
+    state.set_pow_difficulty(0);
+    state
+}
+
+#[test]
+fn confirmed_transactions_are_found_by_txid() {
+    let dir = empty_dir("tx_index");
+    let mut state = with_tx_index(&dir);
+
+    let [other, first, second, pending] = fund_payments(&mut state);
+    for transactions in [vec![other, first.clone()], vec![second.clone()]] {
+        let block = next_block(&state, transactions);
+        state.accept_block(block).unwrap();
+    }
+    state.add_transaction(pending.clone()).unwrap();
+
+    let found = state.find_transaction(&first.txid).unwrap().unwrap();
+    assert_eq!(found.transaction.txid, first.txid);
+    assert_eq!(found.block_hash, Some(state.chain().blocks[2].get_hash()));
+    assert_eq!(found.confirmations, 2);
+
+    let found = state.find_transaction(&pending.txid).unwrap().unwrap();
+    assert_eq!(found.block_hash, None);
+    assert_eq!(found.confirmations, 0);
+    assert!(state.find_transaction(&[0; 32]).unwrap().is_none());
+    drop(state);
+
+    // Saved across restarts, and rebuilt from the blocks if it's lost
+    for _ in 0..2 {
+        let state = with_tx_index(&dir);
+        let found = state.find_transaction(&second.txid).unwrap().unwrap();
+        assert_eq!(found.confirmations, 1);
+        drop(state);
+        std::fs::remove_file(dir.join("txindex.json")).unwrap();
+    }
+
+    // Without the index, only the pool is searched
+    let state = NodeState::load(BlockStore::open(&dir).unwrap()).unwrap();
+    assert!(matches!(
+        state.find_transaction(&second.txid),
+        Err(CleytonError::TxIndexDisabled)
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}